@@ -0,0 +1,82 @@
+// Micro-benchmarks of kernel code, run with 'cargo bench'. Each one is a Bench
+// static, and is run by rustos::bench::bench_runner, which prints the cycles
+// per iteration over serial.
+// ---
+// Interrupts are left disabled, so that timer and keyboard interrupts don't
+// land in the middle of a sample. The only interrupts are the ones the
+// benchmarks raise themselves.
+// ---
+// The kernel doesn't have a heap allocator yet, so once it does, a benchmark
+// of allocating and freeing should be added here.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(abi_x86_interrupt)]
+#![test_runner(rustos::bench::bench_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use rustos::bench::{Bench, Bencher};
+use rustos::bench_fn;
+use rustos::console::{Console, RING_BUFFER};
+use rustos::vga_buffer::WRITER;
+
+lazy_static! {
+    static ref BENCH_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rustos::gdt::init();
+    BENCH_IDT.load();
+
+    test_main();
+
+    rustos::hlt_loop();
+}
+
+// Does nothing, so that the breakpoint benchmark only measures getting into
+// and out of an interrupt handler.
+extern "x86-interrupt" fn breakpoint_handler(
+    _stack_frame: &mut InterruptStackFrame,
+) {
+}
+
+// The cost of writing a new line at the bottom of the VGA text buffer, which
+// moves every line up by one.
+fn bench_new_line(bencher: &mut Bencher) {
+    let mut writer = WRITER.lock();
+    bencher.iter(|| writer.write_byte(b'\n'));
+}
+
+#[test_case]
+static NEW_LINE: Bench = bench_fn!(bench_new_line);
+
+// The round trip of an interrupt: the CPU pushing the interrupt stack frame
+// and jumping to the handler, and the handler returning with iretq.
+fn bench_interrupt_latency(bencher: &mut Bencher) {
+    bencher.iter(x86_64::instructions::interrupts::int3);
+}
+
+#[test_case]
+static INTERRUPT_LATENCY: Bench = bench_fn!(bench_interrupt_latency);
+
+// The cost of formatting a short line into the console ring buffer, which
+// every line of output pays.
+fn bench_ring_buffer_write(bencher: &mut Bencher) {
+    bencher.iter(|| RING_BUFFER.write(format_args!("bench {}\n", 42)));
+}
+
+#[test_case]
+static RING_BUFFER_WRITE: Bench = bench_fn!(bench_ring_buffer_write);
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
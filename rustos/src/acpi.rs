@@ -0,0 +1,825 @@
+// The Advanced Configuration and Power Interface (ACPI) tables, which the
+// firmware leaves in memory to describe the parts of the machine which can't
+// be found by probing for them: how many CPUs there are, where the interrupt
+// controllers and timers live, and how to power the machine off.
+// ---
+// Everything starts from the Root System Description Pointer (RSDP), which the
+// firmware puts on a 16-byte boundary in either the first KiB of the Extended
+// BIOS Data Area (EBDA), or the BIOS area from 0xe0000 to 0xfffff. It starts
+// with the signature 'RSD PTR ', and holds the physical address of the Root
+// System Description Table (RSDT), which has a 32-bit address for every other
+// table, or, from ACPI 2.0, the Extended System Description Table (XSDT),
+// which has 64-bit addresses instead.
+// ---
+// Every table starts with the same 36-byte header:
+//
+// +--------+--------+------------------------------------------------------+
+// | Offset |  Size  |                       Contents                       |
+// +--------+--------+------------------------------------------------------+
+// |    0   |    4   | Signature, such as 'APIC' or 'FACP'                  |
+// |    4   |    4   | Length of the table, including the header            |
+// |    8   |    1   | Revision                                             |
+// |    9   |    1   | Checksum, which makes every byte of the table add up |
+// |        |        | to zero                                              |
+// |   10   |    6   | OEM ID                                               |
+// |   16   |    8   | OEM table ID                                         |
+// |   24   |    4   | OEM revision                                         |
+// |   28   |    4   | Creator ID                                           |
+// |   32   |    4   | Creator revision                                     |
+// +--------+--------+------------------------------------------------------+
+//
+// We parse the tables the rest of the kernel needs:
+// - MADT ('APIC'): The CPUs, each with its local APIC, the I/O APICs, and how
+//               the legacy ISA interrupts are wired to them.
+// - FADT ('FACP'): The power management I/O ports, the ACPI PM timer, and
+//               how to reset the machine.
+// - HPET ('HPET'): Where the High Precision Event Timer is.
+// - MCFG ('MCFG'): Where the memory mapped PCI Express configuration space
+//               is.
+// The tables are read through the mapping of physical memory, so this only
+// works once memory::init has been called. As we have no heap, the lists in
+// the tables are kept in fixed-size arrays, and anything past the end of them
+// is dropped, with a warning.
+
+use core::fmt;
+use spin::Once;
+use x86_64::PhysAddr;
+use crate::memory;
+
+const HEADER_SIZE: usize = 36;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+// The real mode segment of the EBDA is kept in the BIOS Data Area at 0x40e.
+const EBDA_POINTER: u64 = 0x40e;
+const EBDA_SEARCH_SIZE: usize = 1024;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_SIZE: usize = 0x20000;
+
+pub const MAX_PROCESSORS: usize = 64;
+pub const MAX_IO_APICS: usize = 8;
+pub const MAX_OVERRIDES: usize = 16;
+pub const MAX_NMIS: usize = 16;
+pub const MAX_MCFG_ENTRIES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    // memory::init hasn't been called, so the tables can't be read.
+    PhysicalMemoryNotMapped,
+    RsdpNotFound,
+    BadChecksum([u8; 4]),
+
+    // A table is shorter than it has to be, or one of its entries runs past
+    // its end.
+    Truncated([u8; 4]),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::PhysicalMemoryNotMapped => {
+                write!(f, "physical memory isn't mapped")
+            }
+            AcpiError::RsdpNotFound => write!(f, "no RSDP was found"),
+            AcpiError::BadChecksum(signature) => {
+                write!(f, "the {} table has a bad checksum", name(signature))
+            }
+            AcpiError::Truncated(signature) => {
+                write!(f, "the {} table is truncated", name(signature))
+            }
+        }
+    }
+}
+
+fn name(signature: &[u8]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+// Everything found in the tables.
+pub struct Acpi {
+    pub rsdp: Rsdp,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+static ACPI: Once<Result<Acpi, AcpiError>> = Once::new();
+
+// Find and parse the tables. They are only read the first time this is
+// called, which has to be after memory::init.
+pub fn init() -> Result<&'static Acpi, AcpiError> {
+    ACPI.call_once(parse).as_ref().map_err(|error| *error)
+}
+
+// The tables, if init has found them.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.r#try()?.as_ref().ok()
+}
+
+fn parse() -> Result<Acpi, AcpiError> {
+    let rsdp = find_rsdp()?;
+    let mut acpi = Acpi { rsdp, madt: None, fadt: None, hpet: None,
+                          mcfg: None };
+
+    let (root, entry_size) = match rsdp.xsdt_address {
+        Some(address) => (read_table(address)?, 8),
+        None => (read_table(u64::from(rsdp.rsdt_address))?, 4),
+    };
+
+    for entry in root[HEADER_SIZE..].chunks_exact(entry_size) {
+        let address = match entry_size {
+            8 => read_u64(entry, 0),
+            _ => u64::from(read_u32(entry, 0)),
+        };
+
+        // A broken table is skipped, rather than losing every other one.
+        let parsed = read_table(address).and_then(|table| {
+            match &table[..4] {
+                b"APIC" => acpi.madt = Some(Madt::parse(table)?),
+                b"FACP" => acpi.fadt = Some(Fadt::parse(table)?),
+                b"HPET" => acpi.hpet = Some(Hpet::parse(table)?),
+                b"MCFG" => acpi.mcfg = Some(Mcfg::parse(table)?),
+                _ => {}
+            }
+            Ok(())
+        });
+        if let Err(error) = parsed {
+            log::warn!("skipping the ACPI table at {:#x}: {}", address, error);
+        }
+    }
+
+    Ok(acpi)
+}
+
+// The bytes of physical memory at the given address.
+fn physical(address: u64, len: usize) -> Result<&'static [u8], AcpiError> {
+    let address = memory::phys_to_virt(PhysAddr::new(address))
+        .ok_or(AcpiError::PhysicalMemoryNotMapped)?;
+    Ok(unsafe { core::slice::from_raw_parts(address.as_ptr(), len) })
+}
+
+// The table at the given physical address, after checking its checksum.
+fn read_table(address: u64) -> Result<&'static [u8], AcpiError> {
+    let header = physical(address, HEADER_SIZE)?;
+    let mut signature = [0; 4];
+    signature.copy_from_slice(&header[..4]);
+
+    let length = read_u32(header, 4) as usize;
+    if length < HEADER_SIZE {
+        return Err(AcpiError::Truncated(signature));
+    }
+
+    let table = physical(address, length)?;
+    if checksum(table) != 0 {
+        return Err(AcpiError::BadChecksum(signature));
+    }
+
+    Ok(table)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+// Add an item to a fixed-size list, dropping it if the list is full.
+fn push<T>(list: &mut [T], count: &mut usize, item: T, what: &str) {
+    match list.get_mut(*count) {
+        Some(slot) => {
+            *slot = item;
+            *count += 1;
+        }
+        None => log::warn!("ACPI: too many {}, ignoring the rest", what),
+    }
+}
+
+
+// RSDP
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt_address: u32,
+
+    // Only ACPI 2.0 and later have an XSDT.
+    pub xsdt_address: Option<u64>,
+}
+
+impl Rsdp {
+    // Parse an RSDP, if the bytes start with one.
+    fn parse(bytes: &[u8]) -> Option<Rsdp> {
+        if bytes.len() < RSDP_V1_SIZE || &bytes[..8] != RSDP_SIGNATURE
+                || checksum(&bytes[..RSDP_V1_SIZE]) != 0 {
+            return None;
+        }
+
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        let revision = bytes[15];
+
+        // The extended part has a checksum of its own.
+        let xsdt_address = if revision >= 2 && bytes.len() >= RSDP_V2_SIZE
+                && checksum(&bytes[..RSDP_V2_SIZE]) == 0 {
+            Some(read_u64(bytes, 24)).filter(|address| *address != 0)
+        } else {
+            None
+        };
+
+        Some(Rsdp { revision, oem_id, rsdt_address: read_u32(bytes, 16),
+                    xsdt_address })
+    }
+
+    pub fn oem_id(&self) -> &str {
+        name(&self.oem_id).trim_end()
+    }
+}
+
+fn find_rsdp() -> Result<Rsdp, AcpiError> {
+    let ebda = u64::from(read_u16(physical(EBDA_POINTER, 2)?, 0)) << 4;
+    let areas = [(ebda, EBDA_SEARCH_SIZE), (BIOS_AREA_START, BIOS_AREA_SIZE)];
+
+    for (start, size) in areas.iter() {
+        if *start == 0 {
+            continue;
+        }
+
+        let area = physical(*start, *size)?;
+        for offset in (0..size - RSDP_V1_SIZE).step_by(16) {
+            if let Some(rsdp) = Rsdp::parse(&area[offset..]) {
+                return Ok(rsdp);
+            }
+        }
+    }
+
+    Err(AcpiError::RsdpNotFound)
+}
+
+
+// MADT
+
+// The polarity and trigger mode of an interrupt. Conforming means whatever is
+// usual for the bus, which for ISA is active high and edge triggered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Conforming,
+    Edge,
+    Level,
+}
+
+// Both are packed into the MPS INTI flags of an entry: polarity in bits 0-1,
+// and trigger mode in bits 2-3.
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::Conforming,
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::Conforming,
+    };
+
+    (polarity, trigger)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    // The ID the rest of ACPI uses for the processor.
+    pub processor_uid: u32,
+    pub apic_id: u32,
+
+    // A processor which isn't enabled can't be started. If it is online
+    // capable, it could be hot-plugged later.
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+
+    // The first Global System Interrupt (GSI) the I/O APIC handles. Each
+    // input of the I/O APIC handles the next GSI on.
+    pub gsi_base: u32,
+}
+
+// An ISA interrupt which isn't wired to the GSI with the same number, or
+// doesn't use the ISA polarity and trigger mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub bus: u8,
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+// Which local interrupt pin (LINT0 or LINT1) the Non-Maskable Interrupt is
+// connected to, on one processor, or every processor if processor_uid is None.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    pub processor_uid: Option<u32>,
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+// The Multiple APIC Description Table.
+pub struct Madt {
+    // The physical address of every CPU's local APIC.
+    pub local_apic_address: u64,
+
+    // Whether the machine also has the legacy 8259 PICs, which need masking
+    // before the APICs are used.
+    pub legacy_pics: bool,
+
+    processors: [Processor; MAX_PROCESSORS],
+    processor_count: usize,
+    io_apics: [IoApic; MAX_IO_APICS],
+    io_apic_count: usize,
+    overrides: [InterruptOverride; MAX_OVERRIDES],
+    override_count: usize,
+    nmis: [LocalApicNmi; MAX_NMIS],
+    nmi_count: usize,
+}
+
+const MADT_PROCESSOR: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_APIC_ADDRESS: u8 = 5;
+const MADT_X2APIC_PROCESSOR: u8 = 9;
+const MADT_X2APIC_NMI: u8 = 10;
+
+const EMPTY_PROCESSOR: Processor = Processor {
+    processor_uid: 0, apic_id: 0, enabled: false, online_capable: false,
+};
+const EMPTY_IO_APIC: IoApic = IoApic { id: 0, address: 0, gsi_base: 0 };
+const EMPTY_OVERRIDE: InterruptOverride = InterruptOverride {
+    bus: 0, irq: 0, gsi: 0, polarity: Polarity::Conforming,
+    trigger: TriggerMode::Conforming,
+};
+const EMPTY_NMI: LocalApicNmi = LocalApicNmi {
+    processor_uid: None, lint: 0, polarity: Polarity::Conforming,
+    trigger: TriggerMode::Conforming,
+};
+
+impl Madt {
+    // The table is made up of the header, the local APIC address and flags,
+    // followed by a list of entries, each starting with its type and length.
+    fn parse(table: &[u8]) -> Result<Madt, AcpiError> {
+        let truncated = AcpiError::Truncated(*b"APIC");
+        if table.len() < HEADER_SIZE + 8 {
+            return Err(truncated);
+        }
+
+        let mut madt = Madt {
+            local_apic_address: u64::from(read_u32(table, 36)),
+            legacy_pics: read_u32(table, 40) & 1 != 0,
+            processors: [EMPTY_PROCESSOR; MAX_PROCESSORS],
+            processor_count: 0,
+            io_apics: [EMPTY_IO_APIC; MAX_IO_APICS],
+            io_apic_count: 0,
+            overrides: [EMPTY_OVERRIDE; MAX_OVERRIDES],
+            override_count: 0,
+            nmis: [EMPTY_NMI; MAX_NMIS],
+            nmi_count: 0,
+        };
+
+        let mut offset = HEADER_SIZE + 8;
+        while offset + 2 <= table.len() {
+            let kind = table[offset];
+            let length = table[offset + 1] as usize;
+            if length < 2 || offset + length > table.len() {
+                return Err(truncated);
+            }
+
+            madt.parse_entry(kind, &table[offset..offset + length]);
+            offset += length;
+        }
+
+        Ok(madt)
+    }
+
+    // Entries which are too short for their type, or of types we don't use,
+    // are skipped.
+    fn parse_entry(&mut self, kind: u8, entry: &[u8]) {
+        match kind {
+            MADT_PROCESSOR if entry.len() >= 8 => {
+                let flags = read_u32(entry, 4);
+                push(&mut self.processors, &mut self.processor_count,
+                     Processor {
+                         processor_uid: u32::from(entry[2]),
+                         apic_id: u32::from(entry[3]),
+                         enabled: flags & 1 != 0,
+                         online_capable: flags & 2 != 0,
+                     }, "processors");
+            }
+
+            MADT_X2APIC_PROCESSOR if entry.len() >= 16 => {
+                let flags = read_u32(entry, 8);
+                push(&mut self.processors, &mut self.processor_count,
+                     Processor {
+                         processor_uid: read_u32(entry, 12),
+                         apic_id: read_u32(entry, 4),
+                         enabled: flags & 1 != 0,
+                         online_capable: flags & 2 != 0,
+                     }, "processors");
+            }
+
+            MADT_IO_APIC if entry.len() >= 12 => {
+                push(&mut self.io_apics, &mut self.io_apic_count, IoApic {
+                    id: entry[2],
+                    address: read_u32(entry, 4),
+                    gsi_base: read_u32(entry, 8),
+                }, "I/O APICs");
+            }
+
+            MADT_INTERRUPT_OVERRIDE if entry.len() >= 10 => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 8));
+                push(&mut self.overrides, &mut self.override_count,
+                     InterruptOverride {
+                         bus: entry[2],
+                         irq: entry[3],
+                         gsi: read_u32(entry, 4),
+                         polarity,
+                         trigger,
+                     }, "interrupt overrides");
+            }
+
+            MADT_LOCAL_APIC_NMI if entry.len() >= 6 => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 3));
+                push(&mut self.nmis, &mut self.nmi_count, LocalApicNmi {
+                    processor_uid: Some(u32::from(entry[2]))
+                        .filter(|uid| *uid != 0xff),
+                    lint: entry[5],
+                    polarity,
+                    trigger,
+                }, "NMIs");
+            }
+
+            MADT_X2APIC_NMI if entry.len() >= 12 => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 2));
+                push(&mut self.nmis, &mut self.nmi_count, LocalApicNmi {
+                    processor_uid: Some(read_u32(entry, 4))
+                        .filter(|uid| *uid != 0xffff_ffff),
+                    lint: entry[8],
+                    polarity,
+                    trigger,
+                }, "NMIs");
+            }
+
+            // A 64-bit address for the local APICs, replacing the one in the
+            // table's header.
+            MADT_LOCAL_APIC_ADDRESS if entry.len() >= 12 => {
+                self.local_apic_address = read_u64(entry, 4);
+            }
+
+            _ => {}
+        }
+    }
+
+    pub fn processors(&self) -> &[Processor] {
+        &self.processors[..self.processor_count]
+    }
+
+    pub fn io_apics(&self) -> &[IoApic] {
+        &self.io_apics[..self.io_apic_count]
+    }
+
+    pub fn overrides(&self) -> &[InterruptOverride] {
+        &self.overrides[..self.override_count]
+    }
+
+    pub fn nmis(&self) -> &[LocalApicNmi] {
+        &self.nmis[..self.nmi_count]
+    }
+
+    // How an ISA interrupt is wired: the override for it if there is one, or
+    // otherwise the GSI with the same number, with the ISA defaults.
+    pub fn isa_interrupt(&self, irq: u8) -> InterruptOverride {
+        self.overrides().iter()
+            .find(|entry| entry.bus == 0 && entry.irq == irq)
+            .copied()
+            .unwrap_or(InterruptOverride { bus: 0, irq, gsi: u32::from(irq),
+                                           ..EMPTY_OVERRIDE })
+    }
+}
+
+
+// FADT
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfiguration,
+    Other(u8),
+}
+
+// The Generic Address Structure, which ACPI uses for registers which could be
+// in memory, I/O ports or elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    const SIZE: usize = 12;
+
+    fn parse(bytes: &[u8]) -> GenericAddress {
+        GenericAddress {
+            address_space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfiguration,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+// The Fixed ACPI Description Table. The I/O port fields are 0 if the machine
+// doesn't have that block of registers.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    // The physical address of the Differentiated System Description Table,
+    // which holds the AML code describing the rest of the machine.
+    pub dsdt_address: u64,
+
+    // The interrupt ACPI events arrive on, as an ISA IRQ.
+    pub sci_interrupt: u16,
+
+    // Writing acpi_enable to the SMI command port switches the machine from
+    // legacy mode into ACPI mode. A port of 0 means it is always in ACPI mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+
+    // The ACPI PM timer, which counts at 3.579545MHz. It is 24 bits wide,
+    // unless pm_timer_32bit is set.
+    pub pm_timer_block: u32,
+    pub pm_timer_32bit: bool,
+
+    // The index of the century register in the CMOS RTC, or 0 if there isn't
+    // one.
+    pub century_register: u8,
+
+    // The IA-PC boot architecture flags, such as whether there is an 8042
+    // keyboard controller. Only ACPI 2.0 and later have them.
+    pub boot_flags: u16,
+
+    // Writing reset_value to the reset register resets the machine.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+const FADT_V1_SIZE: usize = 116;
+const FADT_FLAG_TIMER_32BIT: u32 = 1 << 8;
+const FADT_FLAG_RESET_SUPPORTED: u32 = 1 << 10;
+
+impl Fadt {
+    fn parse(table: &[u8]) -> Result<Fadt, AcpiError> {
+        if table.len() < FADT_V1_SIZE {
+            return Err(AcpiError::Truncated(*b"FACP"));
+        }
+
+        let flags = read_u32(table, 112);
+        let mut fadt = Fadt {
+            dsdt_address: u64::from(read_u32(table, 40)),
+            sci_interrupt: read_u16(table, 46),
+            smi_command_port: read_u32(table, 48),
+            acpi_enable: table[52],
+            acpi_disable: table[53],
+            pm1a_event_block: read_u32(table, 56),
+            pm1b_event_block: read_u32(table, 60),
+            pm1a_control_block: read_u32(table, 64),
+            pm1b_control_block: read_u32(table, 68),
+            pm_timer_block: read_u32(table, 76),
+            pm_timer_32bit: flags & FADT_FLAG_TIMER_32BIT != 0,
+            century_register: table[108],
+            boot_flags: 0,
+            reset_register: None,
+            reset_value: 0,
+        };
+
+        // The fields added by ACPI 2.0 are only there if the table is long
+        // enough to hold them.
+        if table.len() >= 129 {
+            fadt.boot_flags = read_u16(table, 109);
+            if flags & FADT_FLAG_RESET_SUPPORTED != 0 {
+                let register = &table[116..116 + GenericAddress::SIZE];
+                fadt.reset_register = Some(GenericAddress::parse(register));
+                fadt.reset_value = table[128];
+            }
+        }
+        if table.len() >= 148 && read_u64(table, 140) != 0 {
+            fadt.dsdt_address = read_u64(table, 140);
+        }
+
+        Ok(fadt)
+    }
+}
+
+
+// HPET
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    // The physical address of the HPET's registers.
+    pub base_address: u64,
+    pub hpet_number: u8,
+
+    // The smallest period, in main counter ticks, a timer can be set to in
+    // periodic mode without losing interrupts.
+    pub minimum_tick: u16,
+
+    pub comparator_count: u8,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    pub vendor_id: u16,
+}
+
+impl Hpet {
+    fn parse(table: &[u8]) -> Result<Hpet, AcpiError> {
+        if table.len() < HEADER_SIZE + 20 {
+            return Err(AcpiError::Truncated(*b"HPET"));
+        }
+
+        let block_id = read_u32(table, 36);
+        let address = GenericAddress::parse(&table[40..40 + 12]);
+
+        Ok(Hpet {
+            base_address: address.address,
+            hpet_number: table[52],
+            minimum_tick: read_u16(table, 53),
+            comparator_count: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_64bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            vendor_id: (block_id >> 16) as u16,
+        })
+    }
+}
+
+
+// MCFG
+
+// A range of PCI buses, in one PCI segment group, whose configuration space is
+// mapped into memory. Each bus takes 1MiB, starting at base_address for bus 0,
+// even if start_bus is later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+pub struct Mcfg {
+    entries: [McfgEntry; MAX_MCFG_ENTRIES],
+    entry_count: usize,
+}
+
+const MCFG_ENTRY_SIZE: usize = 16;
+
+impl Mcfg {
+    // The header is followed by 8 reserved bytes, and then the entries.
+    fn parse(table: &[u8]) -> Result<Mcfg, AcpiError> {
+        if table.len() < HEADER_SIZE + 8 {
+            return Err(AcpiError::Truncated(*b"MCFG"));
+        }
+
+        let empty = McfgEntry { base_address: 0, segment_group: 0,
+                                start_bus: 0, end_bus: 0 };
+        let mut mcfg = Mcfg { entries: [empty; MAX_MCFG_ENTRIES],
+                              entry_count: 0 };
+
+        for entry in table[HEADER_SIZE + 8..].chunks_exact(MCFG_ENTRY_SIZE) {
+            push(&mut mcfg.entries, &mut mcfg.entry_count, McfgEntry {
+                base_address: read_u64(entry, 0),
+                segment_group: read_u16(entry, 8),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            }, "MCFG entries");
+        }
+
+        Ok(mcfg)
+    }
+
+    pub fn entries(&self) -> &[McfgEntry] {
+        &self.entries[..self.entry_count]
+    }
+}
+
+
+// TESTING
+
+// Test that an RSDP is only accepted with the right signature and checksum, and
+// that the XSDT address is only used from revision 2.
+#[test_case]
+fn test_rsdp_parse() {
+    let mut bytes = [0; RSDP_V2_SIZE];
+    bytes[..8].copy_from_slice(RSDP_SIGNATURE);
+    bytes[9..15].copy_from_slice(b"BOCHS ");
+    bytes[16..20].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    bytes[8] = 0u8.wrapping_sub(checksum(&bytes[..RSDP_V1_SIZE]));
+
+    let rsdp = Rsdp::parse(&bytes).expect("RSDP wasn't parsed");
+    assert_eq!(rsdp.rsdt_address, 0x1234_5678);
+    assert_eq!(rsdp.xsdt_address, None);
+    assert_eq!(rsdp.oem_id(), "BOCHS");
+
+    bytes[8] = bytes[8].wrapping_add(1);
+    assert!(Rsdp::parse(&bytes).is_none());
+}
+
+// Test that the entries of a MADT are parsed, and that ISA interrupts without
+// an override map to the GSI with the same number.
+#[test_case]
+fn test_madt_parse() {
+    let mut table = [0; HEADER_SIZE + 8 + 8 + 12 + 10];
+    table[..4].copy_from_slice(b"APIC");
+    table[36..40].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+    table[40] = 1;
+
+    // A processor, UID 0 and APIC ID 1, which is enabled.
+    table[44..52].copy_from_slice(&[0, 8, 0, 1, 1, 0, 0, 0]);
+
+    // An I/O APIC at 0xfec00000, handling GSIs from 0.
+    table[52..64].copy_from_slice(&[1, 12, 2, 0, 0, 0, 0xc0, 0xfe,
+                                    0, 0, 0, 0]);
+
+    // IRQ 0 is wired to GSI 2, active high and edge triggered.
+    table[64..74].copy_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0b0101, 0]);
+
+    let madt = Madt::parse(&table).expect("MADT wasn't parsed");
+    assert_eq!(madt.local_apic_address, 0xfee0_0000);
+    assert!(madt.legacy_pics);
+    assert_eq!(madt.processors(), &[Processor {
+        processor_uid: 0, apic_id: 1, enabled: true, online_capable: false,
+    }]);
+    assert_eq!(madt.io_apics(), &[IoApic {
+        id: 2, address: 0xfec0_0000, gsi_base: 0,
+    }]);
+    assert_eq!(madt.isa_interrupt(0).gsi, 2);
+    assert_eq!(madt.isa_interrupt(0).polarity, Polarity::ActiveHigh);
+    assert_eq!(madt.isa_interrupt(0).trigger, TriggerMode::Edge);
+    assert_eq!(madt.isa_interrupt(4).gsi, 4);
+
+    // An entry running past the end of the table is an error.
+    table[65] = 11;
+    assert!(Madt::parse(&table).is_err());
+}
+
+// Test that QEMU's tables are found, and describe at least one processor and
+// I/O APIC, and the power management registers.
+#[test_case]
+fn test_acpi_qemu_tables() {
+    let acpi = init().expect("ACPI tables weren't found");
+    let madt = acpi.madt.as_ref().expect("no MADT");
+
+    assert!(!madt.processors().is_empty());
+    assert!(!madt.io_apics().is_empty());
+    assert_ne!(acpi.fadt.expect("no FADT").pm1a_control_block, 0);
+}
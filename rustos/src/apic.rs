@@ -0,0 +1,446 @@
+// The Advanced Programmable Interrupt Controller (APIC), which took over from
+// the 8259 PICs once machines had more than one CPU. It is split in two:
+// - Local APIC: One in every CPU, which takes interrupts for that CPU, and
+//               has its own timer. It is also how CPUs interrupt each other,
+//               with Inter-Processor Interrupts (IPIs).
+// - I/O APIC: Takes the interrupts from devices, and sends each to a local
+//               APIC, with a vector set in its redirection table. See the
+//               io_apic module.
+//
+// The MADT ACPI table says where they are, and how the ISA interrupts are
+// wired to the I/O APICs' inputs, so the APICs are only used once the ACPI
+// tables have been found. Otherwise, or with 'noapic' on the kernel command
+// line, the kernel carries on with the PICs.
+// ---
+// The local APIC's registers are 32 bits wide, each at a 16-byte aligned
+// offset. It works in one of two modes:
+// - xAPIC: The registers are memory mapped, normally at 0xfee00000, which we
+//               access through the mapping of physical memory.
+// - x2APIC: The registers are Model Specific Registers (MSRs), from 0x800 on,
+//               one for every 16 bytes of the xAPIC layout. x2APIC also widens
+//               the APIC ID to 32 bits. CPUs which support it have bit 21 of
+//               ECX set in CPUID leaf 1.
+// Both are turned on through the IA32_APIC_BASE MSR.
+// ---
+// The local APIC timer counts down from a starting count at a rate which
+// depends on the CPU's bus clock, so it has to be measured against a clock
+// whose rate is known before it is any use. We time it against channel 2 of
+// the PIT, which can be polled without needing an interrupt, and then set it
+// to fire TIMER_HZ times a second.
+// ---
+// CPUs send each other Inter-Processor Interrupts (IPIs) by writing to the
+// Interrupt Command Register (ICR), which is 64 bits, with the destination
+// APIC ID in the top half. In xAPIC mode it is two registers, and writing the
+// bottom half sends the IPI. In x2APIC mode it is one MSR. Instead of an APIC
+// ID, the ICR can use a shorthand to send an IPI to every CPU, with or without
+// the one sending it. See the ipi module.
+
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::{self, Madt, Polarity, TriggerMode};
+use crate::cmdline;
+use crate::interrupts::{self, InterruptIndex};
+use crate::memory;
+use crate::time::pit;
+
+pub mod io_apic;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const CPUID_EDX_APIC: u32 = 1 << 9;
+const CPUID_ECX_X2APIC: u32 = 1 << 21;
+
+// The local APIC's registers, as offsets into the xAPIC layout.
+const ID: u32 = 0x20;
+const TASK_PRIORITY: u32 = 0x80;
+const END_OF_INTERRUPT: u32 = 0xb0;
+const SPURIOUS_VECTOR: u32 = 0xf0;
+const ERROR_STATUS: u32 = 0x280;
+const INTERRUPT_COMMAND_LOW: u32 = 0x300;
+const INTERRUPT_COMMAND_HIGH: u32 = 0x310;
+const LVT_TIMER: u32 = 0x320;
+const LVT_LINT0: u32 = 0x350;
+const LVT_LINT1: u32 = 0x360;
+const LVT_ERROR: u32 = 0x370;
+const TIMER_INITIAL_COUNT: u32 = 0x380;
+const TIMER_CURRENT_COUNT: u32 = 0x390;
+const TIMER_DIVIDE: u32 = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+// The bits of a Local Vector Table (LVT) entry, which says what happens when
+// one of the local APIC's own interrupt sources fires.
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// The bits of the bottom half of the ICR.
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_ALL: u32 = 0b10 << 18;
+const ICR_SHORTHAND_ALL_BUT_SELF: u32 = 0b11 << 18;
+
+// How many times a second the local APIC timer fires.
+pub const TIMER_HZ: u64 = 100;
+
+// The PIT is counted down for this long while the local APIC timer is
+// measured.
+const CALIBRATION_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError {
+    // 'noapic' was on the kernel command line.
+    Disabled,
+    NoMadt,
+    NotSupported,
+    NoIoApic,
+    NotMapped,
+}
+
+impl fmt::Display for ApicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApicError::Disabled => write!(f, "turned off by 'noapic'"),
+            ApicError::NoMadt => write!(f, "there is no MADT ACPI table"),
+            ApicError::NotSupported => write!(f, "the CPU has no local APIC"),
+            ApicError::NoIoApic => write!(f, "there is no I/O APIC"),
+            ApicError::NotMapped => write!(f, "physical memory isn't mapped"),
+        }
+    }
+}
+
+// Which CPUs an IPI is sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Apic(u32),
+    All,
+    AllButSelf,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    XApic(VirtAddr),
+    X2Apic,
+}
+
+static MODE: Once<Mode> = Once::new();
+
+// The local APIC timer's rate, in counts per second, with the divider set to
+// 16.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Whether interrupts are going through the APICs, rather than the PICs.
+pub fn enabled() -> bool {
+    MODE.r#try().is_some()
+}
+
+fn mode() -> Mode {
+    *MODE.r#try().expect("the local APIC hasn't been set up")
+}
+
+unsafe fn read(register: u32) -> u32 {
+    match mode() {
+        Mode::XApic(base) => {
+            let address = base + u64::from(register);
+            core::ptr::read_volatile(address.as_ptr::<u32>())
+        }
+        Mode::X2Apic => {
+            Msr::new(X2APIC_MSR_BASE + (register >> 4)).read() as u32
+        }
+    }
+}
+
+unsafe fn write(register: u32, value: u32) {
+    match mode() {
+        Mode::XApic(base) => {
+            let address = base + u64::from(register);
+            core::ptr::write_volatile(address.as_mut_ptr::<u32>(), value);
+        }
+        Mode::X2Apic => {
+            Msr::new(X2APIC_MSR_BASE + (register >> 4)).write(u64::from(value));
+        }
+    }
+}
+
+// Switch from the PICs to the APICs. This has to be called with interrupts
+// disabled, after the ACPI tables have been found, and the PICs remapped.
+pub fn init() -> Result<(), ApicError> {
+    if cmdline::get().has_flag("noapic") {
+        return Err(ApicError::Disabled);
+    }
+
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .ok_or(ApicError::NoMadt)?;
+    if madt.io_apics().is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let cpuid = unsafe { __cpuid(1) };
+    if cpuid.edx & CPUID_EDX_APIC == 0 {
+        return Err(ApicError::NotSupported);
+    }
+
+    let x2apic = cpuid.ecx & CPUID_ECX_X2APIC != 0;
+    let mode = if x2apic {
+        Mode::X2Apic
+    } else {
+        let base = PhysAddr::new(madt.local_apic_address);
+        Mode::XApic(memory::phys_to_virt(base).ok_or(ApicError::NotMapped)?)
+    };
+
+    // Everything which can fail has to be done before we start switching over,
+    // as there's no going back to the PICs once they have been disabled.
+    io_apic::init(madt)?;
+
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let flags = if x2apic {
+            APIC_BASE_ENABLE | APIC_BASE_X2APIC
+        } else {
+            APIC_BASE_ENABLE
+        };
+        apic_base.write(apic_base.read() | flags);
+    }
+    MODE.call_once(|| mode);
+
+    // The PICs stay remapped, so that a spurious interrupt from one of them
+    // can't be mistaken for a CPU exception.
+    interrupts::disable_pics();
+
+    init_local(madt);
+    io_apic::route_isa_irqs(madt, id());
+
+    // The PICs came with the timer and keyboard lines unmasked, so carry on
+    // taking their interrupts.
+    interrupts::enable_irq(0);
+    interrupts::enable_irq(1);
+
+    let frequency = calibrate_timer();
+    TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    start_timer(TIMER_HZ);
+
+    log::info!("using the {} ({} local APIC timer counts per second)",
+               if x2apic { "x2APIC" } else { "xAPIC" }, frequency);
+    Ok(())
+}
+
+// Set up the local APIC of the CPU this runs on.
+fn init_local(madt: &Madt) {
+    unsafe {
+        // Accept interrupts of every priority.
+        write(TASK_PRIORITY, 0);
+        write(SPURIOUS_VECTOR, SPURIOUS_APIC_ENABLE
+              | u32::from(InterruptIndex::ApicSpurious.as_u8()));
+
+        // On a machine with PICs, LINT0 is where they are connected, which we
+        // no longer want. The MADT says which pin is wired to the NMI.
+        write(LVT_LINT0, LVT_MASKED);
+        write(LVT_LINT1, LVT_MASKED);
+
+        let uid = madt.processors().iter()
+            .find(|processor| processor.apic_id == id())
+            .map(|processor| processor.processor_uid);
+        for nmi in madt.nmis() {
+            if nmi.processor_uid.is_some() && nmi.processor_uid != uid {
+                continue;
+            }
+
+            let mut entry = LVT_DELIVERY_NMI;
+            if nmi.polarity == Polarity::ActiveLow {
+                entry |= LVT_ACTIVE_LOW;
+            }
+            if nmi.trigger == TriggerMode::Level {
+                entry |= LVT_LEVEL_TRIGGERED;
+            }
+            match nmi.lint {
+                0 => write(LVT_LINT0, entry),
+                _ => write(LVT_LINT1, entry),
+            }
+        }
+
+        // The error status register has to be written before it is read, so
+        // it is cleared the same way.
+        write(LVT_ERROR, u32::from(InterruptIndex::ApicError.as_u8()));
+        write(ERROR_STATUS, 0);
+        write(ERROR_STATUS, 0);
+
+        write(LVT_TIMER, LVT_MASKED);
+        write(END_OF_INTERRUPT, 0);
+    }
+}
+
+// Turn on and set up the local APIC of an application processor, in the mode
+// init chose for the bootstrap processor. Its timer is left masked.
+pub fn init_ap() {
+    let flags = match mode() {
+        Mode::XApic(_) => APIC_BASE_ENABLE,
+        Mode::X2Apic => APIC_BASE_ENABLE | APIC_BASE_X2APIC,
+    };
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        apic_base.write(apic_base.read() | flags);
+    }
+
+    if let Some(madt) = acpi::get().and_then(|acpi| acpi.madt.as_ref()) {
+        init_local(madt);
+    }
+}
+
+// The APIC ID of the CPU this runs on. In xAPIC mode, it is in the top 8
+// bits of the register.
+pub fn id() -> u32 {
+    match mode() {
+        Mode::XApic(_) => unsafe { read(ID) >> 24 },
+        Mode::X2Apic => unsafe { read(ID) },
+    }
+}
+
+// Tell the local APIC the current interrupt has been handled.
+pub fn end_of_interrupt() {
+    unsafe { write(END_OF_INTERRUPT, 0) };
+}
+
+// Write an IPI to the ICR, waiting until the local APIC has sent it. In xAPIC
+// mode, an interrupt handler sending an IPI between the two writes would
+// change the destination, so interrupts are disabled while it is written.
+unsafe fn send_command(destination: u32, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| match mode() {
+        Mode::XApic(_) => {
+            write(INTERRUPT_COMMAND_HIGH, destination << 24);
+            write(INTERRUPT_COMMAND_LOW, command);
+            while read(INTERRUPT_COMMAND_LOW) & ICR_SEND_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+        Mode::X2Apic => {
+            let register = X2APIC_MSR_BASE + (INTERRUPT_COMMAND_LOW >> 4);
+            Msr::new(register)
+                .write(u64::from(destination) << 32 | u64::from(command));
+        }
+    });
+}
+
+// Send an interrupt with the given vector to other CPUs, or this one.
+pub fn send_ipi(destination: Destination, vector: u8) {
+    let (apic_id, shorthand) = match destination {
+        Destination::Apic(apic_id) => (apic_id, 0),
+        Destination::All => (0, ICR_SHORTHAND_ALL),
+        Destination::AllButSelf => (0, ICR_SHORTHAND_ALL_BUT_SELF),
+    };
+
+    unsafe {
+        send_command(apic_id, shorthand | ICR_LEVEL_ASSERT | u32::from(vector));
+    }
+}
+
+// Send an INIT IPI, which resets the CPU with the given APIC ID, and leaves it
+// waiting for a startup IPI.
+pub fn send_init(apic_id: u32) {
+    unsafe { send_command(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT) };
+}
+
+// Send a startup IPI, which starts the CPU with the given APIC ID in real
+// mode, running the code at the start of the given 4KiB page of the first
+// 1MiB of memory.
+pub fn send_startup(apic_id: u32, page: u8) {
+    unsafe {
+        send_command(apic_id, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT
+                     | u32::from(page));
+    }
+}
+
+// Called by the local APIC error interrupt handler. The error status register
+// has to be written before reading it, to latch the latest errors.
+pub fn handle_error() {
+    let status = unsafe {
+        write(ERROR_STATUS, 0);
+        read(ERROR_STATUS)
+    };
+    log::warn!("local APIC error: {:#x}", status);
+    end_of_interrupt();
+}
+
+// Called by the local APIC timer interrupt handler, which also runs the
+// kernel's timers. See the time module.
+pub fn handle_timer() {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    end_of_interrupt();
+    crate::time::run_timers();
+}
+
+// The number of times the local APIC timer has fired.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+// The local APIC timer's rate, in counts per second, or 0 before it has been
+// measured.
+pub fn timer_frequency() -> u64 {
+    TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Make the local APIC timer fire the given number of times a second.
+pub fn start_timer(hz: u64) {
+    let count = core::cmp::max(timer_frequency() / hz, 1);
+
+    unsafe {
+        write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(LVT_TIMER, LVT_TIMER_PERIODIC
+              | u32::from(InterruptIndex::ApicTimer.as_u8()));
+        write(TIMER_INITIAL_COUNT, count as u32);
+    }
+}
+
+// Measure how fast the local APIC timer counts, by letting it count down from
+// its highest value while PIT channel 2 counts down CALIBRATION_MS.
+fn calibrate_timer() -> u64 {
+    let count = pit::FREQUENCY_HZ * CALIBRATION_MS / 1000;
+
+    unsafe {
+        pit::start_countdown(count as u16);
+
+        write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        write(LVT_TIMER, LVT_MASKED);
+        write(TIMER_INITIAL_COUNT, u32::MAX);
+
+        while !pit::countdown_done() {
+            core::hint::spin_loop();
+        }
+
+        let elapsed = u32::MAX - read(TIMER_CURRENT_COUNT);
+        write(TIMER_INITIAL_COUNT, 0);
+
+        u64::from(elapsed) * 1000 / CALIBRATION_MS
+    }
+}
+
+// TESTING
+
+// Test that the kernel switched to the APICs under QEMU, and that the local
+// APIC timer is firing.
+#[test_case]
+fn test_local_apic_timer() {
+    assert!(enabled());
+    assert!(timer_frequency() > 0);
+
+    let start = timer_ticks();
+    while timer_ticks() < start + 2 {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,238 @@
+// The I/O APIC, which takes interrupts from devices and sends them on to the
+// local APICs. Each of its inputs handles one Global System Interrupt (GSI),
+// counting on from the I/O APIC's GSI base, and has an entry in the
+// redirection table saying which vector, and which CPU, the interrupt goes to.
+// ---
+// Like cmdline's fw_cfg device, it is programmed through a pair of registers:
+// the index of a register is written to IOREGSEL, at the I/O APIC's address,
+// and the register is then read or written through IOWIN, 16 bytes on. Each
+// redirection entry is 64 bits, split over two registers:
+//
+// +--------+--------------------------------------------------------------+
+// |  Bits  |                           Contents                           |
+// +--------+--------------------------------------------------------------+
+// |  0-7   | Vector                                                       |
+// |  8-10  | Delivery mode (0 is fixed, to the vector)                    |
+// |   11   | Destination mode (0 is physical, by APIC ID)                 |
+// |   13   | Polarity (1 is active low)                                   |
+// |   15   | Trigger mode (1 is level triggered)                          |
+// |   16   | Masked                                                       |
+// | 56-63  | Destination APIC ID                                          |
+// +--------+--------------------------------------------------------------+
+//
+// The legacy ISA interrupts are given the same vectors they had with the PICs,
+// PIC_1_OFFSET plus the IRQ number, so the interrupt handlers don't change.
+// The MADT says which GSI each is wired to. An IRQ without an override isn't
+// routed if its GSI is taken by another IRQ's override, as with QEMU's IRQ 0
+// going to GSI 2, which IRQ 2 (the PICs' cascade) would otherwise overwrite.
+// Everything starts masked, and is unmasked with enable_irq, as with the PICs.
+
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::{self, InterruptOverride, Madt, Polarity, TriggerMode,
+                  MAX_IO_APICS};
+use crate::interrupts::PIC_1_OFFSET;
+use crate::memory;
+use super::ApicError;
+
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+const ENTRY_ACTIVE_LOW: u64 = 1 << 13;
+const ENTRY_LEVEL_TRIGGERED: u64 = 1 << 15;
+const ENTRY_MASKED: u64 = 1 << 16;
+
+const ISA_IRQS: u8 = 16;
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    inputs: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(),
+                                  register);
+        core::ptr::read_volatile((self.base + IOWIN).as_ptr())
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr(),
+                                  register);
+        core::ptr::write_volatile((self.base + IOWIN).as_mut_ptr(), value);
+    }
+
+    fn set_entry(&self, input: u32, entry: u64) {
+        let register = IOREDTBL + input * 2;
+
+        // Mask the input while the entry is half written.
+        unsafe {
+            self.write(register, ENTRY_MASKED as u32);
+            self.write(register + 1, (entry >> 32) as u32);
+            self.write(register, entry as u32);
+        }
+    }
+
+    fn entry(&self, input: u32) -> u64 {
+        let register = IOREDTBL + input * 2;
+        unsafe {
+            u64::from(self.read(register + 1)) << 32
+                | u64::from(self.read(register))
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.inputs
+    }
+}
+
+// Only ever locked with interrupts disabled, as with the PICs.
+static IO_APICS: Mutex<[Option<IoApic>; MAX_IO_APICS]> =
+    Mutex::new([None; MAX_IO_APICS]);
+
+// Build a redirection table entry, for a fixed interrupt to one CPU.
+pub fn redirection_entry(vector: u8, polarity: Polarity, trigger: TriggerMode,
+                         destination: u32, masked: bool) -> u64 {
+    let mut entry = u64::from(vector) | u64::from(destination as u8) << 56;
+
+    // Conforming means the ISA defaults, active high and edge triggered.
+    if polarity == Polarity::ActiveLow {
+        entry |= ENTRY_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        entry |= ENTRY_LEVEL_TRIGGERED;
+    }
+    if masked {
+        entry |= ENTRY_MASKED;
+    }
+
+    entry
+}
+
+// Find the I/O APICs and mask every input. Every one of them is mapped before
+// any is touched, so that if this fails, they are all left as they were.
+pub fn init(madt: &Madt) -> Result<(), ApicError> {
+    let mut found = [None; MAX_IO_APICS];
+    for (slot, io_apic) in found.iter_mut().zip(madt.io_apics()) {
+        let base = PhysAddr::new(u64::from(io_apic.address));
+        let base = memory::phys_to_virt(base).ok_or(ApicError::NotMapped)?;
+        *slot = Some(IoApic { base, gsi_base: io_apic.gsi_base, inputs: 0 });
+    }
+
+    let mut io_apics = IO_APICS.lock();
+    for (slot, found) in io_apics.iter_mut().zip(found.iter()) {
+        let mut found = match found {
+            Some(found) => *found,
+            None => continue,
+        };
+
+        // Bits 16-23 of the version register are the index of the last
+        // redirection entry.
+        let version = unsafe { found.read(IOAPICVER) };
+        found.inputs = ((version >> 16) & 0xff) + 1;
+        for input in 0..found.inputs {
+            found.set_entry(input, ENTRY_MASKED);
+        }
+
+        *slot = Some(found);
+    }
+
+    Ok(())
+}
+
+// Point the ISA interrupts at the given local APIC, still masked.
+pub fn route_isa_irqs(madt: &Madt, destination: u32) {
+    let io_apics = IO_APICS.lock();
+    for irq in 0..ISA_IRQS {
+        let route = match isa_route(madt, irq) {
+            Some(route) => route,
+            None => continue,
+        };
+        let entry = redirection_entry(PIC_1_OFFSET + irq, route.polarity,
+                                      route.trigger, destination, true);
+
+        if let Some(io_apic) = find(&io_apics[..], route.gsi) {
+            io_apic.set_entry(route.gsi - io_apic.gsi_base, entry);
+        }
+    }
+}
+
+// How an ISA interrupt is wired, or None if it has no override and its GSI
+// is the target of another IRQ's override.
+fn isa_route(madt: &Madt, irq: u8) -> Option<InterruptOverride> {
+    let overrides = || madt.overrides().iter().filter(|entry| entry.bus == 0);
+    if overrides().any(|entry| entry.irq == irq) {
+        return Some(madt.isa_interrupt(irq));
+    }
+
+    let gsi = u32::from(irq);
+    if overrides().any(|entry| entry.gsi == gsi) {
+        None
+    } else {
+        Some(madt.isa_interrupt(irq))
+    }
+}
+
+fn find(io_apics: &[Option<IoApic>], gsi: u32) -> Option<IoApic> {
+    io_apics.iter().flatten().find(|io_apic| io_apic.handles(gsi)).copied()
+}
+
+// Mask or unmask an ISA interrupt.
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let gsi = match acpi::get().and_then(|acpi| acpi.madt.as_ref())
+        .and_then(|madt| isa_route(madt, irq)) {
+        Some(route) => route.gsi,
+        None => return,
+    };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        if let Some(io_apic) = find(&io_apics[..], gsi) {
+            let input = gsi - io_apic.gsi_base;
+            let entry = io_apic.entry(input);
+            let entry = if masked {
+                entry | ENTRY_MASKED
+            } else {
+                entry & !ENTRY_MASKED
+            };
+            io_apic.set_entry(input, entry);
+        }
+    });
+}
+
+
+// TESTING
+
+// Test that redirection entries are built with the fields in the right place.
+#[test_case]
+fn test_redirection_entry() {
+    assert_eq!(redirection_entry(0x21, Polarity::Conforming,
+                                 TriggerMode::Conforming, 0, false), 0x21);
+    assert_eq!(redirection_entry(0x30, Polarity::ActiveLow,
+                                 TriggerMode::Level, 3, true),
+               0x0300_0000_0001_a030);
+}
+
+// Test that the PIT's GSI is routed to the timer's vector, and isn't
+// overwritten by the ISA IRQ whose number matches it.
+#[test_case]
+fn test_pit_route() {
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .expect("there is no MADT");
+    assert!(super::enabled());
+
+    let gsi = madt.isa_interrupt(0).gsi;
+    let entry = x86_64::instructions::interrupts::without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let io_apic = find(&io_apics[..], gsi)
+            .expect("no I/O APIC has the PIT's GSI");
+        io_apic.entry(gsi - io_apic.gsi_base)
+    });
+    assert_eq!(entry & 0xff, 0x20);
+}
@@ -0,0 +1,356 @@
+// Stack traces, printed when the kernel panics or takes a fault, so we can see
+// how it got there rather than just where it stopped.
+// ---
+// Walking The Stack
+// The kernel is built with frame pointers (see .cargo/config.toml), so every
+// function starts by pushing the caller's rbp register and then pointing rbp
+// at it. Each stack frame therefore begins with a link to the one before it,
+// next to the address the function will return to:
+//
+//          +--------------------+
+//          |        ...         |
+//          +--------------------+
+//          |   return address   | rbp + 8
+//          +--------------------+
+//   rbp -> |   caller's rbp     | ----> the same two values for the caller
+//          +--------------------+
+//          |  local variables   |
+//          +--------------------+
+//
+// Following the links from the current rbp gives the return address of every
+// function on the stack. We stop when a link looks wrong: null, unaligned,
+// non-canonical, or pointing back down the stack, as the stack only grows down.
+// ---
+// Symbols
+// A return address on its own doesn't tell us much, so the kernel carries a
+// table of its function names and addresses. The compiler can't build that
+// table, as the addresses aren't known until the kernel is linked, so instead
+// the kernel reserves an empty .ksyms section, and tools/ksyms.py fills it in
+// afterwards from the ELF symbol table, demangling the names as it goes.
+// tools/runner.py does this before every run. Without it, addresses are
+// printed without names. The table has the following layout, with all values
+// little-endian:
+//
+// +--------+--------+------------------------------------------------------+
+// | Offset |  Size  |                       Contents                       |
+// +--------+--------+------------------------------------------------------+
+// |    0   |    4   | Magic, 'KSYM'                                        |
+// |    4   |    4   | Number of symbols                                    |
+// |    8   |    8   | Reserved                                             |
+// |   16   | 24 * n | Symbols, sorted by address:                          |
+// |        |        |     u64 address, u32 size, u32 name offset,          |
+// |        |        |     u32 name length, u32 reserved                    |
+// |   ...  |   ...  | Names, as UTF-8, found by offset from the table start|
+// +--------+--------+------------------------------------------------------+
+
+use core::fmt::{self, Write};
+use crate::console::ConsoleWriter;
+
+// The size of the space reserved for the symbol table.
+pub const KSYMS_SIZE: usize = 512 * 1024;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+// The most frames printed, in case the walk never finds the end of the stack.
+const MAX_FRAMES: usize = 64;
+
+// The furthest apart two stack frames can be before the link between them is
+// assumed to be garbage.
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+// The reserved space for the symbol table. It starts off as a valid, empty,
+// table. It has to have something in it, or the compiler would put it in a
+// section which takes up no room in the kernel file, leaving nowhere for
+// tools/ksyms.py to write the table.
+#[link_section = ".ksyms"]
+#[used]
+static mut KSYMS: [u8; KSYMS_SIZE] = empty_table();
+
+const fn empty_table() -> [u8; KSYMS_SIZE] {
+    let mut table = [0; KSYMS_SIZE];
+    table[0] = MAGIC[0];
+    table[1] = MAGIC[1];
+    table[2] = MAGIC[2];
+    table[3] = MAGIC[3];
+    table
+}
+
+pub struct SymbolTable<'a> {
+    table: &'a [u8],
+    count: usize,
+}
+
+// A function found in the symbol table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub address: u64,
+    pub size: u64,
+}
+
+impl<'a> SymbolTable<'a> {
+    // Read a symbol table, checking that it is complete.
+    pub fn new(table: &'a [u8]) -> Option<SymbolTable<'a>> {
+        if table.len() < HEADER_SIZE || &table[..4] != MAGIC {
+            return None;
+        }
+
+        let count = read_u32(table, 4) as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > table.len() {
+            return None;
+        }
+
+        Some(SymbolTable { table, count })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn symbol(&self, index: usize) -> Symbol<'a> {
+        let entry = HEADER_SIZE + index * ENTRY_SIZE;
+        let name_offset = read_u32(self.table, entry + 12) as usize;
+        let name_len = read_u32(self.table, entry + 16) as usize;
+
+        let name = self.table.get(name_offset..name_offset + name_len)
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .unwrap_or("?");
+
+        Symbol {
+            name,
+            address: read_u64(self.table, entry),
+            size: u64::from(read_u32(self.table, entry + 8)),
+        }
+    }
+
+    // Find the function containing an address.
+    pub fn lookup(&self, address: u64) -> Option<Symbol<'a>> {
+        // Find the number of symbols which start at or before the address,
+        // so the last of them is the only one which could contain it.
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let middle = (low + high) / 2;
+            if self.symbol(middle).address <= address {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        let symbol = self.symbol(low.checked_sub(1)?);
+        if address < symbol.address + core::cmp::max(symbol.size, 1) {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_u32(table: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&table[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(table: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&table[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// The kernel's own symbol table, if tools/ksyms.py has filled it in.
+pub fn kernel_symbols() -> Option<SymbolTable<'static>> {
+    // As far as the compiler knows, KSYMS never changes from the empty table,
+    // so it would happily read the magic and the count straight out of the
+    // initial value. Reading the address back through a volatile read hides
+    // where it came from, so the table is really read from memory.
+    let table = unsafe {
+        let address = KSYMS.as_ptr();
+        core::ptr::read_volatile(&address)
+    };
+    let table = unsafe { core::slice::from_raw_parts(table, KSYMS_SIZE) };
+
+    SymbolTable::new(table).filter(|symbols| !symbols.is_empty())
+}
+
+// Call the given function with the return address of each frame on the stack,
+// starting from the frame the given rbp points to.
+pub fn walk<F: FnMut(u64)>(rbp: u64, mut function: F) {
+    let mut rbp = rbp;
+
+    for _ in 0..MAX_FRAMES {
+        if rbp == 0 || rbp % 8 != 0 || !is_canonical(rbp) {
+            break;
+        }
+
+        let (next, return_address) = unsafe {
+            (*(rbp as *const u64), *((rbp + 8) as *const u64))
+        };
+        if return_address == 0 {
+            break;
+        }
+
+        function(return_address);
+
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+// Whether an address has bits 48 to 63 all the same as bit 47, which every
+// address in use has to.
+fn is_canonical(address: u64) -> bool {
+    let top = address >> 47;
+    top == 0 || top == 0x1ffff
+}
+
+// The rbp of the function this is written in. It has to be inlined, even
+// without optimisations, as otherwise it would read the rbp of its own frame.
+#[inline(always)]
+fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack,
+                                                  preserves_flags));
+    }
+    rbp
+}
+
+// Write a backtrace of the code which called this.
+#[inline(never)]
+pub fn write(out: &mut dyn Write) -> fmt::Result {
+    let symbols = kernel_symbols();
+    let mut number = 0;
+    let mut result = writeln!(out, "Backtrace:");
+
+    walk(frame_pointer(), |address| {
+        if result.is_ok() {
+            result = write_frame(out, symbols.as_ref(), number, address);
+        }
+        number += 1;
+    });
+
+    result
+}
+
+// Write a backtrace of code which was stopped at the given instruction, with
+// the given frame pointer, such as code stopped at a breakpoint.
+pub fn write_from(out: &mut dyn Write, address: u64, rbp: u64) -> fmt::Result {
+    let symbols = kernel_symbols();
+
+    writeln!(out, "Backtrace:")?;
+
+    // The stopped instruction is the first frame. Its address is where it
+    // was, rather than a return address, so it is looked up as it is.
+    write!(out, "{:>4}: {:#018x}", 0, address)?;
+    write_symbol(out, symbols.as_ref(), address)?;
+
+    let mut number = 1;
+    let mut result = Ok(());
+    walk(rbp, |address| {
+        if result.is_ok() {
+            result = write_frame(out, symbols.as_ref(), number, address);
+        }
+        number += 1;
+    });
+
+    result
+}
+
+// The frame pointer of the code an interrupt handler interrupted, to give to
+// write_from along with the interrupted instruction. This has to be called
+// directly from the handler, rather than from a function it calls, as it finds
+// the interrupted code's frame by following the handler's frame pointer.
+#[inline(never)]
+pub fn interrupted_frame_pointer() -> u64 {
+    // Our caller is the handler, whose saved rbp is the interrupted code's.
+    let handler_rbp = unsafe { *(frame_pointer() as *const u64) };
+    unsafe { *(handler_rbp as *const u64) }
+}
+
+// A return address points at the instruction after the call, which may be the
+// start of a different function if the call was the last thing in its own, so
+// one is taken off before looking it up.
+fn write_frame(out: &mut dyn Write, symbols: Option<&SymbolTable>,
+               number: usize, address: u64) -> fmt::Result {
+    write!(out, "{:>4}: {:#018x}", number, address)?;
+    write_symbol(out, symbols, address - 1)
+}
+
+fn write_symbol(out: &mut dyn Write, symbols: Option<&SymbolTable>,
+                address: u64) -> fmt::Result {
+    match symbols.and_then(|symbols| symbols.lookup(address)) {
+        Some(symbol) => writeln!(out, " - {}+{:#x}", symbol.name,
+                                 address - symbol.address),
+        None => writeln!(out),
+    }
+}
+
+// Print a backtrace of the code which called this to the consoles.
+#[inline(never)]
+pub fn print() {
+    let _ = write(&mut ConsoleWriter);
+}
+
+// Print a backtrace of code stopped at the given instruction, with the given
+// frame pointer, to the consoles.
+pub fn print_from(address: u64, rbp: u64) {
+    let _ = write_from(&mut ConsoleWriter, address, rbp);
+}
+
+
+// TESTING
+
+// Build a symbol table for two functions, 'a' at 0x1000 and 'bc' at 0x1010.
+#[cfg(test)]
+fn test_table() -> [u8; 72] {
+    let mut table = [0; 72];
+    table[..4].copy_from_slice(MAGIC);
+    table[4] = 2;
+
+    for (i, (address, size, name_offset, name_len)) in
+            [(0x1000u64, 0x10u32, 64u32, 1u32), (0x1010, 0x20, 65, 2)]
+                .iter().enumerate() {
+        let entry = HEADER_SIZE + i * ENTRY_SIZE;
+        table[entry..entry + 8].copy_from_slice(&address.to_le_bytes());
+        table[entry + 8..entry + 12].copy_from_slice(&size.to_le_bytes());
+        table[entry + 12..entry + 16]
+            .copy_from_slice(&name_offset.to_le_bytes());
+        table[entry + 16..entry + 20].copy_from_slice(&name_len.to_le_bytes());
+    }
+    table[64..67].copy_from_slice(b"abc");
+
+    table
+}
+
+// Test that addresses are found in the right function, and that addresses
+// outside every function aren't.
+#[test_case]
+fn test_symbol_lookup() {
+    let table = test_table();
+    let symbols = SymbolTable::new(&table).unwrap();
+
+    assert_eq!(symbols.lookup(0x1000).map(|s| s.name), Some("a"));
+    assert_eq!(symbols.lookup(0x100f).map(|s| s.name), Some("a"));
+    assert_eq!(symbols.lookup(0x1010).map(|s| s.name), Some("bc"));
+    assert_eq!(symbols.lookup(0xfff), None);
+    assert_eq!(symbols.lookup(0x1030), None);
+    assert!(SymbolTable::new(&table[..40]).is_none());
+}
+
+// Test that walking the stack from here finds at least the test runner.
+#[test_case]
+fn test_walk_finds_frames() {
+    let mut frames = 0;
+    walk(frame_pointer(), |_| frames += 1);
+
+    assert!(frames >= 2);
+}
@@ -0,0 +1,264 @@
+// A micro-benchmark harness, used to measure how many CPU cycles small pieces
+// of kernel code take, so we can see how they change from commit to commit.
+// ---
+// Benchmarks live in their own binary (benches/kernel.rs), which uses
+// bench_runner as its test runner, and are declared as #[test_case] statics:
+//
+//     fn bench_new_line(bencher: &mut Bencher) {
+//         bencher.iter(|| ...);
+//     }
+//
+//     #[test_case]
+//     static NEW_LINE: Bench = rustos::bench_fn!(bench_new_line);
+//
+// 'cargo bench' then builds the binary with optimisations, and runs it in QEMU.
+// The test-filter, test-skip and test-exact command line options choose which
+// benchmarks are run, in the same way as they do for tests.
+// ---
+// Timing
+// Cycles are counted with the CPU's Time Stamp Counter. The RDTSC instruction
+// which reads it isn't serialising, so the CPU is free to run it before the
+// instructions in front of it have finished, or after the ones behind it have
+// started. To stop that, the start of each sample is read with
+// 'lfence; rdtsc; lfence', and the end with 'rdtscp; lfence'. RDTSCP waits for
+// everything before it to finish, and the final LFENCE stops anything after it
+// starting early.
+// ---
+// Each benchmark is run for some warm-up iterations first, to fill the caches
+// and train the branch predictors. The number of iterations per sample is then
+// doubled until a sample takes at least SAMPLE_CYCLES_MIN cycles, so the cost
+// of reading the counter is small compared to the code being measured, and
+// that cost is subtracted anyway. The results are the minimum, median and
+// maximum cycles per iteration, over SAMPLES samples.
+// ---
+// Note that QEMU's TCG emulation doesn't run at a fixed speed, so the numbers
+// are only comparable between runs on the same machine, preferably with KVM.
+
+use core::fmt::{self, Write};
+use crate::cmdline;
+use crate::serial::Com;
+use crate::testing::output::{self, Format, JsonString};
+use crate::testing::Filter;
+use crate::{exit_qemu, QemuExitCode};
+
+// The number of samples taken of each benchmark.
+pub const SAMPLES: usize = 101;
+
+// The number of times each benchmark is run before it is measured.
+pub const WARM_UP_ITERATIONS: u64 = 1_000;
+
+// The shortest a sample can take, in cycles, before the iterations per sample
+// stop being increased.
+const SAMPLE_CYCLES_MIN: u64 = 10_000;
+
+// The most iterations per sample, in case the code being measured is optimised
+// away altogether.
+const ITERATIONS_MAX: u64 = 1 << 20;
+
+pub struct Bench {
+    pub name: &'static str,
+    pub function: fn(&mut Bencher),
+}
+
+// Create a Bench for a function, named after its path in the same way as
+// tests are.
+#[macro_export]
+macro_rules! bench_fn {
+    ($function:ident) => {
+        $crate::bench::Bench {
+            name: concat!(module_path!(), "::", stringify!($function)),
+            function: $function,
+        }
+    };
+}
+
+// Passed to each benchmark, which calls iter with the code to be measured.
+pub struct Bencher {
+    stats: Option<Stats>,
+}
+
+impl Bencher {
+    fn new() -> Bencher {
+        Bencher { stats: None }
+    }
+
+    pub fn iter<T, F: FnMut() -> T>(&mut self, mut function: F) {
+        for _ in 0..WARM_UP_ITERATIONS {
+            black_box(function());
+        }
+
+        let overhead = timer_overhead();
+        let mut sample = |iterations: u64| {
+            let start = start_timestamp();
+            for _ in 0..iterations {
+                black_box(function());
+            }
+            let cycles = end_timestamp() - start;
+            cycles.saturating_sub(overhead)
+        };
+
+        let mut iterations = 1;
+        while iterations < ITERATIONS_MAX
+            && sample(iterations) < SAMPLE_CYCLES_MIN {
+            iterations *= 2;
+        }
+
+        let mut samples = [0; SAMPLES];
+        for cycles in samples.iter_mut() {
+            *cycles = sample(iterations) / iterations;
+        }
+
+        self.stats = Some(Stats::new(&mut samples, iterations));
+    }
+}
+
+// The cycles per iteration taken by a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub min: u64,
+    pub median: u64,
+    pub max: u64,
+    pub samples: usize,
+    pub iterations: u64,
+}
+
+impl Stats {
+    // Work out the statistics of a set of samples. This sorts the samples.
+    pub fn new(samples: &mut [u64], iterations: u64) -> Stats {
+        samples.sort_unstable();
+
+        Stats {
+            min: samples[0],
+            median: samples[samples.len() / 2],
+            max: samples[samples.len() - 1],
+            samples: samples.len(),
+            iterations,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "min {} / median {} / max {} cycles/iter ({} x {})",
+               self.min, self.median, self.max, self.samples,
+               self.iterations)
+    }
+}
+
+// Stop the compiler from optimising away a value, or the code which produced
+// it. Reading the value back through a volatile pointer means the compiler has
+// to assume it is used.
+pub fn black_box<T>(value: T) -> T {
+    unsafe {
+        let result = core::ptr::read_volatile(&value);
+        core::mem::forget(value);
+        result
+    }
+}
+
+fn start_timestamp() -> u64 {
+    unsafe {
+        asm!("lfence", options(nostack, preserves_flags));
+        let timestamp = core::arch::x86_64::_rdtsc();
+        asm!("lfence", options(nostack, preserves_flags));
+        timestamp
+    }
+}
+
+fn end_timestamp() -> u64 {
+    unsafe {
+        let mut processor: u32 = 0;
+        let timestamp = core::arch::x86_64::__rdtscp(&mut processor);
+        asm!("lfence", options(nostack, preserves_flags));
+        timestamp
+    }
+}
+
+// The fewest cycles it takes to read the start and end timestamps, with
+// nothing in between.
+fn timer_overhead() -> u64 {
+    (0..SAMPLES)
+        .map(|_| {
+            let start = start_timestamp();
+            end_timestamp() - start
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+// The test runner used by the benchmark binary. Each benchmark is run in turn,
+// and its results are written to the serial port in the format chosen for
+// test output.
+pub fn bench_runner(benches: &[&Bench]) {
+    let cmdline = cmdline::get();
+    let filter = Filter::new(cmdline);
+    let format = match cmdline.value("test-format") {
+        Some(name) => name.parse().unwrap_or_else(|_| output::format()),
+        None => output::format(),
+    };
+
+    // Output errors are ignored, as the serial port is the only place they
+    // could be reported.
+    let out = &mut Com::Com1;
+    let selected = benches.iter().filter(|bench| filter.matches(bench.name));
+    let _ = output::start(out, format, selected.count());
+
+    let mut number = 0;
+    for bench in benches.iter().filter(|bench| filter.matches(bench.name)) {
+        number += 1;
+        let _ = output::test_started(out, format, bench.name);
+
+        let mut bencher = Bencher::new();
+        (bench.function)(&mut bencher);
+        let _ = report(out, format, number, bench.name, bencher.stats);
+    }
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+// Write the results of a benchmark. A benchmark which never called iter has
+// nothing to report, so is listed as ignored.
+fn report(out: &mut dyn Write, format: Format, number: usize, name: &str,
+          stats: Option<Stats>) -> fmt::Result {
+    let stats = match stats {
+        Some(stats) => stats,
+        None => return match format {
+            Format::Pretty => writeln!(out, "[no iterations]"),
+            Format::Tap => {
+                writeln!(out, "ok {} - {} # SKIP no iterations", number, name)
+            }
+            Format::JsonLines => writeln!(
+                out, "{{\"type\":\"bench\",\"name\":{},\"samples\":0}}",
+                JsonString(name)),
+        },
+    };
+
+    match format {
+        Format::Pretty => writeln!(out, "{}", stats),
+        Format::Tap => writeln!(out,
+                                "ok {} - {}\n  ---\n  min_cycles: {}\n  \
+                                 median_cycles: {}\n  max_cycles: {}\n  \
+                                 samples: {}\n  iterations: {}\n  ...",
+                                number, name, stats.min, stats.median,
+                                stats.max, stats.samples, stats.iterations),
+        Format::JsonLines => writeln!(
+            out, "{{\"type\":\"bench\",\"name\":{},\"min_cycles\":{},\
+                  \"median_cycles\":{},\"max_cycles\":{},\"samples\":{},\
+                  \"iterations\":{}}}",
+            JsonString(name), stats.min, stats.median, stats.max, stats.samples,
+            stats.iterations),
+    }
+}
+
+
+// TESTING
+
+// Test that the statistics are taken from the sorted samples.
+#[test_case]
+fn test_bench_stats() {
+    let mut samples = [9, 3, 7, 1, 5];
+    let stats = Stats::new(&mut samples, 4);
+
+    assert_eq!((stats.min, stats.median, stats.max), (1, 5, 9));
+    assert_eq!((stats.samples, stats.iterations), (5, 4));
+}
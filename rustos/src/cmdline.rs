@@ -0,0 +1,214 @@
+// The kernel command line is a list of space separated arguments, such as
+// 'log=debug test-filter=serial', used to change how the kernel behaves without
+// rebuilding it.
+// ---
+// The bootloader we use doesn't support passing a command line to the kernel,
+// so instead we read it from QEMU's firmware configuration device (fw_cfg). It
+// exposes a set of named files to the guest, and the command line is read from
+// the file 'opt/rustos/cmdline', which is added when QEMU is started with:
+//
+//     -fw_cfg name=opt/rustos/cmdline,string=log=debug
+//
+// The tools/runner.py script, which cargo uses to start QEMU, does this for us.
+// On real hardware, or if the file isn't there, the command line is empty.
+// ---
+// The fw_cfg device is programmed using two I/O ports. An item is chosen by
+// writing its 16-bit selector to the selector port, and its contents are then
+// read a byte at a time from the data port. Item 0x0000 holds the signature
+// 'QEMU', and item 0x0019 holds the file directory: a big-endian 32-bit count
+// of files, followed by an entry for each:
+//
+// +--------+--------+------------------------------------------------------+
+// | Offset |  Size  |                       Contents                       |
+// +--------+--------+------------------------------------------------------+
+// |    0   |    4   | Size of the file in bytes (big-endian)               |
+// |    4   |    2   | Selector of the file (big-endian)                    |
+// |    6   |    2   | Reserved                                             |
+// |    8   |   56   | Name of the file, padded with zeros                  |
+// +--------+--------+------------------------------------------------------+
+
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+const FW_CFG_SELECTOR_PORT: u16 = 0x510;
+const FW_CFG_DATA_PORT: u16 = 0x511;
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+
+const FILE_NAME_SIZE: usize = 56;
+
+// The name of the fw_cfg file the command line is read from.
+pub const CMDLINE_FILE: &str = "opt/rustos/cmdline";
+
+// The longest command line which can be read. Anything longer is cut short.
+pub const CMDLINE_MAX: usize = 1024;
+
+pub struct CommandLine {
+    buffer: [u8; CMDLINE_MAX],
+    len: usize,
+}
+
+impl CommandLine {
+    pub const fn empty() -> CommandLine {
+        CommandLine { buffer: [0; CMDLINE_MAX], len: 0 }
+    }
+
+    // Create a command line from a string, cutting it short if it is too long.
+    pub fn new(s: &str) -> CommandLine {
+        let mut cmdline = CommandLine::empty();
+        let len = core::cmp::min(s.len(), CMDLINE_MAX);
+        cmdline.buffer[..len].copy_from_slice(&s.as_bytes()[..len]);
+        cmdline.len = len;
+        cmdline
+    }
+
+    // Read the command line from QEMU's fw_cfg device.
+    fn read() -> CommandLine {
+        let mut cmdline = CommandLine::empty();
+        let mut fw_cfg = FwCfg::new();
+
+        if let Some((selector, size)) = fw_cfg.find_file(CMDLINE_FILE) {
+            cmdline.len = core::cmp::min(size as usize, CMDLINE_MAX);
+            fw_cfg.select(selector);
+            fw_cfg.read(&mut cmdline.buffer[..cmdline.len]);
+        }
+
+        cmdline
+    }
+
+    // The command line as a string. Anything which isn't valid UTF-8, or comes
+    // after the first zero byte, is ignored.
+    pub fn as_str(&self) -> &str {
+        let bytes = &self.buffer[..self.len];
+        let bytes = match bytes.iter().position(|byte| *byte == 0) {
+            Some(end) => &bytes[..end],
+            None => bytes,
+        };
+
+        match core::str::from_utf8(bytes) {
+            Ok(s) => s.trim(),
+            Err(error) => core::str::from_utf8(&bytes[..error.valid_up_to()])
+                .unwrap_or("").trim(),
+        }
+    }
+
+    pub fn arguments(&self) -> impl Iterator<Item = &str> {
+        self.as_str().split_whitespace()
+    }
+
+    // Whether the command line contains the given argument on its own, such as
+    // 'test-exact'.
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.arguments().any(|argument| argument == flag)
+    }
+
+    // The value of the first 'key=value' argument with the given key.
+    pub fn value(&self, key: &str) -> Option<&str> {
+        self.values(key).next()
+    }
+
+    // The values of every 'key=value' argument with the given key, in order.
+    pub fn values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.arguments().filter_map(move |argument| {
+            let value = argument.strip_prefix(key)?.strip_prefix('=')?;
+            Some(value)
+        })
+    }
+}
+
+lazy_static! {
+    static ref CMDLINE: CommandLine = CommandLine::read();
+}
+
+// The kernel command line. It is read the first time this is called.
+pub fn get() -> &'static CommandLine {
+    &CMDLINE
+}
+
+struct FwCfg {
+    selector: Port<u16>,
+    data: Port<u8>,
+}
+
+impl FwCfg {
+    fn new() -> FwCfg {
+        FwCfg {
+            selector: Port::new(FW_CFG_SELECTOR_PORT),
+            data: Port::new(FW_CFG_DATA_PORT),
+        }
+    }
+
+    fn select(&mut self, item: u16) {
+        unsafe { self.selector.write(item) };
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) {
+        for byte in buffer.iter_mut() {
+            *byte = unsafe { self.data.read() };
+        }
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.read(&mut bytes);
+        u32::from_be_bytes(bytes)
+    }
+
+    // Whether there is an fw_cfg device. Reading the ports when there isn't
+    // one returns 0xff, so the signature won't match.
+    fn present(&mut self) -> bool {
+        let mut signature = [0; 4];
+        self.select(FW_CFG_SIGNATURE);
+        self.read(&mut signature);
+        &signature == b"QEMU"
+    }
+
+    // Look up a file in the directory, returning its selector and size.
+    fn find_file(&mut self, name: &str) -> Option<(u16, u32)> {
+        if !self.present() {
+            return None;
+        }
+
+        self.select(FW_CFG_FILE_DIR);
+        let count = self.read_u32();
+
+        for _ in 0..count {
+            let mut entry = [0; 8 + FILE_NAME_SIZE];
+            self.read(&mut entry);
+
+            if file_name(&entry[8..]) == name.as_bytes() {
+                let size = u32::from_be_bytes([entry[0], entry[1], entry[2],
+                                               entry[3]]);
+                let selector = u16::from_be_bytes([entry[4], entry[5]]);
+                return Some((selector, size));
+            }
+        }
+
+        None
+    }
+}
+
+// The name of a file in the fw_cfg directory, without the zero padding.
+fn file_name(name: &[u8]) -> &[u8] {
+    match name.iter().position(|byte| *byte == 0) {
+        Some(end) => &name[..end],
+        None => name,
+    }
+}
+
+
+// TESTING
+
+// Test that arguments and 'key=value' options are found, and that a key which
+// is only the start of another one doesn't match it.
+#[test_case]
+fn test_cmdline_values() {
+    let cmdline = CommandLine::new(" log=debug  test-filter=a\0junk");
+
+    assert_eq!(cmdline.as_str(), "log=debug  test-filter=a");
+    assert_eq!(cmdline.value("log"), Some("debug"));
+    assert_eq!(cmdline.value("test"), None);
+    assert_eq!(cmdline.value("test-filter"), Some("a"));
+    assert!(!cmdline.has_flag("log"));
+}
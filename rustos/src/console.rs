@@ -0,0 +1,368 @@
+// The console module sits between the print! and println! macros and the
+// devices which output is actually written to. Each device implements the
+// Console trait and is registered as a sink, under a name and with a level
+// filter. Every time something is printed it is fanned out to each registered
+// sink whose filter allows it, so adding a new output device only means
+// registering it, rather than changing every call to print!.
+// ---
+// Out of the box the VGA Buffer (or the framebuffer console, when the
+// 'framebuffer' feature is enabled) and an in-memory ring buffer are
+// registered. The serial port isn't registered by default, as the test
+// framework uses it to report results to the host, and doesn't want the output
+// of every println! mixed in with them. The kernel registers it in _start.
+// ---
+// As we have no heap allocator, the registry is a fixed-size array, which
+// limits us to MAX_CONSOLES sinks at a time.
+// ---
+// Printing has to be safe to call from interrupt handlers. If an interrupt
+// arrives while the main code holds one of the console locks, and the handler
+// then tries to print, the handler spins forever waiting for a lock which will
+// never be released, as the code holding it can't run until the handler
+// returns. To prevent this, every lock in this module, and in the sinks, is
+// only ever taken with interrupts disabled.
+// ---
+// Messages and filters use the log crate's Level and LevelFilter, so records
+// from the logger can be passed straight through. print! and println! print at
+// Info, and a filter of Off means the sink accepts nothing.
+
+use core::fmt;
+use lazy_static::lazy_static;
+use log::{Level, LevelFilter};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::vga_buffer::Colour;
+
+// The maximum number of sinks which can be registered at once.
+pub const MAX_CONSOLES: usize = 8;
+
+// The size of the in-memory ring buffer, in bytes.
+pub const RING_BUFFER_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleError {
+    // Every slot in the registry is already in use.
+    Full,
+
+    // A sink with the same name has already been registered.
+    AlreadyRegistered,
+
+    // There is no sink registered with the given name.
+    NotFound,
+}
+
+// A device which output can be written to.
+// ---
+// Sinks are shared between every caller of print!, so the write method only
+// takes &self. Any sink which needs to change state while writing, which is
+// nearly all of them, should keep that state behind a lock.
+// ---
+// Sinks which can display colours should override write_coloured, otherwise
+// the colour is ignored and the text is written as normal.
+pub trait Console: Sync {
+    fn write(&self, args: fmt::Arguments);
+
+    fn write_coloured(&self, _colour: Colour, args: fmt::Arguments) {
+        self.write(args);
+    }
+}
+
+// A registered sink.
+#[derive(Clone, Copy)]
+struct Sink {
+    name: &'static str,
+    console: &'static dyn Console,
+    filter: LevelFilter,
+}
+
+lazy_static! {
+    static ref SINKS: Mutex<[Option<Sink>; MAX_CONSOLES]> = {
+        let mut sinks = [None; MAX_CONSOLES];
+
+        sinks[0] = Some(Sink {
+            name: "ring",
+            console: &RING_BUFFER,
+            filter: LevelFilter::Trace,
+        });
+        sinks[1] = Some(default_display());
+
+        Mutex::new(sinks)
+    };
+}
+
+#[cfg(not(feature = "framebuffer"))]
+fn default_display() -> Sink {
+    Sink {
+        name: "vga",
+        console: &crate::vga_buffer::VgaConsole,
+        filter: LevelFilter::Trace,
+    }
+}
+
+#[cfg(feature = "framebuffer")]
+fn default_display() -> Sink {
+    Sink {
+        name: "fb",
+        console: &crate::framebuffer::console::FrameBufferConsole,
+        filter: LevelFilter::Trace,
+    }
+}
+
+// Register a new sink, which will receive any message allowed by the filter.
+pub fn register(name: &'static str, console: &'static dyn Console,
+                filter: LevelFilter) -> Result<(), ConsoleError> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+
+        if sinks.iter().flatten().any(|sink| sink.name == name) {
+            return Err(ConsoleError::AlreadyRegistered);
+        }
+
+        let slot = sinks.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ConsoleError::Full)?;
+        *slot = Some(Sink { name, console, filter });
+
+        Ok(())
+    })
+}
+
+// Remove a sink, so it no longer receives any messages.
+pub fn unregister(name: &str) -> Result<(), ConsoleError> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+
+        let slot = sinks.iter_mut()
+            .find(|slot| matches!(slot, Some(sink) if sink.name == name))
+            .ok_or(ConsoleError::NotFound)?;
+        *slot = None;
+
+        Ok(())
+    })
+}
+
+// Change the level filter of a registered sink.
+pub fn set_filter(name: &str, filter: LevelFilter) -> Result<(), ConsoleError> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+
+        let sink = sinks.iter_mut()
+            .flatten()
+            .find(|sink| sink.name == name)
+            .ok_or(ConsoleError::NotFound)?;
+        sink.filter = filter;
+
+        Ok(())
+    })
+}
+
+// Get the level filter of a registered sink.
+pub fn filter(name: &str) -> Option<LevelFilter> {
+    interrupts::without_interrupts(|| {
+        SINKS.lock().iter()
+            .flatten()
+            .find(|sink| sink.name == name)
+            .map(|sink| sink.filter)
+    })
+}
+
+// Hidden, helper method which fans a message out to every sink which accepts
+// messages of the given level.
+#[doc(hidden)]
+pub fn _print(level: Level, args: fmt::Arguments) {
+    fan_out(level, |console| console.write(args));
+}
+
+// Print a message in the given foreground colour, on the sinks which support
+// colour.
+pub fn print_coloured(level: Level, colour: Colour, args: fmt::Arguments) {
+    fan_out(level, |console| console.write_coloured(colour, args));
+}
+
+fn fan_out<F: Fn(&dyn Console)>(level: Level, write: F) {
+    // Interrupts stay disabled until every sink has been written to, which
+    // also stops an interrupt handler's output from landing in the middle of
+    // this message.
+    interrupts::without_interrupts(|| {
+        // Take a copy of the registry, so that the lock isn't held while we
+        // write to the sinks. Otherwise a sink which itself prints something,
+        // or calls register, would deadlock.
+        let sinks = *SINKS.lock();
+
+        for sink in sinks.iter().flatten() {
+            if level <= sink.filter {
+                write(sink.console);
+            }
+        }
+    });
+}
+
+// Implement the standard print! macro, passing it through to every registered
+// console.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(
+        $crate::log::Level::Info, format_args!($($arg)*)));
+}
+
+// Implement the standard println! macro, passing it through to every
+// registered console.
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+// A writer which prints everything written to it, for code which writes its
+// output to a fmt::Write, such as the backtrace and monitor modules.
+pub struct ConsoleWriter;
+
+impl fmt::Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+
+// RING BUFFER
+
+// A sink which keeps the last RING_BUFFER_SIZE bytes of output in memory, so it
+// can be looked at later, even if nothing was attached to see it at the time.
+// Once the buffer is full, the oldest output is overwritten.
+pub struct RingBuffer {
+    inner: Mutex<RingBufferInner>,
+}
+
+struct RingBufferInner {
+    data: [u8; RING_BUFFER_SIZE],
+
+    // The index the next byte will be written to.
+    head: usize,
+
+    // The number of bytes currently held in the buffer.
+    len: usize,
+}
+
+impl RingBuffer {
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            inner: Mutex::new(RingBufferInner {
+                data: [0; RING_BUFFER_SIZE],
+                head: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    // Copy the contents of the buffer, oldest first, into the given slice.
+    // Returns the number of bytes copied. If the slice is too small, only the
+    // most recent output is copied.
+    pub fn contents(&self, out: &mut [u8]) -> usize {
+        interrupts::without_interrupts(|| {
+            let inner = self.inner.lock();
+            let count = core::cmp::min(inner.len, out.len());
+            let start =
+                (inner.head + RING_BUFFER_SIZE - count) % RING_BUFFER_SIZE;
+
+            for (i, byte) in out[..count].iter_mut().enumerate() {
+                *byte = inner.data[(start + i) % RING_BUFFER_SIZE];
+            }
+
+            count
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        interrupts::without_interrupts(|| self.inner.lock().len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            inner.head = 0;
+            inner.len = 0;
+        });
+    }
+}
+
+impl Default for RingBuffer {
+    fn default() -> RingBuffer {
+        RingBuffer::new()
+    }
+}
+
+impl fmt::Write for RingBufferInner {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[self.head] = byte;
+            self.head = (self.head + 1) % RING_BUFFER_SIZE;
+            self.len = core::cmp::min(self.len + 1, RING_BUFFER_SIZE);
+        }
+
+        Ok(())
+    }
+}
+
+impl Console for RingBuffer {
+    fn write(&self, args: fmt::Arguments) {
+        use core::fmt::Write;
+        interrupts::without_interrupts(|| {
+            self.inner.lock().write_fmt(args).unwrap();
+        });
+    }
+}
+
+// The ring buffer which is registered by default.
+pub static RING_BUFFER: RingBuffer = RingBuffer::new();
+
+
+// TESTING
+
+// Test that the ring buffer gives back what was written to it, oldest first,
+// and only keeps the most recent output once it wraps around.
+#[test_case]
+fn test_ring_buffer_wraps() {
+    use core::fmt::Write;
+
+    static BUFFER: RingBuffer = RingBuffer::new();
+    interrupts::without_interrupts(|| {
+        for _ in 0..RING_BUFFER_SIZE {
+            BUFFER.inner.lock().write_str("x").unwrap();
+        }
+    });
+    BUFFER.write(format_args!("end"));
+
+    let mut out = [0; 8];
+    let count = BUFFER.contents(&mut out);
+
+    assert_eq!(BUFFER.len(), RING_BUFFER_SIZE);
+    assert_eq!(&out[..count], b"xxxxxend");
+}
+
+// Test that print! reaches a newly registered sink, and that the sink's level
+// filter is respected.
+#[test_case]
+fn test_print_fans_out_to_sinks() {
+    static SINK: RingBuffer = RingBuffer::new();
+    register("test", &SINK, LevelFilter::Info).unwrap();
+    assert_eq!(register("test", &SINK, LevelFilter::Info),
+               Err(ConsoleError::AlreadyRegistered));
+
+    crate::print!("hello");
+    _print(Level::Debug, format_args!("hidden"));
+
+    let mut out = [0; 16];
+    let count = SINK.contents(&mut out);
+    assert_eq!(&out[..count], b"hello");
+
+    set_filter("test", LevelFilter::Off).unwrap();
+    crate::print!("hidden");
+    assert_eq!(SINK.len(), 5);
+
+    unregister("test").unwrap();
+    assert_eq!(filter("test"), None);
+}
@@ -0,0 +1,51 @@
+// Code coverage for the kernel tests. When the kernel is built with LLVM's
+// source-based coverage instrumentation (-Z instrument-coverage), the compiler
+// adds a counter to every branch of the code, and records which counters
+// belong to which lines of source. Normally the profiler runtime writes the
+// counters to a .profraw file when the program exits, but there is no file
+// system here, so the minicov crate provides a runtime which can collect the
+// counters into a buffer instead.
+// ---
+// The profile is written out through QEMU's ISA debug console device, which is
+// at I/O port 0xe9. Every byte written to the port is passed straight through
+// to the host, so starting QEMU with '-debugcon file:<path>' saves the profile
+// exactly as it was written. tools/runner.py adds this option when the
+// RUSTOS_COVERAGE_DIR environment variable is set, and tools/coverage.py
+// builds the tests, runs them, and merges the profiles with llvm-profdata.
+// ---
+// This only exists when the coverage cargo feature is enabled.
+
+use x86_64::instructions::port::Port;
+
+const DEBUGCON_PORT: u16 = 0xe9;
+
+// The largest profile which can be written. As there is no heap, this is set
+// aside up front, so it is only part of kernels built with coverage.
+const PROFILE_MAX: usize = 1 << 20;
+
+static mut PROFILE: [u8; PROFILE_MAX] = [0; PROFILE_MAX];
+
+// Write the coverage profile to the debug console. This should be called once,
+// just before QEMU exits, as calling it again would write a second profile
+// after the first.
+pub fn dump() {
+    let size = minicov::get_coverage_data_size();
+    if size > PROFILE_MAX {
+        crate::serial_println!("coverage: the profile needs {} bytes, but only \
+                                {} are available", size, PROFILE_MAX);
+        return;
+    }
+
+    unsafe {
+        let profile = &mut PROFILE[..size];
+        if minicov::capture_coverage(profile).is_err() {
+            crate::serial_println!("coverage: failed to capture the profile");
+            return;
+        }
+
+        let mut port: Port<u8> = Port::new(DEBUGCON_PORT);
+        for byte in profile.iter() {
+            port.write(*byte);
+        }
+    }
+}
@@ -0,0 +1,682 @@
+// The driver model. Rather than the rest of the kernel reaching for the static
+// behind each device, devices are kept in a registry, bound to the drivers
+// which handle them, and looked up by name or by class.
+// ---
+// Each device sits on a bus, which says how it was found and how it is
+// reached (its Resource):
+// - Platform: Legacy devices at fixed places, which can't be probed for, such
+//               as the serial ports and the VGA text buffer. They are known
+//               by a name, like 'com1'.
+// - PCI: Every function pci::init found on the PCI buses.
+// - Virtio: Devices behind a virtio transport, which are added by the driver
+//               for the transport when it is probed.
+// ---
+// Drivers implement the Driver trait, and are listed in the DRIVERS table. The
+// lifecycle of a device always runs in the same order:
+// 1. add: The device is put in the registry, unbound. init adds the platform
+//               devices, and then the PCI devices.
+// 2. probe: Each unbound device, in the order of the registry, is offered to
+//               the first driver in DRIVERS for its bus which matches it. If
+//               the driver's probe succeeds, the device is bound to it, and
+//               given a name. Devices added while probing, such as those on
+//               a virtio transport, are probed in the same pass.
+// 3. remove: The device is unbound, so it can no longer be looked up, and then
+//               the driver's remove is called. shutdown removes every bound
+//               device in the reverse of the order they were probed, so
+//               children go before the devices they hang off.
+// ---
+// Probing gives the device an Interface, which is how the rest of the kernel
+// uses it, and which decides its class, and a name, like the files in a Unix
+// /dev directory. The driver picks the start of the name, such as 'ttyS' for
+// a serial port, and the registry adds the lowest number not already used,
+// so the first serial port found is 'ttyS0'.
+// ---
+// As we have no heap allocator, the registry is a fixed-size array, which
+// limits us to MAX_DEVICES devices. It is only locked with interrupts
+// disabled, so devices can be looked up from interrupt handlers, and is never
+// locked while a driver is called, so drivers can add and look up devices.
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::vga_buffer::Colour;
+use crate::{pci, virtio};
+
+pub mod platform;
+
+// The most devices which can be in the registry at once.
+pub const MAX_DEVICES: usize = 64;
+
+// The drivers, in the order they are tried.
+pub const DRIVERS: &[&dyn Driver] = &[
+    &platform::SerialDriver,
+    &platform::VgaDriver,
+    &virtio::pci::VirtioPciDriver,
+    &virtio::blk::BlockDriver,
+    &virtio::net::NetDriver,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Platform,
+    Pci,
+    Virtio,
+}
+
+// How a device was found, and how its driver reaches it.
+#[derive(Debug, Clone, Copy)]
+pub enum Resource {
+    Platform(&'static str),
+    Pci(&'static pci::Device),
+    // The transport is an index the driver for the transport gave out, which
+    // the device's driver hands back to it.
+    Virtio { device_type: u32, transport: usize },
+}
+
+impl Resource {
+    pub fn bus(&self) -> Bus {
+        match self {
+            Resource::Platform(_) => Bus::Platform,
+            Resource::Pci(_) => Bus::Pci,
+            Resource::Virtio { .. } => Bus::Virtio,
+        }
+    }
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::Platform(name) => write!(f, "platform {}", name),
+            Resource::Pci(device) => write!(f, "pci {}", device),
+            Resource::Virtio { device_type, transport } => {
+                write!(f, "virtio type {} on transport {}", device_type,
+                       transport)
+            }
+        }
+    }
+}
+
+// A device which reads and writes a stream of bytes, such as a terminal.
+pub trait CharDevice: Sync {
+    fn write(&self, bytes: &[u8]);
+
+    // Write in a colour, on a device which can show one.
+    fn write_coloured(&self, _colour: Colour, bytes: &[u8]) {
+        self.write(bytes);
+    }
+
+    // Read the bytes which are already waiting, without blocking. Returns the
+    // number of bytes read. This works with interrupts disabled too.
+    fn read(&self, buffer: &mut [u8]) -> usize;
+
+    // Release the device's locks, after whatever held them has been abandoned,
+    // such as a test which panicked. This is unsafe, as anything which really
+    // is still using the device will carry on doing so.
+    unsafe fn force_unlock(&self) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // The sectors run past the end of the device, or the buffer isn't a whole
+    // number of sectors.
+    OutOfRange,
+    ReadOnly,
+    Unsupported,
+    Io,
+    // Too many requests are already in flight.
+    Busy,
+    // Part of the buffer isn't mapped, so the device can't be given it.
+    NotMapped,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => {
+                write!(f, "the request is outside of the device")
+            }
+            BlockError::ReadOnly => write!(f, "the device is read only"),
+            BlockError::Unsupported => {
+                write!(f, "the device doesn't support the request")
+            }
+            BlockError::Io => write!(f, "the device reported an I/O error"),
+            BlockError::Busy => {
+                write!(f, "the device has too many requests in flight")
+            }
+            BlockError::NotMapped => write!(f, "the buffer isn't mapped"),
+        }
+    }
+}
+
+// A device which stores data in fixed-size sectors, such as a disk. Each call
+// waits for the request to finish.
+pub trait BlockDevice: Sync {
+    fn sector_size(&self) -> usize;
+
+    // The size of the device, in sectors.
+    fn sectors(&self) -> u64;
+
+    // Read whole sectors, starting at the given one, to fill the buffer.
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    // Wait for everything written so far to reach permanent storage.
+    fn flush(&self) -> Result<(), BlockError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    // The frame is larger than the device can send.
+    TooLarge,
+    // Every transmit buffer is in use.
+    Busy,
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::TooLarge => write!(f, "the frame is too large"),
+            NetError::Busy => write!(f, "the device is still sending"),
+        }
+    }
+}
+
+// A network interface, which sends and receives Ethernet frames.
+pub trait NetDevice: Sync {
+    fn mac_address(&self) -> [u8; 6];
+
+    // Queue a frame to be sent, starting with its Ethernet header. The device
+    // adds the checksum.
+    fn send(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    // Take the next frame which has arrived, without blocking. Returns its
+    // length, and cuts it short if it doesn't fit in the buffer.
+    fn receive(&self, buffer: &mut [u8]) -> Option<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Char,
+    Block,
+    Net,
+}
+
+// How the rest of the kernel uses a bound device.
+#[derive(Clone, Copy)]
+pub enum Interface {
+    // The device has no interface of its own, such as a bus transport, which
+    // adds the devices behind it instead.
+    None,
+    Char(&'static dyn CharDevice),
+    Block(&'static dyn BlockDevice),
+    Net(&'static dyn NetDevice),
+}
+
+impl Interface {
+    pub fn class(&self) -> Option<Class> {
+        match self {
+            Interface::None => None,
+            Interface::Char(_) => Some(Class::Char),
+            Interface::Block(_) => Some(Class::Block),
+            Interface::Net(_) => Some(Class::Net),
+        }
+    }
+}
+
+// What a driver's probe gives back for a device it will handle.
+#[derive(Clone, Copy)]
+pub struct Binding {
+    // The start of the device's name, to which a number is added.
+    pub name: &'static str,
+    pub interface: Interface,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeError {
+    // The device isn't really there, such as a serial port with no UART.
+    NotPresent,
+    Failed(&'static str),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeError::NotPresent => write!(f, "the device isn't present"),
+            ProbeError::Failed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    // The bus the driver's devices are on. It is only offered devices on it.
+    fn bus(&self) -> Bus;
+
+    // Whether the driver handles the device, from its IDs alone. The device
+    // shouldn't be touched until probe is called.
+    fn matches(&self, resource: &Resource) -> bool;
+
+    // Set the device up, and say how it is to be used.
+    fn probe(&self, resource: &Resource) -> Result<Binding, ProbeError>;
+
+    // Stop using the device. It has already been removed from the registry.
+    fn remove(&self, _resource: &Resource) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    Full,
+    NotFound,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceError::Full => {
+                write!(f, "there are already {} devices", MAX_DEVICES)
+            }
+            DeviceError::NotFound => write!(f, "there is no such device"),
+        }
+    }
+}
+
+// A device's place in the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+// A device's name, such as 'ttyS0'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Name {
+    base: &'static str,
+    number: u8,
+}
+
+impl Name {
+    // Whether the name is the given string. Numbers with leading zeroes, like
+    // 'ttyS01', don't match.
+    pub fn matches(&self, name: &str) -> bool {
+        let number = match name.strip_prefix(self.base) {
+            Some(number) => number,
+            None => return false,
+        };
+
+        number.bytes().all(|byte| byte.is_ascii_digit())
+            && !(number.len() > 1 && number.starts_with('0'))
+            && number.parse::<u8>().ok() == Some(self.number)
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", self.base, self.number)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Bound {
+    driver: &'static dyn Driver,
+    name: Name,
+    interface: Interface,
+    // Counts up with each device probed, so they can be removed in reverse.
+    sequence: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    resource: Resource,
+    bound: Option<Bound>,
+}
+
+// A device in the registry, as it was when it was looked up.
+#[derive(Clone, Copy)]
+pub struct Device {
+    pub id: DeviceId,
+    pub resource: Resource,
+    // The driver's name, the device's name and its interface, once it is
+    // bound.
+    pub driver: Option<&'static str>,
+    pub name: Option<Name>,
+    pub interface: Interface,
+}
+
+impl Device {
+    pub fn class(&self) -> Option<Class> {
+        self.interface.class()
+    }
+}
+
+struct Registry {
+    entries: [Option<Entry>; MAX_DEVICES],
+    sequence: u64,
+}
+
+impl Registry {
+    const fn new() -> Registry {
+        Registry { entries: [None; MAX_DEVICES], sequence: 0 }
+    }
+
+    fn add(&mut self, resource: Resource) -> Result<DeviceId, DeviceError> {
+        let index = self.entries.iter()
+            .position(|entry| entry.is_none())
+            .ok_or(DeviceError::Full)?;
+        self.entries[index] = Some(Entry { resource, bound: None });
+        Ok(DeviceId(index))
+    }
+
+    fn get(&self, id: DeviceId) -> Option<Device> {
+        let entry = self.entries.get(id.0)?.as_ref()?;
+        let bound = entry.bound.as_ref();
+
+        Some(Device {
+            id,
+            resource: entry.resource,
+            driver: bound.map(|bound| bound.driver.name()),
+            name: bound.map(|bound| bound.name),
+            interface: bound.map_or(Interface::None, |bound| bound.interface),
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<Device> {
+        let index = self.entries.iter().position(|entry| {
+            matches!(entry, Some(Entry { bound: Some(bound), .. })
+                     if bound.name.matches(name))
+        })?;
+        self.get(DeviceId(index))
+    }
+
+    fn bind(&mut self, id: DeviceId, driver: &'static dyn Driver,
+            binding: Binding) -> Name {
+        let number = (0..=u8::MAX).find(|number| {
+            let name = Name { base: binding.name, number: *number };
+            !self.entries.iter().flatten()
+                .filter_map(|entry| entry.bound.as_ref())
+                .any(|bound| bound.name == name)
+        }).expect("there are more numbers than devices");
+
+        let name = Name { base: binding.name, number };
+        let bound = Bound {
+            driver,
+            name,
+            interface: binding.interface,
+            sequence: self.sequence,
+        };
+        self.sequence += 1;
+
+        if let Some(entry) = self.entries[id.0].as_mut() {
+            entry.bound = Some(bound);
+        }
+        name
+    }
+
+    // Take a device's driver away, returning it and the device's resource.
+    fn unbind(&mut self, id: DeviceId)
+            -> Option<(&'static dyn Driver, Resource)> {
+        let entry = self.entries.get_mut(id.0)?.as_mut()?;
+        let bound = entry.bound.take()?;
+        Some((bound.driver, entry.resource))
+    }
+
+    // The bound device which was probed last.
+    fn last_bound(&self) -> Option<DeviceId> {
+        self.entries.iter().enumerate()
+            .filter_map(|(index, entry)| {
+                let bound = entry.as_ref()?.bound.as_ref()?;
+                Some((bound.sequence, index))
+            })
+            .max()
+            .map(|(_, index)| DeviceId(index))
+    }
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+fn with<R>(registry: &Mutex<Registry>, f: impl FnOnce(&mut Registry) -> R)
+        -> R {
+    without_interrupts(|| f(&mut registry.lock()))
+}
+
+fn probe_in(registry: &Mutex<Registry>, drivers: &[&'static dyn Driver])
+        -> usize {
+    let mut probed = 0;
+
+    for index in 0..MAX_DEVICES {
+        let id = DeviceId(index);
+        let resource = match with(registry, |registry| registry.get(id)) {
+            Some(device) if device.driver.is_none() => device.resource,
+            _ => continue,
+        };
+
+        let driver = drivers.iter().find(|driver| {
+            driver.bus() == resource.bus() && driver.matches(&resource)
+        });
+        let driver = match driver {
+            Some(driver) => *driver,
+            None => continue,
+        };
+
+        match driver.probe(&resource) {
+            Ok(binding) => {
+                let name = with(registry, |registry| {
+                    registry.bind(id, driver, binding)
+                });
+                log::info!("{}: {} bound to {}", name, resource, driver.name());
+                probed += 1;
+            }
+            Err(ProbeError::NotPresent) => {}
+            Err(error) => {
+                log::warn!("{} couldn't probe {}: {}", driver.name(), resource,
+                           error);
+            }
+        }
+    }
+
+    probed
+}
+
+fn remove_in(registry: &Mutex<Registry>, id: DeviceId)
+        -> Result<(), DeviceError> {
+    let unbound = with(registry, |registry| {
+        registry.get(id).ok_or(DeviceError::NotFound)?;
+        let unbound = registry.unbind(id);
+        registry.entries[id.0] = None;
+        Ok(unbound)
+    })?;
+
+    if let Some((driver, resource)) = unbound {
+        driver.remove(&resource);
+    }
+    Ok(())
+}
+
+fn shutdown_in(registry: &Mutex<Registry>) {
+    while let Some((driver, resource)) = with(registry, |registry| {
+        let id = registry.last_bound()?;
+        registry.unbind(id)
+    }) {
+        driver.remove(&resource);
+    }
+}
+
+// Add the platform devices and the PCI devices found by pci::init, and probe
+// them all.
+pub fn init() {
+    let resources = platform::DEVICES.iter().copied()
+        .map(Resource::Platform)
+        .chain(pci::devices().map(Resource::Pci));
+
+    for resource in resources {
+        if let Err(error) = add(resource) {
+            log::warn!("ignoring {}: {}", resource, error);
+        }
+    }
+
+    probe();
+}
+
+// Put a device in the registry. It isn't bound to a driver until probe is
+// called.
+pub fn add(resource: Resource) -> Result<DeviceId, DeviceError> {
+    with(&REGISTRY, |registry| registry.add(resource))
+}
+
+// Offer each unbound device to the drivers. Returns the number bound.
+pub fn probe() -> usize {
+    probe_in(&REGISTRY, DRIVERS)
+}
+
+// Unbind a device from its driver, if it has one, and take it out of the
+// registry.
+pub fn remove(id: DeviceId) -> Result<(), DeviceError> {
+    remove_in(&REGISTRY, id)
+}
+
+// Unbind every device, in the reverse of the order they were probed. They
+// stay in the registry, so probe binds them again.
+pub fn shutdown() {
+    shutdown_in(&REGISTRY);
+}
+
+pub fn get(id: DeviceId) -> Option<Device> {
+    with(&REGISTRY, |registry| registry.get(id))
+}
+
+// Every device in the registry, bound or not.
+pub fn devices() -> impl Iterator<Item = Device> {
+    (0..MAX_DEVICES).filter_map(|index| get(DeviceId(index)))
+}
+
+// The bound device with the given name, like 'ttyS0'.
+pub fn find(name: &str) -> Option<Device> {
+    with(&REGISTRY, |registry| registry.find(name))
+}
+
+// The bound devices of the given class.
+pub fn by_class(class: Class) -> impl Iterator<Item = Device> {
+    devices().filter(move |device| device.class() == Some(class))
+}
+
+// The character device with the given name.
+pub fn char_device(name: &str) -> Option<&'static dyn CharDevice> {
+    match find(name)?.interface {
+        Interface::Char(device) => Some(device),
+        _ => None,
+    }
+}
+
+// The block device with the given name.
+pub fn block_device(name: &str) -> Option<&'static dyn BlockDevice> {
+    match find(name)?.interface {
+        Interface::Block(device) => Some(device),
+        _ => None,
+    }
+}
+
+// The network device with the given name.
+pub fn net_device(name: &str) -> Option<&'static dyn NetDevice> {
+    match find(name)?.interface {
+        Interface::Net(device) => Some(device),
+        _ => None,
+    }
+}
+
+
+// TESTING
+
+// Test that names only match with the right start and number.
+#[test_case]
+fn test_device_names() {
+    let name = Name { base: "tty", number: 10 };
+
+    assert!(name.matches("tty10"));
+    assert!(!name.matches("tty010"));
+    assert!(!name.matches("tty1"));
+    assert!(!name.matches("ttyS10"));
+}
+
+// Test that devices are probed by the first driver which matches them, in the
+// order they were added, that they are numbered in that order, and that
+// shutdown removes them in reverse.
+#[test_case]
+fn test_device_lifecycle() {
+    static CALLS: Mutex<[(&str, &str); 8]> = Mutex::new([("", ""); 8]);
+    static CALL_COUNT: Mutex<usize> = Mutex::new(0);
+
+    fn record(call: &'static str, resource: &Resource) {
+        if let Resource::Platform(name) = resource {
+            let mut count = CALL_COUNT.lock();
+            CALLS.lock()[*count] = (call, *name);
+            *count += 1;
+        }
+    }
+
+    struct TestDriver(&'static str);
+
+    impl Driver for TestDriver {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn bus(&self) -> Bus {
+            Bus::Platform
+        }
+
+        fn matches(&self, resource: &Resource) -> bool {
+            matches!(resource, Resource::Platform(name)
+                     if name.starts_with("test"))
+        }
+
+        fn probe(&self, resource: &Resource) -> Result<Binding, ProbeError> {
+            record("probe", resource);
+            match resource {
+                Resource::Platform("test-missing") => {
+                    Err(ProbeError::NotPresent)
+                }
+                _ => Ok(Binding { name: "test", interface: Interface::None }),
+            }
+        }
+
+        fn remove(&self, resource: &Resource) {
+            record("remove", resource);
+        }
+    }
+
+    static FIRST: TestDriver = TestDriver("first");
+    static SECOND: TestDriver = TestDriver("second");
+    static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+    for name in ["test-a", "other", "test-missing", "test-b"].iter() {
+        with(&REGISTRY, |registry| registry.add(Resource::Platform(*name)))
+            .unwrap();
+    }
+    let drivers: [&'static dyn Driver; 2] = [&FIRST, &SECOND];
+    assert_eq!(probe_in(&REGISTRY, &drivers), 2);
+
+    let b = with(&REGISTRY, |registry| registry.find("test1")).unwrap();
+    assert!(matches!(b.resource, Resource::Platform("test-b")));
+    assert_eq!(b.driver, Some("first"));
+
+    shutdown_in(&REGISTRY);
+    assert!(with(&REGISTRY, |registry| registry.find("test0")).is_none());
+
+    let count = *CALL_COUNT.lock();
+    assert_eq!(&CALLS.lock()[..count], &[
+        ("probe", "test-a"), ("probe", "test-missing"), ("probe", "test-b"),
+        ("remove", "test-b"), ("remove", "test-a"),
+    ]);
+}
+
+// Test that the first serial port is registered as a character device, and
+// can be found by its name.
+#[test_case]
+fn test_find_serial_port() {
+    let device = find("ttyS0").expect("ttyS0 wasn't found");
+
+    assert!(matches!(device.resource, Resource::Platform("com1")));
+    assert_eq!(device.driver, Some("serial"));
+    assert!(by_class(Class::Char).any(|device| {
+        device.name.map_or(false, |name| name.matches("ttyS0"))
+    }));
+    assert!(char_device("ttyS0").is_some());
+}
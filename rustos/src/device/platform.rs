@@ -0,0 +1,128 @@
+// The platform devices, which every PC has at the same fixed places, and their
+// drivers. They can't be found by probing a bus, so init adds them by name:
+// - com1 to com4: The standard serial ports, which become 'ttyS0' onwards.
+//               A port is only bound if there is a UART there.
+// - vga: The VGA text buffer, which becomes 'tty0'. Only when the kernel is
+//               using it, rather than the framebuffer.
+
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::serial::{self, Com};
+use crate::vga_buffer::{Colour, WRITER};
+use super::{Binding, Bus, CharDevice, Driver, Interface, ProbeError,
+            Resource};
+
+#[cfg(not(feature = "framebuffer"))]
+pub const DEVICES: &[&str] = &["com1", "com2", "com3", "com4", "vga"];
+
+#[cfg(feature = "framebuffer")]
+pub const DEVICES: &[&str] = &["com1", "com2", "com3", "com4"];
+
+static PORTS: [(&str, Com); 4] = [
+    ("com1", Com::Com1),
+    ("com2", Com::Com2),
+    ("com3", Com::Com3),
+    ("com4", Com::Com4),
+];
+
+fn port(resource: &Resource) -> Option<&'static Com> {
+    match resource {
+        Resource::Platform(name) => {
+            PORTS.iter().find(|(port, _)| port == name).map(|(_, com)| com)
+        }
+        _ => None,
+    }
+}
+
+impl CharDevice for Com {
+    fn write(&self, bytes: &[u8]) {
+        Com::write(*self, bytes);
+    }
+
+    // Anything still in the UART is taken first, as the receive interrupt
+    // won't have been handled if interrupts are disabled.
+    fn read(&self, buffer: &mut [u8]) -> usize {
+        without_interrupts(|| serial::handle_interrupt(&[*self]));
+        self.try_read(buffer)
+    }
+
+    unsafe fn force_unlock(&self) {
+        self.port().force_unlock();
+    }
+}
+
+pub struct SerialDriver;
+
+impl Driver for SerialDriver {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Platform
+    }
+
+    fn matches(&self, resource: &Resource) -> bool {
+        port(resource).is_some()
+    }
+
+    fn probe(&self, resource: &Resource) -> Result<Binding, ProbeError> {
+        let com = port(resource).ok_or(ProbeError::NotPresent)?;
+        if !com.is_present() {
+            return Err(ProbeError::NotPresent);
+        }
+
+        Ok(Binding { name: "ttyS", interface: Interface::Char(com) })
+    }
+}
+
+// The VGA text buffer, as a terminal which can only be written to.
+struct VgaTerminal;
+
+impl CharDevice for VgaTerminal {
+    fn write(&self, bytes: &[u8]) {
+        without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            for byte in bytes {
+                writer.write_byte(*byte);
+            }
+        });
+    }
+
+    fn write_coloured(&self, colour: Colour, bytes: &[u8]) {
+        without_interrupts(|| {
+            WRITER.lock().in_colour(colour, |writer| {
+                for byte in bytes {
+                    writer.write_byte(*byte);
+                }
+            });
+        });
+    }
+
+    fn read(&self, _buffer: &mut [u8]) -> usize {
+        0
+    }
+
+    unsafe fn force_unlock(&self) {
+        WRITER.force_unlock();
+    }
+}
+
+pub struct VgaDriver;
+
+impl Driver for VgaDriver {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Platform
+    }
+
+    fn matches(&self, resource: &Resource) -> bool {
+        matches!(resource, Resource::Platform("vga"))
+    }
+
+    fn probe(&self, _resource: &Resource) -> Result<Binding, ProbeError> {
+        Ok(Binding { name: "tty", interface: Interface::Char(&VgaTerminal) })
+    }
+}
@@ -0,0 +1,418 @@
+// A linear framebuffer is a region of memory in which every pixel on the
+// screen is represented by one or more bytes, laid out row by row from the top
+// left corner of the screen. Unlike the VGA text buffer at 0xb8000, where the
+// graphics card draws each character cell for us, a framebuffer leaves it up to
+// us to draw every pixel, including the pixels which make up text.
+// ---
+// There are two common ways of getting hold of a framebuffer:
+// - VGA mode 13h: A legacy 320x200 mode with 256 colours, where each pixel is
+//               a single byte indexing into the VGA palette. The framebuffer is
+//               always located at the physical address 0xa0000. The bootloader
+//               can switch into this mode for us when the 'vga_320x200' feature
+//               is enabled, which we expose through our 'framebuffer' feature.
+// - VESA/UEFI GOP: The firmware (via the bootloader) hands us a framebuffer at
+//               a higher resolution, usually with 24 or 32 bits per pixel in
+//               either RGB or BGR order.
+// ---
+// To support both, the FrameBuffer struct is given a description of the memory
+// layout (width, height, bytes per row and pixel format) and converts our
+// Colour enum into the correct bytes for each pixel as it draws.
+
+use core::ptr;
+use crate::vga_buffer::Colour;
+
+pub mod console;
+pub mod font;
+
+// The physical address and dimensions of the VGA mode 13h framebuffer.
+pub const MODE_13H_ADDRESS: usize = 0xa0000;
+pub const MODE_13H_INFO: FrameBufferInfo = FrameBufferInfo {
+    width: 320,
+    height: 200,
+    stride: 320,
+    format: PixelFormat::Indexed8,
+};
+
+// The pixel formats we know how to draw to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // One byte per pixel, which indexes into the VGA palette.
+    Indexed8,
+
+    // Three bytes per pixel, in red-green-blue or blue-green-red order.
+    Rgb24,
+    Bgr24,
+
+    // Four bytes per pixel, with the last byte unused.
+    Rgb32,
+    Bgr32,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Indexed8 => 1,
+            PixelFormat::Rgb24 | PixelFormat::Bgr24 => 3,
+            PixelFormat::Rgb32 | PixelFormat::Bgr32 => 4,
+        }
+    }
+}
+
+// A description of the layout of a framebuffer in memory.
+// ---
+// The stride is the number of bytes between the start of one row and the start
+// of the next. This is often larger than width * bytes_per_pixel, as graphics
+// cards like to pad rows out to a convenient size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+// A 24-bit true colour value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+// The default VGA palette for the first 16 colours. In mode 13h these are the
+// colours the graphics card displays for palette entries 0 to 15, which line up
+// with the values of the Colour enum, so for true colour framebuffers we use
+// this table to get the same colours.
+const PALETTE: [Rgb; 16] = [
+    Rgb { red: 0x00, green: 0x00, blue: 0x00 },
+    Rgb { red: 0x00, green: 0x00, blue: 0xaa },
+    Rgb { red: 0x00, green: 0xaa, blue: 0x00 },
+    Rgb { red: 0x00, green: 0xaa, blue: 0xaa },
+    Rgb { red: 0xaa, green: 0x00, blue: 0x00 },
+    Rgb { red: 0xaa, green: 0x00, blue: 0xaa },
+    Rgb { red: 0xaa, green: 0x55, blue: 0x00 },
+    Rgb { red: 0xaa, green: 0xaa, blue: 0xaa },
+    Rgb { red: 0x55, green: 0x55, blue: 0x55 },
+    Rgb { red: 0x55, green: 0x55, blue: 0xff },
+    Rgb { red: 0x55, green: 0xff, blue: 0x55 },
+    Rgb { red: 0x55, green: 0xff, blue: 0xff },
+    Rgb { red: 0xff, green: 0x55, blue: 0x55 },
+    Rgb { red: 0xff, green: 0x55, blue: 0xff },
+    Rgb { red: 0xff, green: 0xff, blue: 0x55 },
+    Rgb { red: 0xff, green: 0xff, blue: 0xff },
+];
+
+impl From<Colour> for Rgb {
+    fn from(colour: Colour) -> Rgb {
+        PALETTE[colour as usize]
+    }
+}
+
+pub struct FrameBuffer {
+    // The framebuffer memory, covering stride * height bytes.
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+}
+
+impl FrameBuffer {
+    // Create a new FrameBuffer over the memory starting at the given address.
+    // ---
+    // This is unsafe as the caller must guarantee that the memory is mapped,
+    // is at least stride * height bytes long, and isn't used by anything else
+    // for the rest of the runtime of the kernel.
+    pub unsafe fn new(base: *mut u8, info: FrameBufferInfo) -> FrameBuffer {
+        FrameBuffer {
+            buffer: core::slice::from_raw_parts_mut(
+                base, info.stride * info.height),
+            info,
+        }
+    }
+
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    // Convert a colour into the bytes which represent it in this framebuffer.
+    // Only the first bytes_per_pixel bytes of the returned array are used.
+    fn encode(&self, colour: Colour) -> [u8; 4] {
+        let rgb = Rgb::from(colour);
+
+        match self.info.format {
+            PixelFormat::Indexed8 => [colour as u8, 0, 0, 0],
+            PixelFormat::Rgb24 | PixelFormat::Rgb32 =>
+                [rgb.red, rgb.green, rgb.blue, 0],
+            PixelFormat::Bgr24 | PixelFormat::Bgr32 =>
+                [rgb.blue, rgb.green, rgb.red, 0],
+        }
+    }
+
+    // Write the encoded pixel bytes to the given position. The caller is
+    // responsible for ensuring the position is on the screen.
+    fn write_encoded(&mut self, x: usize, y: usize, bytes: &[u8; 4]) {
+        let bytes_per_pixel = self.info.format.bytes_per_pixel();
+        let offset = y * self.info.stride + x * bytes_per_pixel;
+
+        // As with the VGA text buffer, the compiler can't see that anything
+        // reads this memory, so we use volatile writes to make sure they are
+        // never optimised away.
+        for (i, byte) in bytes[..bytes_per_pixel].iter().enumerate() {
+            unsafe { ptr::write_volatile(&mut self.buffer[offset + i], *byte) };
+        }
+    }
+
+    // Read back the raw bytes of the pixel at the given position, packed into
+    // a u32 in the order they appear in memory.
+    pub fn read_raw(&self, x: usize, y: usize) -> u32 {
+        let bytes_per_pixel = self.info.format.bytes_per_pixel();
+        let offset = y * self.info.stride + x * bytes_per_pixel;
+
+        let mut value = 0;
+        for i in 0..bytes_per_pixel {
+            let byte = unsafe { ptr::read_volatile(&self.buffer[offset + i]) };
+            value |= (byte as u32) << (i * 8);
+        }
+
+        value
+    }
+
+    // Set a single pixel. Pixels which fall outside of the screen are ignored,
+    // which means the other drawing methods don't need to clip their output.
+    pub fn set_pixel(&mut self, x: usize, y: usize, colour: Colour) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+
+        let bytes = self.encode(colour);
+        self.write_encoded(x, y, &bytes);
+    }
+
+    // Fill the whole screen with a single colour.
+    pub fn clear(&mut self, colour: Colour) {
+        let (width, height) = (self.info.width, self.info.height);
+        self.fill_rect(0, 0, width, height, colour);
+    }
+
+    // Draw a line between two points using Bresenham's line algorithm. The
+    // algorithm steps along the line one pixel at a time, keeping track of the
+    // error between the pixel it has drawn and the true line, and moves along
+    // the other axis whenever the error grows too large. This means we only
+    // ever need integer arithmetic.
+    pub fn draw_line(&mut self, x0: usize, y0: usize, x1: usize, y1: usize,
+                     colour: Colour) {
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let (x1, y1) = (x1 as isize, y1 as isize);
+
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            self.set_pixel(x as usize, y as usize, colour);
+
+            if x == x1 && y == y1 {
+                break;
+            }
+
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    // Draw the outline of a rectangle. The rectangle is clipped to the screen,
+    // so an edge which is off the screen isn't drawn.
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize,
+                     height: usize, colour: Colour) {
+        if width == 0 || height == 0 || x >= self.info.width
+            || y >= self.info.height {
+            return;
+        }
+
+        let right = x.saturating_add(width - 1);
+        let bottom = y.saturating_add(height - 1);
+        let clipped_right = core::cmp::min(right, self.info.width - 1);
+        let clipped_bottom = core::cmp::min(bottom, self.info.height - 1);
+
+        self.draw_line(x, y, clipped_right, y, colour);
+        self.draw_line(x, y, x, clipped_bottom, colour);
+        if bottom == clipped_bottom {
+            self.draw_line(x, bottom, clipped_right, bottom, colour);
+        }
+        if right == clipped_right {
+            self.draw_line(right, y, right, clipped_bottom, colour);
+        }
+    }
+
+    // Draw a filled rectangle.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize,
+                     height: usize, colour: Colour) {
+        // Clip the rectangle to the screen up front, so we only encode the
+        // colour once and don't check the bounds of every pixel.
+        let right = core::cmp::min(x.saturating_add(width), self.info.width);
+        let bottom = core::cmp::min(y.saturating_add(height), self.info.height);
+        let bytes = self.encode(colour);
+
+        for row in y..bottom {
+            for col in x..right {
+                self.write_encoded(col, row, &bytes);
+            }
+        }
+    }
+
+    // Copy a block of pixels to the screen, clipped to the screen. The pixels
+    // are given row by row, so the slice must contain at least width * height
+    // colours.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize,
+                pixels: &[Colour]) {
+        let enough = width.checked_mul(height)
+            .map_or(false, |count| pixels.len() >= count);
+        assert!(enough, "blit source is too small");
+
+        let width_shown = core::cmp::min(width,
+                                         self.info.width.saturating_sub(x));
+        let height_shown = core::cmp::min(height,
+                                          self.info.height.saturating_sub(y));
+
+        for row in 0..height_shown {
+            for col in 0..width_shown {
+                let bytes = self.encode(pixels[row * width + col]);
+                self.write_encoded(x + col, y + row, &bytes);
+            }
+        }
+    }
+
+    // Move the contents of the screen up by the given number of pixel rows,
+    // filling the rows which are uncovered at the bottom with a colour. This is
+    // used by the text console to scroll.
+    pub fn scroll_up(&mut self, rows: usize, fill: Colour) {
+        let rows = core::cmp::min(rows, self.info.height);
+        let stride = self.info.stride;
+        let height = self.info.height;
+
+        self.buffer.copy_within(rows * stride.., 0);
+
+        let width = self.info.width;
+        self.fill_rect(0, height - rows, width, rows, fill);
+    }
+}
+
+
+// TESTING
+
+// The tests draw into a small buffer in normal memory, rather than the real
+// framebuffer, so they run the same regardless of which display mode QEMU is
+// in.
+#[cfg(test)]
+const TEST_INFO: FrameBufferInfo = FrameBufferInfo {
+    width: 16,
+    height: 8,
+    stride: 16,
+    format: PixelFormat::Indexed8,
+};
+
+#[cfg(test)]
+static mut TEST_BUFFER: [u8; 16 * 8] = [0; 16 * 8];
+
+#[cfg(test)]
+fn test_framebuffer() -> FrameBuffer {
+    let mut framebuffer =
+        unsafe { FrameBuffer::new(TEST_BUFFER.as_mut_ptr(), TEST_INFO) };
+    framebuffer.clear(Colour::Black);
+    framebuffer
+}
+
+// Test that pixels are written where we expect them, and that pixels off the
+// edge of the screen are ignored rather than panicking.
+#[test_case]
+fn test_set_pixel() {
+    let mut framebuffer = test_framebuffer();
+    framebuffer.set_pixel(3, 2, Colour::Red);
+    framebuffer.set_pixel(100, 100, Colour::Red);
+
+    assert_eq!(framebuffer.read_raw(3, 2), Colour::Red as u32);
+    assert_eq!(framebuffer.read_raw(2, 3), Colour::Black as u32);
+}
+
+// Test that a filled rectangle is clipped to the screen.
+#[test_case]
+fn test_fill_rect() {
+    let mut framebuffer = test_framebuffer();
+    framebuffer.fill_rect(12, 6, 10, 10, Colour::Green);
+
+    assert_eq!(framebuffer.read_raw(12, 6), Colour::Green as u32);
+    assert_eq!(framebuffer.read_raw(15, 7), Colour::Green as u32);
+    assert_eq!(framebuffer.read_raw(11, 6), Colour::Black as u32);
+}
+
+// Test that a rectangle's outline is clipped to the screen, leaving out the
+// edges which are off it, even when its far corner is past the end of usize.
+#[test_case]
+fn test_draw_rect_clipped() {
+    let mut framebuffer = test_framebuffer();
+    framebuffer.draw_rect(12, 2, 10, usize::MAX, Colour::Green);
+
+    assert_eq!(framebuffer.read_raw(12, 2), Colour::Green as u32);
+    assert_eq!(framebuffer.read_raw(15, 2), Colour::Green as u32);
+    assert_eq!(framebuffer.read_raw(12, 7), Colour::Green as u32);
+    assert_eq!(framebuffer.read_raw(15, 7), Colour::Black as u32);
+    assert_eq!(framebuffer.read_raw(13, 3), Colour::Black as u32);
+}
+
+// Test that a block of pixels hanging off the screen is clipped to it.
+#[test_case]
+fn test_blit_clipped() {
+    let mut framebuffer = test_framebuffer();
+    let pixels = [Colour::Red; 4 * 4];
+    framebuffer.blit(14, 6, 4, 4, &pixels);
+
+    assert_eq!(framebuffer.read_raw(14, 6), Colour::Red as u32);
+    assert_eq!(framebuffer.read_raw(15, 7), Colour::Red as u32);
+    assert_eq!(framebuffer.read_raw(13, 6), Colour::Black as u32);
+    framebuffer.blit(usize::MAX, usize::MAX, 4, 4, &pixels);
+}
+
+// Test that a diagonal line touches both end points and the middle.
+#[test_case]
+fn test_draw_line() {
+    let mut framebuffer = test_framebuffer();
+    framebuffer.draw_line(7, 7, 0, 0, Colour::White);
+
+    for i in 0..8 {
+        assert_eq!(framebuffer.read_raw(i, i), Colour::White as u32);
+    }
+    assert_eq!(framebuffer.read_raw(1, 0), Colour::Black as u32);
+}
+
+// Test that true colour framebuffers get the bytes in the right order.
+#[test_case]
+fn test_true_colour_encoding() {
+    let info = FrameBufferInfo {
+        width: 4,
+        height: 2,
+        stride: 16,
+        format: PixelFormat::Bgr32,
+    };
+    let mut framebuffer =
+        unsafe { FrameBuffer::new(TEST_BUFFER.as_mut_ptr(), info) };
+    framebuffer.set_pixel(1, 1, Colour::LightRed);
+
+    assert_eq!(framebuffer.read_raw(1, 1), 0x00ff5555);
+    assert_eq!(unsafe { TEST_BUFFER[16 + 4] }, 0x55);
+    assert_eq!(unsafe { TEST_BUFFER[16 + 6] }, 0xff);
+}
@@ -0,0 +1,257 @@
+// A text console which draws characters into a framebuffer using a bitmap
+// font. It behaves the same way as the VGA text buffer Writer: text is always
+// written to the bottom line, and the screen scrolls up by one line when a
+// line is full or a \n is received. This means print! and println! look the
+// same whichever backend is in use.
+// ---
+// A framebuffer smaller than one character cell has no whole rows or columns,
+// in which case the characters are drawn into the top left cell, clipped to
+// the screen.
+
+use core::fmt;
+use crate::vga_buffer::Colour;
+use super::FrameBuffer;
+use super::font::Font;
+
+#[cfg(feature = "framebuffer")]
+use lazy_static::lazy_static;
+#[cfg(feature = "framebuffer")]
+use spin::Mutex;
+#[cfg(feature = "framebuffer")]
+use crate::console::Console;
+#[cfg(feature = "framebuffer")]
+use x86_64::instructions::interrupts;
+
+pub struct TextConsole {
+    framebuffer: FrameBuffer,
+    font: &'static Font,
+
+    // The size of the console in character cells.
+    columns: usize,
+    rows: usize,
+
+    // Stores the current position in the last row.
+    column_position: usize,
+
+    // Stores the current foreground and background colours.
+    foreground: Colour,
+    background: Colour,
+}
+
+impl TextConsole {
+    pub fn new(framebuffer: FrameBuffer, font: &'static Font) -> TextConsole {
+        let columns = framebuffer.width() / font.width();
+        let rows = framebuffer.height() / font.height();
+
+        let mut console = TextConsole {
+            framebuffer,
+            font,
+            columns,
+            rows,
+            column_position: 0,
+            foreground: Colour::Cyan,
+            background: Colour::Black,
+        };
+
+        console.framebuffer.clear(console.background);
+        console
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn set_colours(&mut self, foreground: Colour, background: Colour) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    pub fn colours(&self) -> (Colour, Colour) {
+        (self.foreground, self.background)
+    }
+
+    // Gives access to the underlying framebuffer, so the drawing primitives
+    // can be used alongside the text.
+    pub fn framebuffer(&mut self) -> &mut FrameBuffer {
+        &mut self.framebuffer
+    }
+
+    // Write a byte to the console.
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+
+            // Backspace moves back a column, without rubbing anything out.
+            0x08 => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                }
+            }
+
+            byte => {
+                if self.column_position >= self.columns {
+                    self.new_line();
+                }
+
+                let row = self.rows.saturating_sub(1);
+                let col = self.column_position;
+                self.draw_char(col, row, byte);
+
+                self.column_position += 1;
+            }
+        }
+    }
+
+    // Write a string to the console, replacing any bytes outside of the
+    // printable ASCII range with a ■ character, as the VGA Writer does.
+    pub fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            match byte {
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
+                _ => self.write_byte(0xfe),
+            }
+        }
+    }
+
+    // Draw a character into the given character cell, painting every pixel of
+    // the cell so that it replaces whatever was there before.
+    fn draw_char(&mut self, col: usize, row: usize, byte: u8) {
+        let glyph = self.font.glyph(byte);
+        let origin_x = col * self.font.width();
+        let origin_y = row * self.font.height();
+
+        for y in 0..self.font.height() {
+            for x in 0..self.font.width() {
+                let colour = if glyph.is_set(x, y) {
+                    self.foreground
+                } else {
+                    self.background
+                };
+
+                self.framebuffer.set_pixel(origin_x + x, origin_y + y, colour);
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        let height = self.font.height();
+        self.framebuffer.scroll_up(height, self.background);
+
+        // If the screen height isn't a multiple of the font height, there are
+        // some spare pixel rows below the last line of text, which have now
+        // been scrolled into it. Clear the whole of the last line so none of
+        // them are left behind.
+        let width = self.columns * self.font.width();
+        self.framebuffer.fill_rect(
+            0, self.rows.saturating_sub(1) * height, width, height,
+            self.background);
+
+        self.column_position = 0;
+    }
+}
+
+impl fmt::Write for TextConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+// When the 'framebuffer' feature is enabled the bootloader has switched the
+// display into mode 13h, so we set up a text console over that framebuffer
+// using the built-in font. This is only created the first time it is used.
+#[cfg(feature = "framebuffer")]
+lazy_static! {
+    pub static ref CONSOLE: Mutex<TextConsole> = Mutex::new(TextConsole::new(
+        unsafe { FrameBuffer::new(
+            super::MODE_13H_ADDRESS as *mut u8, super::MODE_13H_INFO) },
+        &super::font::DEFAULT_FONT,
+    ));
+}
+
+// The framebuffer console as a console sink, so that the console module can
+// route the output of print! and println! to it.
+#[cfg(feature = "framebuffer")]
+pub struct FrameBufferConsole;
+
+#[cfg(feature = "framebuffer")]
+impl Console for FrameBufferConsole {
+    fn write(&self, args: fmt::Arguments) {
+        use core::fmt::Write;
+        interrupts::without_interrupts(|| {
+            CONSOLE.lock().write_fmt(args).unwrap();
+        });
+    }
+
+    // Temporarily switch the console to the given foreground colour, restoring
+    // the original colours once the text has been written.
+    fn write_coloured(&self, colour: Colour, args: fmt::Arguments) {
+        use core::fmt::Write;
+        interrupts::without_interrupts(|| {
+            let mut console = CONSOLE.lock();
+            let (foreground, background) = console.colours();
+
+            console.set_colours(colour, background);
+            console.write_fmt(args).unwrap();
+            console.set_colours(foreground, background);
+        });
+    }
+}
+
+
+// TESTING
+
+// Test that a character is drawn into the bottom left cell of the console, in
+// the foreground colour, and that the rest of the cell is left in the
+// background colour.
+#[test_case]
+fn test_console_draws_glyph() {
+    use super::{FrameBufferInfo, PixelFormat};
+    use super::font::DEFAULT_FONT;
+
+    static mut BUFFER: [u8; 32 * 26] = [0; 32 * 26];
+    let info = FrameBufferInfo {
+        width: 32,
+        height: 26,
+        stride: 32,
+        format: PixelFormat::Indexed8,
+    };
+
+    let framebuffer = unsafe { FrameBuffer::new(BUFFER.as_mut_ptr(), info) };
+    let mut console = TextConsole::new(framebuffer, &DEFAULT_FONT);
+    console.set_colours(Colour::Yellow, Colour::Blue);
+    console.write_byte(0xfe);
+
+    // The ■ glyph is solid in the middle of the cell, and empty at the top.
+    let top = 13;
+    assert_eq!(console.framebuffer.read_raw(3, top + 6), Colour::Yellow as u32);
+    assert_eq!(console.framebuffer.read_raw(3, top), Colour::Blue as u32);
+    assert_eq!(console.columns(), 4);
+    assert_eq!(console.rows(), 2);
+}
+
+// Test that a framebuffer shorter than the font still takes text, rather than
+// panicking, with the characters clipped to the screen.
+#[test_case]
+fn test_console_smaller_than_font() {
+    use super::{FrameBufferInfo, PixelFormat};
+    use super::font::DEFAULT_FONT;
+
+    static mut BUFFER: [u8; 32 * 8] = [0; 32 * 8];
+    let info = FrameBufferInfo {
+        width: 32,
+        height: 8,
+        stride: 32,
+        format: PixelFormat::Indexed8,
+    };
+
+    let framebuffer = unsafe { FrameBuffer::new(BUFFER.as_mut_ptr(), info) };
+    let mut console = TextConsole::new(framebuffer, &DEFAULT_FONT);
+    console.write_string("hello\nworld");
+
+    assert_eq!(console.rows(), 0);
+}
@@ -0,0 +1,192 @@
+// When drawing to a framebuffer there is no graphics card drawing characters
+// for us, so we need a font. We use the PC Screen Font (PSF) format, which is
+// the format used by the Linux console. A PSF font is a small header followed
+// by a bitmap for every glyph, where each row of the glyph is stored as one or
+// more bytes, with the most significant bit being the leftmost pixel.
+// ---
+// There are two versions of the format:
+// - PSF1: A 4 byte header (magic 0x36 0x04, a mode byte and the glyph height).
+//               Glyphs are always 8 pixels wide, and there are 256 or 512 of
+//               them.
+// - PSF2: A 32 byte header (magic 0x72 0xb5 0x4a 0x86) which gives the number
+//               of glyphs, their width and height, and the number of bytes
+//               used to store each glyph.
+// ---
+// The built-in font is an 8x13 font laid out in the IBM PC code page 437 order,
+// so that the byte values we print match those of the VGA text buffer (e.g.
+// 0xfe is a ■ character). It was converted from the public domain X11
+// misc-fixed 8x13 font.
+
+use lazy_static::lazy_static;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+// The widest and tallest glyphs we accept, which is far bigger than any
+// console font, but keeps the glyph arithmetic well away from overflowing.
+const MAX_GLYPH_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    // The data doesn't start with a PSF1 or PSF2 magic number.
+    BadMagic,
+
+    // The data is shorter than the header says it should be.
+    Truncated,
+
+    // The glyphs are empty or too big, or the header gives fewer bytes for
+    // each glyph than its width and height need.
+    BadSize,
+}
+
+pub struct Font {
+    glyphs: &'static [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    bytes_per_row: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Font {
+    // Parse a PSF1 or PSF2 font.
+    pub fn parse(data: &'static [u8]) -> Result<Font, FontError> {
+        if data.len() >= 4 && data[..2] == PSF1_MAGIC {
+            let glyph_count =
+                if data[2] & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            let height = data[3] as usize;
+
+            Font::new(&data[4..], glyph_count, height, 8, height)
+        } else if data.len() >= 32 && data[..4] == PSF2_MAGIC {
+            // All fields in the PSF2 header are little-endian u32s.
+            let field = |index: usize| {
+                let offset = index * 4;
+                let bytes = [data[offset], data[offset + 1],
+                             data[offset + 2], data[offset + 3]];
+                u32::from_le_bytes(bytes) as usize
+            };
+
+            let header_size = field(2);
+            if data.len() < header_size {
+                return Err(FontError::Truncated);
+            }
+
+            Font::new(&data[header_size..], field(4), field(5), field(7),
+                      field(6))
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn new(glyphs: &'static [u8], glyph_count: usize, bytes_per_glyph: usize,
+           width: usize, height: usize) -> Result<Font, FontError> {
+        if glyph_count == 0 || width == 0 || height == 0
+            || width > MAX_GLYPH_SIZE || height > MAX_GLYPH_SIZE {
+            return Err(FontError::BadSize);
+        }
+
+        // Every row of every glyph has to be in the data.
+        let bytes_per_row = (width + 7) / 8;
+        if bytes_per_glyph < bytes_per_row * height {
+            return Err(FontError::BadSize);
+        }
+        let size = glyph_count.checked_mul(bytes_per_glyph)
+            .ok_or(FontError::BadSize)?;
+        if glyphs.len() < size {
+            return Err(FontError::Truncated);
+        }
+
+        Ok(Font {
+            glyphs,
+            glyph_count,
+            bytes_per_glyph,
+            bytes_per_row,
+            width,
+            height,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Get the bitmap for the given character. Characters which aren't in the
+    // font are drawn as the first glyph.
+    pub fn glyph(&self, character: u8) -> Glyph {
+        let index = character as usize;
+        let index = if index < self.glyph_count { index } else { 0 };
+        let start = index * self.bytes_per_glyph;
+
+        Glyph {
+            bitmap: &self.glyphs[start..start + self.bytes_per_glyph],
+            bytes_per_row: self.bytes_per_row,
+        }
+    }
+}
+
+// A single glyph bitmap.
+pub struct Glyph {
+    bitmap: &'static [u8],
+    bytes_per_row: usize,
+}
+
+impl Glyph {
+    // Check if the pixel at the given position within the glyph is set.
+    pub fn is_set(&self, x: usize, y: usize) -> bool {
+        let byte = self.bitmap[y * self.bytes_per_row + x / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+lazy_static! {
+    pub static ref DEFAULT_FONT: Font =
+        Font::parse(include_bytes!("font8x13.psf"))
+            .expect("built-in font is invalid");
+}
+
+
+// TESTING
+
+// Test that the built-in font is parsed with the expected dimensions.
+#[test_case]
+fn test_default_font_dimensions() {
+    assert_eq!(DEFAULT_FONT.width(), 8);
+    assert_eq!(DEFAULT_FONT.height(), 13);
+}
+
+// Test that glyphs are looked up in the right place, by checking the ■
+// character is a solid block in the middle and empty at the top.
+#[test_case]
+fn test_glyph_lookup() {
+    let glyph = DEFAULT_FONT.glyph(0xfe);
+
+    assert!(!glyph.is_set(0, 0));
+    assert!(glyph.is_set(3, 6));
+}
+
+// Test that fonts with empty or oversized glyphs are turned away, rather than
+// overflowing or giving the console a zero to divide by.
+#[test_case]
+fn test_bad_glyph_sizes() {
+    static EMPTY_PSF1: [u8; 4] = [0x36, 0x04, 0, 0];
+    assert!(matches!(Font::parse(&EMPTY_PSF1), Err(FontError::BadSize)));
+
+    // A PSF2 header for 2^32 - 1 glyphs of 2^32 - 1 bytes each, which is
+    // found to be missing its glyphs, and then one for glyphs 4096 pixels
+    // wide.
+    static HUGE_PSF2: [u8; 32] = [
+        0x72, 0xb5, 0x4a, 0x86, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 8, 0, 0, 0, 8, 0, 0, 0,
+    ];
+    assert!(matches!(Font::parse(&HUGE_PSF2), Err(FontError::Truncated)));
+    static WIDE_PSF2: [u8; 32] = [
+        0x72, 0xb5, 0x4a, 0x86, 0, 0, 0, 0, 32, 0, 0, 0, 0, 0, 0, 0,
+        1, 0, 0, 0, 0, 2, 0, 0, 1, 0, 0, 0, 0, 0x10, 0, 0,
+    ];
+    assert!(matches!(Font::parse(&WIDE_PSF2), Err(FontError::BadSize)));
+}
@@ -0,0 +1,383 @@
+// A GDB stub, which lets GDB debug the kernel over the second serial port
+// (ttyS1, normally COM2), the same way it would debug a program over a network
+// connection or a board over a serial cable.
+// ---
+// The stub takes over two exceptions:
+// - Breakpoint (int3): Raised by the 0xcc instruction, which is what GDB
+//               writes over an instruction to set a breakpoint on it.
+// - Debug: Raised after every instruction while the Trap Flag (bit 8) of
+//               RFLAGS is set, which is how single-stepping works.
+//
+// When either happens, the stub tells GDB the kernel has stopped, and then
+// answers GDB's requests to read and change registers and memory until GDB
+// tells it to continue or step. See the packet module for the protocol.
+// ---
+// The registers of the stopped code come from the trap module's entry points,
+// which pass them to handle_exception as a TrapFrame, and load any changes GDB
+// makes back into the registers when it returns.
+// ---
+// To use it, start the kernel with 'gdb' on the command line, and QEMU's
+// second serial port connected to a TCP port. tools/runner.py does this when
+// the command line includes 'gdb', using port 4444, or RUSTOS_GDB_PORT if it
+// is set. With 'gdb-wait' as well, the kernel stops during init, waiting for
+// GDB to connect:
+//
+//     cargo run -- gdb gdb-wait
+//     gdb target/x86_64-rustos/debug/rustos -ex 'target remote :4444'
+//
+// The stub runs with interrupts disabled, and polls the serial port, so it
+// works however broken the rest of the kernel is. Stopping a running kernel
+// with Ctrl-C isn't supported, so set a breakpoint before continuing.
+// ---
+// Only the CPU which took the exception is stopped. The other CPUs keep
+// running while GDB is in control, so memory GDB reads may change under it,
+// and they can hit breakpoints of their own, which wait for the stub until
+// the stopped CPU has been continued or stepped. Stopping them too would need
+// an NMI, as they may be running with interrupts disabled, and nothing sends
+// one yet.
+
+use lazy_static::lazy_static;
+use spin::{Mutex, Once};
+use crate::cmdline;
+use crate::device::{self, CharDevice};
+use crate::memory;
+use crate::trap::{TrapFrame, BREAKPOINT_VECTOR, RFLAGS_TRAP};
+
+pub mod packet;
+
+use packet::{Command, PacketReader, PacketWriter, ReadEvent, PACKET_MAX};
+
+// The serial port GDB is connected to.
+const PORT: &str = "ttyS1";
+
+// The port's device, which is looked up by init, so that the stub doesn't
+// need the device registry once the kernel has stopped.
+static DEVICE: Once<&'static dyn CharDevice> = Once::new();
+
+const INT3: u8 = 0xcc;
+
+// The most software breakpoints which can be set at once.
+const BREAKPOINTS_MAX: usize = 32;
+
+const REGISTER_COUNT: usize = 24;
+
+lazy_static! {
+    static ref ENABLED: bool = cmdline::get().has_flag("gdb");
+}
+
+// Whether the stub was turned on with the 'gdb' command line option, and has
+// a port to talk to GDB over.
+pub fn enabled() -> bool {
+    *ENABLED && DEVICE.r#try().is_some()
+}
+
+fn port() -> &'static dyn CharDevice {
+    *DEVICE.r#try().expect("the gdb stub has no port")
+}
+
+// Called during init. With 'gdb-wait' on the command line, this stops the
+// kernel until GDB connects and continues it.
+pub fn init() {
+    if !*ENABLED {
+        return;
+    }
+    let device = match device::char_device(PORT) {
+        Some(device) => device,
+        None => {
+            log::warn!("not starting the gdb stub, as there is no {}", PORT);
+            return;
+        }
+    };
+    DEVICE.call_once(|| device);
+
+    log::info!("gdb stub listening on {}", PORT);
+    if cmdline::get().has_flag("gdb-wait") {
+        log::info!("waiting for gdb to connect");
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64,
+
+    // The byte the int3 instruction replaced.
+    original: u8,
+}
+
+struct Stub {
+    reader: PacketReader,
+    writer: PacketWriter,
+    breakpoints: [Option<Breakpoint>; BREAKPOINTS_MAX],
+}
+
+// The stub is only ever entered from an exception handler with interrupts
+// disabled. Another CPU which stops while GDB is looking at this one waits
+// here, with interrupts disabled, until GDB lets this one carry on, and then
+// reports its own stop.
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    reader: PacketReader::new(),
+    writer: PacketWriter::new(),
+    breakpoints: [None; BREAKPOINTS_MAX],
+});
+
+// What to do once a command has been answered.
+enum Next {
+    Wait,
+    Resume,
+}
+
+// Called by the trap module when the kernel stops at a breakpoint, or after a
+// single step, while the stub is turned on.
+pub fn handle_exception(frame: &mut TrapFrame) {
+    let mut stub = STUB.lock();
+
+    // The Breakpoint exception happens after the int3 instruction has run,
+    // so if it was one of our breakpoints, move back to it, which is where
+    // GDB expects the stopped code to be.
+    if frame.vector == BREAKPOINT_VECTOR {
+        let address = frame.rip.wrapping_sub(1);
+        if stub.breakpoints.iter().flatten().any(|b| b.address == address) {
+            frame.rip = address;
+        }
+    }
+    frame.rflags &= !RFLAGS_TRAP;
+
+    // Tell GDB why we stopped. The signal is always SIGTRAP (5).
+    stub.writer.clear();
+    stub.writer.push(b"S05");
+    stub.send();
+
+    loop {
+        let len = match stub.receive() {
+            Some(len) => len,
+            None => continue,
+        };
+
+        let mut packet = [0; PACKET_MAX];
+        packet[..len].copy_from_slice(stub.reader.packet(len));
+
+        stub.writer.clear();
+        let next = stub.execute(Command::parse(&packet[..len]), frame);
+        stub.send();
+
+        if let Next::Resume = next {
+            return;
+        }
+    }
+}
+
+impl Stub {
+    // Wait for a packet, acknowledging it. Returns the length of the packet,
+    // or None if something else arrived.
+    fn receive(&mut self) -> Option<usize> {
+        match self.reader.push(read_byte()) {
+            ReadEvent::Packet(len) => {
+                port().write(b"+");
+                Some(len)
+            }
+            ReadEvent::BadChecksum => {
+                port().write(b"-");
+                None
+            }
+
+            // We're already stopped.
+            ReadEvent::Interrupt | ReadEvent::Pending => None,
+        }
+    }
+
+    // Send the reply in the writer, until GDB acknowledges it.
+    fn send(&mut self) {
+        loop {
+            self.writer.frame(|bytes| port().write(bytes));
+
+            loop {
+                match read_byte() {
+                    b'+' => return,
+                    b'-' => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command, frame: &mut TrapFrame) -> Next {
+        match command {
+            Command::Status => self.writer.push(b"S05"),
+
+            Command::ReadRegisters => {
+                for number in 0..REGISTER_COUNT {
+                    if let Some((value, size)) = frame.register(number) {
+                        self.writer.push_hex(&value.to_le_bytes()[..size]);
+                    }
+                }
+            }
+
+            Command::WriteRegisters(data) => {
+                let mut offset = 0;
+                for number in 0..REGISTER_COUNT {
+                    let size = match frame.register(number) {
+                        Some((_, size)) => size * 2,
+                        None => break,
+                    };
+                    if let Some(value) = data.get(offset..offset + size)
+                            .and_then(decode_register) {
+                        frame.set_register(number, value);
+                    }
+                    offset += size;
+                }
+                self.writer.push(b"OK");
+            }
+
+            Command::ReadRegister(number) => match frame.register(number) {
+                Some((value, size)) => {
+                    self.writer.push_hex(&value.to_le_bytes()[..size])
+                }
+                None => self.writer.push(b"E01"),
+            },
+
+            Command::WriteRegister(number, data) => {
+                match decode_register(data) {
+                    Some(value) if frame.set_register(number, value) => {
+                        self.writer.push(b"OK")
+                    }
+                    _ => self.writer.push(b"E01"),
+                }
+            }
+
+            Command::ReadMemory { address, length } => {
+                let length = core::cmp::min(length, PACKET_MAX / 2);
+                let mut bytes = [0; PACKET_MAX / 2];
+
+                for (i, byte) in bytes[..length].iter_mut().enumerate() {
+                    match memory::read_byte(address.wrapping_add(i as u64)) {
+                        Some(value) => *byte = value,
+                        None if i == 0 => {
+                            self.writer.push(b"E14");
+                            return Next::Wait;
+                        }
+
+                        // Send back what could be read.
+                        None => {
+                            self.writer.push_hex(&bytes[..i]);
+                            return Next::Wait;
+                        }
+                    }
+                }
+                self.writer.push_hex(&bytes[..length]);
+            }
+
+            Command::WriteMemory { address, data } => {
+                let mut bytes = [0; PACKET_MAX / 2];
+                let written = packet::decode_hex(data, &mut bytes)
+                    .map(|length| {
+                        bytes[..length].iter().enumerate().all(|(i, byte)| {
+                            let address = address.wrapping_add(i as u64);
+                            memory::write_byte(address, *byte)
+                        })
+                    });
+
+                match written {
+                    Some(true) => self.writer.push(b"OK"),
+                    _ => self.writer.push(b"E14"),
+                }
+            }
+
+            Command::InsertBreakpoint(address) => {
+                if self.insert_breakpoint(address) {
+                    self.writer.push(b"OK");
+                } else {
+                    self.writer.push(b"E0e");
+                }
+            }
+
+            Command::RemoveBreakpoint(address) => {
+                self.remove_breakpoint(address);
+                self.writer.push(b"OK");
+            }
+
+            // There is no reply to these until the kernel stops again.
+            Command::Step(address) | Command::Continue(address) => {
+                if let Some(address) = address {
+                    frame.rip = address;
+                }
+                if let Command::Step(_) = command {
+                    frame.rflags |= RFLAGS_TRAP;
+                }
+                return Next::Resume;
+            }
+
+            // The packet size is PACKET_MAX, in hex.
+            Command::Supported => self.writer.push(b"PacketSize=1000"),
+
+            // We can't stop the kernel running, so both of these take out
+            // every breakpoint and let it carry on.
+            Command::Detach | Command::Kill => {
+                let breakpoints = self.breakpoints;
+                for breakpoint in breakpoints.iter().flatten() {
+                    self.remove_breakpoint(breakpoint.address);
+                }
+                self.writer.push(b"OK");
+                return Next::Resume;
+            }
+
+            Command::Invalid => self.writer.push(b"E01"),
+            Command::Unsupported => {}
+        }
+
+        Next::Wait
+    }
+
+    fn insert_breakpoint(&mut self, address: u64) -> bool {
+        if self.breakpoints.iter().flatten().any(|b| b.address == address) {
+            return true;
+        }
+
+        let slot = match self.breakpoints.iter_mut().find(|b| b.is_none()) {
+            Some(slot) => slot,
+            None => return false,
+        };
+
+        let original = match memory::read_byte(address) {
+            Some(original) => original,
+            None => return false,
+        };
+        if !memory::write_byte(address, INT3) {
+            return false;
+        }
+
+        *slot = Some(Breakpoint { address, original });
+        true
+    }
+
+    fn remove_breakpoint(&mut self, address: u64) {
+        for slot in self.breakpoints.iter_mut() {
+            if let Some(breakpoint) = *slot {
+                if breakpoint.address == address {
+                    memory::write_byte(address, breakpoint.original);
+                    *slot = None;
+                }
+            }
+        }
+    }
+}
+
+// Register values are sent in the target's byte order, so little-endian.
+fn decode_register(digits: &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    packet::decode_hex(digits, &mut bytes)?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+// Wait for a byte from GDB. Interrupts are disabled while the stub runs, so
+// the port is polled for received data, rather than waiting for its
+// interrupt.
+fn read_byte() -> u8 {
+    let mut byte = [0];
+    loop {
+        if port().read(&mut byte) == 1 {
+            return byte[0];
+        }
+        core::hint::spin_loop();
+    }
+}
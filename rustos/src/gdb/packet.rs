@@ -0,0 +1,387 @@
+// The framing and commands of the GDB Remote Serial Protocol. Everything here
+// works on plain byte slices, so it can be tested without a serial port.
+// ---
+// Every message is sent as a packet, '$<data>#<checksum>', where the checksum
+// is the sum of the data bytes modulo 256, written as two hex digits. The
+// receiver answers each packet with '+' if the checksum matched, or '-' to ask
+// for it to be sent again. Within the data, '}' escapes the next byte, which
+// is XORed with 0x20, so that '$', '#' and '}' can be sent.
+// ---
+// The commands the stub understands are:
+// - ?: Why the target stopped.
+// - g / G: Read or write all the registers.
+// - p n / P n=value: Read or write register n.
+// - m addr,length / M addr,length:data: Read or write memory.
+// - Z0,addr,kind / z0,addr,kind: Insert or remove a software breakpoint.
+// - s / c: Single-step or continue, optionally from a new address.
+// - qSupported: Which features the stub supports.
+// - D / k: Detach or kill, which both leave the kernel running.
+//
+// Numbers are all in hex. Register and memory contents are sent as pairs of
+// hex digits, a byte at a time, in the target's byte order.
+
+// The most data a packet can hold. This is told to GDB in the qSupported
+// reply, so it won't send bigger packets.
+pub const PACKET_MAX: usize = 4096;
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadEvent {
+    // Nothing to do yet.
+    Pending,
+
+    // A whole packet was received, with the given length. Answer it with '+'.
+    Packet(usize),
+
+    // A packet was received, but its checksum didn't match. Answer it with
+    // '-'.
+    BadChecksum,
+
+    // GDB sent Ctrl-C, asking for the target to stop.
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    Idle,
+    Data,
+    Escape,
+    Checksum(Option<u8>),
+}
+
+// Collects the bytes of an incoming packet, one at a time.
+pub struct PacketReader {
+    buffer: [u8; PACKET_MAX],
+    len: usize,
+    state: ReadState,
+    overflowed: bool,
+
+    // The checksum of the data as it was sent, before unescaping.
+    sum: u8,
+}
+
+impl PacketReader {
+    pub const fn new() -> PacketReader {
+        PacketReader {
+            buffer: [0; PACKET_MAX],
+            len: 0,
+            state: ReadState::Idle,
+            overflowed: false,
+            sum: 0,
+        }
+    }
+
+    // The data of the last packet received.
+    pub fn packet(&self, len: usize) -> &[u8] {
+        &self.buffer[..len]
+    }
+
+    pub fn push(&mut self, byte: u8) -> ReadEvent {
+        if let ReadState::Data | ReadState::Escape = self.state {
+            if byte != b'#' || self.state == ReadState::Escape {
+                self.sum = self.sum.wrapping_add(byte);
+            }
+        }
+
+        match (self.state, byte) {
+            (ReadState::Idle, b'$') => {
+                self.len = 0;
+                self.overflowed = false;
+                self.sum = 0;
+                self.state = ReadState::Data;
+            }
+            (ReadState::Idle, 0x03) => return ReadEvent::Interrupt,
+
+            // Acknowledgements, and anything else between packets, are
+            // ignored.
+            (ReadState::Idle, _) => {}
+
+            (ReadState::Data, b'#') => self.state = ReadState::Checksum(None),
+            (ReadState::Data, b'}') => self.state = ReadState::Escape,
+            (ReadState::Data, _) => self.store(byte),
+
+            (ReadState::Escape, _) => {
+                self.store(byte ^ 0x20);
+                self.state = ReadState::Data;
+            }
+
+            (ReadState::Checksum(None), _) => {
+                self.state = ReadState::Checksum(Some(byte));
+            }
+            (ReadState::Checksum(Some(high)), low) => {
+                self.state = ReadState::Idle;
+
+                let expected = parse_hex(&[high, low]).map(|sum| sum as u8);
+                if expected != Some(self.sum) || self.overflowed {
+                    return ReadEvent::BadChecksum;
+                }
+                return ReadEvent::Packet(self.len);
+            }
+        }
+
+        ReadEvent::Pending
+    }
+
+    fn store(&mut self, byte: u8) {
+        if self.len < PACKET_MAX {
+            self.buffer[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+}
+
+impl Default for PacketReader {
+    fn default() -> PacketReader {
+        PacketReader::new()
+    }
+}
+
+// Builds the data of an outgoing packet.
+pub struct PacketWriter {
+    buffer: [u8; PACKET_MAX],
+    len: usize,
+}
+
+impl PacketWriter {
+    pub const fn new() -> PacketWriter {
+        PacketWriter { buffer: [0; PACKET_MAX], len: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let count = core::cmp::min(bytes.len(), PACKET_MAX - self.len);
+        self.buffer[self.len..self.len + count]
+            .copy_from_slice(&bytes[..count]);
+        self.len += count;
+    }
+
+    // Add bytes as pairs of hex digits.
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        const DIGITS: &[u8; 16] = b"0123456789abcdef";
+        for byte in bytes {
+            self.push(&[DIGITS[usize::from(byte >> 4)],
+                        DIGITS[usize::from(byte & 0xf)]]);
+        }
+    }
+
+    // Write the whole packet, with its framing, to the given function. The
+    // stub never sends '$', '#' or '}' in data, so nothing needs escaping.
+    pub fn frame<F: FnMut(&[u8])>(&self, mut output: F) {
+        let sum = checksum(self.data());
+        let mut trailer = PacketWriter::new();
+        trailer.push(b"#");
+        trailer.push_hex(&[sum]);
+
+        output(b"$");
+        output(self.data());
+        output(trailer.data());
+    }
+}
+
+impl Default for PacketWriter {
+    fn default() -> PacketWriter {
+        PacketWriter::new()
+    }
+}
+
+// Parse a hex number, such as an address.
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    digits.iter().try_fold(0u64, |value, digit| {
+        let digit = (*digit as char).to_digit(16)?;
+        Some(value << 4 | u64::from(digit))
+    })
+}
+
+// Decode pairs of hex digits into bytes, returning the number of bytes
+// decoded.
+pub fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<usize> {
+    if digits.len() % 2 != 0 || digits.len() / 2 > out.len() {
+        return None;
+    }
+
+    for (pair, byte) in digits.chunks(2).zip(out.iter_mut()) {
+        *byte = parse_hex(pair)? as u8;
+    }
+
+    Some(digits.len() / 2)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command<'a> {
+    Status,
+    ReadRegisters,
+    WriteRegisters(&'a [u8]),
+    ReadRegister(usize),
+    WriteRegister(usize, &'a [u8]),
+    ReadMemory { address: u64, length: usize },
+    WriteMemory { address: u64, data: &'a [u8] },
+    InsertBreakpoint(u64),
+    RemoveBreakpoint(u64),
+    Step(Option<u64>),
+    Continue(Option<u64>),
+    Supported,
+    Detach,
+    Kill,
+
+    // A command the stub doesn't support, which is answered with an empty
+    // packet.
+    Unsupported,
+
+    // A command which was understood, but had bad arguments.
+    Invalid,
+}
+
+impl<'a> Command<'a> {
+    pub fn parse(packet: &'a [u8]) -> Command<'a> {
+        let (&kind, arguments) = match packet.split_first() {
+            Some(split) => split,
+            None => return Command::Unsupported,
+        };
+
+        let command = match kind {
+            b'?' => Some(Command::Status),
+            b'g' => Some(Command::ReadRegisters),
+            b'G' => Some(Command::WriteRegisters(arguments)),
+            b'p' => parse_hex(arguments)
+                .map(|number| Command::ReadRegister(number as usize)),
+            b'P' => split_at_byte(arguments, b'=').and_then(|(number, value)| {
+                Some(Command::WriteRegister(parse_hex(number)? as usize,
+                                            value))
+            }),
+            b'm' => split_at_byte(arguments, b',').and_then(|(address, rest)| {
+                Some(Command::ReadMemory {
+                    address: parse_hex(address)?,
+                    length: parse_hex(rest)? as usize,
+                })
+            }),
+            b'M' => split_at_byte(arguments, b',').and_then(|(address, rest)| {
+                let (_, data) = split_at_byte(rest, b':')?;
+                let address = parse_hex(address)?;
+                Some(Command::WriteMemory { address, data })
+            }),
+            b'Z' | b'z' => return parse_breakpoint(kind, arguments),
+            b's' => parse_resume(arguments).map(Command::Step),
+            b'c' => parse_resume(arguments).map(Command::Continue),
+            b'q' if arguments.starts_with(b"Supported") => {
+                Some(Command::Supported)
+            }
+            b'D' => Some(Command::Detach),
+            b'k' => Some(Command::Kill),
+            _ => return Command::Unsupported,
+        };
+
+        command.unwrap_or(Command::Invalid)
+    }
+}
+
+// Only software breakpoints (type 0) are supported.
+fn parse_breakpoint(kind: u8, arguments: &[u8]) -> Command {
+    let mut fields = arguments.split(|byte| *byte == b',');
+    if fields.next() != Some(b"0") {
+        return Command::Unsupported;
+    }
+
+    match fields.next().and_then(parse_hex) {
+        Some(address) if kind == b'Z' => Command::InsertBreakpoint(address),
+        Some(address) => Command::RemoveBreakpoint(address),
+        None => Command::Invalid,
+    }
+}
+
+// The optional address to resume from.
+fn parse_resume(arguments: &[u8]) -> Option<Option<u64>> {
+    if arguments.is_empty() {
+        Some(None)
+    } else {
+        parse_hex(arguments).map(Some)
+    }
+}
+
+fn split_at_byte(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|byte| *byte == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+
+// TESTING
+
+// Feed bytes to a packet reader, returning the last event.
+#[cfg(test)]
+fn read(reader: &mut PacketReader, bytes: &[u8]) -> ReadEvent {
+    bytes.iter().fold(ReadEvent::Pending, |_, byte| reader.push(*byte))
+}
+
+// Test that packets are checked and unescaped, and that acknowledgements
+// between them are ignored.
+#[test_case]
+fn test_gdb_packet_reader() {
+    let mut reader = PacketReader::new();
+
+    assert_eq!(read(&mut reader, b"+$g#67"), ReadEvent::Packet(1));
+    assert_eq!(reader.packet(1), b"g");
+
+    assert_eq!(read(&mut reader, b"$g#68"), ReadEvent::BadChecksum);
+
+    // '}' followed by 0x03 is an escaped '#'.
+    let checksum = checksum(b"Ma,1:}\x03");
+    let mut packet = PacketWriter::new();
+    packet.push(b"$Ma,1:}\x03#");
+    packet.push_hex(&[checksum]);
+    assert_eq!(read(&mut reader, packet.data()), ReadEvent::Packet(6));
+    assert_eq!(reader.packet(6), b"Ma,1:#");
+
+    assert_eq!(reader.push(0x03), ReadEvent::Interrupt);
+}
+
+// Test that outgoing packets are framed with the right checksum.
+#[test_case]
+fn test_gdb_packet_writer() {
+    let mut packet = PacketWriter::new();
+    packet.push(b"OK");
+    packet.push_hex(&[0xab, 0x01]);
+
+    let mut framed = PacketWriter::new();
+    packet.frame(|bytes| framed.push(bytes));
+
+    assert_eq!(framed.data(), b"$OKab01#be");
+}
+
+// Test that each command is parsed along with its arguments.
+#[test_case]
+fn test_gdb_command_parse() {
+    assert_eq!(Command::parse(b"?"), Command::Status);
+    assert_eq!(Command::parse(b"p10"), Command::ReadRegister(16));
+    assert_eq!(Command::parse(b"P10=0011"),
+               Command::WriteRegister(16, b"0011"));
+    assert_eq!(Command::parse(b"m1000,20"),
+               Command::ReadMemory { address: 0x1000, length: 0x20 });
+    assert_eq!(Command::parse(b"Mff,2:cc90"),
+               Command::WriteMemory { address: 0xff, data: b"cc90" });
+    assert_eq!(Command::parse(b"Z0,2000,1"),
+               Command::InsertBreakpoint(0x2000));
+    assert_eq!(Command::parse(b"z0,2000,1"),
+               Command::RemoveBreakpoint(0x2000));
+    assert_eq!(Command::parse(b"Z1,2000,1"), Command::Unsupported);
+    assert_eq!(Command::parse(b"c"), Command::Continue(None));
+    assert_eq!(Command::parse(b"s3000"), Command::Step(Some(0x3000)));
+    assert_eq!(Command::parse(b"qSupported:multiprocess+"),
+               Command::Supported);
+    assert_eq!(Command::parse(b"mzz,1"), Command::Invalid);
+    assert_eq!(Command::parse(b"vCont?"), Command::Unsupported);
+}
@@ -19,53 +19,76 @@
 // exception occurs while in user mode [level 3], the CPU will typically switch
 // to kernel mode [level 0] before invoking the exception handler). In this
 // example, the CPU would switch to the stack in the 0th index of the PST.
+// ---
+// Every CPU needs a TSS of its own, as the CPU marks the TSS it loads as busy,
+// and two CPUs can't share an IST stack, so each CPU has its own GDT, pointing
+// at its own TSS. They are set up by init_cpu, as each CPU starts.
 
+use spin::Once;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor};
 use x86_64::structures::gdt::SegmentSelector;
-use lazy_static::lazy_static;
+use crate::percpu::MAX_CPUS;
 
 // The 0th IST entry will be the double fault stack.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// This stack has no guard page to prevent a stack overflow, which means we
+// shouldn't do anything too stack-heavy within the double fault handler,
+// becuase a stack overflow could corrupt the memory below the stack.
+const STACK_SIZE: usize = 4096 * 5;
+
 //
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
-// Lazy static to ensure that the TaskStateSegment is initialised on first call.
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
+const NO_TSS: Once<TaskStateSegment> = Once::new();
+const NO_GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+
+static TSS: [Once<TaskStateSegment>; MAX_CPUS] = [NO_TSS; MAX_CPUS];
+static GDT: [Once<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] =
+    [NO_GDT; MAX_CPUS];
+
+// Set up an array as the underlying stack data structure for each CPU's double
+// fault stack. Each stack consists of STACK_SIZE u8 integers.
+static mut DOUBLE_FAULT_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] =
+    [[0; STACK_SIZE]; MAX_CPUS];
+
+// Load the GDT and TSS of the bootstrap processor, the CPU the kernel starts
+// on.
+pub fn init() {
+    init_cpu(0);
+}
+
+// Load the GDT and TSS of the given CPU, on the CPU this runs on.
+pub fn init_cpu(cpu: usize) {
+    use x86_64::instructions::segmentation::set_cs;
+    use x86_64::instructions::tables::load_tss;
+
+    let tss = TSS[cpu].call_once(|| {
         let mut tss = TaskStateSegment::new();
 
         // Create the Double Fault stack at the desired entry within the IST.
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // Set up an array as the underlying stack data structure. The stack
-            // consists of STACK_SIZE u8 integers.
-            // ---
-            // This stack has no guard page to prevent a stack overflow, which
-            // means we shouldn't do anything too stack-heavy within the double
-            // fault handler, becuase a stack overflow could corrupt the memory
-            // below the stack.
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe {&STACK});
+            let stack_start = VirtAddr::from_ptr(unsafe {
+                &DOUBLE_FAULT_STACKS[cpu]
+            });
             let stack_end = stack_start + STACK_SIZE;
 
             stack_end
         };
 
         tss
-    };
+    });
 
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    let (gdt, selectors) = GDT[cpu].call_once(|| {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
         (
             gdt,
             Selectors {
@@ -73,20 +96,15 @@ lazy_static! {
                 tss_selector,
             }
         )
-    };
-}
-
-pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
-    use x86_64::instructions::tables::load_tss;
+    });
 
-    GDT.0.load();
+    gdt.load();
 
     // Use set_cs to reload the Code Segment register, and use load_tss to load
     // the TSS. These are considered unsafe operations as they may break memory
     // safety by loading invalid selectors.
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(selectors.code_selector);
+        load_tss(selectors.tss_selector);
     }
 }
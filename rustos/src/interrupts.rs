@@ -114,11 +114,99 @@
 //              the interrupt handler and invoke it, by loading the values into
 //              the rip and cs registers.
 
-use x86_64::structures::idt::InterruptDescriptorTable;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable};
 use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::idt::PageFaultErrorCode;
 use lazy_static::lazy_static;
+use pic8259_simple::ChainedPics;
 use crate::println;
 use crate::gdt;
+use crate::monitor::Stop;
+use crate::serial::{self, Com};
+use crate::time::pit;
+
+// Hardware Interrupts
+// Exceptions are raised by the CPU itself, but hardware devices such as the
+// timer or the keyboard also need a way to get the CPU's attention. Rather than
+// connecting every device directly to the CPU, they are connected to an
+// interrupt controller, which passes their interrupts on to the CPU in order of
+// priority.
+//
+// The classic interrupt controller on x86 is the Intel 8259 Programmable
+// Interrupt Controller (PIC). Each PIC has 8 interrupt lines, and two of them
+// are chained together, with the secondary PIC connected to line 2 of the
+// primary PIC, giving 15 usable lines:
+//
+//                      ____________                          ____________
+// Real Time Clock --> |            |   Timer -------------> |            |
+// ACPI -------------> |            |   Keyboard-----------> |            |      _____
+// Available --------> | Secondary  |----------------------> | Primary    |     |     |
+// Available --------> | Interrupt  |   Serial Port 2 -----> | Interrupt  |---> | CPU |
+// Mouse ------------> | Controller |   Serial Port 1 -----> | Controller |     |_____|
+// Co-Processor -----> |            |   Parallel Port 2/3 -> |            |
+// Primary ATA ------> |            |   Floppy disk -------> |            |
+// Secondary ATA ----> |____________|   Parallel Port 1----> |____________|
+//
+// By default the PICs send the interrupt vectors 0 to 15 to the CPU, which are
+// already used by CPU exceptions (e.g. 8 is the Double Fault). To avoid these
+// clashing, we remap the PICs to use the vectors 32 to 47, which are the first
+// free vectors after the 32 exception slots.
+// ---
+// Machines with more than one CPU have APICs instead, which the kernel switches
+// to when it can. The ISA interrupts keep the same vectors, so the handlers
+// below don't need to know which controller is in use, as long as they use
+// end_of_interrupt to acknowledge them. See the apic module.
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+// The chained PICs, wrapped in a spinlock so they can be accessed safely. The
+// creation of the ChainedPics is unsafe as the offsets could be wrong.
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+// The vector numbers of the hardware interrupts we handle.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    SerialPort2 = PIC_1_OFFSET + 3,
+    SerialPort1,
+
+    // The local APIC's own interrupts, and the IPIs, at the top of the vector
+    // range.
+    ApicTimer = 0xf0,
+    CallFunction = 0xf1,
+    TlbShootdown = 0xf2,
+    ApicError = 0xfe,
+    ApicSpurious = 0xff,
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    pub fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+// The number of timer interrupts received since interrupts were enabled. The
+// Programmable Interval Timer fires roughly 18.2 times per second by default.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// The number of timer ticks in the given number of seconds, rounded up.
+pub fn ticks_from_secs(secs: u64) -> u64 {
+    let cycles = secs * pit::FREQUENCY_HZ;
+    (cycles + pit::DIVISOR - 1) / pit::DIVISOR
+}
 
 // Initialise the Interrupt Descriptor Table. The IDT is a table which contains
 // a pointer to each of the handler functions for each exception which can
@@ -140,7 +228,11 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
         
         // Set the handler functions for the exceptions we currently handle.
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+
+        // The breakpoint and debug exceptions save every register, for the
+        // GDB stub and the monitor. See the trap module.
+        crate::trap::install(&mut idt);
 
         // This is an unsafe operation becuase we need to ensure the given stack
         // is valid and not used by any other exception.
@@ -148,6 +240,30 @@ lazy_static! {
             idt.double_fault.set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
+
+        // Set the handler functions for the hardware interrupts.
+        idt[InterruptIndex::Timer.as_usize()]
+            .set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()]
+            .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::SerialPort1.as_usize()]
+            .set_handler_fn(serial_port_1_interrupt_handler);
+        idt[InterruptIndex::SerialPort2.as_usize()]
+            .set_handler_fn(serial_port_2_interrupt_handler);
+        idt[InterruptIndex::ApicTimer.as_usize()]
+            .set_handler_fn(apic_timer_interrupt_handler);
+        idt[InterruptIndex::ApicError.as_usize()]
+            .set_handler_fn(apic_error_interrupt_handler);
+        idt[InterruptIndex::ApicSpurious.as_usize()]
+            .set_handler_fn(apic_spurious_interrupt_handler);
+        idt[InterruptIndex::CallFunction.as_usize()]
+            .set_handler_fn(call_function_interrupt_handler);
+        idt[InterruptIndex::TlbShootdown.as_usize()]
+            .set_handler_fn(tlb_shootdown_interrupt_handler);
+        for (index, handler) in DEVICE_INTERRUPT_HANDLERS.iter().enumerate() {
+            idt[usize::from(DEVICE_VECTOR_BASE) + index]
+                .set_handler_fn(*handler);
+        }
         
         // Return the IDT
         idt
@@ -158,10 +274,98 @@ pub fn init_idt() {
     IDT.load();
 }
 
-// Breakpoint handler, typically used in debuggers to pause execution.
-extern "x86-interrupt" fn breakpoint_handler(
-    stack_frame: &mut InterruptStackFrame) {
-        println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+// Initialise the PICs with our vector offsets.
+pub fn init_pics() {
+    unsafe { PICS.lock().initialize() };
+}
+
+// Unmask one of the 15 interrupt lines on the PICs, so that its interrupts are
+// passed on to the CPU. Each PIC has an 8-bit mask register, at port 0x21 for
+// the primary PIC and 0xa1 for the secondary, where a set bit stops the
+// interrupts from the corresponding line. When the APICs are in use, the
+// line's entry in the I/O APIC is unmasked instead.
+pub fn enable_irq(line: u8) {
+    use x86_64::instructions::port::Port;
+
+    if crate::apic::enabled() {
+        crate::apic::io_apic::set_irq_masked(line, false);
+        return;
+    }
+
+    let (port, bit) = if line < 8 {
+        (0x21, line)
+    } else {
+        (0xa1, line - 8)
+    };
+
+    // Hold the PICS lock so this can't interleave with anything else talking
+    // to the PICs.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        let mut mask_port: Port<u8> = Port::new(port);
+
+        unsafe {
+            let mask = mask_port.read();
+            mask_port.write(mask & !(1 << bit));
+        }
+    });
+}
+
+// Mask every line on both PICs, once the APICs have taken over from them.
+pub fn disable_pics() {
+    use x86_64::instructions::port::Port;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        unsafe {
+            Port::<u8>::new(0x21).write(0xff);
+            Port::<u8>::new(0xa1).write(0xff);
+        }
+    });
+}
+
+// Tell whichever interrupt controller is in use that we're done with an
+// interrupt. Neither will send us another interrupt at the same or a lower
+// priority until we have.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::enabled() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
+// Page Fault Handler, called when an instruction accesses memory which isn't
+// mapped, or in a way the page tables don't allow. The CR2 register holds the
+// address which was accessed.
+// ---
+// The debuggers read whatever memory they are asked to, which may not be
+// mapped, so the memory module is given the chance to recover from the fault
+// first. Otherwise there's nothing we can do to carry on, so the fault is
+// printed, and handed to the monitor if it is turned on, or the CPU halted.
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame, error_code: PageFaultErrorCode) {
+        use x86_64::registers::control::Cr2;
+
+        if crate::memory::fixup_page_fault(stack_frame) {
+            return;
+        }
+
+        let rbp = crate::backtrace::interrupted_frame_pointer();
+
+        println!("EXCEPTION: PAGE FAULT");
+        println!("Accessed Address: {:?}", Cr2::read());
+        println!("Error Code: {:?}\n{:#?}", error_code, stack_frame);
+        crate::backtrace::print_from(
+            stack_frame.instruction_pointer.as_u64(), rbp);
+        if crate::monitor::enabled() {
+            crate::monitor::enter(Stop::Fault {
+                name: "Page fault",
+                stack_frame,
+                rbp,
+            });
+        }
+        crate::hlt_loop();
 }
 
 // Double Fault Handler. This handler is typically called when an exception
@@ -172,9 +376,189 @@ extern "x86-interrupt" fn breakpoint_handler(
 // It is important to always have at least a Double Fault Handler, as without it
 // a Triple Fault interrupt will be thrown, which typically results in the host
 // system resetting and rebooting.
+// ---
+// A panic would print a backtrace of the handler, so instead we print one of
+// the code which faulted, and stop, in the monitor if it is turned on.
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: &mut InterruptStackFrame, _error_code: u64) -> ! {
-        panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+        let rbp = crate::backtrace::interrupted_frame_pointer();
+        println!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+        crate::backtrace::print_from(
+            stack_frame.instruction_pointer.as_u64(), rbp);
+        if crate::monitor::enabled() {
+            crate::monitor::enter(Stop::Fault {
+                name: "Double fault",
+                stack_frame,
+                rbp,
+            });
+        }
+        crate::hlt_loop();
+}
+
+// Timer Interrupt Handler, called each time the Programmable Interval Timer
+// fires.
+extern "x86-interrupt" fn timer_interrupt_handler(
+    stack_frame: &mut InterruptStackFrame) {
+        let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+        // The interrupt controller won't send us another interrupt until we
+        // tell it that we're done with this one, by sending an End Of
+        // Interrupt (EOI) signal.
+        end_of_interrupt(InterruptIndex::Timer);
+
+        // With the APICs, the kernel's timers are run by the local APIC timer
+        // instead.
+        if !crate::apic::enabled() {
+            crate::time::run_timers();
+        }
+
+        // If a test has been running for too long, this makes us return to
+        // the test runner, rather than to the test.
+        crate::testing::check_watchdog(ticks, stack_frame);
+}
+
+// Keyboard Interrupt Handler, called each time a key is pressed or released.
+extern "x86-interrupt" fn keyboard_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        use x86_64::instructions::port::Port;
+
+        // The keyboard controller won't send another interrupt until we have
+        // read the scancode of the key from its data port, so read it even
+        // though nothing uses key presses at the moment.
+        let mut port = Port::new(0x60);
+        let _scancode: u8 = unsafe { port.read() };
+
+        end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+// Serial Port Interrupt Handlers, called when data has been received on one of
+// the serial ports. COM1 and COM3 share IRQ 4, and COM2 and COM4 share IRQ 3,
+// so we check both ports on the line.
+extern "x86-interrupt" fn serial_port_1_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        serial::handle_interrupt(&[Com::Com1, Com::Com3]);
+
+        end_of_interrupt(InterruptIndex::SerialPort1);
+}
+
+extern "x86-interrupt" fn serial_port_2_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        serial::handle_interrupt(&[Com::Com2, Com::Com4]);
+
+        end_of_interrupt(InterruptIndex::SerialPort2);
+}
+
+// Local APIC Interrupt Handlers. The spurious interrupt is raised when an
+// interrupt goes away before the CPU gets to it, and mustn't be acknowledged.
+extern "x86-interrupt" fn apic_timer_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        crate::apic::handle_timer();
+}
+
+extern "x86-interrupt" fn apic_error_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        crate::apic::handle_error();
+}
+
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+}
+
+// Inter-Processor Interrupt Handlers, called when another CPU has asked this
+// one to run a function, or to flush its TLB. See the ipi and tlb modules.
+extern "x86-interrupt" fn call_function_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        crate::ipi::handle_call();
+
+        end_of_interrupt(InterruptIndex::CallFunction);
+}
+
+extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(
+    _stack_frame: &mut InterruptStackFrame) {
+        crate::tlb::handle_shootdown();
+
+        end_of_interrupt(InterruptIndex::TlbShootdown);
+}
+
+
+// Device Interrupts
+// Devices which use Message Signalled Interrupts (see the pci::msi module) can
+// be given any vector, so rather than each having its own InterruptIndex, the
+// DEVICE_VECTORS vectors from DEVICE_VECTOR_BASE are handed out by
+// allocate_vector, each with a function to call when it fires, and a value to
+// call it with. MSIs are delivered by the local APIC, so they are only used
+// when the APICs are.
+
+pub const DEVICE_VECTOR_BASE: u8 = 0x50;
+pub const DEVICE_VECTORS: usize = 8;
+
+#[derive(Clone, Copy)]
+struct DeviceHandler {
+    handler: fn(usize),
+    context: usize,
+}
+
+// Only locked with interrupts disabled.
+static DEVICE_HANDLERS: spin::Mutex<[Option<DeviceHandler>; DEVICE_VECTORS]> =
+    spin::Mutex::new([None; DEVICE_VECTORS]);
+
+// Take a free device vector, which calls handler with context when it fires.
+// Returns None if they are all in use.
+pub fn allocate_vector(handler: fn(usize), context: usize) -> Option<u8> {
+    without_interrupts(|| {
+        let mut handlers = DEVICE_HANDLERS.lock();
+        let index = handlers.iter().position(Option::is_none)?;
+        handlers[index] = Some(DeviceHandler { handler, context });
+        Some(DEVICE_VECTOR_BASE + index as u8)
+    })
+}
+
+// Give back a vector from allocate_vector, once the device will no longer
+// use it.
+pub fn free_vector(vector: u8) {
+    let index = usize::from(vector.wrapping_sub(DEVICE_VECTOR_BASE));
+    without_interrupts(|| {
+        if let Some(handler) = DEVICE_HANDLERS.lock().get_mut(index) {
+            *handler = None;
+        }
+    });
+}
+
+// The lock isn't held while the handler runs, so it can allocate and free
+// vectors itself.
+fn handle_device_interrupt(index: usize) {
+    let handler = DEVICE_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        (handler.handler)(handler.context);
+    }
+
+    crate::apic::end_of_interrupt();
+}
+
+// Each vector needs a handler of its own, which knows which vector it is.
+macro_rules! device_interrupt_handlers {
+    ($($index:literal => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(
+                _stack_frame: &mut InterruptStackFrame) {
+                    handle_device_interrupt($index);
+            }
+        )*
+
+        const DEVICE_INTERRUPT_HANDLERS: [HandlerFunc; DEVICE_VECTORS] =
+            [$($name),*];
+    };
+}
+
+device_interrupt_handlers! {
+    0 => device_interrupt_handler_0,
+    1 => device_interrupt_handler_1,
+    2 => device_interrupt_handler_2,
+    3 => device_interrupt_handler_3,
+    4 => device_interrupt_handler_4,
+    5 => device_interrupt_handler_5,
+    6 => device_interrupt_handler_6,
+    7 => device_interrupt_handler_7
 }
 
 
@@ -187,3 +571,32 @@ fn test_breakpoint_exception() {
     // Invoke a Breakpoint Exception
     x86_64::instructions::interrupts::int3();
 }
+
+// Test that a device vector calls its handler, by sending it to this CPU as
+// an IPI, and that it can be given back. IPIs need the local APIC, so this is
+// skipped when the legacy PICs are in use.
+#[test_case]
+fn test_device_vector() {
+    use crate::apic::{self, Destination};
+
+    if !apic::enabled() {
+        return;
+    }
+
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+
+    let vector = allocate_vector(|context| {
+        CALLS.fetch_add(context as u64, Ordering::Relaxed);
+    }, 3).expect("no free device vectors");
+
+    apic::send_ipi(Destination::Apic(apic::id()), vector);
+    let end = crate::time::now() + core::time::Duration::from_secs(1);
+    while CALLS.load(Ordering::Relaxed) == 0 && crate::time::now() < end {
+        core::hint::spin_loop();
+    }
+    free_vector(vector);
+
+    assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+    assert_eq!(allocate_vector(|_| {}, 0), Some(vector));
+    free_vector(vector);
+}
@@ -0,0 +1,143 @@
+// Inter-Processor Interrupts (IPIs), which CPUs send each other through their
+// local APICs, to get another CPU to do something straight away. Each kind of
+// request has its own vector in the IDT, and the data it needs is left where
+// the other CPU's handler can find it:
+// - CallFunction: Run a function, with run_on.
+// - TlbShootdown: Flush pages from the TLB. See the tlb module.
+// ---
+// run_on leaves a pointer to the function in the target CPU's mailbox, and
+// waits for it to say it has finished. As the caller waits, the function can
+// borrow from its stack, so nothing needs to be allocated. A CPU waiting on
+// another may be asked to do something itself, possibly by the CPU it is
+// waiting for, and may have interrupts disabled, so while it waits it keeps
+// checking for requests with handle_pending, rather than relying on its
+// interrupt handlers.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::apic::{self, Destination};
+use crate::interrupts::InterruptIndex;
+use crate::percpu::{self, MAX_CPUS};
+
+// Which CPUs an IPI is sent to. CPUs are numbered as in the percpu module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Cpu(usize),
+    All,
+    AllButSelf,
+}
+
+// A function left in a CPU's mailbox by run_on. The pointers are to the
+// caller's stack, which stays put until done is set.
+struct Call {
+    function: *mut (dyn FnMut() + Send),
+    done: *const AtomicBool,
+}
+
+unsafe impl Send for Call {}
+
+const EMPTY: Mutex<Option<Call>> = Mutex::new(None);
+
+// Only locked with interrupts disabled, as the handler locks it too.
+static MAILBOXES: [Mutex<Option<Call>>; MAX_CPUS] = [EMPTY; MAX_CPUS];
+
+// Send an IPI with the given vector. Only CPUs which have been started get it.
+pub fn send(target: Target, vector: u8) {
+    let destination = match target {
+        Target::Cpu(cpu) => {
+            let per_cpu = percpu::get(cpu).expect("CPU number out of range");
+            Destination::Apic(per_cpu.apic_id())
+        }
+        Target::All => Destination::All,
+        Target::AllButSelf => Destination::AllButSelf,
+    };
+
+    apic::send_ipi(destination, vector);
+}
+
+// Run a function on the given CPU, and return its result once it has finished.
+// The function runs in an interrupt handler, so it mustn't wait for anything
+// which needs interrupts on that CPU.
+pub fn run_on<F, R>(cpu: usize, function: F) -> R
+        where F: FnOnce() -> R + Send, R: Send {
+    if cpu == percpu::current().index() {
+        return function();
+    }
+
+    let online = percpu::get(cpu).map_or(false, |per_cpu| per_cpu.online());
+    assert!(online, "CPU {} isn't online", cpu);
+
+    let mut function = Some(function);
+    let mut result = None;
+    let mut call = || {
+        let function = function.take().expect("function already called");
+        result = Some(function());
+    };
+    call_on(cpu, &mut call);
+
+    result.expect("function wasn't called")
+}
+
+fn call_on(cpu: usize, function: &mut (dyn FnMut() + Send)) {
+    let done = AtomicBool::new(false);
+
+    // The function only lives as long as this call, which doesn't return until
+    // the other CPU has finished with it.
+    let function: *mut (dyn FnMut() + Send + '_) = function;
+    let mut call = Some(Call {
+        function: unsafe { core::mem::transmute(function) },
+        done: &done,
+    });
+
+    // Another CPU may already have a call waiting in the mailbox.
+    while call.is_some() {
+        without_interrupts(|| {
+            let mut mailbox = MAILBOXES[cpu].lock();
+            if mailbox.is_none() {
+                *mailbox = call.take();
+            }
+        });
+        if call.is_some() {
+            handle_pending();
+            core::hint::spin_loop();
+        }
+    }
+
+    send(Target::Cpu(cpu), InterruptIndex::CallFunction.as_u8());
+    while !done.load(Ordering::Acquire) {
+        handle_pending();
+        core::hint::spin_loop();
+    }
+}
+
+// Called by the CallFunction interrupt handler, to run the function left in
+// this CPU's mailbox.
+pub fn handle_call() {
+    let cpu = percpu::current().index();
+    let call = without_interrupts(|| MAILBOXES[cpu].lock().take());
+
+    if let Some(call) = call {
+        unsafe {
+            (*call.function)();
+            (*call.done).store(true, Ordering::Release);
+        }
+    }
+}
+
+// Deal with any requests other CPUs have made of this one, whether or not the
+// IPI for them has arrived. Called while waiting on another CPU.
+pub fn handle_pending() {
+    handle_call();
+    crate::tlb::handle_shootdown();
+}
+
+
+// TESTING
+
+// Test that running a function on the current CPU returns its result.
+#[test_case]
+fn test_run_on_current_cpu() {
+    let cpu = percpu::current().index();
+    assert_eq!(run_on(cpu, || percpu::current().index() + 1), cpu + 1);
+}
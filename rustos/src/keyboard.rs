@@ -0,0 +1,63 @@
+// The PS/2 keyboard. The keyboard controller has two ports:
+// - 0x60: The data port, where the scancode of each key pressed or released
+//               is read from.
+// - 0x64: The status port. Bit 0 is set when there is a byte waiting in the
+//               data port, and bit 5 is set if that byte came from the mouse,
+//               rather than the keyboard.
+//
+// A scancode says which key changed, not what was typed, so the pc-keyboard
+// crate turns them into characters, keeping track of the shift and caps lock
+// keys, using the US keyboard layout and scancode set 1, which is the set the
+// controller translates to by default.
+// ---
+// Key presses normally arrive through the keyboard interrupt. The monitor runs
+// with interrupts disabled, so it calls poll instead, which reads the
+// controller directly.
+
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_FROM_MOUSE: u8 = 0x20;
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,
+                                 HandleControl::MapLettersToUnicode));
+}
+
+// Turn a scancode into the character typed, if it completes one. Keys which
+// don't type anything, such as the arrow keys, are ignored.
+pub fn decode(scancode: u8) -> Option<char> {
+    let mut keyboard = KEYBOARD.lock();
+
+    let event = keyboard.add_byte(scancode).ok()??;
+    match keyboard.process_keyevent(event)? {
+        DecodedKey::Unicode(character) => Some(character),
+        DecodedKey::RawKey(_) => None,
+    }
+}
+
+// Check the keyboard controller for a key, without waiting for one.
+pub fn poll() -> Option<char> {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+
+    let status = unsafe { status.read() };
+    if status & STATUS_OUTPUT_FULL == 0 {
+        return None;
+    }
+
+    // Mouse data has to be read to clear it, but isn't a key.
+    let byte = unsafe { data.read() };
+    if status & STATUS_FROM_MOUSE != 0 {
+        return None;
+    }
+
+    decode(byte)
+}
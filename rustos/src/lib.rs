@@ -9,14 +9,23 @@
 // Enable the Custom Test Frameworks feature to allow for unit testing of the
 // OS code. This has to be done becuase the default test libary relies on the
 // standard library to function correctly. By implementing our own test running
-// we are still able to unit test our code, though we will not have the more
-// advanced features of Rust's default test framework, such as should_panic
-// tests.
+// we are still able to unit test our code. The testing module adds back some
+// of the features of Rust's default test framework, such as should_panic and
+// ignored tests.
 #![feature(custom_test_frameworks)]
 
 //
 #![feature(abi_x86_interrupt)]
 
+// Used by the testing module, to recover from panics in tests which are
+// expected to panic, and to check the panic message.
+#![feature(global_asm)]
+#![feature(panic_info_message)]
+
+// Used by the bench module, to put fences around reads of the Time Stamp
+// Counter, and by the backtrace module to read the frame pointer.
+#![feature(asm)]
+
 // Point to the custom test runner method.
 #![test_runner(crate::test_runner)]
 
@@ -37,32 +46,46 @@
 // the Rust compiler to link the crate.
 extern crate rlibc;
 
-use core::panic::PanicInfo;
-
+pub mod acpi;
+pub mod apic;
+pub mod backtrace;
+pub mod bench;
+pub mod cmdline;
+pub mod console;
+pub mod device;
+#[cfg(feature = "coverage")]
+pub mod coverage;
+pub mod keyboard;
+pub mod logger;
+pub mod memory;
+pub mod monitor;
+pub mod net;
+pub mod pci;
+pub mod percpu;
 pub mod serial;
+pub mod smp;
 pub mod vga_buffer;
 pub mod interrupts;
+pub mod ipi;
 pub mod gdt;
-
-// Create a new trait 'Testable' which enables us to automatically print out the
-// names of the test methods prior to execution, as well as the '[ok]' status
-// message afterwards.
-pub trait Testable {
-    fn run(&self) -> ();
-}
-
-impl<T> Testable for T
-where T: Fn(), {
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-
-        // We are able to get the method to run itself becuase we have told the
-        // compiler that we require the type to have the 'Fn' trait.
-        self();
-        
-        serial_println!("[ok]");
-    }
-}
+pub mod gdb;
+pub mod framebuffer;
+pub mod shell;
+pub mod testing;
+pub mod time;
+pub mod tlb;
+pub mod trap;
+pub mod virtio;
+
+// The test framework lives in the testing module, but is re-exported here so
+// that test binaries can keep using rustos::test_runner and friends.
+pub use testing::{test_panic_handler, test_runner, ShouldPanic, TestCase,
+                  Testable};
+
+// The print! macro names the log crate's levels, so it has to be reachable
+// through this crate from wherever the macro is used.
+#[doc(hidden)]
+pub use log;
 
 // QEMU Exit Code Enum
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -74,59 +97,103 @@ pub enum QemuExitCode {
 
 // Indicate to the QEMU emulator what we want to exit. We do this by opening the
 // 0xf4 port, and writing the given exit code to the attached device.
+// ---
+// When collecting code coverage, this is the last chance to save it, so the
+// profile is written out first.
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
+    #[cfg(feature = "coverage")]
+    coverage::dump();
+
     unsafe {
         let mut port = Port::new(0xf4);
         port.write(exit_code as u32);
     }
 }
 
-// Implement the custom test runner method. the cfg(test) attribute tells the
-// compiler that it should only be included within the test environment.
-pub fn test_runner(tests: &[&dyn Testable]) {
-    // Print the number of tests run
-    serial_println!("Running {} tests", tests.len());
-    
-    // Iterate through the list of tests...
-    for test in tests {
-        // ... and run each one.
-        test.run();
-    }
+// Restart the machine, by asking the keyboard controller to pulse the CPU's
+// reset line. If that doesn't work, load an empty Interrupt Descriptor Table
+// and raise an exception, which with no handlers to call becomes a Triple
+// Fault, and the CPU resets itself.
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    use x86_64::structures::DescriptorTablePointer;
 
-    // Exit QEMU
-    exit_qemu(QemuExitCode::Success);
-}
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        let mut port: Port<u8> = Port::new(0x64);
+        port.write(0xfe);
 
-// Test-mode panic handler, which prints output to the serial interface, and
-// then exits QEMU with the fail status code.
-pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+        let empty = DescriptorTablePointer { limit: 0, base: 0 };
+        x86_64::instructions::tables::lidt(&empty);
+        x86_64::instructions::interrupts::int3();
+    }
 
-    exit_qemu(QemuExitCode::Failed);
-    loop {}
+    hlt_loop();
 }
 
-// General init method to initialise any modules which we have imported. In this
-// instance the only thing we're setting up is the Interrupt Descriptor Table.
+// General init method to initialise any modules which we have imported. This
+// sets up the logger, using any options from the kernel command line, the GDT,
+// the per-CPU data, the Interrupt Descriptor Table, the ACPI tables, the
+// interrupt controllers (the APICs, or the PICs if they can't be used), the
+// clock, the other CPUs, the PCI devices, the serial ports, the drivers for
+// the devices which were found and the GDB stub, and then enables hardware
+// interrupts. The network is brought up last, as waiting for a DHCP server
+// needs the clock's interrupts. The ACPI tables can only be found once
+// memory::init has been called.
 pub fn init() {
+    logger::init();
+    if let Err(error) = logger::apply_arguments(cmdline::get().as_str()) {
+        log::warn!("ignoring log level from the command line: {}", error);
+    }
     gdt::init();
+    percpu::init(0);
     interrupts::init_idt();
+    match acpi::init() {
+        Ok(acpi) => log::info!("found ACPI tables from {}", acpi.rsdp.oem_id()),
+        Err(error) => log::warn!("ACPI tables aren't available: {}", error),
+    }
+    interrupts::init_pics();
+    if let Err(error) = apic::init() {
+        log::info!("using the legacy PICs, as the APICs can't be: {}", error);
+    }
+    time::init();
+    match smp::init() {
+        Ok(cpus) => log::info!("{} CPUs online", cpus),
+        Err(error) => log::info!("only using one CPU, as {}", error),
+    }
+    log::info!("found {} PCI devices", pci::init());
+    serial::init();
+    device::init();
+    gdb::init();
+    x86_64::instructions::interrupts::enable();
+    net::init();
+}
+
+// Halt the CPU until the next interrupt arrives, forever. This is used instead
+// of an empty loop, which would keep the CPU busy spinning at full speed.
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
 }
 
-// 'cargo test' entrypoint
+// 'cargo test' entrypoint. The entry_point macro checks that the function has
+// the signature the bootloader expects, and defines _start to call it.
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
 #[cfg(test)]
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    memory::init(boot_info);
     init();
     test_main();
-    loop {}
+    hlt_loop();
 }
 
 #[cfg(test)]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
     test_panic_handler(info)
 }
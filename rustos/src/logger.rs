@@ -0,0 +1,163 @@
+// The log crate provides a set of logging macros (error!, warn!, info!, debug!
+// and trace!) which are used throughout the Rust ecosystem, along with a Log
+// trait which decides what to do with each record they produce. The macros
+// don't do anything until a logger implementing the trait has been installed,
+// which is what this module does.
+// ---
+// Each record is formatted with a timestamp (the date and time in UTC once the
+// wall clock has been set, or the seconds since boot before then), its level
+// and the path of the module it came from, coloured by level, and then handed
+// to the console module. This means log output goes to every registered
+// console sink (e.g. the VGA Buffer and the serial port), subject to each
+// sink's own filter.
+// ---
+// There are two levels of filtering. The log crate's maximum level stops the
+// macros from even formatting records which are too verbose, and can be
+// changed at runtime with set_level. The console sinks then filter the
+// records which get through, so, for example, the screen can show debug
+// output while the serial port only gets warnings.
+
+use core::fmt;
+use core::str::FromStr;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::console;
+use crate::time;
+use crate::vga_buffer::Colour;
+
+// The level the logger starts at, until it is changed with set_level.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        console::print_coloured(
+            record.level(),
+            level_colour(record.level()),
+            format_args!("[{}] {:<5} {}: {}\n",
+                         Timestamp,
+                         record.level(),
+                         record.module_path().unwrap_or("?"),
+                         record.args()),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+// The colour each level is displayed in.
+fn level_colour(level: Level) -> Colour {
+    match level {
+        Level::Error => Colour::LightRed,
+        Level::Warn => Colour::Yellow,
+        Level::Info => Colour::White,
+        Level::Debug => Colour::LightGrey,
+        Level::Trace => Colour::DarkGrey,
+    }
+}
+
+// The time a record was logged at, to the millisecond. The time since boot
+// is padded to the same width as the date, so that the columns line up.
+struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match time::wall_clock() {
+            Some(wall_clock) => {
+                write!(f, "{}.{:03}",
+                       time::DateTime::from_unix(wall_clock.as_secs()),
+                       wall_clock.subsec_millis())
+            }
+            None => {
+                let uptime = time::now();
+                write!(f, "{:>19}.{:03}", uptime.as_secs(),
+                       uptime.subsec_millis())
+            }
+        }
+    }
+}
+
+// Install the kernel logger, so the log crate's macros start producing output.
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger has already been initialised");
+    log::set_max_level(DEFAULT_LEVEL);
+}
+
+// Change the most verbose level of record which will be logged.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLevelError;
+
+impl fmt::Display for ParseLevelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected one of off, error, warn, info, debug or trace")
+    }
+}
+
+// Change the level from a string such as "debug". The level names aren't case
+// sensitive.
+pub fn set_level_from_str(level: &str) -> Result<(), ParseLevelError> {
+    let level = LevelFilter::from_str(level).map_err(|_| ParseLevelError)?;
+    set_level(level);
+    Ok(())
+}
+
+// Apply any logging options found in a list of space separated arguments, such
+// as a kernel command line. At the moment the only option is 'log=<level>'.
+pub fn apply_arguments(arguments: &str) -> Result<(), ParseLevelError> {
+    for argument in arguments.split_whitespace() {
+        if let Some(level) = argument.strip_prefix("log=") {
+            set_level_from_str(level)?;
+        }
+    }
+
+    Ok(())
+}
+
+
+// TESTING
+
+// Test that a record reaches the console sinks with its level and module path,
+// by checking the end of the ring buffer.
+#[test_case]
+fn test_log_reaches_console() {
+    log::info!("test_log_reaches_console output");
+
+    let expected = b"INFO  rustos::logger: test_log_reaches_console output\n";
+    let mut out = [0; 64];
+    let count = console::RING_BUFFER.contents(&mut out[..expected.len()]);
+
+    assert_eq!(&out[..count], &expected[..]);
+}
+
+// Test that records more verbose than the current level are dropped, and that
+// the level can be changed through a list of arguments.
+#[test_case]
+fn test_runtime_level_filter() {
+    console::RING_BUFFER.clear();
+    log::debug!("hidden");
+    assert!(console::RING_BUFFER.is_empty());
+
+    apply_arguments("quiet log=DEBUG").unwrap();
+    assert_eq!(level(), LevelFilter::Debug);
+    assert_eq!(apply_arguments("log=loud"), Err(ParseLevelError));
+
+    set_level(DEFAULT_LEVEL);
+}
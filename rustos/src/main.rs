@@ -15,8 +15,11 @@
 #![test_runner(rustos::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use rustos::println;
+use rustos::{console, memory, serial};
+use log::LevelFilter;
 
 // As we are operating in a no_std environment we need to define our own
 // panic_handler method. This is usually implemented by the standard library.
@@ -33,9 +36,17 @@ fn panic(_info: &PanicInfo) -> ! {
     // within the code where the panic occurred, as well as the optional panic
     // message.
 
-    // Now we can print panic info to the VGA Buffer.
+    // Now we can print panic info to the VGA Buffer, followed by the functions
+    // which led up to the panic.
     println!("{}", _info);
-    loop {}
+    rustos::backtrace::print();
+
+    // Once the kernel is up, stop in the monitor, so that what went wrong can
+    // be looked into.
+    if rustos::monitor::enabled() {
+        rustos::monitor::enter(rustos::monitor::Stop::Panic(_info));
+    }
+    rustos::hlt_loop();
 }
 
 #[cfg(test)]
@@ -45,39 +56,52 @@ fn panic(info: &PanicInfo) -> ! {
 }
 
 // We no longer need the main method, as it was the underlying Rust runtime
-// which called it. Instead we define the _start method, which overwrites the
-// standard entry point.
-// ---
-// The no_mangle attribute disables name mangiling to ensure the Rust compiler
-// keeps the _start name on this method. This is necessary as we need to tell
-// the linker which method is the entry point to the executable.
+// which called it. Instead we define our own entry point, which the bootloader
+// jumps to once it has set up paging.
 // ---
-// The method is marked at 'extern "C"' to indicate to the compiler that we want
-// to use the C calling convention for this function, instead of the standard
-// Rust calling convention.
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    // The _start method is also a diverging function which is not allowed to
-    // return. This is becuase this method is invoked directly by the host OS
-    // or bootloader. Instead of returning this method would, within the context
-    // of producing an OS, invoke the exit system call, or shut down the
-    // machine.
+// The bootloader passes the entry point a BootInfo, which describes the memory
+// of the machine and where the kernel can find it. The entry_point macro
+// checks that kernel_main takes the arguments the bootloader gives it, and
+// defines the real entry point, _start, to call it. _start has to be marked
+// as 'extern "C"' and 'no_mangle', so the linker can find it, and so that it
+// uses the C calling convention, which is what the bootloader calls it with.
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // The kernel_main method is also a diverging function which is not allowed
+    // to return. This is becuase this method is invoked directly by the host
+    // OS or bootloader. Instead of returning this method would, within the
+    // context of producing an OS, invoke the exit system call, or shut down
+    // the machine.
+
+    // Send the output of print! and println! to the host over the serial port,
+    // as well as to the screen.
+    console::register("serial", &serial::SerialConsole, LevelFilter::Info)
+        .expect("failed to register the serial console");
 
     println!("Hello World{}", "!");
 
     // Initialise the common modules.
+    memory::init(boot_info);
     rustos::init();
 
     // invoke a breakpoint exception
     x86_64::instructions::interrupts::int3();
 
+    // From here on, breakpoints, panics and faults stop in the monitor, which
+    // takes commands from the keyboard or the first serial port.
+    #[cfg(not(test))]
+    rustos::monitor::enable();
+
     // Within the test environment, we want to call the main test method.
     #[cfg(test)]
     test_main();
 
     println!("It did not crash!");
 
-    loop {}
+    // Hand over to the shell, which takes commands from the host over the
+    // first serial port.
+    rustos::shell::run(serial::Com::Com1);
 }
 
 // #[test_case]
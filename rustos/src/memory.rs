@@ -0,0 +1,341 @@
+// Virtual memory. At the moment this covers what the debuggers need: finding
+// physical memory, walking the page tables to translate an address, and
+// reading and writing memory which may not be mapped, along with handing out
+// physical frames, and identity mapping them.
+// ---
+// With paging turned on, every address the CPU uses is a virtual address,
+// which it translates to a physical address through four levels of page table.
+// Bits 39 to 47 of the address index the level 4 table, whose entry points to
+// a level 3 table, and so on down to the level 1 table, whose entry points to
+// the 4KiB physical frame holding the page. The bottom 12 bits are the offset
+// into the frame:
+//
+// +---------+---------+---------+---------+---------+-----------+
+// | 63..48  | 47..39  | 38..30  | 29..21  | 20..12  |   11..0   |
+// +---------+---------+---------+---------+---------+-----------+
+// | Sign    | Level 4 | Level 3 | Level 2 | Level 1 | Offset    |
+// | extend  | index   | index   | index   | index   | in frame  |
+// +---------+---------+---------+---------+---------+-----------+
+//
+// A level 3 or level 2 entry with the HUGE_PAGE flag set points straight at a
+// 1GiB or 2MiB frame, rather than at another table. The CR3 register holds the
+// physical address of the level 4 table.
+// ---
+// The page tables hold physical addresses, which the kernel can't use once
+// paging is on. The bootloader's map_physical_memory feature maps the whole of
+// physical memory into the virtual address space at an offset, which it tells
+// us in the BootInfo, so physical address p can be read at offset + p. Test
+// binaries which don't call init don't know the offset, so walking the page
+// tables isn't possible there.
+// ---
+// The BootInfo also has the memory map, which says which physical frames are
+// free. There is no way to give a frame back yet, so frames are handed out in
+// order, and never reused. Frames below 1MiB are left alone, as code which
+// starts in real mode, such as the SMP trampoline, can only run from there.
+// Devices which read and write memory themselves, such as virtio devices, can
+// be given runs of frames which are next to each other in physical memory.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use bootloader::BootInfo;
+use core::fmt;
+use spin::{Mutex, Once};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable};
+use x86_64::structures::paging::{PageTable, PageTableEntry, PageTableFlags};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+// Also held while the page tables are changed, so that only one CPU changes
+// them at a time.
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> =
+    Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    NotMapped,
+    OutOfFrames,
+    // The page is already mapped, to a different frame.
+    AlreadyMapped,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::NotMapped => write!(f, "physical memory isn't mapped"),
+            MapError::OutOfFrames => write!(f, "there are no free frames left"),
+            MapError::AlreadyMapped => write!(f, "the page is already in use"),
+        }
+    }
+}
+
+struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    next: usize,
+}
+
+impl BootInfoFrameAllocator {
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
+        self.memory_map.iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| region.range.start_addr()..region.range.end_addr())
+            .flat_map(|range| range.step_by(4096))
+            .filter(|&address| address >= LOW_MEMORY_END)
+            .map(|address| {
+                PhysFrame::containing_address(PhysAddr::new(address))
+            })
+    }
+}
+
+impl BootInfoFrameAllocator {
+    // Take the next run of count frames which are next to each other,
+    // skipping over any shorter runs. The frames skipped are lost.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        loop {
+            let mut frames = self.usable_frames().skip(self.next);
+            let first = frames.next()?;
+            let run = 1 + frames.take(count.saturating_sub(1))
+                .zip(1..)
+                .take_while(|(frame, index)| *frame == first + *index)
+                .count();
+
+            self.next += run;
+            if run >= count {
+                return Some(first);
+            }
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames().nth(self.next);
+        self.next += 1;
+        frame
+    }
+}
+
+// Called from the kernel's entry point, with the information the bootloader
+// passed it.
+pub fn init(boot_info: &'static BootInfo) {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| {
+        VirtAddr::new(boot_info.physical_memory_offset)
+    });
+
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator {
+        memory_map: &boot_info.memory_map,
+        next: 0,
+    });
+}
+
+// Where physical memory is mapped, if init has been called.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.r#try().copied()
+}
+
+// The virtual address a physical address can be accessed at.
+pub fn phys_to_virt(address: PhysAddr) -> Option<VirtAddr> {
+    physical_memory_offset().map(|offset| offset + address.as_u64())
+}
+
+// Walk the page tables for a virtual address, calling the given function with
+// the level (4 down to 1), the index and the entry used at each level. Returns
+// the physical address, or None if the address isn't mapped, or physical
+// memory can't be accessed.
+pub fn walk<F>(address: VirtAddr, mut function: F) -> Option<PhysAddr>
+        where F: FnMut(u8, usize, &PageTableEntry) {
+    let (level_4_table, _) = Cr3::read();
+    let mut table_address = level_4_table.start_address();
+
+    let indexes = [address.p4_index(), address.p3_index(),
+                   address.p2_index(), address.p1_index()];
+
+    for (level, index) in (1..=4).rev().zip(indexes.iter()) {
+        let table = phys_to_virt(table_address)?.as_ptr::<PageTable>();
+        let entry = unsafe { &(*table)[*index] };
+        function(level, usize::from(*index), entry);
+
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+
+        // Each level up covers 512 times as much memory as the one below.
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = 4096u64 << (9 * (level - 1));
+            return Some(entry.addr() + (address.as_u64() & (page_size - 1)));
+        }
+
+        table_address = entry.addr();
+    }
+
+    None
+}
+
+// The physical address a virtual address is mapped to.
+pub fn translate(address: VirtAddr) -> Option<PhysAddr> {
+    walk(address, |_, _, _| {})
+}
+
+// Take a free physical frame, or None if there are none left, or init hasn't
+// been called.
+pub fn allocate_frame() -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+}
+
+// Take count free physical frames which are next to each other, returning the
+// first.
+pub fn allocate_frames(count: usize) -> Option<PhysFrame> {
+    FRAME_ALLOCATOR.lock().as_mut()?.allocate_contiguous(count)
+}
+
+// Map a physical frame at the same virtual address, allocating any page tables
+// the mapping needs. A frame which is already identity mapped is left as it
+// is.
+pub fn identity_map(frame: PhysFrame, flags: PageTableFlags)
+        -> Result<(), MapError> {
+    let address = frame.start_address();
+    if translate(VirtAddr::new(address.as_u64())) == Some(address) {
+        return Ok(());
+    }
+
+    let offset = physical_memory_offset().ok_or(MapError::NotMapped)?;
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let frame_allocator = frame_allocator.as_mut().ok_or(MapError::NotMapped)?;
+
+    let (level_4_table, _) = Cr3::read();
+    let level_4_table = offset + level_4_table.start_address().as_u64();
+
+    unsafe {
+        let level_4_table = &mut *level_4_table.as_mut_ptr::<PageTable>();
+        let mut mapper = OffsetPageTable::new(level_4_table, offset);
+
+        match mapper.identity_map(frame, flags, frame_allocator) {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::FrameAllocationFailed) => {
+                return Err(MapError::OutOfFrames);
+            }
+            Err(_) => return Err(MapError::AlreadyMapped),
+        }
+    }
+
+    Ok(())
+}
+
+// Reading or writing an address which isn't mapped causes a page fault, which
+// would normally bring the kernel down. The debuggers read whatever address
+// they are asked to, so memory_read_byte and memory_write_byte are written in
+// assembly, with a known address for each access. If one of them faults, the
+// page fault handler calls fixup_page_fault, which makes it return 1 through
+// memory_access_fault, instead.
+global_asm!(r#"
+.global memory_read_byte
+memory_read_byte:
+    mov al, [rdi]
+    mov [rsi], al
+    xor eax, eax
+    ret
+
+.global memory_write_byte
+memory_write_byte:
+    mov [rdi], sil
+    xor eax, eax
+    ret
+
+.global memory_access_fault
+memory_access_fault:
+    mov eax, 1
+    ret
+"#);
+
+extern "C" {
+    fn memory_read_byte(address: u64, value: *mut u8) -> u64;
+    fn memory_write_byte(address: u64, value: u8) -> u64;
+    fn memory_access_fault();
+}
+
+// Read a byte of memory, or None if it isn't mapped.
+pub fn read_byte(address: u64) -> Option<u8> {
+    if VirtAddr::try_new(address).is_err() {
+        return None;
+    }
+
+    let mut value = 0;
+    match unsafe { memory_read_byte(address, &mut value) } {
+        0 => Some(value),
+        _ => None,
+    }
+}
+
+// Write a byte of memory, returning false if it isn't mapped. The kernel's code
+// is mapped read-only, so the CPU's write protection is turned off for the
+// write, which lets the debuggers set breakpoints in it.
+pub fn write_byte(address: u64, value: u8) -> bool {
+    if VirtAddr::try_new(address).is_err() {
+        return false;
+    }
+
+    unsafe {
+        let flags = Cr0::read();
+        Cr0::write(flags - Cr0Flags::WRITE_PROTECT);
+        let result = memory_write_byte(address, value);
+        Cr0::write(flags);
+
+        result == 0
+    }
+}
+
+// Called by the page fault handler. If the fault was caused by read_byte or
+// write_byte, make the access fail and return true.
+pub fn fixup_page_fault(stack_frame: &mut InterruptStackFrame) -> bool {
+    let address = stack_frame.instruction_pointer.as_u64();
+    if address != memory_read_byte as u64 && address != memory_write_byte as u64
+    {
+        return false;
+    }
+
+    unsafe {
+        stack_frame.as_mut().instruction_pointer =
+            VirtAddr::new(memory_access_fault as u64);
+    }
+    true
+}
+
+
+// TESTING
+
+// Test that the VGA text buffer, which the bootloader maps to the same
+// physical address, translates to itself, and that an address in the mapping
+// of physical memory translates back to the physical address.
+#[test_case]
+fn test_translate() {
+    let offset = physical_memory_offset().expect("memory::init wasn't called");
+
+    assert_eq!(translate(VirtAddr::new(0xb8000)), Some(PhysAddr::new(0xb8000)));
+    assert_eq!(translate(offset + 0x1234u64), Some(PhysAddr::new(0x1234)));
+}
+
+// Test that frames are handed out once each, and never from low memory.
+#[test_case]
+fn test_allocate_frame() {
+    let first = allocate_frame().expect("no free frames");
+    let second = allocate_frame().expect("no free frames");
+
+    assert_ne!(first, second);
+    assert!(first.start_address().as_u64() >= LOW_MEMORY_END);
+    assert!(second.start_address().as_u64() >= LOW_MEMORY_END);
+}
+
+// Test that a run of frames is handed out next to each other.
+#[test_case]
+fn test_allocate_frames() {
+    let first = allocate_frames(4).expect("no free frames");
+    let next = allocate_frame().expect("no free frames");
+
+    assert!(next.start_address() >= (first + 4).start_address());
+}
@@ -0,0 +1,645 @@
+// A small interactive debugger, which the kernel drops into when it hits a
+// breakpoint, panics, or takes a fault it can't recover from, rather than
+// printing what it can and halting. Commands are typed on the keyboard, or
+// from the host over the first serial port, and can look at the registers,
+// memory, the page tables and the stack of the stopped code:
+//
+//     monitor> regs
+//     monitor> mem rsp 64
+//     monitor> dis rip 8
+//     monitor> pt 0xb8000
+//     monitor> bt
+//     monitor> continue
+//
+// Addresses are given in hex, with or without a leading '0x', or as the name
+// of a register, such as 'rip' or 'rsp'.
+// ---
+// The monitor is entered from exception handlers, and the panic handler, with
+// interrupts disabled. It can't wait for the keyboard or serial interrupts, so
+// it polls both devices for input, and reads memory through the memory
+// module, so that looking at an address which isn't mapped doesn't cause a
+// page fault of its own.
+// ---
+// It is off until the kernel calls enable, so that the tests, which hit
+// breakpoints and panic on purpose, never stop waiting for someone to type.
+// While the GDB stub is turned on, it gets breakpoints instead.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr2, Cr3};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+use yaxpeax_arch::LengthedInstruction;
+use yaxpeax_x86::amd64::InstDecoder;
+use crate::backtrace;
+use crate::console::ConsoleWriter;
+use crate::device::{self, CharDevice};
+use crate::keyboard;
+use crate::memory;
+use crate::serial::line_discipline::LineDiscipline;
+use crate::trap::{TrapFrame, BREAKPOINT_VECTOR, RFLAGS_TRAP};
+
+// The serial port commands are read from, as well as the keyboard.
+const PORT: &str = "ttyS0";
+
+// The port's device, which is looked up by enable, so that the monitor doesn't
+// need the device registry once the kernel has stopped.
+static DEVICE: Once<&'static dyn CharDevice> = Once::new();
+
+// How much mem shows, and how many instructions dis shows, by default.
+const DEFAULT_DUMP_LENGTH: u64 = 128;
+const DEFAULT_INSTRUCTIONS: u64 = 10;
+
+// The most mem will dump at once, as a mistyped length could otherwise keep
+// it printing for hours.
+const MAX_DUMP_LENGTH: u64 = 4096;
+
+// The longest x86 instruction is 15 bytes.
+const INSTRUCTION_MAX: usize = 15;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Set while the monitor is running, so that a fault inside it doesn't try to
+// start it again.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Why the kernel stopped.
+pub enum Stop<'a> {
+    // A breakpoint, or a single step, where every register was saved, and the
+    // stopped code can carry on afterwards.
+    Trap(&'a mut TrapFrame),
+
+    // An exception the kernel can't recover from. Only the interrupt stack
+    // frame is saved, along with the frame pointer of the faulting code, from
+    // backtrace::interrupted_frame_pointer.
+    Fault {
+        name: &'static str,
+        stack_frame: &'a InterruptStackFrame,
+        rbp: u64,
+    },
+
+    Panic(&'a PanicInfo<'a>),
+}
+
+impl Stop<'_> {
+    // Look up a register of the stopped code by name.
+    fn register(&self, name: &str) -> Option<u64> {
+        match self {
+            Stop::Trap(frame) => general_registers(frame).iter()
+                .find(|(register, _)| *register == name)
+                .map(|(_, value)| *value),
+            Stop::Fault { stack_frame, rbp, .. } => match name {
+                "rip" => Some(stack_frame.instruction_pointer.as_u64()),
+                "rsp" => Some(stack_frame.stack_pointer.as_u64()),
+                "rbp" => Some(*rbp),
+                _ => None,
+            },
+            Stop::Panic(_) => None,
+        }
+    }
+}
+
+// Turn the monitor on, so that it is entered on breakpoints, panics and
+// faults. Without the serial port, it only takes commands from the keyboard.
+pub fn enable() {
+    if let Some(device) = device::char_device(PORT) {
+        DEVICE.call_once(|| device);
+    }
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+// Whether the monitor should be entered, which it can't be while it is already
+// running.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst) && !ACTIVE.load(Ordering::SeqCst)
+}
+
+// Run commands until told to continue. Only a Trap can be continued from, so
+// for anything else this never returns.
+pub fn enter(mut stop: Stop) {
+    x86_64::instructions::interrupts::disable();
+    ACTIVE.store(true, Ordering::SeqCst);
+
+    let mut out = ConsoleWriter;
+    let _ = describe(&stop, &mut out);
+    let _ = writeln!(out, "Entered the monitor. Type 'help' for commands.");
+
+    let mut discipline = LineDiscipline::new();
+    loop {
+        let _ = write!(out, "monitor> ");
+        let line = read_line(&mut discipline);
+
+        if let Ok(Next::Resume) = execute(&mut stop, line, &mut out) {
+            break;
+        }
+    }
+
+    ACTIVE.store(false, Ordering::SeqCst);
+}
+
+fn describe(stop: &Stop, out: &mut dyn Write) -> fmt::Result {
+    match stop {
+        Stop::Trap(frame) if frame.vector == BREAKPOINT_VECTOR => {
+            writeln!(out, "Breakpoint at {:#x}", frame.rip.wrapping_sub(1))
+        }
+        Stop::Trap(frame) => writeln!(out, "Stepped to {:#x}", frame.rip),
+        Stop::Fault { name, stack_frame, .. } => {
+            writeln!(out, "{} at {:#x}", name,
+                     stack_frame.instruction_pointer.as_u64())
+        }
+        Stop::Panic(info) => writeln!(out, "{}", info),
+    }
+}
+
+// Read a line from either the keyboard or the serial port, echoing it to the
+// consoles, whichever it was typed on.
+fn read_line(discipline: &mut LineDiscipline) -> &str {
+    discipline.clear();
+
+    loop {
+        let mut received = [0];
+        let port = DEVICE.r#try();
+
+        let byte = if port.map_or(0, |port| port.read(&mut received)) == 1 {
+            received[0]
+        } else {
+            match keyboard::poll() {
+                Some(character) if character.is_ascii() => character as u8,
+                _ => {
+                    core::hint::spin_loop();
+                    continue;
+                }
+            }
+        };
+
+        // The screen doesn't understand carriage returns, and the consoles
+        // all treat a line feed as the start of a new line anyway.
+        let complete = discipline.input(byte, |bytes| {
+            for byte in bytes.iter().filter(|byte| **byte != b'\r') {
+                crate::print!("{}", *byte as char);
+            }
+        });
+        if complete {
+            break;
+        }
+    }
+
+    discipline.line()
+}
+
+// What to do once a command has run.
+#[derive(Debug, PartialEq, Eq)]
+enum Next {
+    Wait,
+    Resume,
+}
+
+type CommandResult = Result<Next, fmt::Error>;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&mut Stop, &mut dyn Write, &str) -> CommandResult,
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "List the available commands",
+        run: help,
+    },
+    Command {
+        name: "regs",
+        help: "Show the registers of the stopped code",
+        run: regs,
+    },
+    Command {
+        name: "mem",
+        help: "Dump memory with 'mem <address> [length]'",
+        run: mem,
+    },
+    Command {
+        name: "dis",
+        help: "Disassemble with 'dis [address] [count]'",
+        run: dis,
+    },
+    Command {
+        name: "pt",
+        help: "Walk the page tables for an address with 'pt [address]'",
+        run: pt,
+    },
+    Command {
+        name: "bt",
+        help: "Show a backtrace of the stopped code",
+        run: bt,
+    },
+    Command {
+        name: "continue",
+        help: "Carry on from a breakpoint",
+        run: resume,
+    },
+    Command {
+        name: "step",
+        help: "Run one instruction, and stop again",
+        run: step,
+    },
+    Command {
+        name: "reboot",
+        help: "Restart the machine",
+        run: reboot,
+    },
+];
+
+// Run a single command line, in the same way as shell::execute.
+fn execute(stop: &mut Stop, line: &str, out: &mut dyn Write) -> CommandResult {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(Next::Wait);
+    }
+
+    let (name, arguments) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+
+    // Let the two most used commands be typed as a single letter.
+    let name = match name {
+        "c" => "continue",
+        "s" => "step",
+        name => name,
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(stop, out, arguments),
+        None => {
+            writeln!(out, "{}: command not found", name)?;
+            Ok(Next::Wait)
+        }
+    }
+}
+
+// Parse an address or a length, which is either a hex number, or the name of a
+// register of the stopped code.
+fn parse_value(stop: &Stop, argument: &str) -> Option<u64> {
+    if let Some(value) = stop.register(argument) {
+        return Some(value);
+    }
+
+    let digits = argument.strip_prefix("0x").unwrap_or(argument);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+// Parse the optional address and count arguments of a command, using the
+// given defaults for any which are missing.
+fn parse_arguments(stop: &Stop, out: &mut dyn Write, arguments: &str,
+                   address: Option<u64>, count: u64)
+        -> Result<Option<(u64, u64)>, fmt::Error> {
+    let mut arguments = arguments.split_whitespace();
+
+    let address = match arguments.next() {
+        Some(argument) => parse_value(stop, argument),
+        None => address,
+    };
+    let count = match arguments.next() {
+        Some(argument) => parse_value(stop, argument),
+        None => Some(count),
+    };
+
+    match (address, count) {
+        (Some(address), Some(count)) => Ok(Some((address, count))),
+        _ => {
+            writeln!(out, "invalid address or count")?;
+            Ok(None)
+        }
+    }
+}
+
+// The general purpose registers of a TrapFrame, in the order they are shown.
+fn general_registers(frame: &TrapFrame) -> [(&'static str, u64); 18] {
+    [
+        ("rax", frame.rax), ("rbx", frame.rbx), ("rcx", frame.rcx),
+        ("rdx", frame.rdx), ("rsi", frame.rsi), ("rdi", frame.rdi),
+        ("rbp", frame.rbp), ("rsp", frame.rsp), ("r8", frame.r8),
+        ("r9", frame.r9), ("r10", frame.r10), ("r11", frame.r11),
+        ("r12", frame.r12), ("r13", frame.r13), ("r14", frame.r14),
+        ("r15", frame.r15), ("rip", frame.rip), ("rflags", frame.rflags),
+    ]
+}
+
+// Write registers three to a line.
+fn write_registers(out: &mut dyn Write, registers: &[(&str, u64)])
+        -> fmt::Result {
+    for line in registers.chunks(3) {
+        for (name, value) in line {
+            write!(out, "{:>6} {:#018x}  ", name, value)?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+
+// COMMANDS
+
+fn help(_stop: &mut Stop, out: &mut dyn Write, _arguments: &str)
+        -> CommandResult {
+    for command in COMMANDS {
+        writeln!(out, "{:<10} {}", command.name, command.help)?;
+    }
+
+    Ok(Next::Wait)
+}
+
+fn regs(stop: &mut Stop, out: &mut dyn Write, _arguments: &str)
+        -> CommandResult {
+    match stop {
+        Stop::Trap(frame) => write_registers(out, &general_registers(frame))?,
+        Stop::Fault { stack_frame, rbp, .. } => {
+            write_registers(out, &[
+                ("rip", stack_frame.instruction_pointer.as_u64()),
+                ("rsp", stack_frame.stack_pointer.as_u64()),
+                ("rbp", *rbp),
+                ("rflags", stack_frame.cpu_flags),
+                ("cs", stack_frame.code_segment),
+                ("ss", stack_frame.stack_segment),
+            ])?;
+        }
+        Stop::Panic(_) => {
+            writeln!(out, "The registers aren't saved when the kernel panics")?;
+        }
+    }
+
+    let (level_4_table, _) = Cr3::read();
+    write_registers(out, &[
+        ("cr0", Cr0::read_raw()),
+        ("cr2", Cr2::read().as_u64()),
+        ("cr3", level_4_table.start_address().as_u64()),
+    ])?;
+
+    Ok(Next::Wait)
+}
+
+// Dump memory as hex, 16 bytes to a line, with the printable characters
+// alongside. Bytes which aren't mapped are shown as '??'.
+fn mem(stop: &mut Stop, out: &mut dyn Write, arguments: &str)
+        -> CommandResult {
+    let rsp = stop.register("rsp");
+    let (address, length) = match parse_arguments(stop, out, arguments, rsp,
+                                                  DEFAULT_DUMP_LENGTH)? {
+        Some(arguments) => arguments,
+        None => return Ok(Next::Wait),
+    };
+    if length > MAX_DUMP_LENGTH {
+        writeln!(out, "mem: only dumping the first {:#x} bytes",
+                 MAX_DUMP_LENGTH)?;
+    }
+    let length = core::cmp::min(length, MAX_DUMP_LENGTH);
+
+    for line in (0..length).step_by(16) {
+        let start = address.wrapping_add(line);
+        let count = core::cmp::min(16, length - line);
+
+        write!(out, "{:#018x}: ", start)?;
+        for i in 0..16 {
+            if i >= count {
+                write!(out, "   ")?;
+                continue;
+            }
+
+            match memory::read_byte(start.wrapping_add(i)) {
+                Some(byte) => write!(out, "{:02x} ", byte)?,
+                None => write!(out, "?? ")?,
+            }
+        }
+
+        write!(out, " ")?;
+        for i in 0..count {
+            let character = match memory::read_byte(start.wrapping_add(i)) {
+                Some(byte @ 0x20..=0x7e) => byte as char,
+                _ => '.',
+            };
+            out.write_char(character)?;
+        }
+        writeln!(out)?;
+    }
+
+    Ok(Next::Wait)
+}
+
+fn dis(stop: &mut Stop, out: &mut dyn Write, arguments: &str)
+        -> CommandResult {
+    let rip = stop.register("rip");
+    let (mut address, count) = match parse_arguments(stop, out, arguments, rip,
+                                                     DEFAULT_INSTRUCTIONS)? {
+        Some(arguments) => arguments,
+        None => return Ok(Next::Wait),
+    };
+
+    let symbols = backtrace::kernel_symbols();
+    let decoder = InstDecoder::default();
+
+    for _ in 0..count {
+        if let Some(symbol) = symbols.as_ref()
+                .and_then(|symbols| symbols.lookup(address))
+                .filter(|symbol| symbol.address == address) {
+            writeln!(out, "{}:", symbol.name)?;
+        }
+
+        // Read as much of the longest possible instruction as is mapped.
+        let mut bytes = [0; INSTRUCTION_MAX];
+        let mut available = 0;
+        while available < INSTRUCTION_MAX {
+            match memory::read_byte(address.wrapping_add(available as u64)) {
+                Some(byte) => bytes[available] = byte,
+                None => break,
+            }
+            available += 1;
+        }
+        if available == 0 {
+            writeln!(out, "{:#018x}: not mapped", address)?;
+            break;
+        }
+
+        write!(out, "{:#018x}: ", address)?;
+        let length = match decoder.decode_slice(&bytes[..available]) {
+            Ok(instruction) => {
+                let length = instruction.len().to_const() as usize;
+                write_bytes(out, &bytes[..length])?;
+                writeln!(out, "{}", instruction)?;
+                length
+            }
+            Err(_) => {
+                write_bytes(out, &bytes[..1])?;
+                writeln!(out, "(bad)")?;
+                1
+            }
+        };
+
+        address = address.wrapping_add(length as u64);
+    }
+
+    Ok(Next::Wait)
+}
+
+// Write an instruction's bytes, padded so the instructions line up.
+fn write_bytes(out: &mut dyn Write, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(out, "{:02x} ", byte)?;
+    }
+    for _ in bytes.len()..INSTRUCTION_MAX {
+        write!(out, "   ")?;
+    }
+
+    Ok(())
+}
+
+fn pt(stop: &mut Stop, out: &mut dyn Write, arguments: &str) -> CommandResult {
+    let rip = stop.register("rip");
+    let address = match parse_arguments(stop, out, arguments, rip, 0)? {
+        Some((address, _)) => address,
+        None => return Ok(Next::Wait),
+    };
+
+    let address = match VirtAddr::try_new(address) {
+        Ok(address) => address,
+        Err(_) => {
+            writeln!(out, "{:#x} isn't a canonical address", address)?;
+            return Ok(Next::Wait);
+        }
+    };
+
+    if memory::physical_memory_offset().is_none() {
+        writeln!(out, "Physical memory isn't mapped, so the page tables \
+                       can't be read")?;
+        return Ok(Next::Wait);
+    }
+
+    let mut result = Ok(());
+    let physical = memory::walk(address, |level, index, entry| {
+        if result.is_ok() {
+            result = writeln!(out, "Level {} [{:>3}]: {:#018x} {:?}", level,
+                              index, entry.addr().as_u64(), entry.flags());
+        }
+    });
+    result?;
+
+    match physical {
+        Some(physical) => writeln!(out, "{:#x} -> {:#x}", address.as_u64(),
+                                   physical.as_u64())?,
+        None => writeln!(out, "{:#x} isn't mapped", address.as_u64())?,
+    }
+
+    Ok(Next::Wait)
+}
+
+fn bt(stop: &mut Stop, out: &mut dyn Write, _arguments: &str)
+        -> CommandResult {
+    match stop {
+        Stop::Trap(frame) => backtrace::write_from(out, frame.rip, frame.rbp)?,
+        Stop::Fault { stack_frame, rbp, .. } => {
+            let address = stack_frame.instruction_pointer.as_u64();
+            backtrace::write_from(out, address, *rbp)?;
+        }
+
+        // The panicking code is further up the stack, under the panic
+        // handler and the monitor.
+        Stop::Panic(_) => backtrace::write(out)?,
+    }
+
+    Ok(Next::Wait)
+}
+
+fn resume(stop: &mut Stop, out: &mut dyn Write, _arguments: &str)
+        -> CommandResult {
+    match stop {
+        Stop::Trap(frame) => {
+            frame.rflags &= !RFLAGS_TRAP;
+            Ok(Next::Resume)
+        }
+        _ => {
+            writeln!(out, "The kernel can't carry on from here, only reboot")?;
+            Ok(Next::Wait)
+        }
+    }
+}
+
+// Single-step by setting the Trap Flag, so the Debug exception brings us back
+// after the next instruction.
+fn step(stop: &mut Stop, out: &mut dyn Write, arguments: &str)
+        -> CommandResult {
+    if let Stop::Trap(frame) = stop {
+        frame.rflags |= RFLAGS_TRAP;
+        return Ok(Next::Resume);
+    }
+
+    resume(stop, out, arguments)
+}
+
+fn reboot(_stop: &mut Stop, out: &mut dyn Write, _arguments: &str)
+        -> CommandResult {
+    writeln!(out, "Rebooting")?;
+    crate::reboot();
+}
+
+
+// TESTING
+
+#[cfg(test)]
+use crate::testing::Output;
+
+#[cfg(test)]
+fn test_frame() -> TrapFrame {
+    TrapFrame {
+        rax: 0, rbx: 0, rcx: 0, rdx: 0, rsi: 0, rdi: 0, rbp: 0, r8: 0,
+        r9: 0, r10: 0, r11: 0, r12: 0, r13: 0, r14: 0, r15: 0,
+        vector: BREAKPOINT_VECTOR, rip: 0x1001, cs: 8, rflags: RFLAGS_TRAP,
+        rsp: 0, ss: 0,
+    }
+}
+
+// Test that memory is dumped from an address given as a register, that bytes
+// which can't be read are shown as such, and that long dumps are cut short.
+#[test_case]
+fn test_monitor_mem() {
+    let bytes = *b"rustos\0\x01";
+    let mut frame = test_frame();
+    frame.rsi = bytes.as_ptr() as u64;
+
+    let mut output = Output::new();
+    let next = execute(&mut Stop::Trap(&mut frame), "mem rsi 8", &mut output);
+
+    assert_eq!(next, Ok(Next::Wait));
+    assert!(output.as_str().ends_with(concat!(
+        ": 72 75 73 74 6f 73 00 01 ",
+        "                         rustos..\n")));
+
+    let mut output = Output::new();
+    execute(&mut Stop::Trap(&mut frame), "mem 0x8000000000000000 1",
+            &mut output).unwrap();
+    assert!(output.as_str().contains(": ?? "));
+
+    let mut output = Output::new();
+    let _ = execute(&mut Stop::Trap(&mut frame),
+                    "mem 0x8000000000000000 ffffffff", &mut output);
+    assert!(output.as_str()
+        .starts_with("mem: only dumping the first 0x1000 bytes\n"));
+}
+
+// Test that continuing from a breakpoint resumes with single-stepping turned
+// off, and that unknown commands are reported.
+#[test_case]
+fn test_monitor_continue() {
+    let mut frame = test_frame();
+    let mut output = Output::new();
+
+    assert_eq!(execute(&mut Stop::Trap(&mut frame), "frobnicate", &mut output),
+               Ok(Next::Wait));
+    assert_eq!(output.as_str(), "frobnicate: command not found\n");
+
+    assert_eq!(execute(&mut Stop::Trap(&mut frame), "c", &mut output),
+               Ok(Next::Resume));
+    assert_eq!(frame.rflags & RFLAGS_TRAP, 0);
+}
@@ -0,0 +1,303 @@
+// A small TCP/IP stack, for the first network card ('eth0'). It is built in
+// layers, each in its own module, which hand packets up and down:
+// - ethernet: Frames, and the MAC addresses they are sent between.
+// - arp: Finding the MAC address which goes with an IPv4 address.
+// - ipv4: Packets between IPv4 addresses, through the gateway when the
+//               destination isn't on our subnet. Fragments aren't supported.
+// - icmp: Answering pings, and sending them.
+// - udp: Datagrams, through UdpSockets.
+// - dhcp: Asking a DHCP server for our address.
+// - tcp: Streams, through TcpStreams. Only outgoing connections are made.
+// - console: A console sink which sends the kernel's output to another
+//               machine, as UDP datagrams.
+// ---
+// There are no threads to wait on, so nothing happens in the background
+// except in poll, which takes every frame the card has received and passes
+// it up the layers, retransmits TCP segments which haven't been
+// acknowledged, and keeps the net console's ARP entry fresh. It is called
+// every POLL_PERIOD from a timer, and over and over by anything waiting for
+// the network, through wait_until.
+// ---
+// init configures the interface from the kernel command line:
+// - ip=<address>/<prefix length>: Use a fixed address, such as
+//               'ip=10.0.2.15/24', with the gateway from 'gateway=<address>'.
+// - ip=off: Don't configure the interface at all.
+// Otherwise the address is asked for with DHCP. QEMU's user networking
+// answers with 10.0.2.15, and has a gateway at 10.0.2.2, which passes
+// connections on to the host, and a DNS server at 10.0.2.3.
+// ---
+// As we have no heap allocator, every table in the stack, such as the ARP
+// cache and the sockets, is a fixed-size array, and packets are built in
+// buffers on the stack. Its locks are only taken with interrupts disabled,
+// and are never held while a packet is sent, as sending may have to wait for
+// ARP, which polls.
+
+use core::fmt;
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::device::{self, NetDevice};
+use crate::{cmdline, time};
+
+pub mod arp;
+pub mod console;
+pub mod dhcp;
+pub mod ethernet;
+pub mod icmp;
+pub mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+pub use ethernet::MacAddress;
+pub use ipv4::Ipv4Addr;
+pub use tcp::TcpStream;
+pub use udp::UdpSocket;
+
+// How often the timer polls the card.
+pub const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+// How long init waits for a DHCP server.
+const DHCP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketError {
+    // There is no network card, or the interface has no address yet.
+    NoInterface,
+    NotConfigured,
+    // No MAC address could be found for the destination, or the next hop to
+    // it.
+    Unreachable,
+    TimedOut,
+    // The port is already bound, or every socket is in use.
+    InUse,
+    Full,
+    TooLarge,
+    // The other end refused the connection, or reset it.
+    Refused,
+    Reset,
+    Closed,
+}
+
+impl fmt::Display for SocketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketError::NoInterface => write!(f, "there is no network card"),
+            SocketError::NotConfigured => {
+                write!(f, "the interface has no address")
+            }
+            SocketError::Unreachable => {
+                write!(f, "the destination is unreachable")
+            }
+            SocketError::TimedOut => write!(f, "timed out"),
+            SocketError::InUse => write!(f, "the port is already in use"),
+            SocketError::Full => write!(f, "every socket is in use"),
+            SocketError::TooLarge => write!(f, "the packet is too large"),
+            SocketError::Refused => write!(f, "the connection was refused"),
+            SocketError::Reset => write!(f, "the connection was reset"),
+            SocketError::Closed => write!(f, "the connection is closed"),
+        }
+    }
+}
+
+// The interface's address, and where to send packets which aren't for our
+// subnet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl Config {
+    pub fn is_local(&self, address: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        address.to_u32() & mask == self.address.to_u32() & mask
+    }
+
+    // The address of the broadcast to our subnet.
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from_u32(self.address.to_u32() | !self.netmask.to_u32())
+    }
+
+    // Where to send a packet for the destination: the destination itself if
+    // it is on our subnet, and otherwise the gateway.
+    pub fn next_hop(&self, destination: Ipv4Addr) -> Option<Ipv4Addr> {
+        if self.is_local(destination) {
+            Some(destination)
+        } else {
+            self.gateway
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.netmask.to_u32().count_ones())?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        Ok(())
+    }
+}
+
+static DEVICE: Once<&'static dyn NetDevice> = Once::new();
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+// The buffer frames are received into. Whoever holds it is polling.
+static RECEIVE_BUFFER: Mutex<[u8; ethernet::MAX_FRAME_SIZE]> =
+    Mutex::new([0; ethernet::MAX_FRAME_SIZE]);
+
+// Start using eth0, if there is one, and give it an address.
+pub fn init() {
+    let device = match device::net_device("eth0") {
+        Some(device) => device,
+        None => {
+            log::info!("there is no network card, so networking is off");
+            return;
+        }
+    };
+    DEVICE.call_once(|| device);
+    if let Err(error) = time::add_periodic_timer(POLL_PERIOD, poll) {
+        log::warn!("the network will only be polled while waiting: {}",
+                   error);
+    }
+
+    let cmdline = cmdline::get();
+    let config = match cmdline.value("ip") {
+        Some("off") => return,
+        Some(value) => match parse_static(value, cmdline.value("gateway")) {
+            Some(config) => Ok(config),
+            None => {
+                log::warn!("ignoring ip={}, as it isn't an address and \
+                            prefix length", value);
+                dhcp::run(DHCP_TIMEOUT)
+            }
+        },
+        None => dhcp::run(DHCP_TIMEOUT),
+    };
+
+    match config {
+        Ok(config) => {
+            set_config(Some(config));
+            log::info!("eth0 is {}", config);
+        }
+        Err(error) => log::warn!("eth0 has no address: {}", error),
+    }
+
+    console::init();
+}
+
+// Parse 'ip=<address>/<prefix length>' and 'gateway=<address>'.
+fn parse_static(ip: &str, gateway: Option<&str>) -> Option<Config> {
+    let mut parts = ip.splitn(2, '/');
+    let address = parts.next()?.parse().ok()?;
+    let prefix: u32 = parts.next()?.parse().ok()?;
+    if prefix > 32 {
+        return None;
+    }
+
+    Some(Config {
+        address,
+        netmask: Ipv4Addr::from_u32(u32::MAX.checked_shl(32 - prefix)
+                                    .unwrap_or(0)),
+        gateway: gateway.and_then(|gateway| gateway.parse().ok()),
+        dns: None,
+    })
+}
+
+fn device() -> Result<&'static dyn NetDevice, SocketError> {
+    DEVICE.r#try().copied().ok_or(SocketError::NoInterface)
+}
+
+pub fn mac_address() -> Option<MacAddress> {
+    device().ok().map(|device| MacAddress(device.mac_address()))
+}
+
+pub fn config() -> Option<Config> {
+    without_interrupts(|| *CONFIG.lock())
+}
+
+pub fn set_config(config: Option<Config>) {
+    without_interrupts(|| *CONFIG.lock() = config);
+}
+
+// Handle every frame the card has received, and then anything which is due,
+// such as retransmissions. Does nothing if something else is already polling.
+pub fn poll() {
+    let device = match device() {
+        Ok(device) => device,
+        Err(_) => return,
+    };
+    let mut frame = match RECEIVE_BUFFER.try_lock() {
+        Some(frame) => frame,
+        None => return,
+    };
+
+    while let Some(len) = device.receive(&mut frame[..]) {
+        ethernet::receive(&frame[..len]);
+    }
+    tcp::poll();
+    console::poll();
+}
+
+// Poll until the condition gives a value, or the timeout passes.
+pub fn wait_until<T>(timeout: Duration,
+                     mut condition: impl FnMut() -> Option<T>) -> Option<T> {
+    let end = time::now() + timeout;
+    loop {
+        poll();
+        if let Some(value) = condition() {
+            return Some(value);
+        }
+        if time::now() >= end {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// The Internet checksum, used by IPv4, ICMP, UDP and TCP: the ones'
+// complement of the ones' complement sum of the data as 16-bit big-endian
+// words. Every part but the last has to be an even number of bytes.
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            let high = u32::from(word[0]) << 8;
+            sum += high | u32::from(word.get(1).copied().unwrap_or(0));
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+
+// TESTING
+
+// Test the checksum against the example in RFC 1071, split unevenly.
+#[test_case]
+fn test_checksum() {
+    let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+
+    assert_eq!(checksum(&[&data]), !0xddf2);
+    assert_eq!(checksum(&[&data[..4], &data[4..]]), !0xddf2);
+    assert_eq!(checksum(&[&data[..7]]), !0xdcfb);
+}
+
+// Test that static configurations are parsed, and route through the gateway.
+#[test_case]
+fn test_static_config() {
+    let config = parse_static("192.168.1.20/24", Some("192.168.1.1"))
+        .expect("the configuration wasn't parsed");
+
+    assert_eq!(config.netmask, Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(config.broadcast(), Ipv4Addr::new(192, 168, 1, 255));
+    assert_eq!(config.next_hop(Ipv4Addr::new(192, 168, 1, 7)),
+               Some(Ipv4Addr::new(192, 168, 1, 7)));
+    assert_eq!(config.next_hop(Ipv4Addr::new(10, 0, 0, 1)),
+               Some(Ipv4Addr::new(192, 168, 1, 1)));
+    assert!(parse_static("192.168.1.20", None).is_none());
+}
@@ -0,0 +1,203 @@
+// The Address Resolution Protocol, which finds the MAC address of the card
+// with an IPv4 address on our subnet. A request for the address is broadcast,
+// and the card with it replies. Every request also carries the sender's
+// addresses, so the card being asked learns about us at the same time.
+// ---
+// The addresses we learn are kept in a small cache. Entries are replaced,
+// oldest first, when it is full, and otherwise kept until they are
+// MAX_AGE old, after which they have to be asked for again. Anything which
+// keeps sending to one address without waiting for it, such as the net
+// console, can use refresh to ask for it again shortly before then.
+// ---
+// The packets we handle are always for Ethernet and IPv4, so are 28 bytes:
+//   0: Hardware type (1 for Ethernet), and protocol type (0x0800 for IPv4).
+//   4: Hardware address length (6), and protocol address length (4).
+//   6: Operation (1 for a request, 2 for a reply).
+//   8: Sender's MAC address, then its IPv4 address.
+//  18: Target's MAC address (zeroes in requests), then its IPv4 address.
+
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::time;
+use super::ethernet::{self, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use super::{Ipv4Addr, MacAddress, SocketError};
+
+const PACKET_SIZE: usize = 28;
+
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+
+const CACHE_SIZE: usize = 16;
+const MAX_AGE: Duration = Duration::from_secs(300);
+
+// How old an entry is when refresh asks for it again.
+const REFRESH_AGE: Duration = Duration::from_secs(240);
+
+// How long resolve waits for a reply before asking again.
+const RETRY_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    address: Ipv4Addr,
+    mac_address: MacAddress,
+    learnt: Duration,
+}
+
+static CACHE: Mutex<[Option<Entry>; CACHE_SIZE]> =
+    Mutex::new([None; CACHE_SIZE]);
+
+// Look for an address in the cache, without asking for it.
+pub fn lookup(address: Ipv4Addr) -> Option<MacAddress> {
+    let now = time::now();
+    without_interrupts(|| {
+        CACHE.lock().iter()
+            .flatten()
+            .find(|entry| entry.address == address)
+            // The address can have been learnt since now was read.
+            .filter(|entry| {
+                now.checked_sub(entry.learnt).map_or(true, |age| age < MAX_AGE)
+            })
+            .map(|entry| entry.mac_address)
+    })
+}
+
+// Remember the MAC address which goes with an IPv4 address.
+pub fn learn(address: Ipv4Addr, mac_address: MacAddress) {
+    let entry = Entry { address, mac_address, learnt: time::now() };
+    without_interrupts(|| {
+        let mut cache = CACHE.lock();
+        let position = cache.iter()
+            .position(|slot| {
+                matches!(slot, Some(old) if old.address == address)
+            })
+            .or_else(|| cache.iter().position(|slot| slot.is_none()))
+            .or_else(|| {
+                (0..CACHE_SIZE).min_by_key(|index| {
+                    cache[*index].map(|old| old.learnt)
+                })
+            });
+        if let Some(position) = position {
+            cache[position] = Some(entry);
+        }
+    });
+}
+
+// Ask for an address again, without waiting for the reply, if it isn't in
+// the cache or will soon drop out of it. The reply updates the entry.
+pub fn refresh(address: Ipv4Addr) -> Result<(), SocketError> {
+    let now = time::now();
+    let fresh = without_interrupts(|| {
+        CACHE.lock().iter()
+            .flatten()
+            .filter(|entry| entry.address == address)
+            .any(|entry| {
+                now.checked_sub(entry.learnt)
+                    .map_or(true, |age| age < REFRESH_AGE)
+            })
+    });
+    if fresh {
+        return Ok(());
+    }
+
+    send(OPERATION_REQUEST, MacAddress::BROADCAST, MacAddress([0; 6]),
+         address)
+}
+
+// Forget every address which has been learnt.
+pub fn flush() {
+    without_interrupts(|| *CACHE.lock() = [None; CACHE_SIZE]);
+}
+
+// Find the MAC address for an IPv4 address on our subnet, asking for it every
+// RETRY_PERIOD if it isn't in the cache. With a timeout of zero, it is asked
+// for once, but not waited for, which is what anything which can run in an
+// interrupt handler does.
+pub fn resolve(address: Ipv4Addr, timeout: Duration)
+        -> Result<MacAddress, SocketError> {
+    if let Some(mac_address) = lookup(address) {
+        return Ok(mac_address);
+    }
+
+    let end = time::now() + timeout;
+    loop {
+        send(OPERATION_REQUEST, MacAddress::BROADCAST, MacAddress([0; 6]),
+             address)?;
+
+        let now = time::now();
+        if now >= end {
+            return Err(SocketError::Unreachable);
+        }
+        let wait = core::cmp::min(end - now, RETRY_PERIOD);
+        if let Some(mac_address) = super::wait_until(wait, || lookup(address)) {
+            return Ok(mac_address);
+        }
+    }
+}
+
+// Handle a request or reply. We learn the sender's addresses from anything
+// sent to us, and update them if we already know them, as the card with the
+// address may have changed.
+pub fn receive(packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || packet[0..6] != [0, 1, 0x08, 0x00, 6, 4] {
+        return;
+    }
+
+    let operation = u16::from_be_bytes([packet[6], packet[7]]);
+    let mut sender_mac = [0; 6];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    let sender_mac = MacAddress(sender_mac);
+    let sender = Ipv4Addr::from_slice(&packet[14..18]);
+    let target = Ipv4Addr::from_slice(&packet[24..28]);
+
+    let for_us = match super::config() {
+        Some(config) => config.address == target,
+        None => false,
+    };
+    if for_us || lookup(sender).is_some() {
+        learn(sender, sender_mac);
+    }
+
+    if for_us && operation == OPERATION_REQUEST {
+        let _ = send(OPERATION_REPLY, sender_mac, sender_mac, sender);
+    }
+}
+
+fn send(operation: u16, destination: MacAddress, target_mac: MacAddress,
+        target: Ipv4Addr) -> Result<(), SocketError> {
+    let mac_address = super::mac_address().ok_or(SocketError::NoInterface)?;
+    let address = super::config()
+        .map(|config| config.address)
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+
+    let mut packet = [0; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&1u16.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&mac_address.0);
+    packet[14..18].copy_from_slice(&address.octets());
+    packet[18..24].copy_from_slice(&target_mac.0);
+    packet[24..28].copy_from_slice(&target.octets());
+
+    ethernet::send(destination, ETHERTYPE_ARP, &[&packet])
+}
+
+
+// TESTING
+
+// Test that QEMU's gateway answers our requests, and that its answer is
+// cached.
+#[test_case]
+fn test_resolve_gateway() {
+    let gateway = Ipv4Addr::new(10, 0, 2, 2);
+    flush();
+
+    let mac_address = resolve(gateway, Duration::from_secs(3))
+        .expect("the gateway didn't answer");
+
+    assert_ne!(mac_address, MacAddress::BROADCAST);
+    assert_eq!(lookup(gateway), Some(mac_address));
+}
@@ -0,0 +1,168 @@
+// A console sink which sends the kernel's output to another machine, one UDP
+// datagram per line, in the style of Linux's netconsole. It is turned on with
+// 'netconsole=<address>:<port>' on the kernel command line, and can be
+// listened to with tools/netconsole.py, or 'nc -ulk <port>'. The datagrams are
+// sent from SOURCE_PORT.
+// ---
+// The sink is written to with interrupts disabled, so it can't wait for ARP.
+// Instead, the listener's MAC address (or the gateway's) is found when the
+// sink is registered. The listener only receives, so nothing it sends keeps
+// the ARP entry fresh. Instead, poll asks for the address again, at most once
+// every REFRESH_PERIOD, once the entry is close to expiring. Lines are only
+// lost if no reply comes before the entry drops out of the cache, until it
+// has been learnt again.
+// ---
+// The test runner also sends its results through the sink, as they are
+// written straight to the serial port, rather than printed.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use crate::{cmdline, time};
+use log::LevelFilter;
+use crate::console::{self, Console};
+use super::ipv4::{self, RESOLVE_TIMEOUT};
+use super::{udp, Ipv4Addr, SocketError};
+
+const SOURCE_PORT: u16 = 6665;
+const DEFAULT_PORT: u16 = 6666;
+
+// Lines longer than this are split over several datagrams.
+const MAX_LINE: usize = 512;
+
+// The least time between asking for the listener's address again.
+const REFRESH_PERIOD: Duration = Duration::from_secs(1);
+
+struct Line {
+    data: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Line {
+    fn send(&mut self) {
+        if let Some((address, port)) = TARGET.r#try() {
+            let _ = udp::send(SOURCE_PORT, *address, *port,
+                              &self.data[..self.len],
+                              core::time::Duration::from_secs(0));
+        }
+        self.len = 0;
+    }
+}
+
+impl fmt::Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[self.len] = byte;
+            self.len += 1;
+            if byte == b'\n' || self.len == MAX_LINE {
+                self.send();
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct NetConsole {
+    line: Mutex<Line>,
+}
+
+impl Console for NetConsole {
+    // Anything written while the line is locked, which can only be by the
+    // network stack while it is sending the line, or by another CPU, is
+    // dropped, rather than waited for.
+    fn write(&self, args: fmt::Arguments) {
+        if let Some(mut line) = self.line.try_lock() {
+            let _ = line.write_fmt(args);
+        }
+    }
+}
+
+static NET_CONSOLE: NetConsole = NetConsole {
+    line: Mutex::new(Line { data: [0; MAX_LINE], len: 0 }),
+};
+static TARGET: Once<(Ipv4Addr, u16)> = Once::new();
+
+// When poll last asked for the listener's address, in nanoseconds.
+static REFRESHED_AT: AtomicU64 = AtomicU64::new(0);
+
+// Parse '<address>:<port>', or just '<address>' for DEFAULT_PORT.
+fn parse_target(value: &str) -> Option<(Ipv4Addr, u16)> {
+    let mut parts = value.splitn(2, ':');
+    let address = parts.next()?.parse().ok()?;
+    let port = match parts.next() {
+        Some(port) => port.parse().ok()?,
+        None => DEFAULT_PORT,
+    };
+    Some((address, port))
+}
+
+// Register the sink, if the command line asks for it.
+pub fn init() {
+    let value = match cmdline::get().value("netconsole") {
+        Some(value) => value,
+        None => return,
+    };
+    let (address, port) = match parse_target(value) {
+        Some(target) => target,
+        None => {
+            log::warn!("ignoring netconsole={}, as it isn't an address and \
+                        port", value);
+            return;
+        }
+    };
+
+    if let Err(error) = ipv4::resolve(address, RESOLVE_TIMEOUT) {
+        log::warn!("not sending the console to {}: {}", address, error);
+        return;
+    }
+    TARGET.call_once(|| (address, port));
+    match console::register("net", &NET_CONSOLE, LevelFilter::Info) {
+        Ok(()) => log::info!("sending the console to {}:{}", address, port),
+        Err(error) => log::warn!("couldn't add the net console: {:?}", error),
+    }
+}
+
+// Called by net::poll, to keep the listener's ARP entry from expiring.
+pub fn poll() {
+    let address = match TARGET.r#try() {
+        Some((address, _)) => *address,
+        None => return,
+    };
+
+    let now = time::now().as_nanos() as u64;
+    let refreshed_at = REFRESHED_AT.load(Ordering::Relaxed);
+    if now.saturating_sub(refreshed_at) < REFRESH_PERIOD.as_nanos() as u64 {
+        return;
+    }
+    REFRESHED_AT.store(now, Ordering::Relaxed);
+
+    let _ = ipv4::refresh(address);
+}
+
+pub fn is_enabled() -> bool {
+    TARGET.r#try().is_some()
+}
+
+// Send text through the sink, whatever its level filter.
+pub fn write(s: &str) -> Result<(), SocketError> {
+    if !is_enabled() {
+        return Err(SocketError::NotConfigured);
+    }
+    NET_CONSOLE.write(format_args!("{}", s));
+    Ok(())
+}
+
+
+// TESTING
+
+// Test that the netconsole option is parsed, with or without a port.
+#[test_case]
+fn test_parse_target() {
+    assert_eq!(parse_target("10.0.2.2:5555"),
+               Some((Ipv4Addr::new(10, 0, 2, 2), 5555)));
+    assert_eq!(parse_target("10.0.2.2"),
+               Some((Ipv4Addr::new(10, 0, 2, 2), DEFAULT_PORT)));
+    assert_eq!(parse_target("10.0.2.2:port"), None);
+    assert_eq!(parse_target("host:5555"), None);
+}
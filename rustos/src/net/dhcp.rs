@@ -0,0 +1,252 @@
+// A DHCP client, which asks the network for our address. It takes four
+// messages, all between port 68 on the client and port 67 on the server:
+// 1. DISCOVER: We broadcast that we are looking for an address.
+// 2. OFFER: A server offers us one, along with the rest of the configuration.
+// 3. REQUEST: We broadcast that we are taking the offer from that server.
+// 4. ACK: The server confirms it, and the address is ours.
+// Until then we have no address, so we send from 0.0.0.0, and ask for the
+// replies to be broadcast back to us.
+// ---
+// Every message has a fixed part of 236 bytes, of which we use:
+//   0: Operation (1 from the client, 2 from the server), hardware type (1 for
+//               Ethernet), hardware address length (6), and hops (0).
+//   4: Transaction ID, picked by the client, so it can tell which replies are
+//               for it.
+//  10: Flags. Bit 15 asks for the reply to be broadcast.
+//  16: The address being offered to the client.
+//  28: The client's MAC address.
+// and is followed by the magic cookie (99, 130, 83, 99) and options, each a
+// code, a length and a value, until the end option (255). We send:
+//   53: Message type (1 DISCOVER, 2 OFFER, 3 REQUEST, 5 ACK, 6 NAK).
+//   50: The address we are asking for, in the REQUEST.
+//   54: The server we are taking the offer from, in the REQUEST.
+//   55: The options we want: subnet mask (1), router (3) and DNS server (6).
+// and read the same options, as well as the lease time (51), from the server.
+// ---
+// The lease is never renewed, so if the server expects it to be, we will
+// carry on using the address after it has expired.
+
+use core::time::Duration;
+use crate::time;
+use super::{Config, Ipv4Addr, SocketError, UdpSocket};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+const FIXED_SIZE: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const MAX_MESSAGE_SIZE: usize = 576;
+
+const OPERATION_REQUEST: u8 = 1;
+const OPERATION_REPLY: u8 = 2;
+const FLAG_BROADCAST: u16 = 1 << 15;
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+// How long to wait for each reply before sending again.
+const RETRY_PERIOD: Duration = Duration::from_secs(1);
+
+// The parts of a reply from a server which we use.
+#[derive(Debug, Default)]
+struct Reply {
+    message_type: u8,
+    address: Option<Ipv4Addr>,
+    server: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+}
+
+// Ask for an address, giving up after the timeout.
+pub fn run(timeout: Duration) -> Result<Config, SocketError> {
+    let mac_address = super::mac_address().ok_or(SocketError::NoInterface)?;
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let transaction = time::now().as_nanos() as u32 ^ 0x5255_0000;
+    let end = time::now() + timeout;
+
+    let offer = exchange(&socket, end, transaction, |message| {
+        build(message, transaction, mac_address.0, DISCOVER, None)
+    }, OFFER)?;
+    let address = offer.address.ok_or(SocketError::Refused)?;
+
+    let ack = exchange(&socket, end, transaction, |message| {
+        build(message, transaction, mac_address.0, REQUEST,
+              Some((address, offer.server)))
+    }, ACK)?;
+
+    let config = Config {
+        address,
+        netmask: ack.netmask.unwrap_or(Ipv4Addr::new(255, 255, 255, 0)),
+        gateway: ack.router,
+        dns: ack.dns,
+    };
+    log::info!("DHCP server {} leased {} for {}s",
+               ack.server.unwrap_or(Ipv4Addr::UNSPECIFIED), address,
+               ack.lease_time.unwrap_or(0));
+    Ok(config)
+}
+
+// Broadcast a message every RETRY_PERIOD, until a reply of the expected type
+// arrives. A NAK means the server has refused us.
+fn exchange(socket: &UdpSocket, end: Duration, transaction: u32,
+            build: impl Fn(&mut [u8]) -> usize, expected: u8)
+        -> Result<Reply, SocketError> {
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    let len = build(&mut message);
+
+    loop {
+        let now = time::now();
+        if now >= end {
+            return Err(SocketError::TimedOut);
+        }
+        socket.send_to(&message[..len], Ipv4Addr::BROADCAST, SERVER_PORT)?;
+
+        let wait = core::cmp::min(end - now, RETRY_PERIOD);
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        let reply = super::wait_until(wait, || {
+            let (len, _, port) = socket.recv_from(&mut buffer)?;
+            if port != SERVER_PORT {
+                return None;
+            }
+            parse(&buffer[..len], transaction)
+        });
+
+        match reply {
+            Some(reply) if reply.message_type == expected => return Ok(reply),
+            Some(reply) if reply.message_type == NAK => {
+                return Err(SocketError::Refused);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Fill in a DISCOVER or REQUEST, returning its length. A REQUEST names the
+// address and server it is taking.
+fn build(message: &mut [u8], transaction: u32, mac_address: [u8; 6],
+         message_type: u8, request: Option<(Ipv4Addr, Option<Ipv4Addr>)>)
+        -> usize {
+    message[0] = OPERATION_REQUEST;
+    message[1] = 1;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&transaction.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(&mac_address);
+    message[FIXED_SIZE..FIXED_SIZE + 4].copy_from_slice(&MAGIC_COOKIE);
+
+    let mut len = FIXED_SIZE + 4;
+    let mut option = |code: u8, value: &[u8]| {
+        message[len] = code;
+        message[len + 1] = value.len() as u8;
+        message[len + 2..len + 2 + value.len()].copy_from_slice(value);
+        len += 2 + value.len();
+    };
+    option(OPTION_MESSAGE_TYPE, &[message_type]);
+    if let Some((address, server)) = request {
+        option(OPTION_REQUESTED_ADDRESS, &address.octets());
+        if let Some(server) = server {
+            option(OPTION_SERVER_ID, &server.octets());
+        }
+    }
+    option(OPTION_PARAMETERS,
+           &[OPTION_SUBNET_MASK, OPTION_ROUTER, OPTION_DNS_SERVER]);
+
+    message[len] = OPTION_END;
+    len + 1
+}
+
+// Read a reply from a server, if it is one, and is part of our transaction.
+fn parse(message: &[u8], transaction: u32) -> Option<Reply> {
+    if message.len() < FIXED_SIZE + 4
+        || message[0] != OPERATION_REPLY
+        || message[4..8] != transaction.to_be_bytes()
+        || message[FIXED_SIZE..FIXED_SIZE + 4] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut reply = Reply::default();
+    let offered = Ipv4Addr::from_slice(&message[16..20]);
+    if offered != Ipv4Addr::UNSPECIFIED {
+        reply.address = Some(offered);
+    }
+
+    let mut options = &message[FIXED_SIZE + 4..];
+    while let Some(&code) = options.first() {
+        if code == OPTION_END {
+            break;
+        }
+        // The pad option (0) is a single byte.
+        if code == 0 {
+            options = &options[1..];
+            continue;
+        }
+
+        let len = usize::from(*options.get(1)?);
+        let value = options.get(2..2 + len)?;
+        let address = if len >= 4 {
+            Some(Ipv4Addr::from_slice(value))
+        } else {
+            None
+        };
+        match code {
+            OPTION_MESSAGE_TYPE if len == 1 => reply.message_type = value[0],
+            OPTION_SUBNET_MASK => reply.netmask = address,
+            OPTION_ROUTER => reply.router = address,
+            OPTION_DNS_SERVER => reply.dns = address,
+            OPTION_SERVER_ID => reply.server = address,
+            OPTION_LEASE_TIME if len == 4 => {
+                reply.lease_time = Some(u32::from_be_bytes(
+                    [value[0], value[1], value[2], value[3]]));
+            }
+            _ => {}
+        }
+        options = &options[2 + len..];
+    }
+
+    Some(reply)
+}
+
+
+// TESTING
+
+// Test that the interface was given the configuration QEMU's user networking
+// hands out, when the kernel started.
+#[test_case]
+fn test_configured_by_dhcp() {
+    let config = super::config().expect("eth0 has no address");
+
+    assert_eq!(config.address, Ipv4Addr::new(10, 0, 2, 15));
+    assert_eq!(config.netmask, Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(config.gateway, Some(Ipv4Addr::new(10, 0, 2, 2)));
+    assert_eq!(config.dns, Some(Ipv4Addr::new(10, 0, 2, 3)));
+}
+
+// Test that a REQUEST can be read back, as far as a reply would be, and that
+// a reply to another transaction is ignored.
+#[test_case]
+fn test_build_and_parse() {
+    let mut message = [0; MAX_MESSAGE_SIZE];
+    let len = build(&mut message, 0x1234_5678, [2, 0, 0, 0, 0, 1], REQUEST,
+                    Some((Ipv4Addr::new(10, 0, 2, 15), None)));
+    message[0] = OPERATION_REPLY;
+
+    assert!(parse(&message[..len], 0x8765_4321).is_none());
+    let reply = parse(&message[..len], 0x1234_5678).unwrap();
+    assert_eq!(reply.message_type, REQUEST);
+    assert_eq!(reply.address, None);
+}
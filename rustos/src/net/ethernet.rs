@@ -0,0 +1,91 @@
+// Ethernet frames. Each starts with a 14 byte header, of the destination and
+// source MAC addresses and the EtherType, which says what the payload is. We
+// handle ARP (0x0806) and IPv4 (0x0800), and drop anything else. The frame
+// check sequence at the end is added and removed by the card.
+// ---
+// Frames have to be at least 60 bytes long, so shorter ones are padded with
+// zeroes. This means the payload of a received frame can be longer than the
+// packet inside it, which the layers above have to allow for.
+
+use core::fmt;
+use core::time::Duration;
+use crate::device::NetError;
+use crate::time;
+use super::{arp, ipv4, SocketError};
+
+pub const HEADER_SIZE: usize = 14;
+pub const MTU: usize = 1500;
+pub const MAX_FRAME_SIZE: usize = HEADER_SIZE + MTU;
+const MIN_FRAME_SIZE: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+// How long send waits for the card to have room for a frame.
+const SEND_TIMEOUT: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+               a, b, c, d, e, g)
+    }
+}
+
+// Pass a received frame up to the layer its EtherType says it is for.
+pub fn receive(frame: &[u8]) {
+    if frame.len() < HEADER_SIZE {
+        return;
+    }
+
+    let mut source = [0; 6];
+    source.copy_from_slice(&frame[6..12]);
+    let payload = &frame[HEADER_SIZE..];
+    match u16::from_be_bytes([frame[12], frame[13]]) {
+        ETHERTYPE_ARP => arp::receive(payload),
+        ETHERTYPE_IPV4 => ipv4::receive(MacAddress(source), payload),
+        _ => {}
+    }
+}
+
+// Send a frame, with the parts of its payload one after the other. If the
+// card's transmit queue is full, it is given a moment to catch up.
+pub fn send(destination: MacAddress, ethertype: u16, parts: &[&[u8]])
+        -> Result<(), SocketError> {
+    let device = super::device()?;
+
+    let len = HEADER_SIZE + parts.iter().map(|part| part.len()).sum::<usize>();
+    if len > MAX_FRAME_SIZE {
+        return Err(SocketError::TooLarge);
+    }
+
+    let mut frame = [0; MAX_FRAME_SIZE];
+    frame[0..6].copy_from_slice(&destination.0);
+    frame[6..12].copy_from_slice(&device.mac_address());
+    frame[12..14].copy_from_slice(&ethertype.to_be_bytes());
+    let mut offset = HEADER_SIZE;
+    for part in parts {
+        frame[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+    let frame = &frame[..core::cmp::max(len, MIN_FRAME_SIZE)];
+
+    let end = time::now() + SEND_TIMEOUT;
+    loop {
+        match device.send(frame) {
+            Ok(()) => return Ok(()),
+            Err(NetError::TooLarge) => return Err(SocketError::TooLarge),
+            Err(NetError::Busy) if time::now() < end => {
+                core::hint::spin_loop()
+            }
+            Err(NetError::Busy) => return Err(SocketError::TimedOut),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// ICMP, which carries errors and diagnostics for IPv4. We only handle echo
+// requests (type 8), which we answer with an echo reply (type 0) carrying the
+// same identifier, sequence number and data, and the replies to our own
+// requests, which ping waits for. Every message starts with an 8 byte header:
+//   0: Type and code.
+//   2: Checksum of the whole message.
+//   4: Identifier, then sequence number, for echo requests and replies.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::time;
+use super::ipv4::{self, PROTOCOL_ICMP, RESOLVE_TIMEOUT};
+use super::{Ipv4Addr, SocketError};
+
+const HEADER_SIZE: usize = 8;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+
+// The identifier of every echo request we send ('RU').
+const IDENTIFIER: u16 = 0x5255;
+
+const PING_DATA: &[u8] = b"rustos ping rustos ping rustos!!";
+
+// The replies which have arrived, and haven't been collected by ping yet.
+const MAX_REPLIES: usize = 4;
+
+static SEQUENCE: AtomicU16 = AtomicU16::new(1);
+static REPLIES: Mutex<[Option<(Ipv4Addr, u16)>; MAX_REPLIES]> =
+    Mutex::new([None; MAX_REPLIES]);
+
+pub fn receive(source: Ipv4Addr, _destination: Ipv4Addr, message: &[u8]) {
+    if message.len() < HEADER_SIZE || super::checksum(&[message]) != 0 {
+        return;
+    }
+
+    let identifier = u16::from_be_bytes([message[4], message[5]]);
+    let sequence = u16::from_be_bytes([message[6], message[7]]);
+    match message[0] {
+        TYPE_ECHO_REQUEST => {
+            let _ = send(source, TYPE_ECHO_REPLY, identifier, sequence,
+                         &message[HEADER_SIZE..], Duration::from_secs(0));
+        }
+        TYPE_ECHO_REPLY if identifier == IDENTIFIER => {
+            without_interrupts(|| {
+                let mut replies = REPLIES.lock();
+                let slot = sequence as usize % MAX_REPLIES;
+                replies[slot] = Some((source, sequence));
+            });
+        }
+        _ => {}
+    }
+}
+
+fn send(destination: Ipv4Addr, kind: u8, identifier: u16, sequence: u16,
+        data: &[u8], timeout: Duration) -> Result<(), SocketError> {
+    let mut header = [0; HEADER_SIZE];
+    header[0] = kind;
+    header[4..6].copy_from_slice(&identifier.to_be_bytes());
+    header[6..8].copy_from_slice(&sequence.to_be_bytes());
+    let checksum = super::checksum(&[&header, data]);
+    header[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv4::send(destination, PROTOCOL_ICMP, &header, data, timeout)
+}
+
+// Send an echo request, and wait for the reply, returning how long it took to
+// arrive.
+pub fn ping(address: Ipv4Addr, timeout: Duration)
+        -> Result<Duration, SocketError> {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let start = time::now();
+    send(address, TYPE_ECHO_REQUEST, IDENTIFIER, sequence, PING_DATA,
+         core::cmp::min(timeout, RESOLVE_TIMEOUT))?;
+
+    let slot = sequence as usize % MAX_REPLIES;
+    super::wait_until(timeout, || {
+        without_interrupts(|| {
+            let mut replies = REPLIES.lock();
+            match replies[slot] {
+                Some(reply) if reply == (address, sequence) => {
+                    replies[slot] = None;
+                    Some(time::now() - start)
+                }
+                _ => None,
+            }
+        })
+    }).ok_or(SocketError::TimedOut)
+}
+
+
+// TESTING
+
+// Test that QEMU's gateway answers pings, and that we answer our own.
+#[test_case]
+fn test_ping() {
+    let gateway = Ipv4Addr::new(10, 0, 2, 2);
+    let ourselves = super::config().expect("eth0 has no address").address;
+
+    assert!(ping(gateway, Duration::from_secs(3)).is_ok());
+    assert!(ping(ourselves, Duration::from_secs(1)).is_ok());
+}
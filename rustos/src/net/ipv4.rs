@@ -0,0 +1,252 @@
+// IPv4 packets. Each starts with a header, which is 20 bytes unless it has
+// options, which we never send, and skip over when we receive them:
+//   0: Version (4) and header length in 32-bit words, then the type of
+//               service, which we leave as 0.
+//   2: Total length of the packet, including the header.
+//   4: Identification, flags and fragment offset, for reassembling packets
+//               which were split up on the way. We set Don't Fragment, and drop
+//               fragments, as we have nowhere to put them back together.
+//   8: Time to live, and the protocol of the payload (1 for ICMP, 6 for TCP
+//               and 17 for UDP).
+//  10: Checksum of the header.
+//  12: Source address, then destination address.
+// ---
+// Packets to our own address never reach the card, but are handed straight
+// back to receive.
+
+use core::fmt;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+use super::ethernet::{self, ETHERTYPE_IPV4, MTU};
+use super::{arp, icmp, tcp, udp, MacAddress, SocketError};
+
+pub const HEADER_SIZE: usize = 20;
+
+// The largest payload a packet can carry.
+pub const MAX_PAYLOAD_SIZE: usize = MTU - HEADER_SIZE;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const TIME_TO_LIVE: u8 = 64;
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+
+// How long sockets wait for ARP to find the next hop.
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Ipv4Addr([u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0; 4]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255; 4]);
+
+    pub const fn new(a: u8, b: u8, c: u8, d: u8) -> Ipv4Addr {
+        Ipv4Addr([a, b, c, d])
+    }
+
+    // Read an address from the first four bytes of a packet.
+    pub fn from_slice(bytes: &[u8]) -> Ipv4Addr {
+        Ipv4Addr([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub const fn from_u32(address: u32) -> Ipv4Addr {
+        Ipv4Addr(address.to_be_bytes())
+    }
+
+    pub const fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub const fn octets(self) -> [u8; 4] {
+        self.0
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrParseError;
+
+impl fmt::Display for AddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected an IPv4 address, such as 10.0.2.15")
+    }
+}
+
+impl FromStr for Ipv4Addr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Ipv4Addr, AddrParseError> {
+        let mut octets = [0; 4];
+        let mut parts = s.split('.');
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or(AddrParseError)?;
+            if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(AddrParseError);
+            }
+            *octet = part.parse().map_err(|_| AddrParseError)?;
+        }
+
+        match parts.next() {
+            Some(_) => Err(AddrParseError),
+            None => Ok(Ipv4Addr(octets)),
+        }
+    }
+}
+
+// The pseudo-header UDP and TCP include in their checksums, so that packets
+// delivered to the wrong address are caught.
+pub fn pseudo_header(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8,
+                     len: usize) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&source.0);
+    header[4..8].copy_from_slice(&destination.0);
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+    header
+}
+
+// The address packets are sent from: ours, or 0.0.0.0 if we don't have one
+// yet.
+pub fn source_address() -> Ipv4Addr {
+    super::config()
+        .map(|config| config.address)
+        .unwrap_or(Ipv4Addr::UNSPECIFIED)
+}
+
+// Find the MAC address to send a packet for the destination to. Packets to
+// anywhere other than a broadcast address need an address of our own.
+pub fn resolve(destination: Ipv4Addr, timeout: Duration)
+        -> Result<MacAddress, SocketError> {
+    if destination == Ipv4Addr::BROADCAST {
+        return Ok(MacAddress::BROADCAST);
+    }
+
+    let config = super::config().ok_or(SocketError::NotConfigured)?;
+    if destination == config.broadcast() {
+        return Ok(MacAddress::BROADCAST);
+    }
+    let next_hop = config.next_hop(destination)
+        .ok_or(SocketError::Unreachable)?;
+    arp::resolve(next_hop, timeout)
+}
+
+// Keep the MAC address packets for the destination are sent to in the ARP
+// cache, asking for it again, without waiting, before it drops out.
+pub fn refresh(destination: Ipv4Addr) -> Result<(), SocketError> {
+    let config = super::config().ok_or(SocketError::NotConfigured)?;
+    if destination == Ipv4Addr::BROADCAST || destination == config.broadcast() {
+        return Ok(());
+    }
+    let next_hop = config.next_hop(destination)
+        .ok_or(SocketError::Unreachable)?;
+    arp::refresh(next_hop)
+}
+
+// Check a received packet, and pass its payload up to its protocol. While we
+// don't have an address, everything is accepted, as DHCP replies may be sent
+// to the address being offered.
+pub fn receive(source_mac: MacAddress, packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    let total_len = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if header_len < HEADER_SIZE || total_len < header_len
+        || total_len > packet.len()
+        || super::checksum(&[&packet[..header_len]]) != 0 {
+        return;
+    }
+
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & FLAG_MORE_FRAGMENTS != 0 || fragment & 0x1fff != 0 {
+        return;
+    }
+
+    let source = Ipv4Addr::from_slice(&packet[12..16]);
+    let destination = Ipv4Addr::from_slice(&packet[16..20]);
+    if let Some(config) = super::config() {
+        if destination != config.address && destination != config.broadcast()
+            && destination != Ipv4Addr::BROADCAST {
+            return;
+        }
+
+        // Remember where packets from our subnet came from, so that replies
+        // can be sent without asking.
+        if config.is_local(source) && source != config.address
+            && source_mac != MacAddress::BROADCAST {
+            arp::learn(source, source_mac);
+        }
+    }
+
+    let payload = &packet[header_len..total_len];
+    match packet[9] {
+        PROTOCOL_ICMP => icmp::receive(source, destination, payload),
+        PROTOCOL_TCP => tcp::receive(source, destination, payload),
+        PROTOCOL_UDP => udp::receive(source, destination, payload),
+        _ => {}
+    }
+}
+
+// Send a packet, whose payload is the header and the data of the protocol
+// above. See arp::resolve for the timeout.
+pub fn send(destination: Ipv4Addr, protocol: u8, header: &[u8], data: &[u8],
+            timeout: Duration) -> Result<(), SocketError> {
+    let len = HEADER_SIZE + header.len() + data.len();
+    if len > MTU {
+        return Err(SocketError::TooLarge);
+    }
+    let source = source_address();
+
+    let mut packet = [0; HEADER_SIZE];
+    packet[0] = 0x45;
+    packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    let identification = IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+    packet[4..6].copy_from_slice(&identification.to_be_bytes());
+    packet[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+    packet[8] = TIME_TO_LIVE;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&source.0);
+    packet[16..20].copy_from_slice(&destination.0);
+    let checksum = super::checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    if destination == source && source != Ipv4Addr::UNSPECIFIED {
+        let mut looped = [0; MTU];
+        looped[..HEADER_SIZE].copy_from_slice(&packet);
+        looped[HEADER_SIZE..HEADER_SIZE + header.len()].copy_from_slice(header);
+        looped[HEADER_SIZE + header.len()..len].copy_from_slice(data);
+        receive(MacAddress::BROADCAST, &looped[..len]);
+        return Ok(());
+    }
+
+    let mac_address = resolve(destination, timeout)?;
+    ethernet::send(mac_address, ETHERTYPE_IPV4, &[&packet, header, data])
+}
+
+
+// TESTING
+
+// Test that addresses are parsed, and that malformed ones are rejected.
+#[test_case]
+fn test_parse_address() {
+    let address: Ipv4Addr = "10.0.2.15".parse().unwrap();
+
+    assert_eq!(address, Ipv4Addr::new(10, 0, 2, 15));
+    assert_eq!(address.to_u32(), 0x0a00020f);
+    for invalid in &["10.0.2", "10.0.2.15.1", "10.0.2.256", "10..2.15",
+                     "10.0.2.+1", ""] {
+        assert_eq!(invalid.parse::<Ipv4Addr>(), Err(AddrParseError));
+    }
+}
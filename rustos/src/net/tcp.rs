@@ -0,0 +1,954 @@
+// TCP streams. Only outgoing connections are supported: TcpStream::connect
+// opens one, and dropping the stream closes it. Each segment starts with a
+// header, which is 20 bytes unless it has options:
+//   0: Source port, then destination port.
+//   4: Sequence number of the first byte of data.
+//   8: Acknowledgement number, the next byte the sender expects to receive.
+//  12: Header length in 32-bit words, and the flags (FIN, SYN, RST, PSH, ACK).
+//  14: Window, the number of bytes the sender has room to receive.
+//  16: Checksum, as for UDP, then the urgent pointer, which we ignore.
+// The only option we use is the maximum segment size (2), which we send in
+// our SYN, and read from the other end's.
+// ---
+// A connection goes through these states:
+// - SynSent: We have sent a SYN, and are waiting for the SYN and ACK back.
+// - Established: Data can be sent both ways.
+// - FinWait1: We have finished sending, and sent a FIN, which hasn't been
+//               acknowledged yet. FinWait2 once it has.
+// - CloseWait: The other end has finished sending, and we haven't yet.
+//               LastAck once we have sent our FIN too.
+// - Closing: Both ends sent a FIN at the same time, and ours hasn't been
+//               acknowledged yet.
+// - Closed: Both FINs have been acknowledged, or the connection was reset or
+//               timed out. We skip TIME_WAIT, as the port we used won't be
+//               given out again for a long time.
+// ---
+// Data waiting to be sent, and data received but not read, are each kept in
+// a ring of BUFFER_SIZE bytes. Segments which arrive out of order are dropped,
+// and anything not acknowledged within the retransmission timeout is sent
+// again, from the first unacknowledged byte (go-back-N). The timeout doubles
+// every time, and the connection is given up on after MAX_RETRIES.
+// ---
+// If the other end's window is shut while we have data waiting, the same
+// timer is kept running as a persist timer, and each time it fires one byte
+// is sent as a probe, so a lost window update can't leave the data stuck.
+// Answers to the probes keep the connection from being given up on.
+// ---
+// Segments are built with the connections locked, but sent after unlocking
+// them, as they are sent in the middle of polling. Only one caller sends a
+// connection's segments at a time, as the timer interrupt could otherwise
+// send later ones while a segment already built is waiting to go. Anything
+// else which wants to send then leaves it to that caller, which keeps going
+// until there is nothing left.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::time;
+use super::ipv4::{self, PROTOCOL_TCP};
+use super::{Ipv4Addr, SocketError};
+
+const HEADER_SIZE: usize = 20;
+const MSS_OPTION_SIZE: usize = 4;
+
+const FLAG_FIN: u8 = 1 << 0;
+const FLAG_SYN: u8 = 1 << 1;
+const FLAG_RST: u8 = 1 << 2;
+const FLAG_PSH: u8 = 1 << 3;
+const FLAG_ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+// The most data we put in a segment, and the most we assume the other end
+// can take if it doesn't say.
+const MAX_SEGMENT_SIZE: usize = ipv4::MAX_PAYLOAD_SIZE - HEADER_SIZE;
+const DEFAULT_SEGMENT_SIZE: usize = 536;
+
+const BUFFER_SIZE: usize = 4096;
+const MAX_CONNECTIONS: usize = 4;
+
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(8);
+const MAX_RETRIES: u32 = 6;
+
+// How long writes through fmt::Write wait for room in the send buffer.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const EPHEMERAL_PORTS: u16 = 49152;
+
+// Sequence numbers wrap around, so are compared by their difference.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    LastAck,
+    Closing,
+    Closed,
+}
+
+struct Ring {
+    data: [u8; BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const EMPTY: Ring = Ring { data: [0; BUFFER_SIZE], start: 0, len: 0 };
+
+    fn space(&self) -> usize {
+        BUFFER_SIZE - self.len
+    }
+
+    // Add as much of the data as there is room for, returning how much.
+    fn push(&mut self, data: &[u8]) -> usize {
+        let count = core::cmp::min(data.len(), self.space());
+        for (index, byte) in data[..count].iter().enumerate() {
+            self.data[(self.start + self.len + index) % BUFFER_SIZE] = *byte;
+        }
+        self.len += count;
+        count
+    }
+
+    // Copy out bytes from the offset onwards, without removing them.
+    fn peek(&self, offset: usize, out: &mut [u8]) -> usize {
+        let count = core::cmp::min(out.len(), self.len.saturating_sub(offset));
+        for (index, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.data[(self.start + offset + index) % BUFFER_SIZE];
+        }
+        count
+    }
+
+    fn consume(&mut self, count: usize) {
+        let count = core::cmp::min(count, self.len);
+        self.start = (self.start + count) % BUFFER_SIZE;
+        self.len -= count;
+    }
+}
+
+struct Connection {
+    state: State,
+    // Why the connection closed, if it didn't close normally.
+    error: Option<SocketError>,
+    // The stream has been dropped, so the connection can be freed once it
+    // has closed.
+    orphaned: bool,
+
+    local_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+
+    // Our initial sequence number, the first byte which hasn't been
+    // acknowledged, the next byte to send, the byte after the last one sent
+    // before any retransmission, and the sequence number of our FIN once it
+    // has been sent.
+    initial_sequence: u32,
+    send_unacknowledged: u32,
+    send_next: u32,
+    send_max: u32,
+    fin_sequence: Option<u32>,
+    // The most the other end will take, in total, and in one segment.
+    send_window: usize,
+    segment_size: usize,
+    // Data from send_unacknowledged onwards.
+    send_buffer: Ring,
+    // The stream wants to close once everything has been sent.
+    closing: bool,
+
+    receive_next: u32,
+    receive_buffer: Ring,
+    fin_received: bool,
+    ack_pending: bool,
+    // Someone is in transmit, sending the connection's segments.
+    transmitting: bool,
+
+    timeout: Duration,
+    retransmit_at: Option<Duration>,
+    retries: u32,
+    // The persist timer fired, so a byte is sent into the shut window.
+    probe_due: bool,
+}
+
+// A segment to send, built by Connection::output.
+struct Segment {
+    local_port: u16,
+    remote: Ipv4Addr,
+    remote_port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: u16,
+    len: usize,
+    data: [u8; MAX_SEGMENT_SIZE],
+}
+
+impl Connection {
+    fn new(local_port: u16, remote: Ipv4Addr, remote_port: u16,
+           initial_sequence: u32) -> Connection {
+        Connection {
+            state: State::SynSent,
+            error: None,
+            orphaned: false,
+            local_port,
+            remote,
+            remote_port,
+            initial_sequence,
+            send_unacknowledged: initial_sequence,
+            send_next: initial_sequence,
+            send_max: initial_sequence,
+            fin_sequence: None,
+            send_window: DEFAULT_SEGMENT_SIZE,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            send_buffer: Ring::EMPTY,
+            closing: false,
+            receive_next: 0,
+            receive_buffer: Ring::EMPTY,
+            fin_received: false,
+            ack_pending: false,
+            transmitting: false,
+            timeout: INITIAL_TIMEOUT,
+            retransmit_at: None,
+            retries: 0,
+            probe_due: false,
+        }
+    }
+
+    // Close the connection, normally or with an error. Nothing more is sent
+    // after an error, but a normal close still acknowledges the last FIN.
+    fn close(&mut self, error: Option<SocketError>) {
+        self.state = State::Closed;
+        self.retransmit_at = None;
+        if error.is_some() {
+            self.error = self.error.or(error);
+            self.ack_pending = false;
+        }
+    }
+
+    fn window(&self) -> u16 {
+        self.receive_buffer.space() as u16
+    }
+
+    fn fin_sent(&self) -> bool {
+        match self.fin_sequence {
+            Some(fin) => seq_lt(fin, self.send_next),
+            None => false,
+        }
+    }
+
+    fn fin_acknowledged(&self) -> bool {
+        match self.fin_sequence {
+            Some(fin) => seq_lt(fin, self.send_unacknowledged),
+            None => false,
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.send_next.wrapping_sub(self.send_unacknowledged) as usize
+    }
+
+    fn unsent(&self) -> usize {
+        self.send_buffer.len.saturating_sub(self.in_flight())
+    }
+
+    // The other end has no room for the data we have waiting to send.
+    fn window_shut(&self) -> bool {
+        self.send_window == 0 && self.unsent() > 0
+            && self.state != State::Closed
+    }
+
+    fn segment(&self, sequence: u32, flags: u8) -> Segment {
+        Segment {
+            local_port: self.local_port,
+            remote: self.remote,
+            remote_port: self.remote_port,
+            sequence,
+            acknowledgement: self.receive_next,
+            flags,
+            window: self.window(),
+            len: 0,
+            data: [0; MAX_SEGMENT_SIZE],
+        }
+    }
+
+    // Work out the next segment to send, if there is one: a SYN, then data,
+    // then a FIN once the stream is closing, or just an ACK if there is
+    // nothing else to carry it.
+    fn output(&mut self, now: Duration) -> Option<Segment> {
+        let segment = match self.state {
+            State::Closed => None,
+            State::SynSent if self.send_next == self.initial_sequence => {
+                self.send_next = self.send_next.wrapping_add(1);
+                Some(self.segment(self.initial_sequence, FLAG_SYN))
+            }
+            State::SynSent => None,
+            _ => self.output_data(),
+        };
+
+        if seq_lt(self.send_max, self.send_next) {
+            self.send_max = self.send_next;
+        }
+
+        // The timer is also kept running while the window is shut, for the
+        // next probe.
+        if (segment.is_some() || self.window_shut())
+            && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.timeout);
+        }
+
+        let segment = match segment {
+            None if self.ack_pending && self.state != State::SynSent => {
+                Some(self.segment(self.send_next, FLAG_ACK))
+            }
+            segment => segment,
+        };
+        if segment.is_some() {
+            self.ack_pending = false;
+        }
+        segment
+    }
+
+    fn output_data(&mut self) -> Option<Segment> {
+        if self.fin_sent() {
+            return None;
+        }
+
+        let in_flight = self.in_flight();
+        let unsent = self.unsent();
+        let usable = self.send_window.saturating_sub(in_flight);
+        let len = if self.window_shut() && self.probe_due {
+            1
+        } else {
+            *[unsent, usable, self.segment_size].iter().min().unwrap()
+        };
+        self.probe_due = false;
+
+        if len > 0 {
+            let mut segment = self.segment(self.send_next, FLAG_ACK | FLAG_PSH);
+            segment.len = self.send_buffer.peek(in_flight,
+                                                &mut segment.data[..len]);
+            self.send_next = self.send_next.wrapping_add(len as u32);
+            return Some(segment);
+        }
+
+        // The FIN is sent again, after the data before it, if it timed out.
+        let can_close = self.fin_sequence.is_some()
+            || matches!(self.state, State::Established | State::CloseWait);
+        if self.closing && unsent == 0 && can_close {
+            self.fin_sequence = Some(self.send_next);
+            self.send_next = self.send_next.wrapping_add(1);
+            self.state = match self.state {
+                State::Established => State::FinWait1,
+                State::CloseWait => State::LastAck,
+                state => state,
+            };
+            return Some(self.segment(self.fin_sequence.unwrap(),
+                                     FLAG_FIN | FLAG_ACK));
+        }
+
+        None
+    }
+
+    // Send everything unacknowledged again, if it has been waiting too long.
+    fn check_timeout(&mut self, now: Duration) {
+        match self.retransmit_at {
+            Some(at) if at <= now => {}
+            _ => return,
+        }
+
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.close(Some(SocketError::TimedOut));
+            return;
+        }
+        self.timeout = core::cmp::min(self.timeout * 2, MAX_TIMEOUT);
+        self.retransmit_at = None;
+        self.send_next = self.send_unacknowledged;
+        self.probe_due = self.send_window == 0;
+    }
+
+    // Handle a segment for this connection.
+    fn input(&mut self, now: Duration, header: &Header, data: &[u8]) {
+        if self.state == State::SynSent {
+            return self.input_syn_sent(header);
+        }
+
+        if header.flags & FLAG_RST != 0 {
+            if header.sequence == self.receive_next {
+                self.close(Some(SocketError::Reset));
+            }
+            return;
+        }
+        if header.flags & FLAG_SYN != 0 || header.flags & FLAG_ACK == 0 {
+            self.ack_pending = true;
+            return;
+        }
+
+        self.input_ack(now, header);
+        if self.state == State::Closed {
+            return;
+        }
+
+        let receiving = matches!(self.state, State::Established
+                                 | State::FinWait1 | State::FinWait2);
+        if !data.is_empty() && receiving {
+            if header.sequence == self.receive_next {
+                let count = self.receive_buffer.push(data);
+                self.receive_next =
+                    self.receive_next.wrapping_add(count as u32);
+            }
+            self.ack_pending = true;
+        }
+
+        let end = header.sequence.wrapping_add(data.len() as u32);
+        if header.flags & FLAG_FIN != 0 && end == self.receive_next
+            && !self.fin_received {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_pending = true;
+            match self.state {
+                State::Established => self.state = State::CloseWait,
+                State::FinWait1 => self.state = State::Closing,
+                State::FinWait2 => self.close(None),
+                _ => {}
+            }
+        }
+    }
+
+    fn input_syn_sent(&mut self, header: &Header) {
+        let expected = self.initial_sequence.wrapping_add(1);
+        let acceptable = header.flags & FLAG_ACK != 0
+            && header.acknowledgement == expected;
+        if !acceptable {
+            return;
+        }
+
+        if header.flags & FLAG_RST != 0 {
+            self.close(Some(SocketError::Refused));
+        } else if header.flags & FLAG_SYN != 0 {
+            self.state = State::Established;
+            self.send_unacknowledged = expected;
+            self.send_window = usize::from(header.window);
+            self.segment_size = header.segment_size
+                .filter(|size| *size > 0)
+                .map(|size| core::cmp::min(size, MAX_SEGMENT_SIZE))
+                .unwrap_or(DEFAULT_SEGMENT_SIZE);
+            self.receive_next = header.sequence.wrapping_add(1);
+            self.ack_pending = true;
+            self.timeout = INITIAL_TIMEOUT;
+            self.retransmit_at = None;
+            self.retries = 0;
+        }
+    }
+
+    fn input_ack(&mut self, now: Duration, header: &Header) {
+        let ack = header.acknowledgement;
+        if seq_lt(self.send_unacknowledged, ack)
+            && seq_le(ack, self.send_max) {
+            let acknowledged = ack.wrapping_sub(self.send_unacknowledged);
+            self.send_buffer.consume(acknowledged as usize);
+            self.send_unacknowledged = ack;
+            // Segments sent before a retransmission may still arrive.
+            if seq_lt(self.send_next, ack) {
+                self.send_next = ack;
+            }
+            self.timeout = INITIAL_TIMEOUT;
+            self.retries = 0;
+            self.retransmit_at = if ack == self.send_next {
+                None
+            } else {
+                Some(now + self.timeout)
+            };
+        }
+        if seq_le(ack, self.send_max) {
+            self.send_window = usize::from(header.window);
+
+            if self.send_window == 0 {
+                // The other end is still answering our probes.
+                self.retries = 0;
+            } else if self.in_flight() == 0 {
+                // The window opened, so the persist timer isn't needed.
+                self.retransmit_at = None;
+            }
+        }
+
+        if self.fin_acknowledged() {
+            match self.state {
+                State::FinWait1 => self.state = State::FinWait2,
+                State::Closing | State::LastAck => self.close(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+// The fields of a received segment's header.
+struct Header {
+    source_port: u16,
+    port: u16,
+    sequence: u32,
+    acknowledgement: u32,
+    flags: u8,
+    window: u16,
+    segment_size: Option<usize>,
+}
+
+impl Header {
+    fn parse(segment: &[u8]) -> Option<(Header, usize)> {
+        if segment.len() < HEADER_SIZE {
+            return None;
+        }
+        let header_len = usize::from(segment[12] >> 4) * 4;
+        if header_len < HEADER_SIZE || header_len > segment.len() {
+            return None;
+        }
+
+        let word = |offset: usize| {
+            u32::from_be_bytes([segment[offset], segment[offset + 1],
+                                segment[offset + 2], segment[offset + 3]])
+        };
+        let half = |offset: usize| {
+            u16::from_be_bytes([segment[offset], segment[offset + 1]])
+        };
+
+        let mut segment_size = None;
+        let mut options = &segment[HEADER_SIZE..header_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = usize::from(*options.get(1)?);
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        segment_size = Some(usize::from(
+                            u16::from_be_bytes([options[2], options[3]])));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some((Header {
+            source_port: half(0),
+            port: half(2),
+            sequence: word(4),
+            acknowledgement: word(8),
+            flags: segment[13],
+            window: half(14),
+            segment_size,
+        }, header_len))
+    }
+}
+
+const NO_CONNECTION: Option<Connection> = None;
+
+static CONNECTIONS: Mutex<[Option<Connection>; MAX_CONNECTIONS]> =
+    Mutex::new([NO_CONNECTION; MAX_CONNECTIONS]);
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS);
+
+fn with_connection<T>(index: usize, f: impl FnOnce(&mut Connection) -> T)
+        -> Option<T> {
+    without_interrupts(|| CONNECTIONS.lock()[index].as_mut().map(f))
+}
+
+fn send_segment(segment: &Segment, with_mss: bool) {
+    let mut header = [0; HEADER_SIZE + MSS_OPTION_SIZE];
+    let header_len = if with_mss {
+        HEADER_SIZE + MSS_OPTION_SIZE
+    } else {
+        HEADER_SIZE
+    };
+    header[0..2].copy_from_slice(&segment.local_port.to_be_bytes());
+    header[2..4].copy_from_slice(&segment.remote_port.to_be_bytes());
+    header[4..8].copy_from_slice(&segment.sequence.to_be_bytes());
+    if segment.flags & FLAG_ACK != 0 {
+        header[8..12].copy_from_slice(&segment.acknowledgement.to_be_bytes());
+    }
+    header[12] = ((header_len / 4) as u8) << 4;
+    header[13] = segment.flags;
+    header[14..16].copy_from_slice(&segment.window.to_be_bytes());
+    if with_mss {
+        header[20] = OPTION_MSS;
+        header[21] = MSS_OPTION_SIZE as u8;
+        let segment_size = MAX_SEGMENT_SIZE as u16;
+        header[22..24].copy_from_slice(&segment_size.to_be_bytes());
+    }
+
+    let header = &mut header[..header_len];
+    let data = &segment.data[..segment.len];
+    let pseudo_header = ipv4::pseudo_header(ipv4::source_address(),
+                                            segment.remote, PROTOCOL_TCP,
+                                            header_len + data.len());
+    let checksum = super::checksum(&[&pseudo_header, &*header, data]);
+    header[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    // The next hop was found when the connection was opened, and a segment
+    // which can't be sent straight away will be retransmitted.
+    let _ = ipv4::send(segment.remote, PROTOCOL_TCP, header, data,
+                       Duration::from_secs(0));
+}
+
+// Send everything the connection has ready, unless it is already being sent.
+fn transmit(index: usize) {
+    let started = with_connection(index, |connection| {
+        !core::mem::replace(&mut connection.transmitting, true)
+    });
+    if started != Some(true) {
+        return;
+    }
+
+    // The flag is cleared along with finding there is nothing left, so
+    // nothing added after then is missed.
+    while let Some(Some(segment)) = with_connection(index, |connection| {
+        let segment = connection.output(time::now());
+        connection.transmitting = segment.is_some();
+        segment
+    }) {
+        send_segment(&segment, segment.flags & FLAG_SYN != 0);
+    }
+}
+
+// Check every connection for segments to retransmit, and free the ones whose
+// streams have been dropped, once they have closed, or the other end has
+// acknowledged our FIN.
+pub fn poll() {
+    let now = time::now();
+    for index in 0..MAX_CONNECTIONS {
+        let active = without_interrupts(|| {
+            let mut connections = CONNECTIONS.lock();
+            let connection = match &mut connections[index] {
+                Some(connection) => connection,
+                None => return false,
+            };
+            connection.check_timeout(now);
+            let finished = matches!(connection.state, State::Closed
+                                    | State::FinWait2);
+            if connection.orphaned && finished && !connection.transmitting {
+                connections[index] = None;
+                return false;
+            }
+            true
+        });
+        if active {
+            transmit(index);
+        }
+    }
+}
+
+pub fn receive(source: Ipv4Addr, destination: Ipv4Addr, segment: &[u8]) {
+    let pseudo_header = ipv4::pseudo_header(source, destination, PROTOCOL_TCP,
+                                            segment.len());
+    if super::checksum(&[&pseudo_header, segment]) != 0 {
+        return;
+    }
+    let (header, header_len) = match Header::parse(segment) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let data = &segment[header_len..];
+
+    let now = time::now();
+    let index = without_interrupts(|| {
+        let mut connections = CONNECTIONS.lock();
+        let index = connections.iter().position(|connection| {
+            matches!(connection, Some(connection)
+                     if connection.local_port == header.port
+                     && connection.remote == source
+                     && connection.remote_port == header.source_port)
+        })?;
+        if let Some(connection) = &mut connections[index] {
+            connection.input(now, &header, data);
+        }
+        Some(index)
+    });
+
+    match index {
+        Some(index) => transmit(index),
+        None => reset(source, &header, data.len()),
+    }
+}
+
+// Answer a segment for a connection we don't have with a RST, unless it is a
+// RST itself.
+fn reset(source: Ipv4Addr, header: &Header, len: usize) {
+    if header.flags & FLAG_RST != 0 {
+        return;
+    }
+
+    let mut segment = Segment {
+        local_port: header.port,
+        remote: source,
+        remote_port: header.source_port,
+        sequence: 0,
+        acknowledgement: 0,
+        flags: FLAG_RST,
+        window: 0,
+        len: 0,
+        data: [0; MAX_SEGMENT_SIZE],
+    };
+    if header.flags & FLAG_ACK != 0 {
+        segment.sequence = header.acknowledgement;
+    } else {
+        let mut len = len as u32;
+        if header.flags & FLAG_SYN != 0 {
+            len += 1;
+        }
+        if header.flags & FLAG_FIN != 0 {
+            len += 1;
+        }
+        segment.acknowledgement = header.sequence.wrapping_add(len);
+        segment.flags |= FLAG_ACK;
+    }
+    send_segment(&segment, false);
+}
+
+// An open connection. Dropping it closes the connection, sending anything
+// which hasn't been sent yet first.
+pub struct TcpStream {
+    index: usize,
+}
+
+impl TcpStream {
+    // Open a connection, waiting until the other end accepts it, refuses it,
+    // or the timeout passes.
+    pub fn connect(address: Ipv4Addr, port: u16, timeout: Duration)
+            -> Result<TcpStream, SocketError> {
+        // Find the next hop first, so segments can be sent without waiting.
+        // The timeout covers that too.
+        let end = time::now() + timeout;
+        ipv4::resolve(address, timeout)?;
+
+        let now = time::now();
+        let initial_sequence = (now.as_nanos() as u32).wrapping_mul(2654435761);
+        let index = without_interrupts(|| {
+            let mut connections = CONNECTIONS.lock();
+            let index = connections.iter()
+                .position(|connection| connection.is_none())
+                .ok_or(SocketError::Full)?;
+            let local_port = loop {
+                let next = &NEXT_EPHEMERAL_PORT;
+                let candidate = next.fetch_add(1, Ordering::Relaxed);
+                if candidate < EPHEMERAL_PORTS {
+                    next.store(EPHEMERAL_PORTS, Ordering::Relaxed);
+                } else if !connections.iter().flatten()
+                    .any(|connection| connection.local_port == candidate) {
+                    break candidate;
+                }
+            };
+            connections[index] = Some(Connection::new(local_port, address,
+                                                      port, initial_sequence));
+            Ok(index)
+        })?;
+
+        transmit(index);
+        let remaining = end.checked_sub(time::now()).unwrap_or_default();
+        let result = super::wait_until(remaining, || {
+            with_connection(index, |connection| match connection.state {
+                State::SynSent => None,
+                State::Closed => Some(Err(connection.error
+                                          .unwrap_or(SocketError::Closed))),
+                _ => Some(Ok(())),
+            }).flatten()
+        }).unwrap_or(Err(SocketError::TimedOut));
+
+        match result {
+            Ok(()) => Ok(TcpStream { index }),
+            Err(error) => {
+                without_interrupts(|| CONNECTIONS.lock()[index] = None);
+                Err(error)
+            }
+        }
+    }
+
+    pub fn peer(&self) -> (Ipv4Addr, u16) {
+        with_connection(self.index, |connection| {
+            (connection.remote, connection.remote_port)
+        }).unwrap()
+    }
+
+    // Queue as much of the data as there is room for, returning how much, and
+    // start sending it.
+    pub fn write(&self, data: &[u8]) -> Result<usize, SocketError> {
+        let written = with_connection(self.index, |connection| {
+            match connection.state {
+                State::Established | State::CloseWait => {
+                    Ok(connection.send_buffer.push(data))
+                }
+                _ => Err(connection.error.unwrap_or(SocketError::Closed)),
+            }
+        }).unwrap()?;
+
+        transmit(self.index);
+        Ok(written)
+    }
+
+    // Queue all of the data, waiting for room as it is acknowledged.
+    pub fn write_all(&self, mut data: &[u8], timeout: Duration)
+            -> Result<(), SocketError> {
+        let end = time::now() + timeout;
+        while !data.is_empty() {
+            let written = self.write(data)?;
+            data = &data[written..];
+            if written == 0 {
+                let now = time::now();
+                if now >= end {
+                    return Err(SocketError::TimedOut);
+                }
+                super::wait_until(core::cmp::min(end - now, super::POLL_PERIOD),
+                                  || None::<()>);
+            }
+        }
+        Ok(())
+    }
+
+    // Wait for everything written to be acknowledged.
+    pub fn flush(&self, timeout: Duration) -> Result<(), SocketError> {
+        super::wait_until(timeout, || {
+            with_connection(self.index, |connection| {
+                if connection.send_buffer.len == 0 {
+                    Some(Ok(()))
+                } else if connection.state == State::Closed {
+                    Some(Err(connection.error.unwrap_or(SocketError::Closed)))
+                } else {
+                    None
+                }
+            }).unwrap()
+        }).unwrap_or(Err(SocketError::TimedOut))
+    }
+
+    // Wait for data to arrive, and read as much as fits in the buffer. Returns
+    // 0 once the other end has closed the connection and everything it sent
+    // has been read.
+    pub fn read(&self, buffer: &mut [u8], timeout: Duration)
+            -> Result<usize, SocketError> {
+        let result = super::wait_until(timeout, || {
+            with_connection(self.index, |connection| {
+                let read = connection.receive_buffer.peek(0, buffer);
+                if read > 0 {
+                    // Tell the other end there is room again, if it may have
+                    // stopped sending for the lack of it.
+                    let was_full = usize::from(connection.window())
+                        < connection.segment_size;
+                    connection.receive_buffer.consume(read);
+                    connection.ack_pending |= was_full;
+                    Some(Ok(read))
+                } else if connection.fin_received {
+                    Some(Ok(0))
+                } else if connection.state == State::Closed {
+                    Some(Err(connection.error.unwrap_or(SocketError::Closed)))
+                } else {
+                    None
+                }
+            }).unwrap()
+        }).unwrap_or(Err(SocketError::TimedOut));
+
+        transmit(self.index);
+        result
+    }
+}
+
+impl fmt::Write for TcpStream {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_all(s.as_bytes(), WRITE_TIMEOUT).map_err(|_| fmt::Error)
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let free = with_connection(self.index, |connection| {
+            connection.orphaned = true;
+            connection.closing = true;
+            connection.state == State::Closed
+        }).unwrap();
+
+        if free {
+            without_interrupts(|| CONNECTIONS.lock()[self.index] = None);
+        } else {
+            transmit(self.index);
+        }
+    }
+}
+
+
+// TESTING
+
+// Test that sequence numbers are compared correctly across the wrap-around.
+#[test_case]
+fn test_sequence_comparisons() {
+    assert!(seq_lt(1, 2));
+    assert!(seq_lt(0xffff_fff0, 0x10));
+    assert!(!seq_lt(0x10, 0xffff_fff0));
+    assert!(seq_le(5, 5));
+    assert!(!seq_lt(5, 5));
+}
+
+// Test that data waiting on a shut window is probed a byte at a time, once
+// the persist timer fires.
+#[test_case]
+fn test_zero_window_probe() {
+    let mut connection = Connection::new(49152, Ipv4Addr::new(10, 0, 2, 100),
+                                         7, 1000);
+    connection.state = State::Established;
+    connection.send_unacknowledged = 1001;
+    connection.send_next = 1001;
+    connection.send_max = 1001;
+    connection.send_window = 0;
+    connection.send_buffer.push(b"hello");
+
+    let now = Duration::from_secs(1);
+    assert!(connection.output(now).is_none());
+    let at = connection.retransmit_at.expect("the persist timer isn't set");
+
+    connection.check_timeout(at);
+    let probe = connection.output(at).expect("no probe was sent");
+    assert_eq!((probe.sequence, probe.len), (1001, 1));
+    assert!(connection.output(at).is_none());
+    assert!(connection.retransmit_at.is_some());
+}
+
+// Test a connection to the echo service the tests are run with (see
+// Cargo.toml), which is sent more than fits in one segment or the buffers.
+#[test_case]
+fn test_echo() {
+    let echo = Ipv4Addr::new(10, 0, 2, 100);
+    let timeout = Duration::from_secs(5);
+    let stream = TcpStream::connect(echo, 7, timeout)
+        .expect("couldn't connect to the echo service");
+
+    // The nth byte sent is n modulo 251, so that it doesn't line up with the
+    // segments or the buffers.
+    let total = 3 * BUFFER_SIZE;
+    let mut chunk = [0; 1000];
+    let mut written = 0;
+    let mut read = 0;
+    while read < total {
+        let len = core::cmp::min(chunk.len(), total - written);
+        for (index, byte) in chunk[..len].iter_mut().enumerate() {
+            *byte = ((written + index) % 251) as u8;
+        }
+        written += stream.write(&chunk[..len]).unwrap();
+
+        let count = stream.read(&mut chunk, timeout).unwrap();
+        assert_ne!(count, 0, "the echo service closed the connection");
+        for (index, byte) in chunk[..count].iter().enumerate() {
+            assert_eq!(usize::from(*byte), (read + index) % 251);
+        }
+        read += count;
+    }
+}
@@ -0,0 +1,246 @@
+// UDP datagrams, sent and received through UdpSockets. Each datagram starts
+// with an 8 byte header:
+//   0: Source port, then destination port.
+//   4: Length of the datagram, including the header.
+//   6: Checksum of the pseudo-header (see ipv4::pseudo_header), the header and
+//               the data, or 0 if it wasn't worked out. A checksum which
+//               comes to 0 is sent as 0xffff instead.
+// ---
+// Each socket keeps up to QUEUE_SIZE datagrams which have arrived for its
+// port, until they are read. Any more, and any for ports which no socket is
+// bound to, are dropped.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use super::ipv4::{self, PROTOCOL_UDP, RESOLVE_TIMEOUT};
+use super::{Ipv4Addr, SocketError};
+
+const HEADER_SIZE: usize = 8;
+
+// The most data a datagram can carry, without being fragmented.
+pub const MAX_DATAGRAM_SIZE: usize = ipv4::MAX_PAYLOAD_SIZE - HEADER_SIZE;
+
+const MAX_SOCKETS: usize = 8;
+const QUEUE_SIZE: usize = 4;
+
+// Ports given to sockets bound to port 0 are picked from here upwards.
+const EPHEMERAL_PORTS: u16 = 49152;
+
+struct Datagram {
+    source: Ipv4Addr,
+    port: u16,
+    len: usize,
+    data: [u8; MAX_DATAGRAM_SIZE],
+}
+
+struct Socket {
+    // The port the socket is bound to, or 0 if it is free.
+    port: u16,
+    queue: [Datagram; QUEUE_SIZE],
+    // The oldest datagram in the queue, and how many there are.
+    head: usize,
+    len: usize,
+}
+
+const NO_DATAGRAM: Datagram = Datagram {
+    source: Ipv4Addr::UNSPECIFIED,
+    port: 0,
+    len: 0,
+    data: [0; MAX_DATAGRAM_SIZE],
+};
+
+const NO_SOCKET: Socket = Socket {
+    port: 0,
+    queue: [NO_DATAGRAM; QUEUE_SIZE],
+    head: 0,
+    len: 0,
+};
+
+static SOCKETS: Mutex<[Socket; MAX_SOCKETS]> =
+    Mutex::new([NO_SOCKET; MAX_SOCKETS]);
+static NEXT_EPHEMERAL_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS);
+
+// A socket, bound to a port until it is dropped.
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    // Bind a socket to a port, or to a free port from the ephemeral range if
+    // the port is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, SocketError> {
+        without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let is_bound = |sockets: &[Socket], port| {
+                sockets.iter().any(|socket| socket.port == port)
+            };
+
+            let port = if port != 0 {
+                if is_bound(&sockets[..], port) {
+                    return Err(SocketError::InUse);
+                }
+                port
+            } else {
+                loop {
+                    let next = &NEXT_EPHEMERAL_PORT;
+                    let port = next.fetch_add(1, Ordering::Relaxed);
+                    if port < EPHEMERAL_PORTS {
+                        next.store(EPHEMERAL_PORTS, Ordering::Relaxed);
+                    } else if !is_bound(&sockets[..], port) {
+                        break port;
+                    }
+                }
+            };
+
+            let socket = sockets.iter_mut()
+                .find(|socket| socket.port == 0)
+                .ok_or(SocketError::Full)?;
+            socket.port = port;
+            socket.head = 0;
+            socket.len = 0;
+            Ok(UdpSocket { port })
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, data: &[u8], address: Ipv4Addr, port: u16)
+            -> Result<(), SocketError> {
+        send(self.port, address, port, data, RESOLVE_TIMEOUT)
+    }
+
+    // Take the oldest datagram from the queue, if there is one, returning its
+    // length and who sent it. Anything which doesn't fit in the buffer is
+    // dropped.
+    pub fn recv_from(&self, buffer: &mut [u8])
+            -> Option<(usize, Ipv4Addr, u16)> {
+        without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            let socket = sockets.iter_mut()
+                .find(|socket| socket.port == self.port)?;
+            if socket.len == 0 {
+                return None;
+            }
+
+            let datagram = &socket.queue[socket.head];
+            let len = core::cmp::min(datagram.len, buffer.len());
+            buffer[..len].copy_from_slice(&datagram.data[..len]);
+            let received = (len, datagram.source, datagram.port);
+
+            socket.head = (socket.head + 1) % QUEUE_SIZE;
+            socket.len -= 1;
+            Some(received)
+        })
+    }
+
+    // Wait for a datagram to arrive, polling the network.
+    pub fn recv_from_timeout(&self, buffer: &mut [u8], timeout: Duration)
+            -> Result<(usize, Ipv4Addr, u16), SocketError> {
+        super::wait_until(timeout, || self.recv_from(buffer))
+            .ok_or(SocketError::TimedOut)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        without_interrupts(|| {
+            let mut sockets = SOCKETS.lock();
+            if let Some(socket) = sockets.iter_mut()
+                .find(|socket| socket.port == self.port) {
+                socket.port = 0;
+            }
+        });
+    }
+}
+
+// Send a datagram from a port, whether or not a socket is bound to it. See
+// arp::resolve for the timeout.
+pub fn send(source_port: u16, destination: Ipv4Addr, port: u16, data: &[u8],
+            timeout: Duration) -> Result<(), SocketError> {
+    if data.len() > MAX_DATAGRAM_SIZE {
+        return Err(SocketError::TooLarge);
+    }
+    let len = HEADER_SIZE + data.len();
+
+    let mut header = [0; HEADER_SIZE];
+    header[0..2].copy_from_slice(&source_port.to_be_bytes());
+    header[2..4].copy_from_slice(&port.to_be_bytes());
+    header[4..6].copy_from_slice(&(len as u16).to_be_bytes());
+    let pseudo_header = ipv4::pseudo_header(ipv4::source_address(),
+                                            destination, PROTOCOL_UDP, len);
+    let checksum = match super::checksum(&[&pseudo_header, &header, data]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    header[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv4::send(destination, PROTOCOL_UDP, &header, data, timeout)
+}
+
+pub fn receive(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = usize::from(u16::from_be_bytes([datagram[4], datagram[5]]));
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if datagram[6..8] != [0, 0] {
+        let pseudo_header = ipv4::pseudo_header(source, destination,
+                                                PROTOCOL_UDP, len);
+        if super::checksum(&[&pseudo_header, datagram]) != 0 {
+            return;
+        }
+    }
+
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    let data = &datagram[HEADER_SIZE..];
+    without_interrupts(|| {
+        let mut sockets = SOCKETS.lock();
+        let socket = match sockets.iter_mut()
+            .find(|socket| socket.port == port) {
+            Some(socket) if socket.len < QUEUE_SIZE => socket,
+            _ => return,
+        };
+
+        let tail = (socket.head + socket.len) % QUEUE_SIZE;
+        let slot = &mut socket.queue[tail];
+        slot.source = source;
+        slot.port = source_port;
+        slot.len = data.len();
+        slot.data[..data.len()].copy_from_slice(data);
+        socket.len += 1;
+    });
+}
+
+
+// TESTING
+
+// Test that datagrams sent to our own address arrive, in order, and that a
+// port can't be bound twice.
+#[test_case]
+fn test_loopback() {
+    let ourselves = super::config().expect("eth0 has no address").address;
+    let receiver = UdpSocket::bind(0).unwrap();
+    let sender = UdpSocket::bind(0).unwrap();
+
+    assert_eq!(UdpSocket::bind(receiver.port()).err(),
+               Some(SocketError::InUse));
+
+    sender.send_to(b"first", ourselves, receiver.port()).unwrap();
+    sender.send_to(b"second", ourselves, receiver.port()).unwrap();
+
+    let mut buffer = [0; 16];
+    let (len, source, port) = receiver.recv_from(&mut buffer).unwrap();
+    assert_eq!((&buffer[..len], source, port),
+               (&b"first"[..], ourselves, sender.port()));
+    let (len, _, _) = receiver.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..len], b"second");
+    assert!(receiver.recv_from(&mut buffer).is_none());
+}
@@ -0,0 +1,546 @@
+// Peripheral Component Interconnect (PCI), the bus almost every device in a PC
+// hangs off, including the ones QEMU emulates. Devices are found by probing,
+// rather than being at fixed ports like the serial ports: every bus has room
+// for 32 devices, each with up to 8 functions, and each function which is
+// there has a configuration space describing it (see the config module). The
+// first 64 bytes of it are a standard header:
+//
+// +--------+--------+------------------------------------------------------+
+// | Offset |  Size  |                       Contents                       |
+// +--------+--------+------------------------------------------------------+
+// |  0x00  |    2   | Vendor ID, which is 0xffff if there is no function   |
+// |  0x02  |    2   | Device ID                                            |
+// |  0x04  |    2   | Command, which turns on I/O and memory decoding, and |
+// |        |        | lets the device access memory itself (bus master)    |
+// |  0x06  |    2   | Status. Bit 4 is set if there is a capability list   |
+// |  0x08  |    1   | Revision                                             |
+// |  0x09  |    3   | Class code: programming interface, subclass, class   |
+// |  0x0e  |    1   | Header type, with bit 7 set if the device has more   |
+// |        |        | than one function                                    |
+// |  0x10  |   24   | Base Address Registers (BARs), 2 for a bridge        |
+// |  0x19  |    1   | Secondary bus, behind a PCI-to-PCI bridge            |
+// |  0x2c  |    4   | Subsystem vendor and subsystem IDs                   |
+// |  0x34  |    1   | Offset of the first capability                       |
+// |  0x3c  |    2   | Interrupt line and pin, for the legacy INTx lines    |
+// +--------+--------+------------------------------------------------------+
+//
+// Each BAR says where one of the device's memory or I/O port ranges has been
+// put by the firmware. Its size is found by writing all ones to it, and
+// seeing which address bits stay zero. Memory BARs can be 64 bits wide, using
+// the next BAR for the top half.
+// ---
+// Capabilities are a linked list through configuration space, each starting
+// with an ID and the offset of the next. The ones we use are for Message
+// Signalled Interrupts, see the msi module.
+// ---
+// init walks the buses, starting at bus 0 and following PCI-to-PCI bridges to
+// the buses behind them, and records every function it finds in a fixed-size
+// registry. Drivers then look through it for the devices they handle, by
+// vendor and device ID, or by class, with a DeviceId.
+
+use core::fmt;
+use spin::Once;
+use crate::acpi;
+
+pub mod config;
+pub mod msi;
+
+pub use msi::{Msi, MsiX};
+
+// The most functions which are recorded.
+pub const MAX_DEVICES: usize = 64;
+pub const MAX_BARS: usize = 6;
+
+// The longest capability list which is followed, in case it loops.
+const MAX_CAPABILITIES: usize = 48;
+
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0a;
+const CLASS: u16 = 0x0b;
+const HEADER_TYPE: u16 = 0x0e;
+const BAR_0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+const SUBSYSTEM_ID: u16 = 0x2e;
+const CAPABILITIES_POINTER: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3c;
+const INTERRUPT_PIN: u16 = 0x3d;
+
+const NO_VENDOR: u16 = 0xffff;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_MEMORY_64BIT: u32 = 0b10 << 1;
+const BAR_MEMORY_TYPE: u32 = 0b11 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+// Where a function is: its segment group, bus, device and function numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Address {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus,
+               self.device, self.function)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory { address: u64, size: u64, prefetchable: bool },
+    Io { port: u16, size: u16 },
+}
+
+// A function found on the bus, with its header.
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub address: Address,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    // Only type 0 headers have subsystem IDs, so they are 0 for bridges.
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    // A 64-bit BAR is recorded in the first of the two it takes, and the
+    // second is None.
+    pub bars: [Option<Bar>; MAX_BARS],
+}
+
+impl Device {
+    fn read(address: Address) -> Device {
+        let header_type = config::read_u8(address, HEADER_TYPE)
+            & HEADER_TYPE_MASK;
+        let mut device = Device {
+            address,
+            vendor_id: config::read_u16(address, VENDOR_ID),
+            device_id: config::read_u16(address, DEVICE_ID),
+            class: config::read_u8(address, CLASS),
+            subclass: config::read_u8(address, SUBCLASS),
+            prog_if: config::read_u8(address, PROG_IF),
+            revision: config::read_u8(address, REVISION),
+            header_type,
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            interrupt_line: config::read_u8(address, INTERRUPT_LINE),
+            interrupt_pin: config::read_u8(address, INTERRUPT_PIN),
+            bars: [None; MAX_BARS],
+        };
+
+        let bars = match header_type {
+            HEADER_TYPE_DEVICE => {
+                device.subsystem_vendor_id =
+                    config::read_u16(address, SUBSYSTEM_VENDOR_ID);
+                device.subsystem_id = config::read_u16(address, SUBSYSTEM_ID);
+                MAX_BARS
+            }
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        read_bars(address, &mut device.bars[..bars]);
+
+        device
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        config::read_u8(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        config::read_u16(self.address, offset)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        config::read_u32(self.address, offset)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        config::write_u16(self.address, offset, value);
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        config::write_u32(self.address, offset, value);
+    }
+
+    // Set the given bits of the command register, such as COMMAND_BUS_MASTER,
+    // and clear the rest of COMMAND_IO_SPACE, COMMAND_MEMORY_SPACE,
+    // COMMAND_BUS_MASTER and COMMAND_INTERRUPT_DISABLE.
+    pub fn set_command(&self, flags: u16) {
+        let mask = COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER
+            | COMMAND_INTERRUPT_DISABLE;
+        let command = self.read_u16(COMMAND) & !mask;
+        self.write_u16(COMMAND, command | (flags & mask));
+    }
+
+    // The device's capabilities, in the order they are listed.
+    pub fn capabilities(&self) -> Capabilities {
+        let next = if self.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read_u8(CAPABILITIES_POINTER)
+        } else {
+            0
+        };
+        Capabilities {
+            address: self.address,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().find(|capability| capability.id == id)
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        let capability = self.find_capability(CAPABILITY_MSI)?;
+        Some(Msi::new(self.address, capability.offset))
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        let capability = self.find_capability(CAPABILITY_MSIX)?;
+        MsiX::new(self, capability.offset)
+    }
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:04x}:{:04x} (class {:02x}.{:02x}.{:02x})",
+               self.address, self.vendor_id, self.device_id, self.class,
+               self.subclass, self.prog_if)
+    }
+}
+
+// Find the address and size of each BAR. Decoding is turned off while they
+// are sized, so the device doesn't answer at the all ones address.
+fn read_bars(address: Address, bars: &mut [Option<Bar>]) {
+    let command = config::read_u16(address, COMMAND);
+    config::write_u16(address, COMMAND,
+                      command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let mut index = 0;
+    while index < bars.len() {
+        let offset = BAR_0 + index as u16 * 4;
+        let low = size_bar(address, offset);
+
+        if low.0 & BAR_IO != 0 {
+            // Only the bottom 16 bits are used, as x86 only has 64Ki ports.
+            let mask = low.1 & !0b11 | 0xffff_0000;
+            if mask != 0xffff_0000 {
+                bars[index] = Some(Bar::Io {
+                    port: (low.0 & !0b11) as u16,
+                    size: (!mask).wrapping_add(1) as u16,
+                });
+            }
+            index += 1;
+            continue;
+        }
+
+        let is_64bit = low.0 & BAR_MEMORY_TYPE == BAR_MEMORY_64BIT
+            && index + 1 < bars.len();
+        let (value, mask) = if is_64bit {
+            let high = size_bar(address, offset + 4);
+            (u64::from(high.0) << 32 | u64::from(low.0),
+             u64::from(high.1) << 32 | u64::from(low.1))
+        } else {
+            (u64::from(low.0), u64::from(low.1) | 0xffff_ffff_0000_0000)
+        };
+
+        let mask = mask & !0xf;
+        if mask != 0 && mask != 0xffff_ffff_0000_0000 {
+            bars[index] = Some(Bar::Memory {
+                address: value & !0xf,
+                size: (!mask).wrapping_add(1),
+                prefetchable: low.0 & BAR_PREFETCHABLE != 0,
+            });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    config::write_u16(address, COMMAND, command);
+}
+
+// Read a BAR, and the mask of address bits it decodes, putting it back as it
+// was.
+fn size_bar(address: Address, offset: u16) -> (u32, u32) {
+    let value = config::read_u32(address, offset);
+    config::write_u32(address, offset, u32::MAX);
+    let mask = config::read_u32(address, offset);
+    config::write_u32(address, offset, value);
+    (value, mask)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    // Where it is in configuration space.
+    pub offset: u16,
+}
+
+pub struct Capabilities {
+    address: Address,
+    next: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability;
+
+    fn next(&mut self) -> Option<Capability> {
+        // The bottom two bits are reserved, and the list can't point back
+        // into the header.
+        let offset = u16::from(self.next & !0b11);
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+
+        self.remaining -= 1;
+        self.next = config::read_u8(self.address, offset + 1);
+        Some(Capability { id: config::read_u8(self.address, offset), offset })
+    }
+}
+
+
+// MATCHING
+
+// Which devices a driver handles. Each field which is Some has to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+    pub prog_if: Option<u8>,
+}
+
+impl DeviceId {
+    // Matches every device.
+    pub const ANY: DeviceId = DeviceId {
+        vendor_id: None,
+        device_id: None,
+        class: None,
+        subclass: None,
+        prog_if: None,
+    };
+
+    pub const fn device(vendor_id: u16, device_id: u16) -> DeviceId {
+        DeviceId {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            ..DeviceId::ANY
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> DeviceId {
+        DeviceId {
+            class: Some(class),
+            subclass: Some(subclass),
+            ..DeviceId::ANY
+        }
+    }
+
+    pub fn matches(&self, device: &Device) -> bool {
+        fn field<T: PartialEq>(wanted: Option<T>, value: T) -> bool {
+            wanted.map_or(true, |wanted| wanted == value)
+        }
+
+        field(self.vendor_id, device.vendor_id)
+            && field(self.device_id, device.device_id)
+            && field(self.class, device.class)
+            && field(self.subclass, device.subclass)
+            && field(self.prog_if, device.prog_if)
+    }
+}
+
+
+// REGISTRY
+
+struct Registry {
+    devices: [Option<Device>; MAX_DEVICES],
+    count: usize,
+}
+
+impl Registry {
+    fn push(&mut self, device: Device) {
+        match self.devices.get_mut(self.count) {
+            Some(slot) => {
+                *slot = Some(device);
+                self.count += 1;
+            }
+            None => log::warn!("PCI: too many devices, ignoring {}", device),
+        }
+    }
+}
+
+static DEVICES: Once<Registry> = Once::new();
+
+// Find every function on the buses. They are only scanned the first time this
+// is called, which has to be after acpi::init if ECAM is to be used. Returns
+// the number found.
+pub fn init() -> usize {
+    let registry = DEVICES.call_once(scan);
+    for device in devices() {
+        log::debug!("PCI: {}", device);
+    }
+    registry.count
+}
+
+fn scan() -> Registry {
+    let mut registry = Registry { devices: [None; MAX_DEVICES], count: 0 };
+
+    // Each PCI segment group has its own buses, starting from the first one
+    // the MCFG table gives for it. Without one, there is only segment 0.
+    let mcfg = acpi::get().and_then(|acpi| acpi.mcfg.as_ref());
+    match mcfg {
+        Some(mcfg) if !mcfg.entries().is_empty() => {
+            for (index, entry) in mcfg.entries().iter().enumerate() {
+                let seen = mcfg.entries()[..index].iter()
+                    .any(|other| other.segment_group == entry.segment_group);
+                if !seen {
+                    scan_root(&mut registry, entry.segment_group,
+                              entry.start_bus);
+                }
+            }
+        }
+        _ => scan_root(&mut registry, 0, 0),
+    }
+
+    registry
+}
+
+// If the host bridge at device 0 of the first bus has more than one function,
+// each function is the host bridge for another bus.
+fn scan_root(registry: &mut Registry, segment: u16, bus: u8) {
+    let host = Address { segment, bus, device: 0, function: 0 };
+    if config::read_u8(host, HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION == 0 {
+        scan_bus(registry, segment, bus);
+        return;
+    }
+
+    for function in 0..8 {
+        let host = Address { function, ..host };
+        if config::read_u16(host, VENDOR_ID) != NO_VENDOR {
+            scan_bus(registry, segment, bus.wrapping_add(function));
+        }
+    }
+}
+
+fn scan_bus(registry: &mut Registry, segment: u16, bus: u8) {
+    for device in 0..32 {
+        let address = Address { segment, bus, device, function: 0 };
+        if config::read_u16(address, VENDOR_ID) == NO_VENDOR {
+            continue;
+        }
+
+        let header_type = config::read_u8(address, HEADER_TYPE);
+        let functions = if header_type & HEADER_TYPE_MULTI_FUNCTION != 0 {
+            8
+        } else {
+            1
+        };
+
+        for function in 0..functions {
+            let address = Address { function, ..address };
+            if config::read_u16(address, VENDOR_ID) == NO_VENDOR {
+                continue;
+            }
+
+            let device = Device::read(address);
+            registry.push(device);
+
+            // Only follow bridges to buses further on, so a badly set up
+            // bridge can't send the scan round in circles.
+            if device.header_type == HEADER_TYPE_BRIDGE
+                && device.class == CLASS_BRIDGE
+                && device.subclass == SUBCLASS_PCI_BRIDGE {
+                let secondary = config::read_u8(address, SECONDARY_BUS);
+                if secondary > bus {
+                    scan_bus(registry, segment, secondary);
+                }
+            }
+        }
+    }
+}
+
+// Every function found by init.
+pub fn devices() -> impl Iterator<Item = &'static Device> {
+    let devices: &[Option<Device>] = match DEVICES.r#try() {
+        Some(registry) => &registry.devices[..registry.count],
+        None => &[],
+    };
+    devices.iter().flatten()
+}
+
+// The functions which match the given ID.
+pub fn find(id: DeviceId) -> impl Iterator<Item = &'static Device> {
+    devices().filter(move |device| id.matches(device))
+}
+
+// The function at the given address, if init found one there.
+pub fn get(address: Address) -> Option<&'static Device> {
+    devices().find(|device| device.address == address)
+}
+
+
+// TESTING
+
+// Test that the host bridge is found at the start of bus 0, and matched by
+// its class.
+#[test_case]
+fn test_host_bridge() {
+    let host = Address { segment: 0, bus: 0, device: 0, function: 0 };
+    let bridge = get(host).expect("no host bridge was found");
+
+    assert_eq!((bridge.class, bridge.subclass), (CLASS_BRIDGE, 0x00));
+    assert!(find(DeviceId::class(CLASS_BRIDGE, 0x00))
+            .any(|device| device.address == host));
+    assert!(!DeviceId::device(bridge.vendor_id, !bridge.device_id)
+            .matches(bridge));
+}
+
+// Test that the BAR of QEMU's VGA framebuffer is found and sized.
+#[test_case]
+fn test_vga_framebuffer_bar() {
+    let vga = find(DeviceId::device(0x1234, 0x1111)).next()
+        .expect("no QEMU VGA device was found");
+
+    match vga.bars[0] {
+        Some(Bar::Memory { address, size, prefetchable }) => {
+            assert!(address != 0);
+            assert!(size >= 0x10_0000 && size.is_power_of_two());
+            assert!(prefetchable);
+        }
+        bar => panic!("unexpected VGA BAR 0: {:?}", bar),
+    }
+}
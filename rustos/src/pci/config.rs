@@ -0,0 +1,162 @@
+// Access to PCI configuration space. Every function of every device has its
+// own, which holds its IDs, its Base Address Registers (BARs), its command and
+// status registers, and its capabilities. It can be reached in two ways:
+// - Legacy: The address of a 32-bit register is written to port 0xcf8, and
+//               the register is then read or written through port 0xcfc. This
+//               only reaches the first 256 bytes of each function, and only
+//               PCI segment group 0. The address is laid out as:
+//
+//               +--------+---------------------------------------------+
+//               |  Bits  |                  Contents                   |
+//               +--------+---------------------------------------------+
+//               |   31   | Enable                                      |
+//               | 16-23  | Bus                                         |
+//               | 11-15  | Device                                      |
+//               |  8-10  | Function                                    |
+//               |  2-7   | Register, the offset divided by 4           |
+//               +--------+---------------------------------------------+
+//
+// - ECAM: The PCI Express Enhanced Configuration Access Mechanism maps the
+//               whole 4KiB of each function's configuration space into
+//               memory, 1MiB for each bus, at the addresses in the MCFG ACPI
+//               table. Each function's space is at the bus's address, plus
+//               the device shifted left by 15, and the function by 12.
+//
+// ECAM is used when the MCFG table covers the bus, as it needs no locking, and
+// reaches the extended configuration space. Anything which can't be reached
+// reads as all ones, as it does for a function which isn't there.
+
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::acpi::{self, McfgEntry};
+use crate::memory;
+use super::Address;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+
+// The size of each function's configuration space through each mechanism.
+const LEGACY_SIZE: u16 = 256;
+const ECAM_SIZE: u16 = 4096;
+
+// The address and data ports are a pair, so only one CPU can use them at a
+// time. Only locked with interrupts disabled.
+static LEGACY: Mutex<()> = Mutex::new(());
+
+// The address of a register through the legacy mechanism.
+fn legacy_address(address: Address, offset: u16) -> u32 {
+    CONFIG_ADDRESS_ENABLE
+        | u32::from(address.bus) << 16
+        | u32::from(address.device) << 11
+        | u32::from(address.function) << 8
+        | u32::from(offset & 0xfc)
+}
+
+// The physical address of a register through ECAM, if the MCFG entry covers
+// the function.
+fn ecam_address(entry: &McfgEntry, address: Address, offset: u16)
+        -> Option<u64> {
+    if entry.segment_group != address.segment
+        || address.bus < entry.start_bus || address.bus > entry.end_bus {
+        return None;
+    }
+
+    Some(entry.base_address
+         + (u64::from(address.bus) << 20)
+         + (u64::from(address.device) << 15)
+         + (u64::from(address.function) << 12)
+         + u64::from(offset & 0xffc))
+}
+
+fn ecam_register(address: Address, offset: u16) -> Option<*mut u32> {
+    let mcfg = acpi::get()?.mcfg.as_ref()?;
+    let physical = mcfg.entries().iter()
+        .find_map(|entry| ecam_address(entry, address, offset))?;
+    let virtual_address = memory::phys_to_virt(PhysAddr::new(physical))?;
+    Some(virtual_address.as_mut_ptr())
+}
+
+// Read the 32-bit register holding the given offset.
+pub fn read_u32(address: Address, offset: u16) -> u32 {
+    if offset >= ECAM_SIZE {
+        return u32::MAX;
+    }
+    if let Some(register) = ecam_register(address, offset) {
+        return unsafe { core::ptr::read_volatile(register) };
+    }
+    if address.segment != 0 || offset >= LEGACY_SIZE {
+        return u32::MAX;
+    }
+
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+    let mut data_port: Port<u32> = Port::new(CONFIG_DATA_PORT);
+    without_interrupts(|| {
+        let _guard = LEGACY.lock();
+        unsafe {
+            address_port.write(legacy_address(address, offset));
+            data_port.read()
+        }
+    })
+}
+
+// Write the 32-bit register holding the given offset.
+pub fn write_u32(address: Address, offset: u16, value: u32) {
+    if offset >= ECAM_SIZE {
+        return;
+    }
+    if let Some(register) = ecam_register(address, offset) {
+        unsafe { core::ptr::write_volatile(register, value) };
+        return;
+    }
+    if address.segment != 0 || offset >= LEGACY_SIZE {
+        return;
+    }
+
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS_PORT);
+    let mut data_port: Port<u32> = Port::new(CONFIG_DATA_PORT);
+    without_interrupts(|| {
+        let _guard = LEGACY.lock();
+        unsafe {
+            address_port.write(legacy_address(address, offset));
+            data_port.write(value);
+        }
+    });
+}
+
+// The narrower reads and writes pick out, or replace, part of the 32-bit
+// register, as neither mechanism is guaranteed to support anything else.
+pub fn read_u16(address: Address, offset: u16) -> u16 {
+    (read_u32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: Address, offset: u16) -> u8 {
+    (read_u32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+pub fn write_u16(address: Address, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let register = read_u32(address, offset) & !(0xffff << shift);
+    write_u32(address, offset, register | u32::from(value) << shift);
+}
+
+
+// TESTING
+
+// Test that registers are addressed with each field in the right place.
+#[test_case]
+fn test_config_addresses() {
+    let address = Address { segment: 0, bus: 0x12, device: 0x1f,
+                            function: 7 };
+    assert_eq!(legacy_address(address, 0x3e), 0x8012_ff3c);
+
+    let entry = McfgEntry { base_address: 0xb000_0000, segment_group: 0,
+                            start_bus: 0, end_bus: 0x3f };
+    assert_eq!(ecam_address(&entry, address, 0x104),
+               Some(0xb12f_f104));
+
+    let outside = Address { bus: 0x40, ..address };
+    assert_eq!(ecam_address(&entry, outside, 0), None);
+}
@@ -0,0 +1,202 @@
+// Message Signalled Interrupts. Rather than raising an interrupt on one of the
+// four shared INTx lines, which are wired to the interrupt controllers in
+// ways only the firmware knows about, a device can write a message to a
+// memory address, which the local APICs pick up as an interrupt. On x86 the
+// address picks the CPU, and the data the vector:
+// - Address: 0xfee00000, with the destination APIC ID in bits 12-19.
+// - Data: The vector, in bits 0-7, with the other bits 0 for a fixed, edge
+//               triggered interrupt.
+// There are two capabilities for them:
+// - MSI (0x05): One address and data, set in the capability itself. The
+//               device may ask for several vectors, which are then
+//               consecutive, but only one is ever used here. Its control
+//               register, at offset 2, has bit 0 to enable it, the number of
+//               vectors the device can use, as a power of 2, in bits 1-3, and
+//               bit 7 set if the address is 64 bits.
+// - MSI-X (0x11): A table of up to 2048 entries, each with its own address
+//               and data, in memory behind one of the BARs. Its control
+//               register has the table size, minus one, in bits 0-10, and
+//               bit 15 to enable it. The two registers after it give the BAR
+//               (bits 0-2) and offset of the table and the Pending Bit Array.
+//               Each table entry is 16 bytes: the address, in two halves, the
+//               data, and a vector control register whose bit 0 masks it.
+
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+use super::{config, Address, Bar, Device};
+
+const MESSAGE_ADDRESS: u32 = 0xfee0_0000;
+
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08;
+const MSI_DATA_32BIT: u16 = 0x08;
+const MSI_DATA_64BIT: u16 = 0x0c;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7ff;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+// The message address and data which interrupt the given local APIC with the
+// given vector.
+pub fn message(apic_id: u32, vector: u8) -> (u32, u32) {
+    (MESSAGE_ADDRESS | (apic_id & 0xff) << 12, u32::from(vector))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Msi {
+    address: Address,
+    offset: u16,
+}
+
+impl Msi {
+    pub(super) fn new(address: Address, offset: u16) -> Msi {
+        Msi { address, offset }
+    }
+
+    fn control(&self) -> u16 {
+        config::read_u16(self.address, self.offset + MSI_CONTROL)
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.control() & MSI_CONTROL_64BIT != 0
+    }
+
+    // The number of vectors the device would like.
+    pub fn vectors(&self) -> u8 {
+        1u8 << ((self.control() >> 1) & 0b111)
+    }
+
+    // Have the device interrupt the given local APIC with one vector.
+    pub fn enable(&self, apic_id: u32, vector: u8) {
+        let (message_address, data) = message(apic_id, vector);
+        let control = self.control();
+
+        config::write_u32(self.address, self.offset + MSI_ADDRESS,
+                          message_address);
+        let data_offset = if control & MSI_CONTROL_64BIT != 0 {
+            config::write_u32(self.address, self.offset + MSI_ADDRESS_HIGH, 0);
+            MSI_DATA_64BIT
+        } else {
+            MSI_DATA_32BIT
+        };
+        config::write_u16(self.address, self.offset + data_offset, data as u16);
+
+        let control = control & !MSI_CONTROL_MULTIPLE_ENABLE
+            | MSI_CONTROL_ENABLE;
+        config::write_u16(self.address, self.offset + MSI_CONTROL, control);
+    }
+
+    pub fn disable(&self) {
+        let control = self.control() & !MSI_CONTROL_ENABLE;
+        config::write_u16(self.address, self.offset + MSI_CONTROL, control);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MsiX {
+    address: Address,
+    offset: u16,
+    table: VirtAddr,
+    table_size: u16,
+}
+
+impl MsiX {
+    // The table is found through the device's BARs, so this returns None if
+    // the BAR it names isn't a memory BAR which can be reached.
+    pub(super) fn new(device: &Device, offset: u16) -> Option<MsiX> {
+        let address = device.address;
+        let control = config::read_u16(address, offset + MSIX_CONTROL);
+        let table = config::read_u32(address, offset + MSIX_TABLE);
+
+        let bar = match device.bars.get(table as usize & 0b111)? {
+            Some(Bar::Memory { address, .. }) => *address,
+            _ => return None,
+        };
+        let table_address = bar + u64::from(table & !0b111);
+
+        Some(MsiX {
+            address,
+            offset,
+            table: memory::phys_to_virt(PhysAddr::new(table_address))?,
+            table_size: (control & MSIX_CONTROL_TABLE_SIZE) + 1,
+        })
+    }
+
+    fn control(&self) -> u16 {
+        config::read_u16(self.address, self.offset + MSIX_CONTROL)
+    }
+
+    fn set_control(&self, control: u16) {
+        config::write_u16(self.address, self.offset + MSIX_CONTROL, control);
+    }
+
+    // The number of entries in the table.
+    pub fn table_size(&self) -> u16 {
+        self.table_size
+    }
+
+    fn entry(&self, entry: u16) -> *mut u32 {
+        assert!(entry < self.table_size, "MSI-X entry {} is past the end of \
+                the table", entry);
+        (self.table + u64::from(entry) * MSIX_ENTRY_SIZE).as_mut_ptr()
+    }
+
+    // Point a table entry at the given local APIC and vector, and unmask it.
+    pub fn set_entry(&self, entry: u16, apic_id: u32, vector: u8) {
+        let (message_address, data) = message(apic_id, vector);
+        let entry = self.entry(entry);
+
+        unsafe {
+            core::ptr::write_volatile(entry, message_address);
+            core::ptr::write_volatile(entry.add(1), 0);
+            core::ptr::write_volatile(entry.add(2), data);
+            core::ptr::write_volatile(entry.add(3), 0);
+        }
+    }
+
+    // Mask or unmask a table entry.
+    pub fn set_masked(&self, entry: u16, masked: bool) {
+        unsafe {
+            let control = self.entry(entry).add(3);
+            let value = core::ptr::read_volatile(control);
+            let value = if masked {
+                value | MSIX_VECTOR_MASKED
+            } else {
+                value & !MSIX_VECTOR_MASKED
+            };
+            core::ptr::write_volatile(control, value);
+        }
+    }
+
+    // Start using the table. The entries should be set first, as each starts
+    // out masked.
+    pub fn enable(&self) {
+        let control = self.control() & !MSIX_CONTROL_FUNCTION_MASK;
+        self.set_control(control | MSIX_CONTROL_ENABLE);
+    }
+
+    pub fn disable(&self) {
+        self.set_control(self.control() & !MSIX_CONTROL_ENABLE);
+    }
+}
+
+
+// TESTING
+
+// Test that messages carry the APIC ID and vector in the right place.
+#[test_case]
+fn test_msi_message() {
+    assert_eq!(message(0, 0x40), (0xfee0_0000, 0x40));
+    assert_eq!(message(3, 0x41), (0xfee0_3000, 0x41));
+}
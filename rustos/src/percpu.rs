@@ -0,0 +1,134 @@
+// Data kept separately for each CPU, such as which CPU it is, and its local
+// APIC ID. Each CPU has a PerCpu structure in CPUS, indexed by the CPU's
+// number, which counts up from 0 for the bootstrap processor in the order the
+// CPUs were started.
+// ---
+// A CPU finds its own structure through the GS segment. Segmentation is mostly
+// gone in 64-bit mode, but the FS and GS segments can still have a base
+// address, set through the IA32_GS_BASE MSR, which is added to any address
+// used with a 'gs:' prefix. Each CPU points its GS base at its own structure,
+// whose first field holds the structure's address, so 'mov rax, gs:[0]' finds
+// it in one instruction, without needing to know which CPU this is.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize};
+use core::sync::atomic::Ordering;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+
+// The most CPUs the kernel will start.
+pub const MAX_CPUS: usize = 16;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+// The fields are atomics, as the structures are statics, and other CPUs may
+// look at them.
+#[repr(C)]
+pub struct PerCpu {
+    // The address of this structure, which has to be the first field, for
+    // current to read it.
+    this: AtomicU64,
+    index: AtomicUsize,
+    apic_id: AtomicU32,
+    online: AtomicBool,
+    address_space: AtomicU64,
+}
+
+const OFFLINE: PerCpu = PerCpu {
+    this: AtomicU64::new(0),
+    index: AtomicUsize::new(0),
+    apic_id: AtomicU32::new(0),
+    online: AtomicBool::new(false),
+    address_space: AtomicU64::new(0),
+};
+
+static CPUS: [PerCpu; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+
+impl PerCpu {
+    // The CPU's number, counting from 0 for the bootstrap processor.
+    pub fn index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    // The local APIC ID of the CPU, which is how other CPUs address it.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub fn set_apic_id(&self, apic_id: u32) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    // Whether the CPU has finished starting up.
+    pub fn online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    // The physical address of the level 4 page table the CPU is using, which
+    // says which CPUs a TLB shootdown has to reach. See the tlb module.
+    pub fn address_space(&self) -> u64 {
+        self.address_space.load(Ordering::Acquire)
+    }
+
+    pub fn set_address_space(&self, level_4_table: u64) {
+        self.address_space.store(level_4_table, Ordering::Release);
+    }
+}
+
+// Point the GS base of the CPU this runs on at the structure of the given CPU.
+pub fn init(cpu: usize) {
+    let per_cpu = &CPUS[cpu];
+    let address = per_cpu as *const PerCpu as u64;
+
+    per_cpu.this.store(address, Ordering::Relaxed);
+    per_cpu.index.store(cpu, Ordering::Relaxed);
+    per_cpu.set_address_space(Cr3::read().0.start_address().as_u64());
+    unsafe { Msr::new(IA32_GS_BASE).write(address) };
+}
+
+// The structure of the CPU this runs on. init has to have been called on this
+// CPU first, which the kernel's init and the SMP startup code do.
+pub fn current() -> &'static PerCpu {
+    let address: u64;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) address, options(nostack, readonly,
+                                                        preserves_flags));
+        &*(address as *const PerCpu)
+    }
+}
+
+// The number of the CPU this runs on. This also works before init has been
+// called, when the GS base is still 0, as only the bootstrap processor runs
+// then, so it is used where init may not have been, such as the test runner.
+pub fn current_index() -> usize {
+    if unsafe { Msr::new(IA32_GS_BASE).read() } == 0 {
+        0
+    } else {
+        current().index()
+    }
+}
+
+// The structure of the given CPU, if there is one.
+pub fn get(cpu: usize) -> Option<&'static PerCpu> {
+    CPUS.get(cpu)
+}
+
+// The CPUs which have finished starting up.
+pub fn online() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter().filter(|per_cpu| per_cpu.online())
+}
+
+
+// TESTING
+
+// Test that GS base points at the bootstrap processor's structure.
+#[test_case]
+fn test_current() {
+    let per_cpu = current();
+
+    assert_eq!(per_cpu.index(), 0);
+    assert!(core::ptr::eq(per_cpu, &CPUS[0]));
+}
@@ -1,37 +1,529 @@
+use core::fmt;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 use spin::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::console::Console;
+use crate::vga_buffer::Colour;
+
+pub mod line_discipline;
 
 // We are going to use the 16550 UART Serial Port in order to communicate with
 // the outsite world. We are doing this to enable communication to the console
-// which is running our unit tests. The uart_16550 crate contains a SerialPort
-// struct which reprects the UART registers.
-use uart_16550::SerialPort;
-
-// Similar to the VGA Buffer, lazy_static and a spinlock have been used to help
-// create a static reference to the Serial Port. This has been done to ensure
-// the init method is only called once, on the first use of the Serial Port.
+// which is running our unit tests, and so that the kernel can be driven from
+// the host without a screen or keyboard.
 // ---
 // Similar to the isa-debug-exit device, the UART is programmed using Port I/O.
 // As this is more complex than the isa-debug-exit device, it uses multiple I/O
-// ports for programming different device registers. The SerialPort::new method
-// required the address of the first I/O port of the UART, from which it then
-// calculates the addresses of the other ports.
+// ports for programming different device registers. Each UART has a base
+// address, and its registers are found at fixed offsets from it:
+//
+// +--------+---------------------------------+--------------------------------+
+// | Offset |             Reading             |             Writing            |
+// +--------+---------------------------------+--------------------------------+
+// |   +0   | Receive buffer                  | Transmit buffer                |
+// |   +1   | Interrupt enable                | Interrupt enable               |
+// |   +2   | Interrupt identification        | FIFO control                   |
+// |   +3   | Line control                    | Line control                   |
+// |   +4   | Modem control                   | Modem control                  |
+// |   +5   | Line status                     |                                |
+// +--------+---------------------------------+--------------------------------+
+//
+// When the Divisor Latch Access Bit (DLAB) of the line control register is
+// set, offsets +0 and +1 instead hold the low and high bytes of the divisor,
+// which sets the baud rate as a fraction of the UART's 115200Hz base rate.
 // ---
-// 0x3F8 has been used as the first I/O port address. This is the standard port
-// number for the first serial interface.
+// The PC has four standard serial ports. COM1 and COM3 share IRQ 4, while COM2
+// and COM4 share IRQ 3.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_CONTROL_DLAB: u8 = 0x80;
+const LINE_STATUS_DATA_READY: u8 = 0x01;
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 0x20;
+
+// The rate the UART's clock runs at, divided down to get the baud rate.
+const BASE_BAUD_RATE: u32 = 115_200;
+
+// The number of received bytes which are buffered for each port, until they
+// are read.
+pub const INPUT_BUFFER_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataBits {
+    Five,
+    Six,
+    Seven,
+    Eight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+// The line settings of a serial port. Both ends of the line need to agree on
+// these for any data to get through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl SerialConfig {
+    // Build the value of the line control register for these settings.
+    fn line_control(&self) -> u8 {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 0b00,
+            DataBits::Six => 0b01,
+            DataBits::Seven => 0b10,
+            DataBits::Eight => 0b11,
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => 1 << 2,
+        };
+        let parity = match self.parity {
+            Parity::None => 0b000 << 3,
+            Parity::Odd => 0b001 << 3,
+            Parity::Even => 0b011 << 3,
+            Parity::Mark => 0b101 << 3,
+            Parity::Space => 0b111 << 3,
+        };
+
+        data_bits | stop_bits | parity
+    }
+}
+
+// 38400 baud, with 8 data bits, no parity and 1 stop bit (8N1).
+impl Default for SerialConfig {
+    fn default() -> SerialConfig {
+        SerialConfig {
+            baud_rate: 38_400,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    // The baud rate can't be reached by dividing down the base rate.
+    InvalidBaudRate,
+}
+
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    // Create a new SerialPort for the UART at the given base I/O port.
+    // ---
+    // This is unsafe as writing to the wrong I/O ports could have all sorts of
+    // side effects.
+    pub const unsafe fn new(base: u16) -> SerialPort {
+        SerialPort { base }
+    }
+
+    fn read_register(&self, offset: u16) -> u8 {
+        unsafe { Port::new(self.base + offset).read() }
+    }
+
+    fn write_register(&mut self, offset: u16, value: u8) {
+        unsafe { Port::new(self.base + offset).write(value) }
+    }
+
+    // Program the line settings, enable the FIFOs and enable the interrupt
+    // which fires when data is received.
+    pub fn init(&mut self, config: SerialConfig) -> Result<(), SerialError> {
+        if config.baud_rate == 0 || BASE_BAUD_RATE % config.baud_rate != 0 {
+            return Err(SerialError::InvalidBaudRate);
+        }
+        let divisor = (BASE_BAUD_RATE / config.baud_rate) as u16;
+
+        // Disable interrupts while the port is being set up.
+        self.write_register(INTERRUPT_ENABLE, 0x00);
+
+        // Set the divisor, which is only accessible while DLAB is set.
+        self.write_register(LINE_CONTROL, LINE_CONTROL_DLAB);
+        self.write_register(DATA, divisor as u8);
+        self.write_register(INTERRUPT_ENABLE, (divisor >> 8) as u8);
+
+        // Clearing DLAB at the same time as setting the line settings.
+        self.write_register(LINE_CONTROL, config.line_control());
+
+        // Enable and clear the FIFOs, raising an interrupt once 14 bytes have
+        // been received (or, if fewer arrive, after a short timeout).
+        self.write_register(FIFO_CONTROL, 0xc7);
+
+        // Set Data Terminal Ready and Request To Send, and the OUT2 line, which
+        // on the PC connects the UART's interrupt output to the PIC.
+        self.write_register(MODEM_CONTROL, 0x0b);
+
+        // Enable the Received Data Available interrupt.
+        self.write_register(INTERRUPT_ENABLE, 0x01);
+
+        Ok(())
+    }
+
+    // Send a byte, waiting for the transmit buffer to be empty first.
+    pub fn send(&mut self, byte: u8) {
+        while self.read_register(LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY
+            == 0 {
+            core::hint::spin_loop();
+        }
+
+        self.write_register(DATA, byte);
+    }
+
+    // Receive a byte, if one is waiting.
+    pub fn try_receive(&mut self) -> Option<u8> {
+        let status = self.read_register(LINE_STATUS);
+
+        // Reading an I/O port which nothing is attached to gives 0xff. A real
+        // UART never reports every status bit at once, so this means there's
+        // no UART at this address, rather than there being data waiting.
+        if status != 0xff && status & LINE_STATUS_DATA_READY != 0 {
+            Some(self.read_register(DATA))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.send(byte);
+        }
+
+        Ok(())
+    }
+}
+
+// Create a serial port with the default settings.
+fn default_port(base: u16) -> Mutex<SerialPort> {
+    let mut serial_port = unsafe { SerialPort::new(base) };
+    serial_port.init(SerialConfig::default())
+        .expect("default serial configuration is invalid");
+    Mutex::new(serial_port)
+}
+
+// Similar to the VGA Buffer, lazy_static and a spinlock have been used to help
+// create static references to the Serial Ports. This has been done to ensure
+// the init method is only called once, on the first use of each port. A port
+// can be given different settings by calling init on it again.
+// ---
+// Each port is at the standard I/O port address for it, such as 0x3F8 for the
+// first serial interface. See Com::base.
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    pub static ref SERIAL1: Mutex<SerialPort> = default_port(Com::Com1.base());
+    pub static ref SERIAL2: Mutex<SerialPort> = default_port(Com::Com2.base());
+    pub static ref SERIAL3: Mutex<SerialPort> = default_port(Com::Com3.base());
+    pub static ref SERIAL4: Mutex<SerialPort> = default_port(Com::Com4.base());
+}
+
+// Identifies one of the four standard serial ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Com {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl Com {
+    // The base I/O port of the UART.
+    pub fn base(self) -> u16 {
+        match self {
+            Com::Com1 => 0x3F8,
+            Com::Com2 => 0x2F8,
+            Com::Com3 => 0x3E8,
+            Com::Com4 => 0x2E8,
+        }
+    }
+
+    // Whether there is a UART at the port. This reads the line status register
+    // directly, rather than through port, so that checking doesn't set up a
+    // UART which isn't there. See try_receive.
+    pub fn is_present(self) -> bool {
+        let mut line_status: Port<u8> = Port::new(self.base() + LINE_STATUS);
+        unsafe { line_status.read() != 0xff }
+    }
+
+    pub fn port(self) -> &'static Mutex<SerialPort> {
+        match self {
+            Com::Com1 => &SERIAL1,
+            Com::Com2 => &SERIAL2,
+            Com::Com3 => &SERIAL3,
+            Com::Com4 => &SERIAL4,
+        }
+    }
+
+    fn input(self) -> &'static Input {
+        &INPUTS[self as usize]
+    }
+
+    // Send some bytes out of the port.
+    pub fn write(self, bytes: &[u8]) {
+        interrupts::without_interrupts(|| {
+            let mut port = self.port().lock();
+            for byte in bytes {
+                port.send(*byte);
+            }
+        });
+    }
+
+    // Read any bytes which have already been received into the buffer,
+    // without waiting. Returns the number of bytes read.
+    pub fn try_read(self, buffer: &mut [u8]) -> usize {
+        interrupts::without_interrupts(|| {
+            self.input().queue.lock().pop_into(buffer)
+        })
+    }
+
+    // Read at least one byte into the buffer, halting the CPU until data
+    // arrives if none is waiting. Returns the number of bytes read. If it is
+    // called with interrupts disabled, they stay disabled, and the UART is
+    // polled instead.
+    pub fn read(self, buffer: &mut [u8]) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+
+        let were_enabled = interrupts::are_enabled();
+        loop {
+            // Interrupts must be disabled between checking the buffer and
+            // halting, otherwise the interrupt for new data could arrive in
+            // between, and we would halt waiting for data which had already
+            // been received. enable_and_hlt re-enables interrupts and halts as
+            // a single step, so no interrupt can be missed.
+            interrupts::disable();
+            let count = self.input().queue.lock().pop_into(buffer);
+            if count > 0 {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                return count;
+            }
+
+            if were_enabled {
+                interrupts::enable_and_hlt();
+            } else {
+                handle_interrupt(&[self]);
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    // Read at least one byte into the buffer, without blocking. The returned
+    // future completes once data is available, and is woken by the receive
+    // interrupt.
+    pub fn read_async(self, buffer: &mut [u8]) -> Read {
+        Read { com: self, buffer }
+    }
+}
+
+impl fmt::Write for Com {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Com::write(*self, s.as_bytes());
+        Ok(())
+    }
+}
+
+// A fixed-size queue of received bytes. When the queue is full, newly received
+// bytes are dropped.
+struct InputQueue {
+    data: [u8; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl InputQueue {
+    const fn new() -> InputQueue {
+        InputQueue {
+            data: [0; INPUT_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == INPUT_BUFFER_SIZE {
+            return false;
+        }
+
+        self.data[(self.head + self.len) % INPUT_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop_into(&mut self, buffer: &mut [u8]) -> usize {
+        let count = core::cmp::min(self.len, buffer.len());
+
+        for byte in buffer[..count].iter_mut() {
+            *byte = self.data[self.head];
+            self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        }
+
+        self.len -= count;
+        count
+    }
+}
+
+// The received data for a port, along with the waker of a task waiting for it.
+struct Input {
+    queue: Mutex<InputQueue>,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Input {
+    const fn new() -> Input {
+        Input {
+            queue: Mutex::new(InputQueue::new()),
+            waker: Mutex::new(None),
+        }
+    }
+}
+
+static INPUTS: [Input; 4] =
+    [Input::new(), Input::new(), Input::new(), Input::new()];
+
+// The future returned by Com::read_async.
+pub struct Read<'a> {
+    com: Com,
+    buffer: &'a mut [u8],
+}
+
+impl<'a> Future for Read<'a> {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<usize> {
+        let this = self.get_mut();
+        let input = this.com.input();
+
+        interrupts::without_interrupts(|| {
+            let count = input.queue.lock().pop_into(this.buffer);
+            if count > 0 || this.buffer.is_empty() {
+                return Poll::Ready(count);
+            }
+
+            // Register to be woken by the interrupt handler. As interrupts are
+            // disabled, no data can arrive between checking the queue and
+            // storing the waker.
+            *input.waker.lock() = Some(context.waker().clone());
+            Poll::Pending
+        })
+    }
+}
+
+// Called by the interrupt handler for IRQ 4 (COM1 and COM3) or IRQ 3 (COM2
+// and COM4). Moves every received byte out of the UART's FIFO and into the
+// port's input buffer, then wakes any task waiting for it.
+pub fn handle_interrupt(ports: &[Com]) {
+    for com in ports {
+        let input = com.input();
+        let mut received = false;
+
+        let mut port = com.port().lock();
+        let mut queue = input.queue.lock();
+        while let Some(byte) = port.try_receive() {
+            queue.push(byte);
+            received = true;
+        }
+
+        if received {
+            if let Some(waker) = input.waker.lock().take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+// Enable the receive interrupts of the serial ports, by setting up all four
+// and unmasking their lines on the PIC. Every port has to be set up here, as
+// the interrupt handlers check COM3 and COM4 too, and setting them up from
+// there would take their locks, and write to the UART, inside the handler.
+pub fn init() {
+    lazy_static::initialize(&SERIAL1);
+    lazy_static::initialize(&SERIAL2);
+    lazy_static::initialize(&SERIAL3);
+    lazy_static::initialize(&SERIAL4);
+
+    crate::interrupts::enable_irq(3);
+    crate::interrupts::enable_irq(4);
+}
+
+// The serial port as a console sink, so that the output of print! and println!
+// can be sent to the host as well as the screen.
+pub struct SerialConsole;
+
+impl Console for SerialConsole {
+    fn write(&self, args: ::core::fmt::Arguments) {
+        _print(args);
+    }
+
+    // Most terminals understand ANSI escape codes, so we can colour the text
+    // by wrapping it in a Select Graphic Rendition code for the colour, and a
+    // reset code afterwards.
+    fn write_coloured(&self, colour: Colour, args: ::core::fmt::Arguments) {
+        _print(format_args!("\x1b[{}m{}\x1b[0m", ansi_colour_code(colour), args));
+    }
+}
+
+// Convert a VGA colour into the closest ANSI foreground colour code.
+fn ansi_colour_code(colour: Colour) -> u8 {
+    match colour {
+        Colour::Black => 30,
+        Colour::Red => 31,
+        Colour::Green => 32,
+        Colour::Brown => 33,
+        Colour::Blue => 34,
+        Colour::Magenta => 35,
+        Colour::Cyan => 36,
+        Colour::LightGrey => 37,
+        Colour::DarkGrey => 90,
+        Colour::LightRed => 91,
+        Colour::LightGreen => 92,
+        Colour::Yellow => 93,
+        Colour::LightBlue => 94,
+        Colour::Pink => 95,
+        Colour::LightCyan => 96,
+        Colour::White => 97,
+    }
 }
 
 // Helper method
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    use x86_64::instructions::interrupts;
+
+    // Disable interrupts while the port is locked, so an interrupt handler
+    // which prints to serial can't deadlock waiting for us to release it.
+    interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+    });
 }
 
 // Prints to the host through the serial interface.
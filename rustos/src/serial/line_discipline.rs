@@ -0,0 +1,204 @@
+// A line discipline sits between a serial port and whatever is reading from
+// it, turning the raw stream of bytes typed at a terminal into whole lines.
+// ---
+// Terminals attached to a serial port send each key as it is pressed, and
+// don't show anything on screen until the other end sends it back. So, as well
+// as collecting bytes until the end of a line, the line discipline:
+// - Echoes each printable character back, so the user can see what they typed.
+// - Handles backspace (sent as either 0x08 or 0x7f), removing the last
+//               character from the line and rubbing it out on the screen.
+// - Handles Ctrl-U (0x15), which throws away the whole line.
+// - Treats a carriage return, a line feed, or a carriage return followed by a
+//               line feed, as the end of the line.
+
+use super::Com;
+
+// The longest line which can be read. Any more characters are ignored.
+pub const LINE_MAX: usize = 256;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const KILL_LINE: u8 = 0x15;
+
+pub struct LineDiscipline {
+    buffer: [u8; LINE_MAX],
+    len: usize,
+
+    // Whether received characters are sent back to the terminal.
+    echo: bool,
+
+    // Whether the last byte received was a carriage return, so that a line
+    // feed straight after it isn't taken as a second, empty, line.
+    last_was_return: bool,
+}
+
+impl LineDiscipline {
+    pub const fn new() -> LineDiscipline {
+        LineDiscipline {
+            buffer: [0; LINE_MAX],
+            len: 0,
+            echo: true,
+            last_was_return: false,
+        }
+    }
+
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    // Process a received byte, passing anything which should be echoed back to
+    // the given function. Returns true once a whole line has been received,
+    // which can then be retrieved with line.
+    pub fn input<F: FnMut(&[u8])>(&mut self, byte: u8, mut output: F) -> bool {
+        let last_was_return = self.last_was_return;
+        self.last_was_return = byte == b'\r';
+
+        let echo_enabled = self.echo;
+        let mut echo = |bytes: &[u8]| {
+            if echo_enabled {
+                output(bytes);
+            }
+        };
+
+        match byte {
+            b'\n' if last_was_return => false,
+
+            b'\r' | b'\n' => {
+                echo(b"\r\n");
+                true
+            }
+
+            BACKSPACE | DELETE => {
+                if self.len > 0 {
+                    self.len -= 1;
+                    echo(b"\x08 \x08");
+                }
+                false
+            }
+
+            KILL_LINE => {
+                for _ in 0..self.len {
+                    echo(b"\x08 \x08");
+                }
+                self.len = 0;
+                false
+            }
+
+            0x20..=0x7e => {
+                if self.len < LINE_MAX {
+                    self.buffer[self.len] = byte;
+                    self.len += 1;
+                    echo(&[byte]);
+                }
+                false
+            }
+
+            // Ignore any other control characters.
+            _ => false,
+        }
+    }
+
+    // The line which has been received so far. Only printable ASCII characters
+    // are ever added to the line, so it is always valid UTF-8.
+    pub fn line(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("")
+    }
+
+    // Start a new, empty, line.
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    // Read a whole line from a serial port, blocking until it has been
+    // received. The line doesn't include the line ending.
+    pub fn read_line(&mut self, com: Com) -> &str {
+        self.clear();
+
+        let mut received = [0; 16];
+        'lines: loop {
+            let count = com.read(&mut received);
+
+            for byte in &received[..count] {
+                if self.input(*byte, |bytes| com.write(bytes)) {
+                    // Anything received after the end of the line is dropped,
+                    // which is what we want for interactive use, where the
+                    // user won't type ahead of the prompt.
+                    break 'lines;
+                }
+            }
+        }
+
+        self.line()
+    }
+}
+
+impl Default for LineDiscipline {
+    fn default() -> LineDiscipline {
+        LineDiscipline::new()
+    }
+}
+
+
+// TESTING
+
+// Feed a string into a line discipline, collecting the echoed output. Returns
+// whether the end of a line was reached, and the length of the output.
+#[cfg(test)]
+fn feed(discipline: &mut LineDiscipline, input: &[u8], echoed: &mut [u8])
+        -> (bool, usize) {
+    let mut length = 0;
+    let mut complete = false;
+
+    for byte in input {
+        complete = discipline.input(*byte, |bytes| {
+            echoed[length..length + bytes.len()].copy_from_slice(bytes);
+            length += bytes.len();
+        });
+    }
+
+    (complete, length)
+}
+
+// Test that characters are collected and echoed, and that backspace removes
+// the last character.
+#[test_case]
+fn test_line_discipline_backspace() {
+    let mut discipline = LineDiscipline::new();
+    let mut echoed = [0; 32];
+    let (complete, length) = feed(&mut discipline, b"ab\x7fc\r", &mut echoed);
+
+    assert!(complete);
+    assert_eq!(discipline.line(), "ac");
+    assert_eq!(&echoed[..length], b"ab\x08 \x08c\r\n");
+}
+
+// Test that a carriage return followed by a line feed is one line ending, and
+// that control characters are ignored.
+#[test_case]
+fn test_line_discipline_line_endings() {
+    let mut discipline = LineDiscipline::new();
+    let mut echoed = [0; 32];
+
+    assert!(feed(&mut discipline, b"x\x01\r", &mut echoed).0);
+    assert_eq!(discipline.line(), "x");
+
+    discipline.clear();
+    assert!(!feed(&mut discipline, b"\n", &mut echoed).0);
+    assert!(feed(&mut discipline, b"\n", &mut echoed).0);
+    assert_eq!(discipline.line(), "");
+}
+
+// Test that Ctrl-U throws the line away, and that nothing is echoed when echo
+// is turned off.
+#[test_case]
+fn test_line_discipline_kill_line() {
+    let mut discipline = LineDiscipline::new();
+    discipline.set_echo(false);
+    let mut echoed = [0; 32];
+    let (complete, length) =
+        feed(&mut discipline, b"abc\x15de\n", &mut echoed);
+
+    assert!(complete);
+    assert_eq!(discipline.line(), "de");
+    assert_eq!(length, 0);
+}
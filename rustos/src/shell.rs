@@ -0,0 +1,188 @@
+// A very small command shell, which reads commands a line at a time from a
+// serial port. Running QEMU with '-serial stdio' connects the first serial port
+// to the terminal QEMU was started from, so the kernel can be driven from the
+// host without needing a screen or keyboard.
+// ---
+// Each command is an entry in the COMMANDS table, made up of its name, a line
+// of help text, and the function which runs it. The function is given the rest
+// of the line after the command name, and somewhere to write its output.
+
+use core::fmt::{self, Write};
+use crate::console;
+use crate::logger;
+use crate::serial::Com;
+use crate::serial::line_discipline::LineDiscipline;
+use crate::time;
+
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub run: fn(&mut dyn Write, &str) -> fmt::Result,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "List the available commands",
+        run: help,
+    },
+    Command {
+        name: "echo",
+        help: "Print the arguments back",
+        run: echo,
+    },
+    Command {
+        name: "log",
+        help: "Show the log level, or set it with 'log <level>'",
+        run: log_level,
+    },
+    Command {
+        name: "dmesg",
+        help: "Print the contents of the console ring buffer",
+        run: dmesg,
+    },
+    Command {
+        name: "date",
+        help: "Print the date and time, in UTC",
+        run: date,
+    },
+];
+
+// Run a single command line, writing any output to the given writer.
+pub fn execute(line: &str, out: &mut dyn Write) -> fmt::Result {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(());
+    }
+
+    let (name, arguments) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(out, arguments),
+        None => writeln!(out, "{}: command not found", name),
+    }
+}
+
+// Read and run commands from a serial port, forever.
+pub fn run(com: Com) -> ! {
+    let mut discipline = LineDiscipline::new();
+    let mut terminal = Terminal(com);
+
+    loop {
+        // Output errors can't be reported anywhere, as the output is where
+        // they would be reported to, so they are ignored.
+        let _ = write!(terminal, "rustos> ");
+        let line = discipline.read_line(com);
+        let _ = execute(line, &mut terminal);
+    }
+}
+
+// Terminals expect a carriage return before each line feed to move the cursor
+// back to the start of the line, so add one to every line feed written.
+struct Terminal(Com);
+
+impl Write for Terminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, part) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write(b"\r\n");
+            }
+            self.0.write(part.as_bytes());
+        }
+
+        Ok(())
+    }
+}
+
+
+// COMMANDS
+
+fn help(out: &mut dyn Write, _arguments: &str) -> fmt::Result {
+    for command in COMMANDS {
+        writeln!(out, "{:<10} {}", command.name, command.help)?;
+    }
+
+    Ok(())
+}
+
+fn echo(out: &mut dyn Write, arguments: &str) -> fmt::Result {
+    writeln!(out, "{}", arguments)
+}
+
+fn log_level(out: &mut dyn Write, arguments: &str) -> fmt::Result {
+    if !arguments.is_empty() {
+        if let Err(error) = logger::set_level_from_str(arguments) {
+            return writeln!(out, "log: {}", error);
+        }
+    }
+
+    writeln!(out, "{}", logger::level())
+}
+
+fn dmesg(out: &mut dyn Write, _arguments: &str) -> fmt::Result {
+    let mut contents = [0; console::RING_BUFFER_SIZE];
+    let count = console::RING_BUFFER.contents(&mut contents);
+
+    // The oldest output may start part of the way through a multi-byte
+    // character, so write each byte as a character rather than trusting the
+    // buffer to be valid UTF-8.
+    for byte in &contents[..count] {
+        let character = if byte.is_ascii() { *byte as char } else { '?' };
+        out.write_char(character)?;
+    }
+
+    Ok(())
+}
+
+fn date(out: &mut dyn Write, _arguments: &str) -> fmt::Result {
+    match time::wall_clock() {
+        Some(wall_clock) => {
+            let date = time::DateTime::from_unix(wall_clock.as_secs());
+            writeln!(out, "{} UTC", date)
+        }
+        None => writeln!(out, "date: the wall clock hasn't been set"),
+    }
+}
+
+
+// TESTING
+
+#[cfg(test)]
+use crate::testing::Output;
+
+// Test that a command is found and given its arguments, and that unknown
+// commands are reported.
+#[test_case]
+fn test_shell_execute() {
+    let mut output = Output::new();
+    execute("  echo   hello world ", &mut output).unwrap();
+    execute("frobnicate", &mut output).unwrap();
+
+    assert_eq!(output.as_str(),
+               "hello world\nfrobnicate: command not found\n");
+}
+
+// Test that the log command changes the log level.
+#[test_case]
+fn test_shell_log_level() {
+    let mut output = Output::new();
+    execute("log trace", &mut output).unwrap();
+
+    assert_eq!(output.as_str(), "TRACE\n");
+    assert_eq!(logger::level(), log::LevelFilter::Trace);
+
+    logger::set_level(logger::DEFAULT_LEVEL);
+}
+
+// Test that the date command prints the date QEMU's RTC was started at.
+#[test_case]
+fn test_shell_date() {
+    let mut output = Output::new();
+    execute("date", &mut output).unwrap();
+
+    assert!(output.as_str().starts_with("2021-06-15 "));
+    assert!(output.as_str().ends_with(" UTC\n"));
+}
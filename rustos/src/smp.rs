@@ -0,0 +1,319 @@
+// Symmetric Multiprocessing (SMP). The firmware only starts one CPU, the
+// Bootstrap Processor (BSP), which runs the bootloader and the kernel. The
+// other CPUs, the Application Processors (APs), wait, halted, until the BSP
+// starts them, by sending each one, through the local APICs:
+// 1. An INIT IPI, which resets the AP. It then needs 10ms to settle.
+// 2. A Startup IPI (SIPI), which starts the AP running in 16-bit real mode,
+//    at the start of the 4KiB page of low memory given in the IPI.
+// 3. A second SIPI, 200us later, in case the first was missed. Intel's
+//    MultiProcessor Specification asks for both.
+// The MADT says how many CPUs there are, and their local APIC IDs.
+// ---
+// An AP starts in the same state as the CPU does at power on, so the code at
+// that page, the trampoline, has to take it through the steps the bootloader
+// took the BSP through:
+// 1. Real mode: Load a temporary GDT, and turn on protected mode.
+// 2. Protected mode: Turn on Physical Address Extension, load the kernel's
+//    page tables into CR3, turn on long mode in the EFER MSR, and then turn
+//    on paging, which puts the CPU into long mode.
+// 3. Long mode: Switch to the AP's stack, and call ap_main, which sets up the
+//    AP's GDT, TSS, IDT and local APIC, in the same way as for the BSP.
+// The trampoline is written in assembly in the kernel, and copied to the page
+// at TRAMPOLINE_ADDRESS before the APs are started. It keeps running from
+// there as paging is turned on, so that page is identity mapped. The APs are
+// started one at a time, so they can share the trampoline's parameters, which
+// the BSP fills in for each. An AP sets the acknowledged parameter once it has
+// read the rest, and they aren't filled in again until it has, so an AP which
+// is slow to start can't pick up the next one's stack.
+
+use core::fmt;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::PhysAddr;
+use crate::acpi;
+use crate::apic;
+use crate::memory::{self, MapError};
+use crate::percpu::{self, MAX_CPUS};
+use crate::time::pit;
+use crate::{gdt, interrupts};
+
+// Has to match the address the trampoline's assembly is written for.
+const TRAMPOLINE_ADDRESS: u64 = 0x8000;
+
+const AP_STACK_SIZE: usize = 4096 * 16;
+
+// How long to wait for an AP to check in, before giving up on it.
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const EFER_LONG_MODE_ACTIVE: u64 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    // The APs are started through the local APIC.
+    NoApic,
+    NoMadt,
+    // The trampoline can only load page tables below 4GiB into CR3, as it
+    // does it in protected mode.
+    PageTablesTooHigh,
+    Trampoline(MapError),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SmpError::NoApic => write!(f, "the APICs aren't in use"),
+            SmpError::NoMadt => write!(f, "there is no MADT ACPI table"),
+            SmpError::PageTablesTooHigh => {
+                write!(f, "the page tables are above 4GiB")
+            }
+            SmpError::Trampoline(error) => {
+                write!(f, "the trampoline can't be mapped: {}", error)
+            }
+        }
+    }
+}
+
+// The parameters the BSP fills in at smp_trampoline_parameters, for the AP
+// being started. The trampoline reads them at fixed offsets.
+#[repr(C)]
+struct TrampolineParameters {
+    cr3: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+    acknowledged: u64,
+}
+
+// The stacks the APs run on, one for each CPU. The BSP keeps the one the
+// bootloader gave it.
+static mut AP_STACKS: [[u8; AP_STACK_SIZE]; MAX_CPUS] =
+    [[0; AP_STACK_SIZE]; MAX_CPUS];
+
+// The trampoline. Every address it uses is worked out from where it is copied
+// to, as the kernel is linked elsewhere. The far jumps between the modes are
+// written out as bytes, as there's no Intel syntax for them.
+global_asm!(r#"
+.global smp_trampoline_start
+.global smp_trampoline_parameters
+.global smp_trampoline_end
+
+.code16
+smp_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+    lgdt [smp_trampoline_gdtr - smp_trampoline_start + 0x8000]
+    mov eax, cr0
+    or eax, 1
+    mov cr0, eax
+    .byte 0x66, 0xea
+    .long smp_trampoline_32 - smp_trampoline_start + 0x8000
+    .word 0x08
+
+.code32
+smp_trampoline_32:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+    mov eax, [smp_trampoline_parameters - smp_trampoline_start + 0x8000]
+    mov cr3, eax
+    mov ecx, 0xc0000080
+    mov eax, [smp_trampoline_parameters - smp_trampoline_start + 0x8008]
+    xor edx, edx
+    wrmsr
+    mov eax, cr0
+    or eax, 0x80010000
+    mov cr0, eax
+    .byte 0xea
+    .long smp_trampoline_64 - smp_trampoline_start + 0x8000
+    .word 0x18
+
+.code64
+smp_trampoline_64:
+    xor eax, eax
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov rsp, [smp_trampoline_parameters - smp_trampoline_start + 0x8010]
+    mov rax, [smp_trampoline_parameters - smp_trampoline_start + 0x8018]
+    mov rdi, [smp_trampoline_parameters - smp_trampoline_start + 0x8020]
+    mov qword ptr [smp_trampoline_parameters - smp_trampoline_start + 0x8028], 1
+    call rax
+1:
+    hlt
+    jmp 1b
+
+.balign 8
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00cf9a000000ffff
+    .quad 0x00cf92000000ffff
+    .quad 0x00af9a000000ffff
+smp_trampoline_gdtr:
+    .word smp_trampoline_gdtr - smp_trampoline_gdt - 1
+    .long smp_trampoline_gdt - smp_trampoline_start + 0x8000
+
+.balign 8
+smp_trampoline_parameters:
+    .quad 0, 0, 0, 0, 0, 0
+smp_trampoline_end:
+"#);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_parameters: u8;
+    static smp_trampoline_end: u8;
+}
+
+// Start every enabled CPU in the MADT, returning how many CPUs are online. This
+// has to be called on the BSP, once the APICs are set up.
+pub fn init() -> Result<usize, SmpError> {
+    if !apic::enabled() {
+        return Err(SmpError::NoApic);
+    }
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .ok_or(SmpError::NoMadt)?;
+
+    let bsp = percpu::current();
+    bsp.set_apic_id(apic::id());
+    bsp.set_online();
+
+    let mut aps = madt.processors().iter()
+        .filter(|processor| processor.enabled)
+        .filter(|processor| processor.apic_id != bsp.apic_id())
+        .peekable();
+    if aps.peek().is_none() {
+        return Ok(1);
+    }
+
+    install_trampoline()?;
+
+    // A CPU which doesn't check in in time may still start later, so its
+    // number isn't given to the next one.
+    for (cpu, processor) in (1..MAX_CPUS).zip(&mut aps) {
+        if start(cpu, processor.apic_id)? {
+            continue;
+        }
+        log::warn!("CPU with APIC ID {} didn't start", processor.apic_id);
+
+        // If it hasn't read its parameters yet, it may still do so after they
+        // have been filled in for the next CPU.
+        let parameters = parameters()?;
+        let acknowledged = unsafe {
+            core::ptr::read_volatile(&(*parameters).acknowledged)
+        };
+        if acknowledged == 0 {
+            log::warn!("not starting any more CPUs");
+            return Ok(online_cpus());
+        }
+    }
+    if aps.next().is_some() {
+        log::warn!("only starting the first {} CPUs", MAX_CPUS);
+    }
+
+    Ok(online_cpus())
+}
+
+// The number of CPUs which have finished starting up.
+pub fn online_cpus() -> usize {
+    percpu::online().count()
+}
+
+// Identity map the trampoline's page, and copy the trampoline into it.
+fn install_trampoline() -> Result<(), SmpError> {
+    let address = PhysAddr::new(TRAMPOLINE_ADDRESS);
+    memory::identity_map(PhysFrame::containing_address(address),
+                         PageTableFlags::PRESENT | PageTableFlags::WRITABLE)
+        .map_err(SmpError::Trampoline)?;
+
+    let destination = memory::phys_to_virt(address)
+        .ok_or(SmpError::Trampoline(MapError::NotMapped))?;
+    unsafe {
+        let start = &smp_trampoline_start as *const u8;
+        let length = &smp_trampoline_end as *const u8 as usize
+            - start as usize;
+        core::ptr::copy_nonoverlapping(start, destination.as_mut_ptr(),
+                                       length);
+    }
+
+    Ok(())
+}
+
+// The trampoline's parameters, in the copy of it in low memory.
+fn parameters() -> Result<*mut TrampolineParameters, SmpError> {
+    let offset = unsafe {
+        &smp_trampoline_parameters as *const u8 as u64
+            - &smp_trampoline_start as *const u8 as u64
+    };
+    let address = PhysAddr::new(TRAMPOLINE_ADDRESS + offset);
+    let address = memory::phys_to_virt(address)
+        .ok_or(SmpError::Trampoline(MapError::NotMapped))?;
+    Ok(address.as_mut_ptr())
+}
+
+// Start one AP, as the given CPU, returning whether it checked in.
+fn start(cpu: usize, apic_id: u32) -> Result<bool, SmpError> {
+    let (level_4_table, _) = Cr3::read();
+    let cr3 = level_4_table.start_address().as_u64();
+    if cr3 > u64::from(u32::MAX) {
+        return Err(SmpError::PageTablesTooHigh);
+    }
+
+    let stack = unsafe { &AP_STACKS[cpu] } as *const _ as u64;
+    let parameters = TrampolineParameters {
+        cr3,
+        // The AP needs the same features turned on as the BSP, such as the
+        // no-execute bit the page tables use.
+        efer: unsafe { Msr::new(IA32_EFER).read() } & !EFER_LONG_MODE_ACTIVE,
+        // The System V calling convention needs the stack 16-byte aligned.
+        stack: (stack + AP_STACK_SIZE as u64) & !0xf,
+        entry: ap_main as u64,
+        cpu: cpu as u64,
+        acknowledged: 0,
+    };
+    unsafe { core::ptr::write_volatile(self::parameters()?, parameters) };
+
+    let per_cpu = percpu::get(cpu).expect("CPU number out of range");
+    let page = (TRAMPOLINE_ADDRESS >> 12) as u8;
+
+    apic::send_init(apic_id);
+    pit::delay_us(10_000);
+    apic::send_startup(apic_id, page);
+    pit::delay_us(200);
+    if !per_cpu.online() {
+        apic::send_startup(apic_id, page);
+    }
+
+    for _ in 0..STARTUP_TIMEOUT_MS {
+        if per_cpu.online() {
+            return Ok(true);
+        }
+        pit::delay_us(1000);
+    }
+    Ok(per_cpu.online())
+}
+
+// Where the trampoline leaves each AP, on its own stack.
+extern "C" fn ap_main(cpu: usize) -> ! {
+    gdt::init_cpu(cpu);
+    percpu::init(cpu);
+    interrupts::init_idt();
+    apic::init_ap();
+
+    let per_cpu = percpu::current();
+    per_cpu.set_apic_id(apic::id());
+    per_cpu.set_online();
+    log::debug!("CPU {} online, with APIC ID {}", cpu, per_cpu.apic_id());
+
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop();
+}
@@ -0,0 +1,782 @@
+// The custom test framework. The compiler collects every item marked with
+// #[test_case] into a slice and passes it to test_runner, which runs each test
+// and reports the results to the host over the serial port, and the net
+// console if it is on.
+// ---
+// A #[test_case] can either be a plain function, which is expected to return
+// without panicking, or a static TestCase, which carries extra information
+// about the test:
+//
+//     #[test_case]
+//     static DIVIDE_BY_ZERO: TestCase = rustos::test_fn!(divide_by_zero)
+//         .should_panic_with("attempt to divide by zero");
+//
+// - should_panic: The test passes only if it panics.
+// - should_panic_with: The test passes only if it panics, and the panic
+//               message contains the given text.
+// - ignore: The test is skipped, but is still listed in the output.
+// - timeout: The number of seconds the test may run for before it fails, in
+//               place of the default from the test-timeout command line option.
+//
+// Every test is run, even if an earlier one fails, and the run finishes with a
+// list of the failed tests and a count of each result. QEMU is only told the
+// run failed once all the tests have finished. The results can be printed in
+// a machine readable format, see the output module.
+//
+// Recovering From Panics
+// Our kernel is built with panic=abort, so a panic doesn't unwind the stack
+// back to the runner like it would in a normal Rust program. Instead it calls
+// the #[panic_handler] of the test binary, which passes it on to
+// test_panic_handler, and that never returns.
+//
+// To let the runner carry on after any test panics, each test is run through
+// rustos_try_call. Before calling the test it saves the callee-saved registers
+// and the stack pointer into a JumpContext. If the test panics, the panic
+// handler calls rustos_jump_back, which restores those registers and returns
+// from rustos_try_call a second time, as if the test had returned. Everything
+// the test had put on the stack is simply abandoned. This is the same trick as
+// C's setjmp and longjmp.
+//
+// The same jump is used to stop tests which run for too long. The timer
+// interrupt handler calls check_watchdog, which, once the current test's
+// deadline has passed, points the interrupted code at rustos_timed_out. That
+// makes the jump back to the runner as soon as the handler has returned, so
+// the handler still finishes with iretq.
+//
+// The watchdog only catches tests which hang with interrupts enabled. A test
+// which hangs with them disabled, such as by spinning on a lock inside
+// without_interrupts, never takes the timer interrupt, so it still has to be
+// stopped by bootimage's test-timeout. Catching those would need a watchdog
+// driven by a Non-Maskable Interrupt, which can arrive anywhere, including
+// inside an interrupt handler. Jumping out of one would leave its locks held
+// and its interrupt never acknowledged, so the runner couldn't be relied on to
+// carry on afterwards, which is what the watchdog is for.
+//
+// Each CPU has its own context to jump back to, so a test which gets another
+// CPU to run code, as the SMP tests do, isn't jumped back to on that CPU's
+// stack if the code panics there. A panic on a CPU which isn't running a test
+// fails the run, as a panic outside of a test does.
+//
+// As nothing the test owned is dropped, any locks it held at the time stay
+// locked. We force the locks of the character devices, which the runner needs
+// to report results, to be released, but a test which panics while holding
+// other locks may cause later tests to fail.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+use crate::{exit_qemu, hlt_loop, serial_println, QemuExitCode};
+use crate::cmdline::{self, CommandLine};
+use crate::device::{self, Class, Interface};
+use crate::percpu::{self, MAX_CPUS};
+use crate::serial::Com;
+
+pub mod output;
+
+pub use output::{set_format, Format};
+
+// Whether a test is expected to panic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShouldPanic {
+    No,
+    Yes,
+
+    // The test must panic with a message containing the given text.
+    YesWithMessage(&'static str),
+}
+
+// Create a new trait 'Testable' which enables the runner to get the name of
+// each test and the information about how it should be run, as well as
+// running it.
+pub trait Testable {
+    fn name(&self) -> &str;
+
+    fn run(&self);
+
+    fn should_panic(&self) -> ShouldPanic {
+        ShouldPanic::No
+    }
+
+    fn ignore(&self) -> bool {
+        false
+    }
+
+    // The number of seconds the test may run for, if it is different from the
+    // default.
+    fn timeout(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<T> Testable for T
+where T: Fn(), {
+    fn name(&self) -> &str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        // We are able to get the method to run itself becuase we have told the
+        // compiler that we require the type to have the 'Fn' trait.
+        self();
+    }
+}
+
+// A test along with its metadata. These are built with const functions, so
+// they can be used to initialise a #[test_case] static.
+pub struct TestCase {
+    pub name: &'static str,
+    pub function: fn(),
+    pub should_panic: ShouldPanic,
+    pub ignore: bool,
+    pub timeout: Option<u64>,
+}
+
+impl TestCase {
+    pub const fn new(name: &'static str, function: fn()) -> TestCase {
+        TestCase {
+            name,
+            function,
+            should_panic: ShouldPanic::No,
+            ignore: false,
+            timeout: None,
+        }
+    }
+
+    pub const fn should_panic(self) -> TestCase {
+        TestCase { should_panic: ShouldPanic::Yes, ..self }
+    }
+
+    pub const fn should_panic_with(self, expected: &'static str) -> TestCase {
+        TestCase { should_panic: ShouldPanic::YesWithMessage(expected), ..self }
+    }
+
+    pub const fn ignore(self) -> TestCase {
+        TestCase { ignore: true, ..self }
+    }
+
+    // Fail the test if it runs for longer than the given number of seconds. A
+    // timeout of 0 lets it run forever.
+    pub const fn timeout(self, secs: u64) -> TestCase {
+        TestCase { timeout: Some(secs), ..self }
+    }
+}
+
+impl Testable for TestCase {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn run(&self) {
+        (self.function)();
+    }
+
+    fn should_panic(&self) -> ShouldPanic {
+        self.should_panic
+    }
+
+    fn ignore(&self) -> bool {
+        self.ignore
+    }
+
+    fn timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+}
+
+// Create a TestCase for a function, named after its path in the same way as
+// plain #[test_case] functions are.
+#[macro_export]
+macro_rules! test_fn {
+    ($function:ident) => {
+        $crate::testing::TestCase::new(
+            concat!(module_path!(), "::", stringify!($function)),
+            $function,
+        )
+    };
+}
+
+
+// PANIC RECOVERY
+
+// The registers which have to be restored to return from rustos_try_call. The
+// layout must match the offsets used in the assembly below.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct JumpContext {
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rsp: u64,
+}
+
+// rustos_try_call(function, data, context) calls function(data), returning 0
+// once it returns. rustos_jump_back(context, value) makes the matching call to
+// rustos_try_call return the given value instead, which must not be 0.
+// ---
+// The stack pointer is saved before it is moved down by 8 bytes, so that it is
+// 16-byte aligned at the call, as the System V calling convention requires.
+global_asm!(r#"
+.global rustos_try_call
+rustos_try_call:
+    mov [rdx + 0x00], rbx
+    mov [rdx + 0x08], rbp
+    mov [rdx + 0x10], r12
+    mov [rdx + 0x18], r13
+    mov [rdx + 0x20], r14
+    mov [rdx + 0x28], r15
+    mov [rdx + 0x30], rsp
+    sub rsp, 8
+    mov rax, rdi
+    mov rdi, rsi
+    call rax
+    add rsp, 8
+    xor eax, eax
+    ret
+
+.global rustos_jump_back
+rustos_jump_back:
+    mov rbx, [rdi + 0x00]
+    mov rbp, [rdi + 0x08]
+    mov r12, [rdi + 0x10]
+    mov r13, [rdi + 0x18]
+    mov r14, [rdi + 0x20]
+    mov r15, [rdi + 0x28]
+    mov rsp, [rdi + 0x30]
+    mov rax, rsi
+    ret
+"#);
+
+// rustos_timed_out is returned to in place of the instruction the timer
+// interrupted, when the test has run for too long. The interrupted code's
+// stack pointer may not be aligned, so it is aligned before the call.
+global_asm!(r#"
+.global rustos_timed_out
+rustos_timed_out:
+    and rsp, -16
+    call rustos_jump_back_timed_out
+"#);
+
+extern "C" {
+    fn rustos_try_call(function: extern "C" fn(*const u8), data: *const u8,
+                       context: *mut JumpContext) -> u64;
+    fn rustos_jump_back(context: *const JumpContext, value: u64) -> !;
+    fn rustos_timed_out() -> !;
+}
+
+#[no_mangle]
+extern "C" fn rustos_jump_back_timed_out() -> ! {
+    unsafe { rustos_jump_back(&CONTEXTS[percpu::current_index()],
+                              JUMP_TIMED_OUT) }
+}
+
+// The values rustos_try_call returns when the test panicked or timed out.
+const JUMP_PANICKED: u64 = 1;
+const JUMP_TIMED_OUT: u64 = 2;
+
+// For each CPU, the context to jump back to, and whether there is a test
+// running on it which can be jumped back from.
+const NO_CONTEXT: JumpContext = JumpContext {
+    rbx: 0, rbp: 0, r12: 0, r13: 0, r14: 0, r15: 0, rsp: 0,
+};
+const NOT_RECOVERABLE: AtomicBool = AtomicBool::new(false);
+static mut CONTEXTS: [JumpContext; MAX_CPUS] = [NO_CONTEXT; MAX_CPUS];
+static RECOVERABLE: [AtomicBool; MAX_CPUS] = [NOT_RECOVERABLE; MAX_CPUS];
+
+// For each CPU, the tick count at which its running test times out, or 0 if
+// it can run forever.
+const NO_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static DEADLINES: [AtomicU64; MAX_CPUS] = [NO_DEADLINE; MAX_CPUS];
+
+// The number of seconds a test may run for, unless it sets its own timeout or
+// the test-timeout command line option is given.
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+
+// The message and source location of the last panic caught while running a
+// test.
+static mut PANIC_MESSAGE: Message = Message::new();
+static mut PANIC_LOCATION: Message = Message::new();
+
+// Holds a panic message, or the reason a test failed. As we have no heap, the
+// message is cut short if it is longer than the buffer.
+struct Message {
+    bytes: [u8; 512],
+    len: usize,
+}
+
+impl Message {
+    const fn new() -> Message {
+        Message { bytes: [0; 512], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        // Cutting the message short could have split a multi-byte character,
+        // so only return the part which is valid.
+        match core::str::from_utf8(&self.bytes[..self.len]) {
+            Ok(message) => message,
+            Err(error) => core::str::from_utf8(
+                &self.bytes[..error.valid_up_to()]).unwrap_or(""),
+        }
+    }
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = core::cmp::min(s.len(), self.bytes.len() - self.len);
+        self.bytes[self.len..self.len + count]
+            .copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+// The way a test finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Returned,
+    Panicked,
+    TimedOut,
+}
+
+// Calls the test through rustos_try_call, which is given a pointer to the
+// &dyn Testable, as a fat pointer can't be passed to assembly directly.
+extern "C" fn call_test(data: *const u8) {
+    let test = unsafe { &*(data as *const &dyn Testable) };
+    test.run();
+}
+
+// Run a test, catching it if it panics or runs for longer than the given
+// number of timer ticks. A timeout of 0 lets it run forever.
+// ---
+// A test may use this to run another test, so the context of any test which
+// is already running is put back afterwards.
+fn run_protected(test: &dyn Testable, timeout: u64) -> Outcome {
+    let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+    let data = &test as *const &dyn Testable as *const u8;
+
+    let deadline = match timeout {
+        0 => 0,
+        _ => crate::interrupts::ticks() + timeout,
+    };
+
+    let cpu = percpu::current_index();
+    let result = unsafe {
+        let outer_context = CONTEXTS[cpu];
+        let outer_recoverable = RECOVERABLE[cpu].load(Ordering::SeqCst);
+        let outer_deadline = DEADLINES[cpu].load(Ordering::SeqCst);
+
+        PANIC_MESSAGE.len = 0;
+        PANIC_LOCATION.len = 0;
+        DEADLINES[cpu].store(deadline, Ordering::SeqCst);
+        RECOVERABLE[cpu].store(true, Ordering::SeqCst);
+        let result = rustos_try_call(call_test, data, &mut CONTEXTS[cpu]);
+
+        CONTEXTS[cpu] = outer_context;
+        DEADLINES[cpu].store(outer_deadline, Ordering::SeqCst);
+        RECOVERABLE[cpu].store(outer_recoverable, Ordering::SeqCst);
+        result
+    };
+
+    if result == 0 {
+        return Outcome::Returned;
+    }
+
+    // The test panicked or was stopped, possibly with interrupts disabled or
+    // while holding the locks we need to report the result, so put things back
+    // the way they were before the test.
+    for device in device::by_class(Class::Char) {
+        if let Interface::Char(device) = device.interface {
+            unsafe { device.force_unlock() };
+        }
+    }
+    if interrupts_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+
+    match result {
+        JUMP_TIMED_OUT => Outcome::TimedOut,
+        _ => Outcome::Panicked,
+    }
+}
+
+// Called by the timer interrupt handler on every tick, with the frame it will
+// return to. If the running test has passed its deadline, the frame is made to
+// return to rustos_timed_out, which stops the test and jumps back to the
+// runner.
+pub fn check_watchdog(ticks: u64, stack_frame: &mut InterruptStackFrame) {
+    let cpu = percpu::current_index();
+    let deadline = DEADLINES[cpu].load(Ordering::SeqCst);
+    if deadline == 0 || ticks < deadline {
+        return;
+    }
+
+    if RECOVERABLE[cpu].swap(false, Ordering::SeqCst) {
+        DEADLINES[cpu].store(0, Ordering::SeqCst);
+        unsafe {
+            stack_frame.as_mut().instruction_pointer =
+                VirtAddr::new(rustos_timed_out as u64);
+        }
+    }
+}
+
+// The result of running a single test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestResult {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+// The number of failed tests which are listed again at the end of the run.
+// Any more are still counted, but aren't named in the summary.
+const FAILURES_MAX: usize = 32;
+
+// A tally of the results of a test run, along with the position in the list of
+// tests of the ones which failed.
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+
+    // The number of tests which weren't run because of the filter.
+    pub filtered: usize,
+    failures: [usize; FAILURES_MAX],
+}
+
+impl Summary {
+    pub const fn new() -> Summary {
+        Summary {
+            passed: 0,
+            failed: 0,
+            ignored: 0,
+            filtered: 0,
+            failures: [0; FAILURES_MAX],
+        }
+    }
+
+    pub fn record(&mut self, index: usize, result: TestResult) {
+        match result {
+            TestResult::Passed => self.passed += 1,
+            TestResult::Ignored => self.ignored += 1,
+            TestResult::Failed => {
+                if self.failed < FAILURES_MAX {
+                    self.failures[self.failed] = index;
+                }
+                self.failed += 1;
+            }
+        }
+    }
+
+    // The position of each failed test which was recorded, in order.
+    pub fn failures(&self) -> &[usize] {
+        &self.failures[..core::cmp::min(self.failed, FAILURES_MAX)]
+    }
+
+    pub fn success(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+impl Default for Summary {
+    fn default() -> Summary {
+        Summary::new()
+    }
+}
+
+// Where the runner writes the results: the first serial port, and the net
+// console too, if it has been turned on, so that the results can be collected
+// over the network.
+struct Results;
+
+impl Write for Results {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if crate::net::console::is_enabled() {
+            let _ = crate::net::console::write(s);
+        }
+        Com::Com1.write_str(s)
+    }
+}
+
+// Implement the custom test runner method. Every test is run, even after one
+// fails, and QEMU is only told whether the run failed once they have all
+// finished.
+// ---
+// Output errors are ignored, as the serial port is the only place they could
+// be reported.
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let cmdline = cmdline::get();
+    let filter = Filter::new(cmdline);
+
+    let mut format = output::format();
+    if let Some(name) = cmdline.value("test-format") {
+        match name.parse() {
+            Ok(parsed) => format = parsed,
+            Err(error) => serial_println!("test-format={}: {}", name, error),
+        }
+    }
+
+    let mut default_timeout = DEFAULT_TIMEOUT_SECS;
+    if let Some(secs) = cmdline.value("test-timeout") {
+        match secs.parse() {
+            Ok(parsed) => default_timeout = parsed,
+            Err(error) => serial_println!("test-timeout={}: {}", secs, error),
+        }
+    }
+
+    let out = &mut Results;
+    let selected = tests.iter().filter(|test| filter.matches(test.name()));
+    let _ = output::start(out, format, selected.count());
+
+    let mut summary = Summary::new();
+    let mut number = 0;
+
+    // Iterate through the list of tests, running each one which matches the
+    // filter and recording the result.
+    for (index, test) in tests.iter().enumerate() {
+        if !filter.matches(test.name()) {
+            summary.filtered += 1;
+            continue;
+        }
+        number += 1;
+
+        let _ = output::test_started(out, format, test.name());
+
+        let mut message = Message::new();
+        let start = (crate::interrupts::ticks(), timestamp());
+        let result = run_test(*test, default_timeout, &mut message);
+        let ticks = crate::interrupts::ticks() - start.0;
+        let duration = timestamp() - start.1;
+
+        let _ = output::test_finished(out, format, &output::Report {
+            number,
+            name: test.name(),
+            result,
+            ticks,
+            duration,
+            message: message.as_str(),
+        });
+        summary.record(index, result);
+    }
+
+    let _ = output::finish(out, format, &summary, tests);
+
+    // Exit QEMU
+    if summary.success() {
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+// Chooses which tests are run, using the same options as cargo test, which
+// tools/runner.py passes on to the kernel command line:
+// - test-filter=<text>: Only run tests whose name contains the text. If given
+//               more than once, tests matching any of them are run.
+// - test-skip=<text>: Don't run tests whose name contains the text, even if it
+//               matches a test-filter.
+// - test-exact: Match test names exactly, rather than by substring.
+//
+// Test names are full paths, starting with the name of the crate, such as
+// 'rustos::serial::line_discipline::test_line_discipline_backspace'.
+pub struct Filter<'a> {
+    cmdline: &'a CommandLine,
+    exact: bool,
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(cmdline: &'a CommandLine) -> Filter<'a> {
+        Filter { cmdline, exact: cmdline.has_flag("test-exact") }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let matches = |pattern: &str| {
+            if self.exact { name == pattern } else { name.contains(pattern) }
+        };
+
+        let mut includes = self.cmdline.values("test-filter").peekable();
+        let included = includes.peek().is_none() || includes.any(matches);
+
+        included && !self.cmdline.values("test-skip").any(matches)
+    }
+}
+
+// The time in nanoseconds, used to time each test.
+fn timestamp() -> u64 {
+    crate::time::now().as_nanos() as u64
+}
+
+// Run a single test, checking that it panicked if it should have. If it
+// failed, the reason is written to the given message.
+fn run_test(test: &dyn Testable, default_timeout: u64, message: &mut Message)
+        -> TestResult {
+    if test.ignore() {
+        return TestResult::Ignored;
+    }
+
+    let timeout = test.timeout().unwrap_or(default_timeout);
+    let ticks = crate::interrupts::ticks_from_secs(timeout);
+    let outcome = run_protected(test, ticks);
+    let (panic_message, location) = unsafe {
+        (PANIC_MESSAGE.as_str(), PANIC_LOCATION.as_str())
+    };
+
+    let _ = match (outcome, test.should_panic()) {
+        (Outcome::Returned, ShouldPanic::No) => return TestResult::Passed,
+
+        (Outcome::TimedOut, _) => {
+            write!(message, "{} timed out after {} seconds", test.name(),
+                   timeout)
+        }
+
+        (Outcome::Returned, _) => {
+            write!(message, "test did not panic as expected")
+        }
+
+        (Outcome::Panicked, ShouldPanic::No) => {
+            write!(message, "panicked at '{}', {}", panic_message, location)
+        }
+
+        (Outcome::Panicked, ShouldPanic::YesWithMessage(expected))
+            if !panic_message.contains(expected) => {
+            write!(message,
+                   "panic did not contain expected string\n  \
+                    panic message: `{}`\n expected substring: `{}`",
+                   panic_message, expected)
+        }
+
+        (Outcome::Panicked, _) => return TestResult::Passed,
+    };
+
+    TestResult::Failed
+}
+
+// Test-mode panic handler. If a test is running on this CPU, the panic message
+// is saved and the runner carries on with the next test. A panic anywhere
+// else, such as in the runner itself, is printed to the serial interface, and
+// QEMU exits with the fail status code.
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let cpu = percpu::current_index();
+    if RECOVERABLE[cpu].swap(false, Ordering::SeqCst) {
+        unsafe {
+            if let Some(message) = info.message() {
+                let _ = PANIC_MESSAGE.write_fmt(*message);
+            }
+            if let Some(location) = info.location() {
+                let _ = write!(PANIC_LOCATION, "{}", location);
+            }
+
+            rustos_jump_back(&CONTEXTS[cpu], JUMP_PANICKED);
+        }
+    }
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    let _ = crate::backtrace::write(&mut Com::Com1);
+
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+// TESTING
+
+// A writer which collects output into a fixed-size buffer, for the tests of
+// anything which writes to a fmt::Write.
+#[cfg(test)]
+pub struct Output {
+    buffer: [u8; 512],
+    len: usize,
+}
+
+#[cfg(test)]
+impl Output {
+    pub fn new() -> Output {
+        Output { buffer: [0; 512], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buffer[..self.len]).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buffer.get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn panics() {
+    panic!("expected panic from the testing module");
+}
+
+// Test that a test which panics as expected passes, and that the runner
+// carries on to the next test afterwards.
+#[test_case]
+static TEST_SHOULD_PANIC: TestCase = crate::test_fn!(panics)
+    .should_panic_with("expected panic");
+
+// Test that ignored tests aren't run, as this one would fail if it was.
+#[test_case]
+static TEST_IGNORE: TestCase = crate::test_fn!(panics).ignore();
+
+// Test that the summary counts each result, and remembers which tests failed.
+#[test_case]
+fn test_summary_records_failures() {
+    let mut summary = Summary::new();
+    summary.record(0, TestResult::Passed);
+    summary.record(1, TestResult::Failed);
+    summary.record(2, TestResult::Ignored);
+    summary.record(3, TestResult::Failed);
+
+    assert_eq!((summary.passed, summary.failed, summary.ignored), (1, 2, 1));
+    assert_eq!(summary.failures(), &[1, 3]);
+    assert!(!summary.success());
+}
+
+// Test that tests are chosen by substring, that skips take priority, and that
+// exact matching needs the whole name.
+#[test_case]
+fn test_filter() {
+    let cmdline = CommandLine::new("test-filter=serial test-filter=vga \
+                                    test-skip=backspace");
+    let filter = Filter::new(&cmdline);
+    assert!(filter.matches("rustos::serial::test_send"));
+    assert!(filter.matches("rustos::vga_buffer::test_println"));
+    assert!(!filter.matches("rustos::serial::test_backspace"));
+    assert!(!filter.matches("rustos::shell::test_shell_execute"));
+
+    let cmdline = CommandLine::new("test-exact test-filter=rustos::a");
+    let filter = Filter::new(&cmdline);
+    assert!(filter.matches("rustos::a"));
+    assert!(!filter.matches("rustos::ab"));
+
+    assert!(Filter::new(&CommandLine::empty()).matches("anything"));
+}
+
+#[cfg(test)]
+fn hangs() {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Test that the watchdog stops a test which never finishes, and that the
+// runner can then carry on with this one.
+#[test_case]
+fn test_watchdog_stops_hung_test() {
+    let hung = TestCase::new("hung", hangs);
+
+    assert_eq!(run_protected(&hung, 2), Outcome::TimedOut);
+    let ok = TestCase::new("ok", || {});
+    assert_eq!(run_protected(&ok, 2), Outcome::Returned);
+}
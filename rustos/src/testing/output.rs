@@ -0,0 +1,269 @@
+// The test runner can report results in a few different formats. The default
+// is the human readable 'name...	[ok]' style, but CI systems are much happier
+// with something they can parse, so there are also two machine readable ones:
+// - TAP: The Test Anything Protocol, version 13. Each test gets an 'ok' or
+//               'not ok' line, followed by a YAML block with its duration and
+//               any panic message.
+// - JSON lines: One JSON object per line, for the start of the run, each
+//               test, and the end of the run.
+// ---
+// The format is chosen at build time with the test-output-tap or
+// test-output-json cargo features, or can be changed before the tests run with
+// set_format. Each test's duration is given twice: in timer ticks, counted by
+// the timer interrupt (see interrupts::ticks), and in nanoseconds, measured
+// with the time module's clock, or 0 if the test binary didn't set it up. The
+// tick count is only as fine as the timer, so the nanoseconds are the ones
+// tools/test_output_to_junit.py uses.
+// ---
+// The tools/test_output_to_junit.py script turns either of the machine
+// readable formats into JUnit XML.
+
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use spin::Mutex;
+use super::{Summary, TestResult, Testable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Pretty,
+    Tap,
+    JsonLines,
+}
+
+impl Format {
+    // The format selected by the cargo features the kernel was built with.
+    pub const DEFAULT: Format = if cfg!(feature = "test-output-json") {
+        Format::JsonLines
+    } else if cfg!(feature = "test-output-tap") {
+        Format::Tap
+    } else {
+        Format::Pretty
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseFormatError;
+
+impl fmt::Display for ParseFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected one of pretty, tap or json")
+    }
+}
+
+impl FromStr for Format {
+    type Err = ParseFormatError;
+
+    fn from_str(s: &str) -> Result<Format, ParseFormatError> {
+        match s {
+            "pretty" => Ok(Format::Pretty),
+            "tap" => Ok(Format::Tap),
+            "json" => Ok(Format::JsonLines),
+            _ => Err(ParseFormatError),
+        }
+    }
+}
+
+static FORMAT: Mutex<Format> = Mutex::new(Format::DEFAULT);
+
+pub fn set_format(format: Format) {
+    *FORMAT.lock() = format;
+}
+
+pub fn format() -> Format {
+    *FORMAT.lock()
+}
+
+// Everything known about a test once it has finished.
+pub struct Report<'a> {
+    // The position of the test in the run, counting from 1.
+    pub number: usize,
+    pub name: &'a str,
+    pub result: TestResult,
+    // In timer ticks, and in nanoseconds.
+    pub ticks: u64,
+    pub duration: u64,
+
+    // Why the test failed, or an empty string if it didn't.
+    pub message: &'a str,
+}
+
+// Called once, before any tests are run.
+pub fn start(out: &mut dyn Write, format: Format, count: usize) -> fmt::Result {
+    match format {
+        Format::Pretty => writeln!(out, "Running {} tests", count),
+        Format::Tap => writeln!(out, "TAP version 13\n1..{}", count),
+        Format::JsonLines => writeln!(
+            out, "{{\"type\":\"suite\",\"event\":\"started\",\
+                  \"test_count\":{}}}",
+            count),
+    }
+}
+
+// Called just before a test is run. Only the pretty format prints anything
+// here, so that the name of a test which hangs is still shown.
+pub fn test_started(out: &mut dyn Write, format: Format, name: &str)
+        -> fmt::Result {
+    match format {
+        Format::Pretty => write!(out, "{}...\t", name),
+        Format::Tap | Format::JsonLines => Ok(()),
+    }
+}
+
+pub fn test_finished(out: &mut dyn Write, format: Format, report: &Report)
+        -> fmt::Result {
+    match format {
+        Format::Pretty => match report.result {
+            TestResult::Passed => writeln!(out, "[ok]"),
+            TestResult::Ignored => writeln!(out, "[ignored]"),
+            TestResult::Failed => {
+                writeln!(out, "[failed]\n\nError: {}\n", report.message)
+            }
+        },
+
+        Format::Tap => {
+            match report.result {
+                TestResult::Passed => {
+                    writeln!(out, "ok {} - {}", report.number, report.name)?
+                }
+                TestResult::Failed => {
+                    writeln!(out, "not ok {} - {}", report.number, report.name)?
+                }
+                TestResult::Ignored => {
+                    return writeln!(out, "ok {} - {} # SKIP ignored",
+                                    report.number, report.name);
+                }
+            }
+
+            writeln!(out, "  ---\n  duration_ticks: {}\n  duration_ns: {}",
+                     report.ticks, report.duration)?;
+            if !report.message.is_empty() {
+                // A block scalar keeps multi-line messages readable, as long
+                // as every line is indented.
+                writeln!(out, "  message: |")?;
+                for line in report.message.lines() {
+                    writeln!(out, "    {}", line)?;
+                }
+            }
+            writeln!(out, "  ...")
+        }
+
+        Format::JsonLines => {
+            let outcome = match report.result {
+                TestResult::Passed => "passed",
+                TestResult::Failed => "failed",
+                TestResult::Ignored => "ignored",
+            };
+
+            writeln!(out,
+                     "{{\"type\":\"test\",\"name\":{},\"outcome\":\"{}\",\
+                      \"duration_ticks\":{},\"duration_ns\":{},\
+                      \"message\":{}}}",
+                     JsonString(report.name), outcome, report.ticks,
+                     report.duration, JsonString(report.message))
+        }
+    }
+}
+
+// Called once all the tests have been run. The pretty format also lists the
+// failed tests by name.
+pub fn finish(out: &mut dyn Write, format: Format, summary: &Summary,
+              tests: &[&dyn Testable]) -> fmt::Result {
+    match format {
+        Format::Pretty => {
+            if !summary.success() {
+                writeln!(out, "\nfailures:")?;
+                for index in summary.failures() {
+                    writeln!(out, "    {}", tests[*index].name())?;
+                }
+                let unlisted = summary.failed - summary.failures().len();
+                if unlisted > 0 {
+                    writeln!(out, "    ... and {} more", unlisted)?;
+                }
+            }
+
+            writeln!(out, "\ntest result: {}. {} passed; {} failed; \
+                           {} ignored; {} filtered out\n",
+                     if summary.success() { "ok" } else { "FAILED" },
+                     summary.passed, summary.failed, summary.ignored,
+                     summary.filtered)
+        }
+
+        Format::Tap => writeln!(out, "# pass {}\n# fail {}\n# skip {}\n\
+                                      # filtered out {}",
+                                summary.passed, summary.failed,
+                                summary.ignored, summary.filtered),
+
+        Format::JsonLines => writeln!(
+            out, "{{\"type\":\"suite\",\"event\":\"finished\",\"passed\":{},\
+                  \"failed\":{},\"ignored\":{},\"filtered_out\":{}}}",
+            summary.passed, summary.failed, summary.ignored, summary.filtered),
+    }
+}
+
+// Writes a string as a quoted JSON string, escaping any characters which
+// aren't allowed in one.
+pub struct JsonString<'a>(pub &'a str);
+
+impl fmt::Display for JsonString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_char('"')?;
+        for character in self.0.chars() {
+            match character {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+
+// TESTING
+
+#[cfg(test)]
+use crate::testing::Output;
+
+// Test that a failed test is reported in TAP with its message in a YAML block.
+#[test_case]
+fn test_tap_failure() {
+    let mut output = Output::new();
+    let report = Report {
+        number: 2,
+        name: "a::b",
+        result: TestResult::Failed,
+        ticks: 22,
+        duration: 1234,
+        message: "first\nsecond",
+    };
+    test_finished(&mut output, Format::Tap, &report).unwrap();
+
+    assert_eq!(output.as_str(),
+               "not ok 2 - a::b\n  ---\n  duration_ticks: 22\n  \
+                duration_ns: 1234\n  \
+                message: |\n    first\n    second\n  ...\n");
+}
+
+// Test that strings are escaped in JSON lines output.
+#[test_case]
+fn test_json_lines_escaping() {
+    let mut output = Output::new();
+    let report = Report {
+        number: 1,
+        name: "a::b",
+        result: TestResult::Passed,
+        ticks: 0,
+        duration: 5,
+        message: "say \"hi\"\\\n",
+    };
+    test_finished(&mut output, Format::JsonLines, &report).unwrap();
+
+    assert_eq!(output.as_str(),
+               "{\"type\":\"test\",\"name\":\"a::b\",\"outcome\":\"passed\",\
+                \"duration_ticks\":0,\"duration_ns\":5,\
+                \"message\":\"say \\\"hi\\\"\\\\\\n\"}\n");
+}
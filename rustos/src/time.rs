@@ -0,0 +1,456 @@
+// Timekeeping. A clock source is a counter which counts up at a known rate,
+// and the kernel's clock is built on the best one available, which is, from
+// best to worst:
+// - TSC: The CPU's Time Stamp Counter, if it is invariant. The fastest to
+//               read, and as precise as the CPU's clock. See the tsc module.
+// - HPET: The High Precision Event Timer, if the ACPI tables list one. Reading
+//               it is slower, as it is outside the CPU. See the hpet module.
+// - PIT: The Programmable Interval Timer, which every PC has, but which is
+//               slow to read, and only counts at 1.193182MHz. See the pit
+//               module.
+// 'clocksource=<name>' on the kernel command line picks one by name instead.
+// ---
+// now returns the time since init was called, in nanoseconds, and never goes
+// backwards. Some counters are only 32 bits wide, and wrap around, so rather
+// than turning the count straight into a time, the counts since the last read
+// are added to a 64-bit total. As long as the clock is read at least once
+// each time the counter wraps, which the timer interrupt makes sure of, the
+// total is right.
+// ---
+// Timers
+// Kernel code can ask for a function to be called after a delay, once or
+// periodically, with add_timer and add_periodic_timer. The timers waiting to
+// fire are kept in a binary min-heap, ordered by the time they are due, so
+// the next one due is always at the front. run_timers is called on each timer
+// interrupt, and calls every function which is due, so timers are only as
+// precise as the interrupt's rate, TIMER_HZ with the local APIC timer.
+// ---
+// Wall clock
+// The clock only counts from boot, so init reads the date and time from the
+// CMOS RTC (see the rtc module), and remembers what it was when the clock
+// read 0. Adding the clock to that gives the wall clock, which then moves
+// forward with the clock rather than with the RTC, which only counts whole
+// seconds.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::cmdline;
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+pub use rtc::DateTime;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+// The most timers which can be waiting at once.
+pub const MAX_TIMERS: usize = 32;
+
+// A counter which counts up at a fixed rate, wrapping around at its mask.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    // The number of counts a second.
+    fn frequency(&self) -> u64;
+
+    // The highest value read can return, after which the counter wraps back
+    // round to 0.
+    fn mask(&self) -> u64;
+
+    fn read(&self) -> u64;
+}
+
+struct Clock {
+    source: &'static dyn ClockSource,
+    last: u64,
+    counts: u64,
+}
+
+static HPET: Once<hpet::Hpet> = Once::new();
+static TSC: Once<tsc::Tsc> = Once::new();
+
+// Only locked with interrupts disabled, as the timer interrupt reads it.
+static CLOCK: Mutex<Option<Clock>> = Mutex::new(None);
+
+// The Unix time, in nanoseconds, when the clock read 0, or 0 until the wall
+// clock has been set.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+// Find the clock sources, and start the clock with the best one.
+pub fn init() {
+    pit::init();
+    if let Some(hpet) = hpet::init() {
+        HPET.call_once(|| hpet);
+    }
+    if let Some(tsc) = tsc::init(hpet().map(|hpet| hpet as &dyn ClockSource)) {
+        TSC.call_once(|| tsc);
+    }
+
+    let mut source = sources().next().expect("there is always the PIT");
+    if let Some(name) = cmdline::get().value("clocksource") {
+        match sources().find(|source| source.name() == name) {
+            Some(requested) => source = requested,
+            None => log::warn!("ignoring unavailable clock source {}", name),
+        }
+    }
+
+    without_interrupts(|| {
+        *CLOCK.lock() = Some(Clock { source, last: source.read(), counts: 0 });
+    });
+    log::info!("using the {} clock source, at {}Hz", source.name(),
+               source.frequency());
+
+    let date = rtc::read();
+    match date.to_unix() {
+        Ok(seconds) => {
+            set_wall_clock(Duration::from_secs(seconds));
+            log::info!("the time is {} UTC", date);
+        }
+        Err(error) => log::warn!("not setting the wall clock: {}", error),
+    }
+}
+
+// The HPET, if there is one.
+pub fn hpet() -> Option<&'static hpet::Hpet> {
+    HPET.r#try()
+}
+
+// The invariant TSC, if the CPU has one.
+pub fn tsc() -> Option<&'static tsc::Tsc> {
+    TSC.r#try()
+}
+
+// The clock sources which are available, best first.
+pub fn sources() -> impl Iterator<Item = &'static dyn ClockSource> {
+    let tsc = tsc().map(|tsc| tsc as &dyn ClockSource);
+    let hpet = hpet().map(|hpet| hpet as &dyn ClockSource);
+    let pit = Some(&pit::PIT as &dyn ClockSource);
+
+    core::iter::once(tsc).chain(core::iter::once(hpet))
+        .chain(core::iter::once(pit))
+        .flatten()
+}
+
+// The clock source the clock is using, once init has been called.
+pub fn clock_source() -> Option<&'static dyn ClockSource> {
+    without_interrupts(|| CLOCK.lock().as_ref().map(|clock| clock.source))
+}
+
+// The time since init was called, or 0 before then.
+pub fn now() -> Duration {
+    let nanos = without_interrupts(|| {
+        let mut clock = CLOCK.lock();
+        let clock = match clock.as_mut() {
+            Some(clock) => clock,
+            None => return 0,
+        };
+
+        let count = clock.source.read();
+        clock.counts += count.wrapping_sub(clock.last) & clock.source.mask();
+        clock.last = count;
+
+        u128::from(clock.counts) * NANOS_PER_SECOND
+            / u128::from(clock.source.frequency())
+    });
+
+    Duration::from_nanos(nanos as u64)
+}
+
+// Set the wall clock to the given time since the Unix epoch.
+pub fn set_wall_clock(unix_time: Duration) {
+    let boot_time = unix_time.checked_sub(now()).unwrap_or_default();
+    BOOT_TIME.store(boot_time.as_nanos() as u64, Ordering::Relaxed);
+}
+
+// The time since the Unix epoch, or None if the wall clock hasn't been set.
+pub fn wall_clock() -> Option<Duration> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot_time => Some(Duration::from_nanos(boot_time) + now()),
+    }
+}
+
+// Wait for the given time, keeping the CPU busy. Before init, PIT channel 2
+// is used to time it.
+pub fn delay(duration: Duration) {
+    if clock_source().is_none() {
+        pit::delay_us(duration.as_micros() as u64);
+        return;
+    }
+
+    let end = now() + duration;
+    while now() < end {
+        core::hint::spin_loop();
+    }
+}
+
+
+// TIMERS
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    Full,
+}
+
+impl fmt::Display for TimerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimerError::Full => {
+                write!(f, "there are already {} timers waiting", MAX_TIMERS)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id: TimerId,
+    // When the timer is due, in nanoseconds on the clock.
+    deadline: u64,
+    // How often it fires, in nanoseconds, or 0 if it only fires once.
+    period: u64,
+    callback: fn(),
+}
+
+// A binary min-heap of timers. The timer at index i is due no later than
+// those at 2i + 1 and 2i + 2, so timers[0] is the next due.
+struct TimerQueue {
+    timers: [Option<Timer>; MAX_TIMERS],
+    len: usize,
+}
+
+impl TimerQueue {
+    const fn new() -> TimerQueue {
+        TimerQueue { timers: [None; MAX_TIMERS], len: 0 }
+    }
+
+    fn deadline(&self, index: usize) -> u64 {
+        self.timers[index].map_or(u64::MAX, |timer| timer.deadline)
+    }
+
+    fn push(&mut self, timer: Timer) -> Result<(), TimerError> {
+        if self.len == MAX_TIMERS {
+            return Err(TimerError::Full);
+        }
+
+        self.timers[self.len] = Some(timer);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        Ok(())
+    }
+
+    // Take the first timer, if it is due by the given time.
+    fn pop_due(&mut self, now: u64) -> Option<Timer> {
+        if self.len == 0 || self.deadline(0) > now {
+            return None;
+        }
+        self.remove_at(0)
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        let index = self.timers[..self.len].iter()
+            .position(|timer| timer.map(|timer| timer.id) == Some(id));
+        match index {
+            Some(index) => self.remove_at(index).is_some(),
+            None => false,
+        }
+    }
+
+    // Move the last timer into the gap, and then up or down to where it
+    // belongs.
+    fn remove_at(&mut self, index: usize) -> Option<Timer> {
+        let timer = self.timers[index].take();
+        self.len -= 1;
+        self.timers.swap(index, self.len);
+
+        if index < self.len {
+            self.sift_down(index);
+            self.sift_up(index);
+        }
+        timer
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.deadline(parent) <= self.deadline(index) {
+                break;
+            }
+            self.timers.swap(parent, index);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let mut first = index;
+            for child in [2 * index + 1, 2 * index + 2].iter() {
+                if *child < self.len
+                    && self.deadline(*child) < self.deadline(first) {
+                    first = *child;
+                }
+            }
+            if first == index {
+                break;
+            }
+            self.timers.swap(first, index);
+            index = first;
+        }
+    }
+}
+
+static TIMERS: Mutex<TimerQueue> = Mutex::new(TimerQueue::new());
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+fn add(delay: Duration, period: Duration, callback: fn())
+        -> Result<TimerId, TimerError> {
+    let id = TimerId(NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed));
+    let timer = Timer {
+        id,
+        deadline: (now() + delay).as_nanos() as u64,
+        period: period.as_nanos() as u64,
+        callback,
+    };
+
+    without_interrupts(|| TIMERS.lock().push(timer))?;
+    Ok(id)
+}
+
+// Call a function once, after the given delay. It is called from the timer
+// interrupt handler, so mustn't wait for anything.
+pub fn add_timer(delay: Duration, callback: fn())
+        -> Result<TimerId, TimerError> {
+    add(delay, Duration::from_secs(0), callback)
+}
+
+// Call a function every period, until the timer is cancelled.
+pub fn add_periodic_timer(period: Duration, callback: fn())
+        -> Result<TimerId, TimerError> {
+    add(period, period, callback)
+}
+
+// Stop a timer, returning false if it had already fired, or was cancelled.
+pub fn cancel_timer(id: TimerId) -> bool {
+    without_interrupts(|| TIMERS.lock().remove(id))
+}
+
+// Called on each timer interrupt, to call the timers which are due. The lock
+// isn't held while they are called, so they can add and cancel timers.
+pub fn run_timers() {
+    let now = now().as_nanos() as u64;
+
+    while let Some(mut timer) = without_interrupts(|| {
+        TIMERS.lock().pop_due(now)
+    }) {
+        (timer.callback)();
+
+        if timer.period == 0 {
+            continue;
+        }
+
+        // If the interrupts fell behind, skip the missed periods rather than
+        // calling the timer over and over to catch up.
+        timer.deadline += timer.period;
+        if timer.deadline <= now {
+            timer.deadline = now + timer.period;
+        }
+        if without_interrupts(|| TIMERS.lock().push(timer)).is_err() {
+            log::warn!("dropped a periodic timer, as there are too many");
+        }
+    }
+}
+
+
+// TESTING
+
+// Test that the timer queue gives timers back in the order they are due, and
+// that removing one keeps the rest in order.
+#[test_case]
+fn test_timer_queue_order() {
+    fn nothing() {}
+
+    let mut queue = TimerQueue::new();
+    for (id, deadline) in [50, 10, 40, 30, 20].iter().enumerate() {
+        let timer = Timer {
+            id: TimerId(id as u64),
+            deadline: *deadline,
+            period: 0,
+            callback: nothing,
+        };
+        queue.push(timer).unwrap();
+    }
+
+    assert!(queue.remove(TimerId(3)));
+    assert!(!queue.remove(TimerId(3)));
+    assert!(queue.pop_due(5).is_none());
+
+    let mut deadlines = [0; 4];
+    for deadline in deadlines.iter_mut() {
+        *deadline = queue.pop_due(100).unwrap().deadline;
+    }
+    assert_eq!(deadlines, [10, 20, 40, 50]);
+    assert!(queue.pop_due(u64::MAX).is_none());
+}
+
+// Test that the clock moves forward, and that a delay lasts as long as it
+// should, measured against the clock.
+#[test_case]
+fn test_delay() {
+    assert!(clock_source().is_some());
+
+    let start = now();
+    delay(Duration::from_millis(20));
+    let elapsed = now() - start;
+
+    assert!(elapsed >= Duration::from_millis(20));
+    assert!(elapsed < Duration::from_millis(500));
+}
+
+// Test that a one-shot timer and a periodic timer fire, and that a cancelled
+// timer doesn't.
+#[test_case]
+fn test_timers() {
+    static ONE_SHOT: AtomicU64 = AtomicU64::new(0);
+    static PERIODIC: AtomicU64 = AtomicU64::new(0);
+    static CANCELLED: AtomicU64 = AtomicU64::new(0);
+
+    add_timer(Duration::from_millis(10), || {
+        ONE_SHOT.fetch_add(1, Ordering::Relaxed);
+    }).unwrap();
+    let periodic = add_periodic_timer(Duration::from_millis(10), || {
+        PERIODIC.fetch_add(1, Ordering::Relaxed);
+    }).unwrap();
+    let cancelled = add_timer(Duration::from_millis(10), || {
+        CANCELLED.fetch_add(1, Ordering::Relaxed);
+    }).unwrap();
+    assert!(cancel_timer(cancelled));
+
+    let end = now() + Duration::from_secs(1);
+    while PERIODIC.load(Ordering::Relaxed) < 3 && now() < end {
+        x86_64::instructions::hlt();
+    }
+    assert!(cancel_timer(periodic));
+
+    assert_eq!(ONE_SHOT.load(Ordering::Relaxed), 1);
+    assert!(PERIODIC.load(Ordering::Relaxed) >= 3);
+    assert_eq!(CANCELLED.load(Ordering::Relaxed), 0);
+}
+
+// Test that the wall clock was set from the RTC, and moves forward with the
+// clock.
+#[test_case]
+fn test_wall_clock() {
+    let start = wall_clock().expect("the wall clock wasn't set");
+    assert_eq!(DateTime::from_unix(start.as_secs()).year, 2021);
+
+    delay(Duration::from_millis(10));
+    assert!(wall_clock().unwrap() >= start + Duration::from_millis(10));
+}
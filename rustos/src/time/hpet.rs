@@ -0,0 +1,108 @@
+// The High Precision Event Timer (HPET), which replaced the PIT and the RTC's
+// periodic interrupt. It has a main counter, which counts up at a fixed rate
+// of at least 10MHz, and a number of comparators, which raise an interrupt
+// when the counter reaches them. Only the main counter is used, as a clock
+// source.
+// ---
+// The HPET ACPI table says where its registers are mapped, which we access
+// through the mapping of physical memory. They are 64 bits wide:
+// - 0x000: General Capabilities. The top 32 bits are the period of the main
+//               counter, in femtoseconds (10^-15 seconds), and bit 13 is set
+//               if the counter is 64 bits wide, rather than 32.
+// - 0x010: General Configuration. Bit 0 starts the main counter.
+// - 0x0f0: The main counter.
+
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi;
+use crate::memory;
+use super::ClockSource;
+
+const GENERAL_CAPABILITIES: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const MAIN_COUNTER: u64 = 0x0f0;
+
+const CAPABILITIES_COUNTER_64BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+
+// The specification limits the period to 100ns.
+const PERIOD_MAX_FS: u64 = 100_000_000;
+
+pub struct Hpet {
+    base: VirtAddr,
+    frequency: u64,
+    mask: u64,
+}
+
+impl Hpet {
+    unsafe fn read_register(&self, register: u64) -> u64 {
+        core::ptr::read_volatile((self.base + register).as_ptr())
+    }
+
+    unsafe fn write_register(&self, register: u64, value: u64) {
+        core::ptr::write_volatile((self.base + register).as_mut_ptr(), value);
+    }
+}
+
+// Find the HPET through the ACPI tables, and start its main counter. Returns
+// None if there isn't one, or it can't be used.
+pub fn init() -> Option<Hpet> {
+    let table = acpi::get()?.hpet.as_ref()?;
+    let base = memory::phys_to_virt(PhysAddr::new(table.base_address))?;
+    let mut hpet = Hpet { base, frequency: 0, mask: 0 };
+
+    unsafe {
+        let capabilities = hpet.read_register(GENERAL_CAPABILITIES);
+        let period = capabilities >> 32;
+        if period == 0 || period > PERIOD_MAX_FS {
+            log::warn!("ignoring the HPET, as its period is {}fs", period);
+            return None;
+        }
+
+        hpet.frequency = FEMTOSECONDS_PER_SECOND / period;
+        hpet.mask = if capabilities & CAPABILITIES_COUNTER_64BIT != 0 {
+            u64::MAX
+        } else {
+            u64::from(u32::MAX)
+        };
+
+        let configuration = hpet.read_register(GENERAL_CONFIGURATION);
+        hpet.write_register(GENERAL_CONFIGURATION,
+                            configuration | CONFIGURATION_ENABLE);
+    }
+
+    Some(hpet)
+}
+
+impl ClockSource for Hpet {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    fn read(&self) -> u64 {
+        unsafe { self.read_register(MAIN_COUNTER) & self.mask }
+    }
+}
+
+
+// TESTING
+
+// Test that QEMU's HPET is found, runs at a believable rate, and counts up.
+#[test_case]
+fn test_hpet_counts() {
+    let hpet = super::hpet().expect("no HPET was found");
+    assert!(hpet.frequency() >= 10_000_000);
+
+    let start = hpet.read();
+    crate::time::pit::delay_us(1000);
+    assert!(hpet.read().wrapping_sub(start) & hpet.mask() > 0);
+}
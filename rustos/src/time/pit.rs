@@ -0,0 +1,147 @@
+// The 8253/8254 Programmable Interval Timer (PIT), which every PC has. Its
+// input clock runs at 1.193182MHz, and it has three channels, each counting
+// down from a value it is given:
+// - Channel 0: Raises IRQ 0 each time it reaches zero. It counts down from
+//               DIVISOR, so fires about 18.2 times a second.
+// - Channel 1: Once used to refresh memory, and no longer there.
+// - Channel 2: Drives the PC speaker. Its gate and output can be read and
+//               written through port 0x61, so it can be polled, without
+//               needing an interrupt, which makes it useful for measuring
+//               other clocks, and short delays.
+// ---
+// As a clock source, the count of channel 0 is combined with the number of
+// timer interrupts so far. Reading the count means latching it first, so that
+// the two bytes come from the same count, and the BIOS leaves channel 0 in
+// mode 3 (square wave), which counts down twice each period, so init switches
+// it to mode 2 (rate generator), which counts down once.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use crate::interrupts;
+use super::ClockSource;
+
+// The rate of the PIT's input clock.
+pub const FREQUENCY_HZ: u64 = 1_193_182;
+
+// What channel 0 counts down from. A count of 0 is taken as 65536.
+pub const DIVISOR: u64 = 65_536;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+
+// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const COMMAND_CHANNEL_0_RATE: u8 = 0b0011_0100;
+// Channel 0, latch the count.
+const COMMAND_CHANNEL_0_LATCH: u8 = 0b0000_0000;
+// Channel 2, low byte then high byte, mode 1 (one-shot), binary.
+const COMMAND_CHANNEL_2_ONE_SHOT: u8 = 0b1011_0010;
+
+// Port 0x61 controls the PC speaker, which is driven by PIT channel 2. Bit 0
+// is channel 2's gate, bit 1 connects it to the speaker, and bit 5 reads back
+// channel 2's output.
+const SPEAKER_PORT: u16 = 0x61;
+const SPEAKER_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const SPEAKER_OUTPUT: u8 = 1 << 5;
+
+pub struct Pit {
+    // The highest count read so far. See read.
+    last: AtomicU64,
+}
+
+pub static PIT: Pit = Pit { last: AtomicU64::new(0) };
+
+// Put channel 0 into mode 2, still counting down from DIVISOR.
+pub fn init() {
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        command.write(COMMAND_CHANNEL_0_RATE);
+        channel_0.write(DIVISOR as u8);
+        channel_0.write((DIVISOR >> 8) as u8);
+    });
+}
+
+impl ClockSource for Pit {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn frequency(&self) -> u64 {
+        FREQUENCY_HZ
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    // If channel 0 has just reached zero, but its interrupt hasn't been
+    // handled yet, the count goes back up before the number of interrupts
+    // does, so the result is never allowed to go backwards.
+    fn read(&self) -> u64 {
+        let mut command: Port<u8> = Port::new(COMMAND_PORT);
+        let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+
+        let count = x86_64::instructions::interrupts::without_interrupts(|| {
+            let ticks = interrupts::ticks();
+            let remaining = unsafe {
+                command.write(COMMAND_CHANNEL_0_LATCH);
+                let low = channel_0.read();
+                let high = channel_0.read();
+                u64::from(u16::from_le_bytes([low, high]))
+            };
+            let remaining = if remaining == 0 { DIVISOR } else { remaining };
+
+            ticks * DIVISOR + (DIVISOR - remaining)
+        });
+
+        let last = self.last.fetch_max(count, Ordering::Relaxed);
+        core::cmp::max(last, count)
+    }
+}
+
+// Start channel 2 counting down from the given count, without sounding the
+// speaker. countdown_done says when it has reached zero.
+pub unsafe fn start_countdown(count: u16) {
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2_PORT);
+
+    // Raise channel 2's gate, without sounding the speaker.
+    let control = speaker.read();
+    speaker.write((control & !SPEAKER_ENABLE) | SPEAKER_GATE);
+
+    command.write(COMMAND_CHANNEL_2_ONE_SHOT);
+    channel_2.write(count as u8);
+    channel_2.write((count >> 8) as u8);
+
+    // A rising edge on the gate starts the count. The output goes low, and
+    // back high once it reaches zero.
+    let control = speaker.read();
+    speaker.write(control & !SPEAKER_GATE);
+    speaker.write(control | SPEAKER_GATE);
+}
+
+pub fn countdown_done() -> bool {
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe { speaker.read() & SPEAKER_OUTPUT != 0 }
+}
+
+// Wait for the given number of microseconds, timed by channel 2. This keeps
+// the CPU busy, so is only for the short waits needed while setting up
+// hardware, before the clock sources have been set up.
+pub fn delay_us(microseconds: u64) {
+    let mut remaining = microseconds * FREQUENCY_HZ / 1_000_000;
+
+    // The count is 16 bits, so longer waits take more than one.
+    while remaining > 0 {
+        let count = core::cmp::min(remaining, u64::from(u16::MAX));
+        unsafe { start_countdown(count as u16) };
+        while !countdown_done() {
+            core::hint::spin_loop();
+        }
+        remaining -= count;
+    }
+}
@@ -0,0 +1,306 @@
+// The Real Time Clock (RTC), part of the CMOS chip, which keeps the date and
+// time while the machine is off, on a battery. Its registers are read through
+// two I/O ports: the index of a register is written to port 0x70, and its
+// value read from port 0x71.
+//
+// +----------+-----------------------------------------------------------+
+// | Register |                         Contents                          |
+// +----------+-----------------------------------------------------------+
+// |   0x00   | Seconds                                                   |
+// |   0x02   | Minutes                                                   |
+// |   0x04   | Hours, with bit 7 set for PM in 12-hour mode              |
+// |   0x07   | Day of the month                                          |
+// |   0x08   | Month                                                     |
+// |   0x09   | Year, within the century                                  |
+// |   0x0a   | Status A. Bit 7 is set while the clock is being updated   |
+// |   0x0b   | Status B. Bit 1 is set for 24-hour mode, and bit 2 if the |
+// |          | values are binary, rather than Binary Coded Decimal (BCD) |
+// +----------+-----------------------------------------------------------+
+//
+// The FADT says which register, if any, holds the century. The values are
+// normally in BCD, where each hex digit is a decimal digit, so 0x59 is 59.
+// ---
+// The RTC updates its registers once a second, and reading them during the
+// update can give a mix of the old and new time, so read waits until no
+// update is in progress, and reads them all again until it gets the same
+// values twice in a row.
+
+use core::fmt;
+use x86_64::instructions::port::Port;
+use crate::acpi;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const HOURS_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// A date which can't be turned into a time, as one of its fields is out of
+// range, such as a month of 0 or 13, or the 31st of April. The RTC can hold
+// anything at all, if its battery has run down or it was never set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidDate(pub DateTime);
+
+impl fmt::Display for InvalidDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} isn't a valid date and time", self.0)
+    }
+}
+
+// A date and time, in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // The date and time a number of seconds after the Unix epoch, midnight at
+    // the start of the 1st of January 1970. This, and to_unix, count days in
+    // 400-year eras, which always have the same number of leap years, with
+    // each year starting in March, so that the leap day is at the end.
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let days = seconds / SECONDS_PER_DAY + 719_468;
+        let time = seconds % SECONDS_PER_DAY;
+
+        let era = days / 146_097;
+        let day_of_era = days % 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+                           - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era
+            - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = era * 400 + year_of_era + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    // The number of seconds since the Unix epoch. Dates before 1970 are taken
+    // as the epoch.
+    pub fn to_unix(&self) -> Result<u64, InvalidDate> {
+        if !self.is_valid() {
+            return Err(InvalidDate(*self));
+        }
+        if self.year < 1970 {
+            return Ok(0);
+        }
+
+        let month = u64::from(self.month);
+        let year = u64::from(self.year) - if month <= 2 { 1 } else { 0 };
+
+        let era = year / 400;
+        let year_of_era = year % 400;
+        let month = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * month + 2) / 5 + u64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4
+            - year_of_era / 100 + day_of_year;
+        let days = (era * 146_097 + day_of_era).saturating_sub(719_468);
+
+        Ok(days * SECONDS_PER_DAY + u64::from(self.hour) * 3600
+           + u64::from(self.minute) * 60 + u64::from(self.second))
+    }
+
+    // Whether every field is in range, and the day is in the month.
+    fn is_valid(&self) -> bool {
+        let leap_year = self.year % 4 == 0
+            && (self.year % 100 != 0 || self.year % 400 == 0);
+        let days_in_month = match self.month {
+            1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+            4 | 6 | 9 | 11 => 30,
+            2 if leap_year => 29,
+            2 => 28,
+            _ => return false,
+        };
+
+        self.day >= 1 && self.day <= days_in_month && self.hour < 24
+            && self.minute < 60 && self.second < 60
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month,
+               self.day, self.hour, self.minute, self.second)
+    }
+}
+
+// The registers, as read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(INDEX_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        index.write(register);
+        data.read()
+    })
+}
+
+fn read_registers(century_register: Option<u8>) -> Registers {
+    while read_register(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    Registers {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century_register.map(read_register),
+    }
+}
+
+// Turn the registers into a date and time, in the format status B says they
+// are in. Without a century register, the year is taken to be in the 2000s.
+fn decode(registers: Registers, status_b: u8) -> DateTime {
+    let binary = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0x0f)
+        }
+    };
+
+    let pm = registers.hour & HOURS_PM != 0;
+    let mut hour = binary(registers.hour & !HOURS_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12AM is midnight, and 12PM is midday.
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    let century = registers.century.map_or(20, binary);
+
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(binary(registers.year)),
+        month: binary(registers.month),
+        day: binary(registers.day),
+        hour,
+        minute: binary(registers.minute),
+        second: binary(registers.second),
+    }
+}
+
+// Read the date and time from the RTC.
+pub fn read() -> DateTime {
+    let century_register = acpi::get()
+        .and_then(|acpi| acpi.fadt.as_ref())
+        .map(|fadt| fadt.century_register)
+        .filter(|&register| register != 0);
+
+    let mut registers = read_registers(century_register);
+    loop {
+        let again = read_registers(century_register);
+        if again == registers {
+            break;
+        }
+        registers = again;
+    }
+
+    decode(registers, read_register(STATUS_B))
+}
+
+
+// TESTING
+
+// Test that BCD and 12-hour values are decoded, as well as binary 24-hour
+// ones.
+#[test_case]
+fn test_decode() {
+    let registers = Registers {
+        second: 0x56, minute: 0x34, hour: 0x80 | 0x12, day: 0x15,
+        month: 0x06, year: 0x21, century: None,
+    };
+    assert_eq!(decode(registers, 0), DateTime {
+        year: 2021, month: 6, day: 15, hour: 12, minute: 34, second: 56,
+    });
+
+    let registers = Registers {
+        second: 59, minute: 59, hour: 23, day: 31, month: 12, year: 99,
+        century: Some(19),
+    };
+    let date = decode(registers, STATUS_B_BINARY | STATUS_B_24_HOUR);
+    assert_eq!(date.to_unix(), Ok(946_684_799));
+}
+
+// Test that Unix times convert to and from dates, across a leap day.
+#[test_case]
+fn test_unix_time() {
+    let date = DateTime::from_unix(951_868_799);
+    assert_eq!(date, DateTime {
+        year: 2000, month: 2, day: 29, hour: 23, minute: 59, second: 59,
+    });
+    assert_eq!(date.to_unix(), Ok(951_868_799));
+    assert_eq!(DateTime::from_unix(951_868_800).month, 3);
+    assert_eq!(DateTime::from_unix(0).to_unix(), Ok(0));
+}
+
+// Test that dates the RTC could hold, but which don't exist, are refused,
+// rather than overflowing.
+#[test_case]
+fn test_invalid_dates() {
+    let date = DateTime {
+        year: 2021, month: 6, day: 15, hour: 12, minute: 0, second: 0,
+    };
+    for invalid in &[
+        DateTime { month: 0, ..date },
+        DateTime { month: 13, ..date },
+        DateTime { day: 0, ..date },
+        DateTime { month: 4, day: 31, ..date },
+        DateTime { month: 2, day: 29, ..date },
+        DateTime { hour: 24, ..date },
+        DateTime { minute: 60, ..date },
+    ] {
+        assert_eq!(invalid.to_unix(), Err(InvalidDate(*invalid)));
+    }
+
+    assert_eq!(DateTime { year: 0, month: 1, day: 1, ..date }.to_unix(),
+               Ok(0));
+}
+
+// Test that the RTC has the date QEMU was given with '-rtc base=' in the
+// test-args in Cargo.toml. The clock starts at 12:34:56, so a slow run may
+// have reached 13:00 by the time this test reads it.
+#[test_case]
+fn test_read_qemu_rtc() {
+    let date = read();
+    assert_eq!((date.year, date.month, date.day), (2021, 6, 15));
+    assert!(date.hour == 12 || date.hour == 13, "hour {}", date.hour);
+}
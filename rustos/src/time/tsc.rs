@@ -0,0 +1,95 @@
+// The Time Stamp Counter (TSC), a 64-bit count of clock cycles, which each CPU
+// has, and which is read with a single instruction, making it the cheapest
+// clock there is. On older CPUs it counts at the CPU's current speed, which
+// changes with power saving, so it is only used as a clock source if it is
+// invariant, counting at the same rate whatever the CPU is doing. CPUs with an
+// invariant TSC have bit 8 of EDX set in CPUID leaf 0x80000007.
+// ---
+// The rate isn't given anywhere we can read it, so it is measured against
+// another clock source, or PIT channel 2 if there is no other.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use super::{pit, ClockSource};
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_EDX_INVARIANT_TSC: u32 = 1 << 8;
+
+// How long the TSC is measured for.
+const CALIBRATION_MS: u64 = 50;
+
+pub struct Tsc {
+    frequency: u64,
+}
+
+// Whether the TSC is invariant.
+pub fn invariant() -> bool {
+    unsafe {
+        __cpuid(CPUID_EXTENDED_MAX).eax >= CPUID_POWER_MANAGEMENT
+            && __cpuid(CPUID_POWER_MANAGEMENT).edx & CPUID_EDX_INVARIANT_TSC
+                != 0
+    }
+}
+
+// Measure the TSC against the given clock source, or PIT channel 2, if it is
+// invariant.
+pub fn init(reference: Option<&dyn ClockSource>) -> Option<Tsc> {
+    if !invariant() {
+        return None;
+    }
+
+    let frequency = match reference {
+        Some(reference) => calibrate(reference),
+        None => calibrate_pit(),
+    };
+    Some(Tsc { frequency })
+}
+
+fn calibrate(reference: &dyn ClockSource) -> u64 {
+    let counts = reference.frequency() * CALIBRATION_MS / 1000;
+
+    let reference_start = reference.read();
+    let start = unsafe { _rdtsc() };
+    let mut elapsed = 0;
+    while elapsed < counts {
+        elapsed = reference.read().wrapping_sub(reference_start)
+            & reference.mask();
+    }
+    let end = unsafe { _rdtsc() };
+
+    let cycles = u128::from(end - start);
+    (cycles * u128::from(reference.frequency()) / u128::from(elapsed)) as u64
+}
+
+fn calibrate_pit() -> u64 {
+    let count = pit::FREQUENCY_HZ * CALIBRATION_MS / 1000;
+
+    unsafe {
+        pit::start_countdown(count as u16);
+        let start = _rdtsc();
+        while !pit::countdown_done() {
+            core::hint::spin_loop();
+        }
+        let end = _rdtsc();
+
+        (end - start) * 1000 / CALIBRATION_MS
+    }
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX
+    }
+
+    fn read(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+}
@@ -0,0 +1,118 @@
+// The Translation Lookaside Buffer (TLB), where each CPU caches the
+// translations it has read from the page tables. The CPU doesn't notice when
+// the page tables change, so after a mapping is changed or removed, the old
+// translation has to be flushed from the TLB, with invlpg for one page, or by
+// reloading CR3 for all of them.
+// ---
+// Flushing only affects the CPU which does it, so every other CPU using the
+// same page tables has to be asked to flush too, with an IPI, and the CPU
+// which made the change has to wait until they all have before it can reuse
+// the frame it unmapped. This is a TLB shootdown:
+// 1. The CPU making the change takes SHOOTDOWN_LOCK, so that only one
+//    shootdown happens at a time, and leaves the pages to flush in SHOOTDOWN.
+// 2. It counts each CPU using the same level 4 page table into remaining,
+//    marks it as requested, and sends it the TlbShootdown IPI.
+// 3. Each of those CPUs flushes the pages, and counts itself out of remaining.
+// 4. Once remaining is 0, the shootdown is finished.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::VirtAddr;
+use crate::interrupts::InterruptIndex;
+use crate::ipi::{self, Target};
+use crate::percpu::{self, MAX_CPUS};
+
+// Past this many pages, it's quicker to flush the whole TLB than each page.
+const FLUSH_ALL_PAGES: u64 = 32;
+
+struct Shootdown {
+    address_space: AtomicU64,
+    start: AtomicU64,
+    pages: AtomicU64,
+    remaining: AtomicUsize,
+}
+
+static SHOOTDOWN: Shootdown = Shootdown {
+    address_space: AtomicU64::new(0),
+    start: AtomicU64::new(0),
+    pages: AtomicU64::new(0),
+    remaining: AtomicUsize::new(0),
+};
+
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+
+const NOT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+// Whether each CPU has been asked to take part in the current shootdown.
+static REQUESTED: [AtomicBool; MAX_CPUS] = [NOT_REQUESTED; MAX_CPUS];
+
+fn address_space() -> u64 {
+    Cr3::read().0.start_address().as_u64()
+}
+
+fn flush_local(start: VirtAddr, pages: u64) {
+    if pages > FLUSH_ALL_PAGES {
+        tlb::flush_all();
+    } else {
+        for page in 0..pages {
+            tlb::flush(start + page * 4096);
+        }
+    }
+}
+
+// Flush the given number of pages, from start on, from the TLB of every CPU
+// using the current page tables, and return once they all have.
+pub fn shootdown(start: VirtAddr, pages: u64) {
+    let current = percpu::current().index();
+    let address_space = address_space();
+
+    flush_local(start, pages);
+
+    // Another CPU's shootdown may need this one to take part before it can
+    // finish.
+    let _lock = loop {
+        if let Some(lock) = SHOOTDOWN_LOCK.try_lock() {
+            break lock;
+        }
+        ipi::handle_pending();
+        core::hint::spin_loop();
+    };
+
+    SHOOTDOWN.address_space.store(address_space, Ordering::Relaxed);
+    SHOOTDOWN.start.store(start.as_u64(), Ordering::Relaxed);
+    SHOOTDOWN.pages.store(pages, Ordering::Relaxed);
+
+    let targets = percpu::online().filter(|per_cpu| {
+        per_cpu.index() != current && per_cpu.address_space() == address_space
+    });
+    for per_cpu in targets {
+        SHOOTDOWN.remaining.fetch_add(1, Ordering::Relaxed);
+        REQUESTED[per_cpu.index()].store(true, Ordering::Release);
+        ipi::send(Target::Cpu(per_cpu.index()),
+                  InterruptIndex::TlbShootdown.as_u8());
+    }
+
+    while SHOOTDOWN.remaining.load(Ordering::Acquire) != 0 {
+        ipi::handle_pending();
+        core::hint::spin_loop();
+    }
+}
+
+// Called by the TlbShootdown interrupt handler, and while waiting on another
+// CPU, to take part in a shootdown if this CPU has been asked to.
+pub fn handle_shootdown() {
+    let cpu = percpu::current().index();
+    if !REQUESTED[cpu].swap(false, Ordering::Acquire) {
+        return;
+    }
+
+    // The page tables may have been switched since the request was made.
+    if address_space() == SHOOTDOWN.address_space.load(Ordering::Relaxed) {
+        let start = VirtAddr::new(SHOOTDOWN.start.load(Ordering::Relaxed));
+        flush_local(start, SHOOTDOWN.pages.load(Ordering::Relaxed));
+    }
+
+    SHOOTDOWN.remaining.fetch_sub(1, Ordering::Release);
+}
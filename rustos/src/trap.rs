@@ -0,0 +1,210 @@
+// Entry points for the Breakpoint and Debug exceptions, which save every
+// register of the stopped code, so that a debugger can look at and change them.
+// ---
+// The usual x86-interrupt handlers only give us the interrupt stack frame, but
+// the GDB stub and the monitor want to see, and the GDB stub wants to change,
+// every register. So the two exceptions have entry points written in assembly,
+// which push all the general purpose registers next to the stack frame,
+// building a TrapFrame, and pass it to handle_exception. Whatever is in the
+// TrapFrame when that returns is loaded back into the registers, before iretq
+// returns to the stopped code.
+// ---
+// Which debugger gets the exception is decided when it happens:
+// - The GDB stub, when the kernel was started with 'gdb' on the command line.
+// - The monitor, once the kernel has turned it on.
+// - Otherwise a breakpoint just prints the registers, and carries on.
+
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable};
+use crate::println;
+use crate::{gdb, monitor};
+
+pub const DEBUG_VECTOR: u64 = 1;
+pub const BREAKPOINT_VECTOR: u64 = 3;
+
+// The Trap Flag (bit 8) of RFLAGS. While it is set, the CPU raises the Debug
+// exception after every instruction.
+pub const RFLAGS_TRAP: u64 = 1 << 8;
+
+// The registers of the stopped code. The layout has to match the order the
+// entry points below push them in, followed by the interrupt stack frame the
+// CPU pushed.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub vector: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    // GDB numbers the amd64 registers rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp,
+    // r8 to r15, rip, and then eflags, cs, ss, ds, es, fs and gs, which are
+    // only 4 bytes each. Returns a register's value and size.
+    pub fn register(&self, number: usize) -> Option<(u64, usize)> {
+        let value = match number {
+            0 => self.rax,
+            1 => self.rbx,
+            2 => self.rcx,
+            3 => self.rdx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            16 => self.rip,
+            17 => return Some((self.rflags, 4)),
+            18 => return Some((self.cs, 4)),
+            19 => return Some((self.ss, 4)),
+
+            // The data segment registers aren't used in 64-bit mode.
+            20..=23 => return Some((0, 4)),
+            _ => return None,
+        };
+
+        Some((value, 8))
+    }
+
+    // Change a register. The segment registers can't be changed, and writes
+    // to them are ignored.
+    pub fn set_register(&mut self, number: usize, value: u64) -> bool {
+        let register = match number {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18..=23 => return true,
+            _ => return false,
+        };
+
+        *register = value;
+        true
+    }
+}
+
+// The exception entry points. The Breakpoint and Debug exceptions don't push
+// an error code, so the stack holds just the interrupt stack frame, 16-byte
+// aligned by the CPU. We push the vector and 15 registers, which leaves the
+// stack 8 bytes off alignment, so 8 more bytes are set aside around the call,
+// as the System V calling convention needs it aligned.
+global_asm!(r#"
+.global trap_breakpoint_entry
+trap_breakpoint_entry:
+    push 3
+    jmp trap_common_entry
+
+.global trap_debug_entry
+trap_debug_entry:
+    push 1
+    jmp trap_common_entry
+
+trap_common_entry:
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+    mov rdi, rsp
+    sub rsp, 8
+    call trap_handle_exception
+    add rsp, 8
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    add rsp, 8
+    iretq
+"#);
+
+extern "C" {
+    fn trap_breakpoint_entry();
+    fn trap_debug_entry();
+}
+
+// Point the Breakpoint and Debug exceptions at the entry points.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    // The entry points aren't x86-interrupt functions, but the IDT only
+    // stores their address, so they can be put in it as if they were.
+    unsafe {
+        let breakpoint = trap_breakpoint_entry as unsafe extern "C" fn();
+        let breakpoint: HandlerFunc = core::mem::transmute(breakpoint);
+        let debug = trap_debug_entry as unsafe extern "C" fn();
+        let debug: HandlerFunc = core::mem::transmute(debug);
+
+        idt.breakpoint.set_handler_fn(breakpoint);
+        idt.debug.set_handler_fn(debug);
+    }
+}
+
+#[no_mangle]
+extern "C" fn trap_handle_exception(frame: &mut TrapFrame) {
+    if gdb::enabled() {
+        gdb::handle_exception(frame);
+    } else if monitor::enabled() {
+        monitor::enter(monitor::Stop::Trap(frame));
+    } else if frame.vector == BREAKPOINT_VECTOR {
+        println!("EXCEPTION: BREAKPOINT\n{:#?}", frame);
+    } else {
+        // Nothing is single-stepping, so stop the Debug exceptions.
+        frame.rflags &= !RFLAGS_TRAP;
+    }
+}
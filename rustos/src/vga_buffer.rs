@@ -21,6 +21,12 @@ use lazy_static::lazy_static;
 // Use a spinlock to ensure a lock can be held on the Writer constant.
 use spin::Mutex;
 
+use crate::console::Console;
+use crate::device::{self, CharDevice};
+
+// Used to disable interrupts while the Writer is locked.
+use x86_64::instructions::interrupts;
+
 // Use a C-like enum to specify the number for each colour, which is stored as a
 // u8, thanks to the repr(u8) attribute.
 // ---
@@ -61,6 +67,12 @@ impl ColourCode {
     fn new(foreground: Colour, background: Colour) -> ColourCode {
         ColourCode((background as u8) << 4 | (foreground as u8))
     }
+
+    // Create a copy of the colour code with a different foreground colour,
+    // keeping the same background colour.
+    fn with_foreground(self, foreground: Colour) -> ColourCode {
+        ColourCode(self.0 & 0xf0 | (foreground as u8))
+    }
 }
 
 // repr(C) guarantees the struct's fields are laid out exactly how they would be
@@ -96,6 +108,17 @@ pub struct Writer {
 
 // Writer implementation
 impl Writer {
+    // Run f with the foreground switched to the given colour, and then switch
+    // back to the original colours.
+    pub fn in_colour<R>(&mut self, colour: Colour,
+                        f: impl FnOnce(&mut Writer) -> R) -> R {
+        let original = self.colour_code;
+        self.colour_code = original.with_foreground(colour);
+        let result = f(self);
+        self.colour_code = original;
+        result
+    }
+
     // Write a byte to the VGA Buffer
     pub fn write_byte(&mut self, byte: u8) {
         // Check the byte we've been given...
@@ -104,6 +127,16 @@ impl Writer {
             // next line of the VGA Buffer.
             b'\n' => self.new_line(),
 
+            // If the byte is a backspace, move back one position in the
+            // current row. Nothing is rubbed out, so to remove a character
+            // the line discipline sends a backspace, a space, and another
+            // backspace.
+            0x08 => {
+                if self.column_position > 0 {
+                    self.column_position -= 1;
+                }
+            }
+
             // otherwise...
             byte => {
                 // If we're at the end of the current row, we want tp move to
@@ -144,8 +177,8 @@ impl Writer {
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
             match byte {
-                // Printable ASCII byte or a new line
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // Printable ASCII byte, a new line or a backspace
+                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
 
                 // Values not part of the printable ASCII range so we will
                 // print a ■ characrer instead
@@ -202,27 +235,68 @@ lazy_static! {
     });
 }
 
-// Implement the standard print! macro, passing it through to our VGA Buffer
-// implementation.
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+// The name the device registry gives the buffer.
+const VGA_DEVICE: &str = "tty0";
+
+// Formats text straight into a character device.
+struct DeviceWriter {
+    device: &'static dyn CharDevice,
+    colour: Option<Colour>,
 }
 
-// Implement the standard println! macro, passing it through to our VGA Buffer
-// implementation.
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+impl fmt::Write for DeviceWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self.colour {
+            Some(colour) => self.device.write_coloured(colour, s.as_bytes()),
+            None => self.device.write(s.as_bytes()),
+        }
+        Ok(())
+    }
 }
 
-// Hidden, helper method to pass input from the print! and println! macros
-// through to our VGA Buffer.
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+// The VGA Buffer as a console sink, so that the console module can route the
+// output of print! and println! to it.
+// ---
+// Once the device registry has bound the buffer as 'tty0', the text goes
+// through the device, like any other terminal. Until then, early in boot, it
+// is written to the Writer itself.
+// ---
+// Interrupts are disabled while the Writer is locked, which stops an interrupt
+// handler from trying to take the lock while we hold it.
+pub struct VgaConsole;
+
+impl VgaConsole {
+    fn write_in(&self, colour: Option<Colour>, args: fmt::Arguments) {
+        use core::fmt::Write;
+
+        if let Some(device) = device::char_device(VGA_DEVICE) {
+            let _ = DeviceWriter { device, colour }.write_fmt(args);
+            return;
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let result = match colour {
+                Some(colour) => {
+                    writer.in_colour(colour, |writer| writer.write_fmt(args))
+                }
+                None => writer.write_fmt(args),
+            };
+            result.unwrap();
+        });
+    }
+}
+
+impl Console for VgaConsole {
+    fn write(&self, args: fmt::Arguments) {
+        self.write_in(None, args);
+    }
+
+    // Temporarily switch to the given foreground colour, restoring the
+    // original colours once the text has been written.
+    fn write_coloured(&self, colour: Colour, args: fmt::Arguments) {
+        self.write_in(Some(colour), args);
+    }
 }
 
 
@@ -247,24 +321,33 @@ fn test_println_many() {
 }
 
 // Test that the text output to the buffer is the same which is input into the
-// buffer.
+// buffer. This can't be checked when print! goes to the framebuffer.
+#[cfg(not(feature = "framebuffer"))]
 #[test_case]
 fn test_println_output() {
-    // Define and print a test string
+    use core::fmt::Write;
+
+    // Define a test string
     let s = "Test string";
-    println!("{}", s);
-
-    // Iterate over the test string
-    for (i, c) in s.chars().enumerate() {
-        // and retrieve the relevant character within the VGA Buffer
-        // N.b. as we called the println macro, the text will be on the second
-        // line, not the bottom, hence BUFFER_HEIGHT - 2.
-        let screen_char = 
-            WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
-
-        // Ensure the characters are the same.
-        assert_eq!(char::from(screen_char.ascii_character), c);
-    }
+
+    // Keep the Writer locked, with interrupts disabled, for the whole test, so
+    // nothing printed from an interrupt handler can end up in the buffer
+    // between the string being printed and it being checked.
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+
+        // Iterate over the test string
+        for (i, c) in s.chars().enumerate() {
+            // and retrieve the relevant character within the VGA Buffer
+            // N.b. as we wrote a new line after the string, the text will be
+            // on the second line, not the bottom, hence BUFFER_HEIGHT - 2.
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+
+            // Ensure the characters are the same.
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
 }
 
 // TODO test printing long lines (shouldn't panic)
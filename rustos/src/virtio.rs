@@ -0,0 +1,91 @@
+// Virtio, the family of devices QEMU and other hypervisors provide for guests,
+// which are much simpler to drive than the real hardware they stand in for.
+// Each virtio device has three parts:
+// - Transport: How the device is found and its registers reached. We only
+//               support PCI (see the pci module), in both its legacy form,
+//               with the registers in I/O ports, and its modern (virtio 1.0)
+//               form, with them in memory found through vendor capabilities.
+// - Virtqueues: Rings of buffers in memory, which the driver fills with
+//               requests and the device hands back when it has finished with
+//               them (see the queue module).
+// - Device configuration: Registers which are particular to the type of
+//               device, such as the capacity of a disk.
+// ---
+// Drivers set a device up in the same order, which is what the status
+// register records:
+// 1. Reset the device, by writing 0 to the status, and then set ACKNOWLEDGE
+//               and DRIVER, to say we have found it and know how to drive it.
+// 2. Read the features the device offers, and write back the ones we will
+//               use. Modern devices then need FEATURES_OK set, and clear it
+//               again if they don't accept the features.
+// 3. Set up the virtqueues, and read the device configuration.
+// 4. Set DRIVER_OK, after which the device starts using the queues.
+// If anything goes wrong, FAILED is set, so the device knows to give up.
+// ---
+// The transport driver is bound to the PCI function, and adds the device
+// behind it to the device registry, on the Virtio bus, with its device type
+// and the index of its Transport, which the driver for that type looks up
+// with the transport function. The types we drive are:
+// - 1: Network card (see the net module).
+// - 2: Block device (see the blk module).
+
+use core::fmt;
+use crate::device::ProbeError;
+
+pub mod blk;
+pub mod net;
+pub mod pci;
+pub mod queue;
+
+pub use pci::{transport, Transport};
+pub use queue::Queue;
+
+pub const DEVICE_TYPE_NET: u32 = 1;
+pub const DEVICE_TYPE_BLOCK: u32 = 2;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+pub const STATUS_DRIVER: u8 = 1 << 1;
+pub const STATUS_DRIVER_OK: u8 = 1 << 2;
+pub const STATUS_FEATURES_OK: u8 = 1 << 3;
+pub const STATUS_FAILED: u8 = 1 << 7;
+
+// The device follows virtio 1.0, rather than the legacy interface. Modern
+// devices won't work unless the driver accepts it.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    FeaturesRejected,
+    MissingQueue,
+    QueueTooLarge,
+    OutOfMemory,
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+
+impl VirtioError {
+    fn reason(&self) -> &'static str {
+        match self {
+            VirtioError::FeaturesRejected => {
+                "the device didn't accept the features"
+            }
+            VirtioError::MissingQueue => "the device doesn't have the queue",
+            VirtioError::QueueTooLarge => {
+                "the device's queue is larger than we support"
+            }
+            VirtioError::OutOfMemory => {
+                "there isn't enough memory for the queue"
+            }
+        }
+    }
+}
+
+impl From<VirtioError> for ProbeError {
+    fn from(error: VirtioError) -> ProbeError {
+        ProbeError::Failed(error.reason())
+    }
+}
@@ -0,0 +1,571 @@
+// The virtio block device (type 2), a disk, which becomes 'vd0' onwards. It
+// has a single queue, and each request on it is a chain of buffers:
+// 1. A 16-byte header the device reads, with the type of request (IN to read,
+//               OUT to write, or FLUSH), 4 reserved bytes, and the sector to
+//               start at. Sectors are always 512 bytes.
+// 2. The data, which the device writes for a read, and reads for a write.
+//               Flushes have none.
+// 3. A status byte the device writes: OK, IOERR or UNSUPP.
+// The device configuration starts with the capacity of the disk, in sectors,
+// as a 64-bit number. The features we use are:
+// - RO (bit 5): The disk can't be written to.
+// - FLUSH (bit 9): The disk has a write cache, which FLUSH requests write out.
+//               Without it, writes are finished once they complete.
+// ---
+// Up to MAX_IN_FLIGHT requests can be on the queue at once. Each takes a
+// slot, which holds its header and status byte in a frame the device can
+// read, and says whether the request is in flight or has finished. The
+// device interrupts when it finishes a request, and the interrupt handler
+// takes it off the used ring and marks its slot done. When the device can't
+// interrupt, or interrupts are disabled while waiting, the used ring is
+// polled instead.
+// ---
+// Requests are submitted with submit_read, submit_write and submit_flush,
+// which return a Request for it straight away, or BlockError::Busy if every
+// slot is taken. The Request borrows the buffer until the device has finished
+// with it, and waits for it if it is dropped before then. As forgetting the
+// Request would give the buffer back early, submit_read and submit_write are
+// unsafe, and their callers have to make sure it isn't. The BlockDevice
+// interface submits one request at a time, waiting for each, and splits
+// large buffers into requests of MAX_REQUEST_SIZE.
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::{are_enabled, without_interrupts};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::device::{Binding, BlockDevice, BlockError, Bus, Driver, Interface,
+                    ProbeError, Resource};
+use crate::{interrupts, memory};
+use super::queue::{self, Buffer, Queue};
+use super::{Transport, VirtioError, DEVICE_TYPE_BLOCK, STATUS_DRIVER,
+            STATUS_DRIVER_OK, STATUS_FAILED};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// The most requests which can be in flight on a disk at once.
+pub const MAX_IN_FLIGHT: usize = 16;
+
+// The most bytes a single request can read or write.
+pub const MAX_REQUEST_SIZE: usize = 12 * 4096;
+const SECTORS_PER_REQUEST: usize = MAX_REQUEST_SIZE / SECTOR_SIZE;
+
+// The most disks which can be probed.
+const MAX_DISKS: usize = 4;
+
+// The data of a request can start part of the way into a page, so covers one
+// more page than its size. Every request in flight fits on a queue of 256.
+const MAX_DATA_BUFFERS: usize = MAX_REQUEST_SIZE / 4096 + 1;
+const MAX_BUFFERS: usize = MAX_DATA_BUFFERS + 2;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const CONFIG_CAPACITY: u64 = 0x00;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+// What the status byte is set to until the device writes it.
+const STATUS_PENDING: u8 = 0xff;
+
+const SLOT_FREE: u8 = 0;
+const SLOT_IN_FLIGHT: u8 = 1;
+const SLOT_DONE: u8 = 2;
+
+const HEADER_SIZE: usize = 16;
+// The status bytes come after every slot's header.
+const STATUSES: usize = HEADER_SIZE * MAX_IN_FLIGHT;
+
+#[repr(C)]
+struct Header {
+    request_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+struct Inner {
+    queue: Queue,
+    // The first descriptor of the request in each slot.
+    heads: [Option<u16>; MAX_IN_FLIGHT],
+}
+
+pub struct Disk {
+    transport: &'static Transport,
+    inner: Mutex<Inner>,
+    slots: [AtomicU8; MAX_IN_FLIGHT],
+    // The frame holding the headers and status bytes.
+    slot_memory: VirtAddr,
+    slot_memory_physical: PhysAddr,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+    vector: Option<u8>,
+    interrupts: bool,
+}
+
+// A request which has been submitted, and may still be in flight.
+pub struct Request<'a> {
+    disk: &'a Disk,
+    slot: usize,
+    finished: bool,
+    // The buffer the device is reading or writing.
+    buffer: PhantomData<&'a mut [u8]>,
+}
+
+impl Request<'_> {
+    pub fn is_done(&self) -> bool {
+        self.disk.is_done(self.slot)
+    }
+
+    // Wait for the device to finish the request, and return how it went.
+    pub fn wait(mut self) -> Result<(), BlockError> {
+        self.finished = true;
+        self.disk.finish(self.slot)
+    }
+}
+
+impl Drop for Request<'_> {
+    // The buffer can't be given back while the device might still use it.
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.disk.finish(self.slot);
+        }
+    }
+}
+
+impl Disk {
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn slot_address(&self, offset: usize) -> (VirtAddr, PhysAddr) {
+        let offset = offset as u64;
+        (self.slot_memory + offset, self.slot_memory_physical + offset)
+    }
+
+    fn take_slot(&self) -> Option<usize> {
+        self.slots.iter().position(|slot| {
+            slot.compare_exchange(SLOT_FREE, SLOT_IN_FLIGHT, Ordering::Acquire,
+                                  Ordering::Relaxed).is_ok()
+        })
+    }
+
+    // Put a request on the queue, returning its slot. The data is the address
+    // and length of the buffer, and whether the device writes to it.
+    fn submit(&self, request_type: u32, sector: u64,
+              data: Option<(VirtAddr, usize, bool)>)
+            -> Result<usize, BlockError> {
+        let slot = self.take_slot().ok_or(BlockError::Busy)?;
+        let (header, header_physical) = self.slot_address(slot * HEADER_SIZE);
+        let (status, status_physical) = self.slot_address(STATUSES + slot);
+        unsafe {
+            core::ptr::write_volatile(header.as_mut_ptr(), Header {
+                request_type,
+                reserved: 0,
+                sector,
+            });
+            core::ptr::write_volatile(status.as_mut_ptr(), STATUS_PENDING);
+        }
+
+        let mut buffers = [Buffer {
+            address: header_physical,
+            len: HEADER_SIZE as u32,
+            device_writes: false,
+        }; MAX_BUFFERS];
+        let mut count = 1;
+        if let Some((address, len, device_writes)) = data {
+            match queue::physical_buffers(address, len, device_writes,
+                                          &mut buffers[1..MAX_BUFFERS - 1]) {
+                Some(pieces) => count += pieces,
+                None => {
+                    self.slots[slot].store(SLOT_FREE, Ordering::Release);
+                    return Err(BlockError::NotMapped);
+                }
+            }
+        }
+        buffers[count] = Buffer {
+            address: status_physical,
+            len: 1,
+            device_writes: true,
+        };
+        count += 1;
+
+        let added = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            let head = inner.queue.add(&buffers[..count])?;
+            inner.heads[slot] = Some(head);
+            self.transport.notify(&inner.queue);
+            Some(head)
+        });
+        if added.is_none() {
+            self.slots[slot].store(SLOT_FREE, Ordering::Release);
+            return Err(BlockError::Busy);
+        }
+
+        Ok(slot)
+    }
+
+    // Like submit, but waiting for a free slot.
+    fn submit_waiting(&self, request_type: u32, sector: u64,
+                      data: Option<(VirtAddr, usize, bool)>)
+            -> Result<usize, BlockError> {
+        loop {
+            match self.submit(request_type, sector, data) {
+                Err(BlockError::Busy) => self.poll(),
+                result => return result,
+            }
+        }
+    }
+
+    // Take the finished requests off the used ring, and mark their slots
+    // done.
+    fn complete(&self) {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            while let Some((head, _)) = inner.queue.pop_used() {
+                let slot = inner.heads.iter()
+                    .position(|slot_head| *slot_head == Some(head));
+                if let Some(slot) = slot {
+                    inner.heads[slot] = None;
+                    self.slots[slot].store(SLOT_DONE, Ordering::Release);
+                }
+            }
+        });
+    }
+
+    // Called while waiting for a request. The interrupt handler completes
+    // requests, unless it can't run.
+    fn poll(&self) {
+        if !self.interrupts || !are_enabled() {
+            self.complete();
+        }
+        core::hint::spin_loop();
+    }
+
+    fn is_done(&self, slot: usize) -> bool {
+        if !self.interrupts || !are_enabled() {
+            self.complete();
+        }
+        self.slots[slot].load(Ordering::Acquire) == SLOT_DONE
+    }
+
+    // Wait for the request in the slot, and free the slot.
+    fn finish(&self, slot: usize) -> Result<(), BlockError> {
+        while self.slots[slot].load(Ordering::Acquire) != SLOT_DONE {
+            self.poll();
+        }
+
+        let (status, _) = self.slot_address(STATUSES + slot);
+        let status = unsafe { core::ptr::read_volatile(status.as_ptr()) };
+        self.slots[slot].store(SLOT_FREE, Ordering::Release);
+
+        match status {
+            STATUS_OK => Ok(()),
+            STATUS_UNSUPPORTED => Err(BlockError::Unsupported),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    // Whether the sectors are all on the disk, and the buffer is a whole
+    // number of them.
+    fn check_range(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        let sectors = (len / SECTOR_SIZE) as u64;
+        let end = sector.checked_add(sectors).ok_or(BlockError::OutOfRange)?;
+        if len % SECTOR_SIZE != 0 || end > self.sectors {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    // The same, for a single request.
+    fn check(&self, sector: u64, len: usize) -> Result<(), BlockError> {
+        if len == 0 || len > MAX_REQUEST_SIZE {
+            return Err(BlockError::OutOfRange);
+        }
+        self.check_range(sector, len)
+    }
+
+    fn request(&self, slot: usize) -> Request {
+        Request { disk: self, slot, finished: false, buffer: PhantomData }
+    }
+
+    // Start reading whole sectors into the buffer, which can be at most
+    // MAX_REQUEST_SIZE.
+    // ---
+    // This is unsafe as the device writes to the buffer until the request has
+    // finished, and the Request only waits for that if it is dropped. The
+    // caller must make sure it is waited for or dropped, not forgotten, or
+    // that the buffer isn't used or freed until the request is done.
+    pub unsafe fn submit_read<'a>(&'a self, sector: u64, buffer: &'a mut [u8])
+            -> Result<Request<'a>, BlockError> {
+        self.check(sector, buffer.len())?;
+        let data = (VirtAddr::from_ptr(buffer.as_ptr()), buffer.len(), true);
+        let slot = self.submit(REQUEST_IN, sector, Some(data))?;
+        Ok(self.request(slot))
+    }
+
+    // Start writing the buffer to whole sectors.
+    // ---
+    // This is unsafe for the same reason as submit_read, as the device reads
+    // the buffer until the request has finished.
+    pub unsafe fn submit_write<'a>(&'a self, sector: u64, buffer: &'a [u8])
+            -> Result<Request<'a>, BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        self.check(sector, buffer.len())?;
+        let data = (VirtAddr::from_ptr(buffer.as_ptr()), buffer.len(), false);
+        let slot = self.submit(REQUEST_OUT, sector, Some(data))?;
+        Ok(self.request(slot))
+    }
+
+    // Start writing out the disk's write cache. Disks without one don't
+    // support it.
+    pub fn submit_flush(&self) -> Result<Request, BlockError> {
+        if !self.flush {
+            return Err(BlockError::Unsupported);
+        }
+        let slot = self.submit(REQUEST_FLUSH, 0, None)?;
+        Ok(self.request(slot))
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.check_range(sector, buffer.len())?;
+        for (index, chunk) in buffer.chunks_mut(MAX_REQUEST_SIZE).enumerate() {
+            let sector = sector + (index * SECTORS_PER_REQUEST) as u64;
+            let data = (VirtAddr::from_ptr(chunk.as_ptr()), chunk.len(), true);
+            let slot = self.submit_waiting(REQUEST_IN, sector, Some(data))?;
+            self.request(slot).wait()?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+
+        self.check_range(sector, buffer.len())?;
+        for (index, chunk) in buffer.chunks(MAX_REQUEST_SIZE).enumerate() {
+            let sector = sector + (index * SECTORS_PER_REQUEST) as u64;
+            let data = (VirtAddr::from_ptr(chunk.as_ptr()), chunk.len(), false);
+            let slot = self.submit_waiting(REQUEST_OUT, sector, Some(data))?;
+            self.request(slot).wait()?;
+        }
+        Ok(())
+    }
+
+    // Without a write cache, there is nothing to flush.
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush {
+            return Ok(());
+        }
+        let slot = self.submit_waiting(REQUEST_FLUSH, 0, None)?;
+        self.request(slot).wait()
+    }
+}
+
+const NO_DISK: Once<Disk> = Once::new();
+
+// Disks are never taken out, so a disk probed again takes a new place.
+static DISKS: [Once<Disk>; MAX_DISKS] = [NO_DISK; MAX_DISKS];
+static DISK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn handle_interrupt(index: usize) {
+    if let Some(disk) = DISKS[index].r#try() {
+        disk.complete();
+    }
+}
+
+fn set_up(transport: &'static Transport) -> Result<&'static Disk, ProbeError> {
+    let index = DISK_COUNT.fetch_add(1, Ordering::SeqCst);
+    if index >= MAX_DISKS {
+        return Err(ProbeError::Failed("there are too many disks"));
+    }
+
+    let features = transport.negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH)?;
+    let vector = if transport.has_interrupts() {
+        interrupts::allocate_vector(handle_interrupt, index)
+    } else {
+        None
+    };
+    let queue = transport.create_queue(0, vector);
+    let vector = match (&queue, vector) {
+        (Ok(queue), Some(vector)) if queue.has_interrupts() => Some(vector),
+        (_, Some(vector)) => {
+            interrupts::free_vector(vector);
+            None
+        }
+        _ => None,
+    };
+    let queue = queue?;
+
+    let slot_memory_physical = memory::allocate_frame()
+        .ok_or(VirtioError::OutOfMemory)?
+        .start_address();
+    let slot_memory = memory::phys_to_virt(slot_memory_physical)
+        .ok_or(VirtioError::OutOfMemory)?;
+
+    const FREE_SLOT: AtomicU8 = AtomicU8::new(SLOT_FREE);
+    let disk = Disk {
+        transport,
+        inner: Mutex::new(Inner { queue, heads: [None; MAX_IN_FLIGHT] }),
+        slots: [FREE_SLOT; MAX_IN_FLIGHT],
+        slot_memory,
+        slot_memory_physical,
+        sectors: transport.read_config_u64(CONFIG_CAPACITY),
+        read_only: features & FEATURE_READ_ONLY != 0,
+        flush: features & FEATURE_FLUSH != 0,
+        vector,
+        interrupts: vector.is_some(),
+    };
+    let disk = DISKS[index].call_once(|| disk);
+
+    transport.add_status(STATUS_DRIVER_OK);
+    log::info!("virtio disk of {} KiB{}{}", disk.sectors / 2,
+               if disk.read_only { ", read only" } else { "" },
+               if disk.interrupts { "" } else { ", polled" });
+    Ok(disk)
+}
+
+pub struct BlockDriver;
+
+impl Driver for BlockDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Virtio
+    }
+
+    fn matches(&self, resource: &Resource) -> bool {
+        matches!(resource,
+                 Resource::Virtio { device_type: DEVICE_TYPE_BLOCK, .. })
+    }
+
+    fn probe(&self, resource: &Resource) -> Result<Binding, ProbeError> {
+        let transport = match resource {
+            Resource::Virtio { transport, .. } => super::transport(*transport),
+            _ => None,
+        };
+        let transport = transport.ok_or(ProbeError::NotPresent)?;
+
+        transport.add_status(STATUS_DRIVER);
+        let disk = set_up(transport).map_err(|error| {
+            transport.add_status(STATUS_FAILED);
+            error
+        })?;
+
+        Ok(Binding { name: "vd", interface: Interface::Block(disk) })
+    }
+
+    // The transport resets the device, so only the vector has to be given
+    // back.
+    fn remove(&self, resource: &Resource) {
+        let transport = match resource {
+            Resource::Virtio { transport, .. } => super::transport(*transport),
+            _ => None,
+        };
+        let disk = DISKS.iter()
+            .filter_map(|disk| disk.r#try())
+            .find(|disk| {
+                transport.map_or(false, |transport| {
+                    core::ptr::eq(disk.transport, transport)
+                })
+            });
+
+        if let Some(vector) = disk.and_then(|disk| disk.vector) {
+            interrupts::free_vector(vector);
+        }
+    }
+}
+
+
+// TESTING
+
+// The tests are run with target/test-disk.img attached (see Cargo.toml), which
+// tools/runner.py writes. It is 128 sectors. The first starts with a
+// signature, and every other sector is filled with its own number.
+
+#[cfg(test)]
+fn test_disk() -> &'static Disk {
+    DISKS[0].r#try().expect("the test disk wasn't probed")
+}
+
+// Test that the disk is found, and its first sector read.
+#[test_case]
+fn test_read_test_disk() {
+    let disk = crate::device::block_device("vd0")
+        .expect("vd0 wasn't found");
+    let mut sector = [0; SECTOR_SIZE];
+
+    assert_eq!(disk.sectors(), 128);
+    disk.read(0, &mut sector).expect("couldn't read the disk");
+    assert_eq!(&sector[..16], b"RUSTOS TEST DISK");
+    assert_eq!(disk.read(128, &mut sector), Err(BlockError::OutOfRange));
+}
+
+// Test that sectors which are written, across more than one request, can be
+// read back, and flushed.
+#[test_case]
+fn test_write_and_read_back() {
+    static mut WRITTEN: [u8; MAX_REQUEST_SIZE + SECTOR_SIZE] =
+        [0; MAX_REQUEST_SIZE + SECTOR_SIZE];
+    static mut READ: [u8; MAX_REQUEST_SIZE + SECTOR_SIZE] =
+        [0; MAX_REQUEST_SIZE + SECTOR_SIZE];
+    let disk = test_disk();
+
+    unsafe {
+        for (index, byte) in WRITTEN.iter_mut().enumerate() {
+            *byte = (index % 251) as u8;
+        }
+        disk.write(20, &WRITTEN).expect("couldn't write the disk");
+        disk.flush().expect("couldn't flush the disk");
+        disk.read(20, &mut READ).expect("couldn't read the disk");
+        assert!(WRITTEN[..] == READ[..]);
+    }
+}
+
+// Test that every slot can be in flight at once, that another request is
+// then turned away, and that each request finishes with its own sector.
+#[test_case]
+fn test_requests_in_flight() {
+    let disk = test_disk();
+    let mut sectors = [[0; SECTOR_SIZE]; MAX_IN_FLIGHT];
+    let mut extra = [0; SECTOR_SIZE];
+
+    let mut requests: [Option<Request>; MAX_IN_FLIGHT] = Default::default();
+    for (index, (slot, sector)) in requests.iter_mut()
+        .zip(sectors.iter_mut())
+        .enumerate()
+    {
+        let request = unsafe { disk.submit_read(1 + index as u64, sector) };
+        *slot = Some(request.unwrap());
+    }
+    let turned_away = unsafe { disk.submit_read(1, &mut extra) };
+    assert_eq!(turned_away.err(), Some(BlockError::Busy));
+
+    for request in requests.iter_mut() {
+        request.take().unwrap().wait().expect("a request failed");
+    }
+    drop(requests);
+
+    for (index, sector) in sectors.iter().enumerate() {
+        assert!(sector.iter().all(|byte| *byte == 1 + index as u8));
+    }
+}
@@ -0,0 +1,271 @@
+// The virtio network card (type 1), which becomes 'eth0' onwards. It has two
+// queues, the first for frames which have been received, and the second for
+// frames to send. Each frame, in either direction, comes after a header for
+// checksum and segmentation offloading, which we don't use, so it is always
+// zeroes on the way out, and skipped on the way in. The header is 10 bytes
+// for legacy devices, and 12 for modern ones. Legacy devices also need the
+// header in a descriptor of its own, so every buffer is offered as two.
+// ---
+// The only feature we use is MAC (bit 5), which says the device configuration
+// starts with the card's MAC address. Without it, we make one up.
+// ---
+// RX_BUFFERS buffers are kept on the receive queue. When a frame is taken
+// off it, it is copied out, and its buffer offered to the device again.
+// Frames to send are copied into one of TX_BUFFERS buffers, which are taken
+// back from the transmit queue the next time a frame is sent.
+// ---
+// The card's interrupts aren't used. The network stack polls it instead (see
+// the net module).
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::device::{Binding, Bus, Driver, Interface, NetDevice, NetError,
+                    ProbeError, Resource};
+use crate::memory;
+use super::queue::{Buffer, Queue, MAX_QUEUE_SIZE};
+use super::{Transport, VirtioError, DEVICE_TYPE_NET, STATUS_DRIVER,
+            STATUS_DRIVER_OK, STATUS_FAILED};
+
+// The largest frame which can be sent or received: 1500 bytes of payload,
+// and the Ethernet header.
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+const BUFFER_SIZE: usize = 2048;
+const RX_BUFFERS: usize = 32;
+const TX_BUFFERS: usize = 16;
+
+// The most cards which can be probed.
+const MAX_CARDS: usize = 2;
+
+const FEATURE_MAC: u64 = 1 << 5;
+
+const CONFIG_MAC: u64 = 0x00;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+const LEGACY_HEADER_SIZE: usize = 10;
+const HEADER_SIZE: usize = 12;
+
+// A block of buffers, next to each other in physical memory.
+struct Buffers {
+    address: VirtAddr,
+    physical: PhysAddr,
+}
+
+impl Buffers {
+    fn new(count: usize) -> Option<Buffers> {
+        let bytes = count * BUFFER_SIZE;
+        let physical = memory::allocate_frames(bytes / 4096)?.start_address();
+        let address = memory::phys_to_virt(physical)?;
+        unsafe { core::ptr::write_bytes(address.as_mut_ptr::<u8>(), 0, bytes) };
+        Some(Buffers { address, physical })
+    }
+
+    fn get(&self, index: usize) -> (VirtAddr, PhysAddr) {
+        let offset = (index * BUFFER_SIZE) as u64;
+        (self.address + offset, self.physical + offset)
+    }
+}
+
+struct Ring {
+    queue: Queue,
+    buffers: Buffers,
+    // The buffer in each chain on the queue, by its first descriptor.
+    by_head: [usize; MAX_QUEUE_SIZE as usize],
+}
+
+impl Ring {
+    // Offer a buffer to the device, as a header and a frame of len bytes.
+    fn add(&mut self, index: usize, header_size: usize, len: usize,
+           device_writes: bool) -> Option<u16> {
+        let (_, physical) = self.buffers.get(index);
+        let head = self.queue.add(&[
+            Buffer {
+                address: physical,
+                len: header_size as u32,
+                device_writes,
+            },
+            Buffer {
+                address: physical + header_size as u64,
+                len: len as u32,
+                device_writes,
+            },
+        ])?;
+        self.by_head[usize::from(head)] = index;
+        Some(head)
+    }
+}
+
+pub struct NetCard {
+    transport: &'static Transport,
+    mac_address: [u8; 6],
+    header_size: usize,
+    receive: Mutex<Ring>,
+    // The transmit buffers which aren't on the queue.
+    transmit: Mutex<(Ring, [bool; TX_BUFFERS])>,
+}
+
+impl NetDevice for NetCard {
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac_address
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(NetError::TooLarge);
+        }
+
+        without_interrupts(|| {
+            let mut transmit = self.transmit.lock();
+            let (ring, free) = &mut *transmit;
+            while let Some((head, _)) = ring.queue.pop_used() {
+                free[ring.by_head[usize::from(head)]] = true;
+            }
+
+            let index = free.iter().position(|free| *free)
+                .ok_or(NetError::Busy)?;
+            let (address, _) = ring.buffers.get(index);
+            unsafe {
+                let buffer = address.as_mut_ptr::<u8>();
+                core::ptr::write_bytes(buffer, 0, self.header_size);
+                core::ptr::copy_nonoverlapping(frame.as_ptr(),
+                                               buffer.add(self.header_size),
+                                               frame.len());
+            }
+
+            ring.add(index, self.header_size, frame.len(), false)
+                .ok_or(NetError::Busy)?;
+            free[index] = false;
+            self.transport.notify(&ring.queue);
+            Ok(())
+        })
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> Option<usize> {
+        without_interrupts(|| {
+            let mut ring = self.receive.lock();
+            let (head, len) = ring.queue.pop_used()?;
+            let index = ring.by_head[usize::from(head)];
+
+            let (address, _) = ring.buffers.get(index);
+            let len = (len as usize).saturating_sub(self.header_size);
+            let len = core::cmp::min(len, buffer.len());
+            unsafe {
+                let frame = address.as_ptr::<u8>().add(self.header_size);
+                core::ptr::copy_nonoverlapping(frame, buffer.as_mut_ptr(), len);
+            }
+
+            ring.add(index, self.header_size, MAX_FRAME_SIZE, true);
+            self.transport.notify(&ring.queue);
+            Some(len)
+        })
+    }
+}
+
+const NO_CARD: Once<NetCard> = Once::new();
+
+// Cards are never taken out, so a card probed again takes a new place.
+static CARDS: [Once<NetCard>; MAX_CARDS] = [NO_CARD; MAX_CARDS];
+static CARD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+fn set_up(transport: &'static Transport)
+        -> Result<&'static NetCard, ProbeError> {
+    let index = CARD_COUNT.fetch_add(1, Ordering::SeqCst);
+    if index >= MAX_CARDS {
+        return Err(ProbeError::Failed("there are too many network cards"));
+    }
+
+    let features = transport.negotiate(FEATURE_MAC)?;
+    let mut receive = Ring {
+        queue: transport.create_queue(RECEIVE_QUEUE, None)?,
+        buffers: Buffers::new(RX_BUFFERS).ok_or(VirtioError::OutOfMemory)?,
+        by_head: [0; MAX_QUEUE_SIZE as usize],
+    };
+    let transmit = Ring {
+        queue: transport.create_queue(TRANSMIT_QUEUE, None)?,
+        buffers: Buffers::new(TX_BUFFERS).ok_or(VirtioError::OutOfMemory)?,
+        by_head: [0; MAX_QUEUE_SIZE as usize],
+    };
+
+    // A locally administered address, if the device doesn't give one.
+    let mut mac_address = [0x02, 0, 0, 0, 0, index as u8 + 1];
+    if features & FEATURE_MAC != 0 {
+        for (offset, byte) in mac_address.iter_mut().enumerate() {
+            *byte = transport.read_config_u8(CONFIG_MAC + offset as u64);
+        }
+    }
+
+    let header_size = if transport.is_legacy() {
+        LEGACY_HEADER_SIZE
+    } else {
+        HEADER_SIZE
+    };
+    for buffer in 0..RX_BUFFERS {
+        receive.add(buffer, header_size, MAX_FRAME_SIZE, true);
+    }
+
+    let card = CARDS[index].call_once(|| NetCard {
+        transport,
+        mac_address,
+        header_size,
+        receive: Mutex::new(receive),
+        transmit: Mutex::new((transmit, [true; TX_BUFFERS])),
+    });
+
+    transport.add_status(STATUS_DRIVER_OK);
+    without_interrupts(|| transport.notify(&card.receive.lock().queue));
+
+    let [a, b, c, d, e, f] = mac_address;
+    log::info!("virtio network card {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+               a, b, c, d, e, f);
+    Ok(card)
+}
+
+pub struct NetDriver;
+
+impl Driver for NetDriver {
+    fn name(&self) -> &'static str {
+        "virtio-net"
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Virtio
+    }
+
+    fn matches(&self, resource: &Resource) -> bool {
+        matches!(resource,
+                 Resource::Virtio { device_type: DEVICE_TYPE_NET, .. })
+    }
+
+    fn probe(&self, resource: &Resource) -> Result<Binding, ProbeError> {
+        let transport = match resource {
+            Resource::Virtio { transport, .. } => super::transport(*transport),
+            _ => None,
+        };
+        let transport = transport.ok_or(ProbeError::NotPresent)?;
+
+        transport.add_status(STATUS_DRIVER);
+        let card = set_up(transport).map_err(|error| {
+            transport.add_status(STATUS_FAILED);
+            error
+        })?;
+
+        Ok(Binding { name: "eth", interface: Interface::Net(card) })
+    }
+}
+
+
+// TESTING
+
+// Test that the card QEMU gives the tests is found, with QEMU's default MAC
+// address.
+#[test_case]
+fn test_network_card() {
+    let card = crate::device::net_device("eth0").expect("eth0 wasn't found");
+
+    assert_eq!(card.mac_address(), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+    assert_eq!(card.send(&[0; MAX_FRAME_SIZE + 1]), Err(NetError::TooLarge));
+}
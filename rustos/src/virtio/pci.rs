@@ -0,0 +1,599 @@
+// The virtio PCI transport. Virtio devices are PCI functions with vendor ID
+// 0x1af4, and device IDs from 0x1000. Transitional devices (0x1000 to 0x103f),
+// which QEMU gives us by default, have both interfaces, and give the type of
+// device in their subsystem ID. Modern devices (0x1040 onwards) only have the
+// modern interface, and their device ID is 0x1040 plus the type.
+// ---
+// The legacy interface is a block of registers in I/O space, behind BAR 0:
+//
+// +--------+--------+------------------------------------------------------+
+// | Offset |  Size  |                       Contents                       |
+// +--------+--------+------------------------------------------------------+
+// |  0x00  |    4   | Device features (only the first 32)                  |
+// |  0x04  |    4   | Driver features                                      |
+// |  0x08  |    4   | Queue address, as a page number                      |
+// |  0x0c  |    2   | Queue size, which the driver can't change            |
+// |  0x0e  |    2   | Queue select                                         |
+// |  0x10  |    2   | Queue notify                                         |
+// |  0x12  |    1   | Device status                                        |
+// |  0x13  |    1   | ISR status                                           |
+// |  0x14  |    2   | MSI-X vector for configuration changes               |
+// |  0x16  |    2   | MSI-X vector for the selected queue                  |
+// +--------+--------+------------------------------------------------------+
+//
+// The two MSI-X registers are only there while MSI-X is enabled, so the
+// device configuration starts at 0x18 then, and at 0x14 otherwise.
+// ---
+// The modern interface is split into structures, which can be in any of the
+// BARs, and which are found through vendor capabilities. Each one gives the
+// type of structure at offset 3, the BAR at 4, and the offset and length of
+// the structure in the BAR at 8 and 12:
+// - Common configuration (1): The features, status and queue registers.
+// - Notifications (2): Where queues are notified. Each queue is notified at
+//               its own offset, which is the queue's notify offset times the
+//               multiplier at offset 16 of the capability.
+// - ISR status (3): A byte which says why the device interrupted.
+// - Device configuration (4).
+// The common configuration is laid out as:
+//
+// +--------+--------+------------------------------------------------------+
+// | Offset |  Size  |                       Contents                       |
+// +--------+--------+------------------------------------------------------+
+// |  0x00  |    4   | Device feature select, which half of the features    |
+// |  0x04  |    4   | Device features                                      |
+// |  0x08  |    4   | Driver feature select                                |
+// |  0x0c  |    4   | Driver features                                      |
+// |  0x10  |    2   | MSI-X vector for configuration changes               |
+// |  0x12  |    2   | Number of queues                                     |
+// |  0x14  |    1   | Device status                                        |
+// |  0x16  |    2   | Queue select, for the registers below                |
+// |  0x18  |    2   | Queue size, which the driver can make smaller        |
+// |  0x1a  |    2   | MSI-X vector for the queue                           |
+// |  0x1c  |    2   | Queue enable                                         |
+// |  0x1e  |    2   | Queue notify offset                                  |
+// |  0x20  |    8   | Physical address of the descriptor table             |
+// |  0x28  |    8   | Physical address of the available ring               |
+// |  0x30  |    8   | Physical address of the used ring                    |
+// +--------+--------+------------------------------------------------------+
+//
+// The modern interface is used when the device has it, unless the kernel
+// command line has 'virtio=legacy'.
+// ---
+// Interrupts are only taken through MSI-X, with the entry in its table for
+// each queue being the queue's number, so they need the APICs. Otherwise
+// drivers poll the used rings.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::structures::port::{PortRead, PortWrite};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::device::{self, Binding, Bus, Driver, Interface, ProbeError,
+                    Resource};
+use crate::pci::{self, Bar, MsiX};
+use crate::{apic, cmdline, memory};
+use super::queue::{Queue, MAX_QUEUE_SIZE};
+use super::{VirtioError, FEATURE_VERSION_1, STATUS_ACKNOWLEDGE,
+            STATUS_FEATURES_OK};
+
+const VENDOR_ID: u16 = 0x1af4;
+const FIRST_DEVICE_ID: u16 = 0x1000;
+const FIRST_MODERN_DEVICE_ID: u16 = 0x1040;
+const LAST_DEVICE_ID: u16 = 0x107f;
+
+// The most virtio devices which can be set up.
+pub const MAX_TRANSPORTS: usize = 16;
+
+const LEGACY_DEVICE_FEATURES: u64 = 0x00;
+const LEGACY_DRIVER_FEATURES: u64 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u64 = 0x08;
+const LEGACY_QUEUE_SIZE: u64 = 0x0c;
+const LEGACY_QUEUE_SELECT: u64 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u64 = 0x10;
+const LEGACY_STATUS: u64 = 0x12;
+const LEGACY_ISR: u64 = 0x13;
+const LEGACY_QUEUE_VECTOR: u64 = 0x16;
+const LEGACY_CONFIG: u64 = 0x14;
+const LEGACY_CONFIG_MSIX: u64 = 0x18;
+
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const DEVICE_STATUS: u64 = 0x14;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFFSET: u64 = 0x1e;
+const QUEUE_DESCRIPTORS: u64 = 0x20;
+const QUEUE_DRIVER: u64 = 0x28;
+const QUEUE_DEVICE: u64 = 0x30;
+
+const CAPABILITY_TYPE: u16 = 3;
+const CAPABILITY_BAR: u16 = 4;
+const CAPABILITY_OFFSET: u16 = 8;
+const CAPABILITY_NOTIFY_MULTIPLIER: u16 = 16;
+
+const CONFIG_COMMON: u8 = 1;
+const CONFIG_NOTIFY: u8 = 2;
+const CONFIG_ISR: u8 = 3;
+const CONFIG_DEVICE: u8 = 4;
+
+// What the MSI-X vector registers hold when there is no vector.
+const NO_VECTOR: u16 = 0xffff;
+
+const PAGE_SHIFT: u64 = 12;
+
+// A block of registers, in I/O space or memory.
+#[derive(Debug, Clone, Copy)]
+enum Window {
+    Ports(u16),
+    Memory(VirtAddr),
+}
+
+impl Window {
+    fn read<T: PortRead>(&self, offset: u64) -> T {
+        unsafe {
+            match *self {
+                Window::Ports(base) => {
+                    Port::<T>::new(base + offset as u16).read()
+                }
+                Window::Memory(base) => {
+                    core::ptr::read_volatile((base + offset).as_ptr())
+                }
+            }
+        }
+    }
+
+    fn write<T: PortWrite>(&self, offset: u64, value: T) {
+        unsafe {
+            match *self {
+                Window::Ports(base) => {
+                    Port::<T>::new(base + offset as u16).write(value)
+                }
+                Window::Memory(base) => {
+                    core::ptr::write_volatile((base + offset).as_mut_ptr(),
+                                              value)
+                }
+            }
+        }
+    }
+
+    // 64-bit registers are written as two halves, low first.
+    fn write_u64(&self, offset: u64, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Registers {
+    Legacy(Window),
+    Modern {
+        common: Window,
+        notify: Window,
+        notify_multiplier: u32,
+        isr: Window,
+        device: Window,
+    },
+}
+
+// The part of a BAR from the given offset.
+fn bar_window(device: &pci::Device, bar: u8, offset: u32) -> Option<Window> {
+    match device.bars.get(usize::from(bar))? {
+        Some(Bar::Memory { address, .. }) => {
+            let address = PhysAddr::new(address + u64::from(offset));
+            Some(Window::Memory(memory::phys_to_virt(address)?))
+        }
+        Some(Bar::Io { port, .. }) => Some(Window::Ports(port + offset as u16)),
+        None => None,
+    }
+}
+
+// Find the modern structures. Returns None if any of them are missing, or
+// can't be reached.
+fn modern_registers(device: &pci::Device) -> Option<Registers> {
+    let mut windows = [None; 4];
+    let mut notify_multiplier = 0;
+
+    let capabilities = device.capabilities()
+        .filter(|capability| capability.id == pci::CAPABILITY_VENDOR);
+    for capability in capabilities {
+        let offset = capability.offset;
+        let config_type = device.read_u8(offset + CAPABILITY_TYPE);
+        if !(CONFIG_COMMON..=CONFIG_DEVICE).contains(&config_type) {
+            continue;
+        }
+
+        // The first structure of each type is the one to use.
+        let window = &mut windows[usize::from(config_type - 1)];
+        if window.is_some() {
+            continue;
+        }
+        *window = bar_window(device, device.read_u8(offset + CAPABILITY_BAR),
+                             device.read_u32(offset + CAPABILITY_OFFSET));
+        if config_type == CONFIG_NOTIFY {
+            notify_multiplier =
+                device.read_u32(offset + CAPABILITY_NOTIFY_MULTIPLIER);
+        }
+    }
+
+    Some(Registers::Modern {
+        common: windows[usize::from(CONFIG_COMMON - 1)]?,
+        notify: windows[usize::from(CONFIG_NOTIFY - 1)]?,
+        notify_multiplier,
+        isr: windows[usize::from(CONFIG_ISR - 1)]?,
+        device: windows[usize::from(CONFIG_DEVICE - 1)]?,
+    })
+}
+
+// A virtio device's PCI function, and how its registers are reached.
+pub struct Transport {
+    device: &'static pci::Device,
+    registers: Registers,
+    // Only when the APICs are in use.
+    msix: Option<MsiX>,
+}
+
+impl Transport {
+    fn new(device: &'static pci::Device) -> Option<Transport> {
+        let legacy = cmdline::get().value("virtio") == Some("legacy");
+        let modern = if legacy && device.device_id < FIRST_MODERN_DEVICE_ID {
+            None
+        } else {
+            modern_registers(device)
+        };
+
+        let registers = match (modern, device.bars[0]) {
+            (Some(modern), _) => modern,
+            (None, Some(Bar::Io { port, .. })) => {
+                Registers::Legacy(Window::Ports(port))
+            }
+            _ => return None,
+        };
+        let msix = if apic::enabled() { device.msix() } else { None };
+
+        Some(Transport { device, registers, msix })
+    }
+
+    pub fn pci_device(&self) -> &'static pci::Device {
+        self.device
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        matches!(self.registers, Registers::Legacy(_))
+    }
+
+    pub fn device_type(&self) -> u32 {
+        if self.device.device_id >= FIRST_MODERN_DEVICE_ID {
+            u32::from(self.device.device_id - FIRST_MODERN_DEVICE_ID)
+        } else {
+            u32::from(self.device.subsystem_id)
+        }
+    }
+
+    // Whether queues can interrupt, through MSI-X.
+    pub fn has_interrupts(&self) -> bool {
+        self.msix.is_some()
+    }
+
+    pub fn status(&self) -> u8 {
+        match self.registers {
+            Registers::Legacy(io) => io.read(LEGACY_STATUS),
+            Registers::Modern { common, .. } => common.read(DEVICE_STATUS),
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match self.registers {
+            Registers::Legacy(io) => io.write(LEGACY_STATUS, status),
+            Registers::Modern { common, .. } => {
+                common.write(DEVICE_STATUS, status)
+            }
+        }
+    }
+
+    // Set more of the status bits, such as STATUS_DRIVER_OK.
+    pub fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    // Stop the device, and forget its queues and features. Modern devices
+    // may take a while, and say they have finished by reading back 0.
+    pub fn reset(&self) {
+        self.set_status(0);
+        while self.status() != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self.registers {
+            Registers::Legacy(io) => {
+                u64::from(io.read::<u32>(LEGACY_DEVICE_FEATURES))
+            }
+            Registers::Modern { common, .. } => {
+                common.write(DEVICE_FEATURE_SELECT, 0u32);
+                let low = common.read::<u32>(DEVICE_FEATURE);
+                common.write(DEVICE_FEATURE_SELECT, 1u32);
+                let high = common.read::<u32>(DEVICE_FEATURE);
+                u64::from(high) << 32 | u64::from(low)
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match self.registers {
+            Registers::Legacy(io) => {
+                io.write(LEGACY_DRIVER_FEATURES, features as u32)
+            }
+            Registers::Modern { common, .. } => {
+                common.write(DRIVER_FEATURE_SELECT, 0u32);
+                common.write(DRIVER_FEATURE, features as u32);
+                common.write(DRIVER_FEATURE_SELECT, 1u32);
+                common.write(DRIVER_FEATURE, (features >> 32) as u32);
+            }
+        }
+    }
+
+    // Accept the features the device offers which are in supported, and
+    // return them. FEATURE_VERSION_1 is added for modern devices.
+    pub fn negotiate(&self, supported: u64) -> Result<u64, VirtioError> {
+        let offered = self.device_features();
+        if self.is_legacy() {
+            let features = offered & supported & u64::from(u32::MAX);
+            self.set_driver_features(features);
+            return Ok(features);
+        }
+
+        if offered & FEATURE_VERSION_1 == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        let features = offered & supported | FEATURE_VERSION_1;
+        self.set_driver_features(features);
+        self.add_status(STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    // Point the queue's MSI-X entry at the vector, returning the value for
+    // the queue's vector register.
+    fn queue_vector(&self, index: u16, vector: Option<u8>) -> u16 {
+        match (self.msix, vector) {
+            (Some(msix), Some(vector)) if index < msix.table_size() => {
+                msix.set_entry(index, apic::id(), vector);
+                index
+            }
+            _ => NO_VECTOR,
+        }
+    }
+
+    // Set up queue number index, as large as the device and MAX_QUEUE_SIZE
+    // allow. If a vector is given, from interrupts::allocate_vector, and the
+    // device can use MSI-X, it interrupts with it when it uses the queue.
+    pub fn create_queue(&self, index: u16, vector: Option<u8>)
+            -> Result<Queue, VirtioError> {
+        let (mut queue, accepted) = match self.registers {
+            Registers::Legacy(io) => {
+                io.write(LEGACY_QUEUE_SELECT, index);
+                let size = io.read::<u16>(LEGACY_QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::MissingQueue);
+                } else if size > MAX_QUEUE_SIZE {
+                    return Err(VirtioError::QueueTooLarge);
+                }
+
+                let queue = Queue::new(index, size)
+                    .ok_or(VirtioError::OutOfMemory)?;
+                let mut accepted = NO_VECTOR;
+                if self.msix.is_some() {
+                    io.write(LEGACY_QUEUE_VECTOR,
+                             self.queue_vector(index, vector));
+                    accepted = io.read(LEGACY_QUEUE_VECTOR);
+                }
+                let page = queue.descriptor_table().as_u64() >> PAGE_SHIFT;
+                io.write(LEGACY_QUEUE_ADDRESS, page as u32);
+                (queue, accepted)
+            }
+            Registers::Modern { common, .. } => {
+                common.write(QUEUE_SELECT, index);
+                let size = common.read::<u16>(QUEUE_SIZE);
+                if size == 0 {
+                    return Err(VirtioError::MissingQueue);
+                }
+
+                let size = core::cmp::min(size, MAX_QUEUE_SIZE);
+                let mut queue = Queue::new(index, size)
+                    .ok_or(VirtioError::OutOfMemory)?;
+                common.write(QUEUE_SIZE, size);
+                common.write(QUEUE_VECTOR, self.queue_vector(index, vector));
+                common.write_u64(QUEUE_DESCRIPTORS,
+                                 queue.descriptor_table().as_u64());
+                common.write_u64(QUEUE_DRIVER, queue.available_ring().as_u64());
+                common.write_u64(QUEUE_DEVICE, queue.used_ring().as_u64());
+                queue.set_notify_offset(common.read(QUEUE_NOTIFY_OFFSET));
+                let accepted = common.read(QUEUE_VECTOR);
+                common.write(QUEUE_ENABLE, 1u16);
+                (queue, accepted)
+            }
+        };
+
+        // The device reads back NO_VECTOR if it couldn't take the vector.
+        queue.set_interrupts(accepted != NO_VECTOR);
+        Ok(queue)
+    }
+
+    // Tell the device there are new buffers in the queue.
+    pub fn notify(&self, queue: &Queue) {
+        match self.registers {
+            Registers::Legacy(io) => {
+                io.write(LEGACY_QUEUE_NOTIFY, queue.index())
+            }
+            Registers::Modern { notify, notify_multiplier, .. } => {
+                let offset = u64::from(queue.notify_offset())
+                    * u64::from(notify_multiplier);
+                notify.write(offset, queue.index());
+            }
+        }
+    }
+
+    // Read the ISR status, which also clears it.
+    pub fn read_isr(&self) -> u8 {
+        match self.registers {
+            Registers::Legacy(io) => io.read(LEGACY_ISR),
+            Registers::Modern { isr, .. } => isr.read(0),
+        }
+    }
+
+    fn config(&self) -> (Window, u64) {
+        match self.registers {
+            Registers::Legacy(io) if self.msix.is_some() => {
+                (io, LEGACY_CONFIG_MSIX)
+            }
+            Registers::Legacy(io) => (io, LEGACY_CONFIG),
+            Registers::Modern { device, .. } => (device, 0),
+        }
+    }
+
+    // Read the device configuration, at an offset from its start.
+    pub fn read_config_u8(&self, offset: u64) -> u8 {
+        let (window, base) = self.config();
+        window.read(base + offset)
+    }
+
+    pub fn read_config_u16(&self, offset: u64) -> u16 {
+        let (window, base) = self.config();
+        window.read(base + offset)
+    }
+
+    pub fn read_config_u32(&self, offset: u64) -> u32 {
+        let (window, base) = self.config();
+        window.read(base + offset)
+    }
+
+    pub fn read_config_u64(&self, offset: u64) -> u64 {
+        u64::from(self.read_config_u32(offset + 4)) << 32
+            | u64::from(self.read_config_u32(offset))
+    }
+}
+
+const NO_TRANSPORT: Once<Transport> = Once::new();
+
+static TRANSPORTS: [Once<Transport>; MAX_TRANSPORTS] =
+    [NO_TRANSPORT; MAX_TRANSPORTS];
+static TRANSPORT_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// The transport with the index given in a Resource::Virtio.
+pub fn transport(index: usize) -> Option<&'static Transport> {
+    TRANSPORTS.get(index)?.r#try()
+}
+
+fn find_transport(address: pci::Address) -> Option<usize> {
+    (0..MAX_TRANSPORTS).find(|index| {
+        transport(*index)
+            .map_or(false, |transport| transport.device.address == address)
+    })
+}
+
+// Set up a transport for the function, or find the one set up when it was
+// last probed.
+fn add_transport(device: &'static pci::Device) -> Result<usize, ProbeError> {
+    if let Some(index) = find_transport(device.address) {
+        return Ok(index);
+    }
+
+    let transport = Transport::new(device)
+        .ok_or(ProbeError::Failed("the device's registers can't be reached"))?;
+    let index = TRANSPORT_COUNT.fetch_add(1, Ordering::SeqCst);
+    if index >= MAX_TRANSPORTS {
+        return Err(ProbeError::Failed("there are too many virtio devices"));
+    }
+
+    TRANSPORTS[index].call_once(|| transport);
+    Ok(index)
+}
+
+pub struct VirtioPciDriver;
+
+impl Driver for VirtioPciDriver {
+    fn name(&self) -> &'static str {
+        "virtio-pci"
+    }
+
+    fn bus(&self) -> Bus {
+        Bus::Pci
+    }
+
+    fn matches(&self, resource: &Resource) -> bool {
+        match resource {
+            Resource::Pci(device) => {
+                device.vendor_id == VENDOR_ID
+                    && (FIRST_DEVICE_ID..=LAST_DEVICE_ID)
+                        .contains(&device.device_id)
+            }
+            _ => false,
+        }
+    }
+
+    // Reset the device and acknowledge it, and then add the device behind
+    // the transport, for the driver for its type to finish setting up.
+    fn probe(&self, resource: &Resource) -> Result<Binding, ProbeError> {
+        let device = match resource {
+            Resource::Pci(device) => *device,
+            _ => return Err(ProbeError::NotPresent),
+        };
+        let index = add_transport(device)?;
+        let transport = transport(index)
+            .ok_or(ProbeError::Failed("the transport wasn't set up"))?;
+
+        transport.reset();
+        device.set_command(pci::COMMAND_IO_SPACE | pci::COMMAND_MEMORY_SPACE
+                           | pci::COMMAND_BUS_MASTER
+                           | pci::COMMAND_INTERRUPT_DISABLE);
+        if let Some(msix) = transport.msix {
+            msix.enable();
+        }
+        transport.add_status(STATUS_ACKNOWLEDGE);
+
+        let child = Resource::Virtio {
+            device_type: transport.device_type(),
+            transport: index,
+        };
+        device::add(child)
+            .map_err(|_| ProbeError::Failed("the device registry is full"))?;
+
+        Ok(Binding { name: "virtio", interface: Interface::None })
+    }
+
+    // Take the device behind the transport out of the registry, and stop
+    // the device.
+    fn remove(&self, resource: &Resource) {
+        let device = match resource {
+            Resource::Pci(device) => *device,
+            _ => return,
+        };
+        let index = match find_transport(device.address) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let children = device::devices().filter(|child| {
+            matches!(child.resource,
+                     Resource::Virtio { transport, .. } if transport == index)
+        });
+        for child in children {
+            let _ = device::remove(child.id);
+        }
+
+        if let Some(transport) = transport(index) {
+            transport.reset();
+            if let Some(msix) = transport.msix {
+                msix.disable();
+            }
+        }
+        device.set_command(0);
+    }
+}
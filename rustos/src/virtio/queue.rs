@@ -0,0 +1,348 @@
+// Split virtqueues, which is how buffers are passed between a driver and a
+// virtio device. A queue is three areas of memory, which the device reads
+// and writes itself:
+// - Descriptor table: One 16-byte descriptor for each buffer, with its
+//               physical address, its length, and flags saying whether the
+//               device writes to it, and whether the request carries on in
+//               the descriptor named by its next field.
+// - Available ring: The driver's ring, with a flags field, the index it will
+//               write the next entry at, and then the first descriptor of
+//               each request it has made, or chain.
+// - Used ring: The device's ring, with the same two fields, and then the
+//               first descriptor of each chain it has finished with, and how
+//               many bytes it wrote.
+//
+// The indexes count up forever, wrapping at 65536, and are taken modulo the
+// size of the queue to find the entry. Legacy devices need all three areas in
+// one block, with the used ring starting on a 4KiB boundary, which modern
+// devices accept too, so every queue is laid out that way, in frames which
+// are next to each other in physical memory.
+// ---
+// The descriptors which aren't part of a request are kept in a free list,
+// linked through their next fields.
+
+use core::sync::atomic::{fence, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::memory;
+
+// The most descriptors a queue is set up with.
+pub const MAX_QUEUE_SIZE: u16 = 256;
+
+const PAGE_SIZE: u64 = 4096;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+const DESCRIPTOR_NEXT: u16 = 1 << 0;
+const DESCRIPTOR_WRITE: u16 = 1 << 1;
+
+// The flags and index fields at the start of both rings.
+const RING_HEADER_SIZE: u64 = 4;
+const USED_ELEMENT_SIZE: u64 = 8;
+
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+// A buffer for the device to read or write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+pub struct Queue {
+    index: u16,
+    size: u16,
+    base: VirtAddr,
+    physical: PhysAddr,
+    available_offset: u64,
+    used_offset: u64,
+    free_head: u16,
+    free_count: u16,
+    next_available: u16,
+    last_used: u16,
+    // Where the queue is notified, for modern devices.
+    notify_offset: u16,
+    interrupts: bool,
+}
+
+// Where the rings start, and how many bytes the queue needs, for a queue of
+// the given size.
+fn layout(size: u16) -> (u64, u64, u64) {
+    let size = u64::from(size);
+    let available_offset = size * DESCRIPTOR_SIZE;
+    let available_end = available_offset + RING_HEADER_SIZE + 2 * size + 2;
+    let used_offset = align_up(available_end, PAGE_SIZE);
+    let used_end = used_offset + RING_HEADER_SIZE + USED_ELEMENT_SIZE * size
+        + 2;
+
+    (available_offset, used_offset, align_up(used_end, PAGE_SIZE))
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+impl Queue {
+    // Allocate and clear the memory for queue number index, with size
+    // descriptors. Returns None if there isn't enough memory.
+    pub fn new(index: u16, size: u16) -> Option<Queue> {
+        assert!(size > 0 && size <= MAX_QUEUE_SIZE, "bad queue size {}", size);
+
+        let (available_offset, used_offset, bytes) = layout(size);
+        let first = memory::allocate_frames((bytes / PAGE_SIZE) as usize)?;
+        let physical = first.start_address();
+        let base = memory::phys_to_virt(physical)?;
+        unsafe {
+            core::ptr::write_bytes(base.as_mut_ptr::<u8>(), 0, bytes as usize);
+        }
+
+        let queue = Queue {
+            index,
+            size,
+            base,
+            physical,
+            available_offset,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            next_available: 0,
+            last_used: 0,
+            notify_offset: 0,
+            interrupts: false,
+        };
+        for descriptor in 0..size - 1 {
+            unsafe { (*queue.descriptor(descriptor)).next = descriptor + 1 };
+        }
+
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    // The physical addresses of the descriptor table and the two rings.
+    pub fn descriptor_table(&self) -> PhysAddr {
+        self.physical
+    }
+
+    pub fn available_ring(&self) -> PhysAddr {
+        self.physical + self.available_offset
+    }
+
+    pub fn used_ring(&self) -> PhysAddr {
+        self.physical + self.used_offset
+    }
+
+    pub(super) fn notify_offset(&self) -> u16 {
+        self.notify_offset
+    }
+
+    pub(super) fn set_notify_offset(&mut self, offset: u16) {
+        self.notify_offset = offset;
+    }
+
+    // Whether the device interrupts when it uses the queue. Otherwise the
+    // used ring has to be polled.
+    pub fn has_interrupts(&self) -> bool {
+        self.interrupts
+    }
+
+    pub(super) fn set_interrupts(&mut self, interrupts: bool) {
+        self.interrupts = interrupts;
+    }
+
+    // The number of descriptors which aren't in use.
+    pub fn free(&self) -> u16 {
+        self.free_count
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        (self.base + u64::from(index) * DESCRIPTOR_SIZE).as_mut_ptr()
+    }
+
+    // The 16-bit field at the given offset from the start of the queue.
+    fn field(&self, offset: u64) -> *mut u16 {
+        (self.base + offset).as_mut_ptr()
+    }
+
+    // Chain the buffers together, and offer them to the device. Returns the
+    // first descriptor, which the used ring gives back once the device has
+    // finished with them, or None if there aren't enough free descriptors.
+    // The device has to be notified afterwards.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(index);
+            unsafe {
+                let next = (*descriptor).next;
+                let mut flags = 0;
+                if buffer.device_writes {
+                    flags |= DESCRIPTOR_WRITE;
+                }
+                if i + 1 < buffers.len() {
+                    flags |= DESCRIPTOR_NEXT;
+                }
+
+                core::ptr::write_volatile(descriptor, Descriptor {
+                    address: buffer.address.as_u64(),
+                    len: buffer.len,
+                    flags,
+                    next,
+                });
+
+                if i + 1 < buffers.len() {
+                    index = next;
+                } else {
+                    self.free_head = next;
+                }
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = u64::from(self.next_available % self.size);
+        let entry = self.available_offset + RING_HEADER_SIZE + 2 * slot;
+        unsafe { core::ptr::write_volatile(self.field(entry), head) };
+
+        // The device mustn't see the new index before the entry it covers.
+        self.next_available = self.next_available.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        unsafe {
+            core::ptr::write_volatile(self.field(self.available_offset + 2),
+                                      self.next_available);
+        }
+        fence(Ordering::SeqCst);
+
+        Some(head)
+    }
+
+    // Take the next chain the device has finished with, returning its first
+    // descriptor and the number of bytes the device wrote to it. Its
+    // descriptors go back on the free list.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe {
+            core::ptr::read_volatile(self.field(self.used_offset + 2))
+        };
+        if used == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+
+        let slot = u64::from(self.last_used % self.size);
+        let element = self.base + self.used_offset + RING_HEADER_SIZE
+            + USED_ELEMENT_SIZE * slot;
+        let (head, len) = unsafe {
+            let element: *const u32 = element.as_ptr();
+            (core::ptr::read_volatile(element) as u16,
+             core::ptr::read_volatile(element.add(1)))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Find the end of the chain, and put the whole chain on the front of
+        // the free list.
+        let mut last = head;
+        let mut count = 1;
+        unsafe {
+            while (*self.descriptor(last)).flags & DESCRIPTOR_NEXT != 0 {
+                last = (*self.descriptor(last)).next;
+                count += 1;
+            }
+            (*self.descriptor(last)).next = self.free_head;
+        }
+        self.free_head = head;
+        self.free_count += count;
+
+        Some((head, len))
+    }
+}
+
+// Split a buffer in virtual memory into the pieces which are next to each
+// other in physical memory, one for each page it covers. Returns the number
+// of pieces written to out, or None if part of the buffer isn't mapped, or
+// there isn't room in out.
+pub fn physical_buffers(address: VirtAddr, len: usize, device_writes: bool,
+                        out: &mut [Buffer]) -> Option<usize> {
+    let mut count = 0;
+    let mut address = address;
+    let end = address + len as u64;
+
+    while address < end {
+        let page_end = align_up(address.as_u64() + 1, PAGE_SIZE);
+        let piece = core::cmp::min(page_end, end.as_u64()) - address.as_u64();
+
+        *out.get_mut(count)? = Buffer {
+            address: memory::translate(address)?,
+            len: piece as u32,
+            device_writes,
+        };
+        count += 1;
+        address += piece;
+    }
+
+    Some(count)
+}
+
+
+// TESTING
+
+// Test that the rings are laid out where legacy devices expect them.
+#[test_case]
+fn test_queue_layout() {
+    assert_eq!(layout(256), (4096, 8192, 12288));
+    assert_eq!(layout(16), (256, 4096, 8192));
+}
+
+// Test that chains are taken from the free list, offered in the available
+// ring, and returned to the free list when the used ring gives them back,
+// playing the part of the device.
+#[test_case]
+fn test_queue_add_and_pop() {
+    let mut queue = Queue::new(0, 8).expect("couldn't allocate a queue");
+    let buffer = |address, device_writes| Buffer {
+        address: PhysAddr::new(address),
+        len: 16,
+        device_writes,
+    };
+
+    let first = queue.add(&[buffer(0x1000, false), buffer(0x2000, true)])
+        .unwrap();
+    let second = queue.add(&[buffer(0x3000, false)]).unwrap();
+    assert_eq!(queue.free(), 5);
+    assert!(queue.add(&[buffer(0x4000, false); 6]).is_none());
+    assert!(queue.pop_used().is_none());
+
+    unsafe {
+        let descriptor = &*queue.descriptor(first);
+        assert_eq!(descriptor.flags, DESCRIPTOR_NEXT);
+        let next = &*queue.descriptor(descriptor.next);
+        assert_eq!((next.address, next.flags), (0x2000, DESCRIPTOR_WRITE));
+        assert_eq!(*queue.field(queue.available_offset + 2), 2);
+
+        let used: *mut u32 = (queue.base + queue.used_offset
+                              + RING_HEADER_SIZE).as_mut_ptr();
+        *used = u32::from(second);
+        *used.add(1) = 0;
+        *used.add(2) = u32::from(first);
+        *used.add(3) = 16;
+        *queue.field(queue.used_offset + 2) = 2;
+    }
+
+    assert_eq!(queue.pop_used(), Some((second, 0)));
+    assert_eq!(queue.pop_used(), Some((first, 16)));
+    assert_eq!(queue.pop_used(), None);
+    assert_eq!(queue.free(), 8);
+}
@@ -0,0 +1,90 @@
+// Tests that the backtrace of a fault starts at the code which faulted, rather
+// than in the handler. The page fault handler is given an error code, which
+// sits where a return address would be if the walk started one frame too late.
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame,
+                              PageFaultErrorCode};
+use rustos::{backtrace, exit_qemu, QemuExitCode, serial_print, serial_println};
+
+// An address which the bootloader doesn't map.
+const UNMAPPED: u64 = 0xdead_0000_0000;
+
+lazy_static! {
+    // Set up the test IDT, to call a custom page fault handler function, which
+    // checks the backtrace of the fault.
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("fault_backtrace::fault_backtrace...\t");
+
+    rustos::gdt::init();
+    TEST_IDT.load();
+
+    call_fault();
+
+    panic!("Execution continued after a page fault");
+}
+
+// The function the backtrace has to find, as the caller of the faulting code.
+#[inline(never)]
+fn call_fault() {
+    fault();
+
+    // Stop the call to fault being made into a tail call.
+    volatile::Volatile::new(0).read();
+}
+
+#[inline(never)]
+fn fault() {
+    unsafe {
+        core::ptr::read_volatile(UNMAPPED as *const u8);
+    }
+}
+
+// Check that the first frame found from the interrupted frame pointer is a
+// return address in call_fault, and exit QEMU with the result.
+extern "x86-interrupt" fn test_page_fault_handler(
+    stack_frame: &mut InterruptStackFrame, _error_code: PageFaultErrorCode) {
+        let rbp = backtrace::interrupted_frame_pointer();
+
+        let symbols = backtrace::kernel_symbols()
+            .expect("tools/ksyms.py didn't fill in the symbol table");
+        let name = |address: u64| symbols.lookup(address).map(|s| s.name);
+
+        let mut caller = None;
+        backtrace::walk(rbp, |address| {
+            if caller.is_none() {
+                caller = Some(address);
+            }
+        });
+
+        let faulted = name(stack_frame.instruction_pointer.as_u64());
+        let caller = caller.and_then(|address| name(address - 1));
+        if faulted.map_or(false, |name| name.ends_with("::fault"))
+                && caller.map_or(false, |name| name.ends_with("::call_fault")) {
+            serial_println!("[ok]");
+            exit_qemu(QemuExitCode::Success);
+        } else {
+            serial_println!("[failed]\n");
+            serial_println!("Error: faulted in {:?}, called from {:?}\n",
+                            faulted, caller);
+            exit_qemu(QemuExitCode::Failed);
+        }
+        rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
@@ -0,0 +1,88 @@
+// Test that printing from an interrupt handler, while the main code is busy
+// printing as well, doesn't deadlock. Before printing was made interrupt safe,
+// the timer firing while println! held the WRITER lock would hang the kernel
+// as soon as the timer handler tried to print.
+// ---
+// We use our own IDT, with a timer handler which prints and counts how many
+// times it has done so. The test then prints until the handler has printed a
+// good number of times. If printing isn't interrupt safe, the test never
+// finishes and is killed by the test timeout.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![feature(abi_x86_interrupt)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use rustos::interrupts::{InterruptIndex, PICS};
+use rustos::{print, println};
+
+// The number of times the timer handler needs to print before the test passes.
+const HANDLER_PRINTS: usize = 20;
+
+static PRINTS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt[InterruptIndex::Timer.as_usize()]
+            .set_handler_fn(test_timer_handler);
+        idt[InterruptIndex::Keyboard.as_usize()]
+            .set_handler_fn(test_keyboard_handler);
+
+        idt
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rustos::gdt::init();
+    TEST_IDT.load();
+    rustos::interrupts::init_pics();
+    x86_64::instructions::interrupts::enable();
+
+    test_main();
+
+    rustos::hlt_loop();
+}
+
+// Print from the timer handler, then acknowledge the interrupt.
+extern "x86-interrupt" fn test_timer_handler(
+    _stack_frame: &mut InterruptStackFrame,
+) {
+    print!("[timer]");
+    PRINTS.fetch_add(1, Ordering::SeqCst);
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    }
+}
+
+// Nothing presses keys during the tests, but a keyboard interrupt without a
+// handler would cause a double fault, so acknowledge any that turn up.
+extern "x86-interrupt" fn test_keyboard_handler(
+    _stack_frame: &mut InterruptStackFrame,
+) {
+    use x86_64::instructions::port::Port;
+
+    let _scancode: u8 = unsafe { Port::new(0x60).read() };
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    }
+}
+
+#[test_case]
+fn test_print_from_timer_handler() {
+    while PRINTS.load(Ordering::SeqCst) < HANDLER_PRINTS {
+        println!("test_print_from_timer_handler output");
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
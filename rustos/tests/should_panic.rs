@@ -1,25 +1,52 @@
+// Tests which are expected to panic. Each test is a static TestCase rather than
+// a function, so that it can say how it expects to fail. The runner catches the
+// panic, checks it against what was expected, and carries on to the next test.
 #![no_std]
 #![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use rustos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use rustos::{test_fn, TestCase};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    should_fail();
-    serial_println!("[test did not panic]");
-    exit_qemu(QemuExitCode::Failed);
-    loop{}
+    test_main();
+    rustos::hlt_loop();
 }
 
 fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
     assert_eq!(0, 1);
 }
 
+#[test_case]
+static SHOULD_FAIL: TestCase = test_fn!(should_fail).should_panic();
+
+fn unwrap_none() {
+    let array = [1, 2, 3];
+    array.iter().find(|&&x| x > 3).unwrap();
+}
+
+#[test_case]
+static UNWRAP_NONE: TestCase = test_fn!(unwrap_none)
+    .should_panic_with("on a `None` value");
+
+fn explicit_panic() {
+    panic!("something went wrong: {}", 42);
+}
+
+#[test_case]
+static EXPLICIT_PANIC: TestCase = test_fn!(explicit_panic)
+    .should_panic_with("went wrong: 42");
+
+// A test which doesn't panic can still be run alongside the others.
+#[test_case]
+fn does_not_panic() {
+    assert_eq!(1 + 1, 2);
+}
+
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
 }
@@ -0,0 +1,82 @@
+// Tests for starting the other CPUs. tools/runner.py starts QEMU with four
+// CPUs for this test, which each have to check in.
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rustos::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rustos::{acpi, ipi, percpu, smp, tlb};
+use x86_64::VirtAddr;
+
+// The number of CPUs tools/runner.py gives QEMU.
+const CPUS: usize = 4;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rustos::memory::init(boot_info);
+    rustos::init();
+    test_main();
+    rustos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rustos::test_panic_handler(info)
+}
+
+// Test that the MADT lists every CPU, and that each of them started.
+#[test_case]
+fn test_every_cpu_online() {
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .expect("there is no MADT");
+    let enabled = madt.processors().iter()
+        .filter(|processor| processor.enabled)
+        .count();
+
+    assert_eq!(enabled, CPUS);
+    assert_eq!(smp::online_cpus(), CPUS);
+}
+
+// Test that each CPU has its own per-CPU data, with its own APIC ID, and that
+// this test runs on the bootstrap processor.
+#[test_case]
+fn test_per_cpu_data() {
+    assert_eq!(percpu::current().index(), 0);
+
+    for (index, cpu) in percpu::online().enumerate() {
+        assert_eq!(cpu.index(), index);
+        for other in percpu::online().skip(index + 1) {
+            assert_ne!(cpu.apic_id(), other.apic_id());
+        }
+    }
+}
+
+// Test that run_on runs a function on each CPU, which can borrow from the
+// caller's stack.
+#[test_case]
+fn test_run_on() {
+    let greeting = "hello";
+
+    for cpu in 0..smp::online_cpus() {
+        let (index, length) = ipi::run_on(cpu, || {
+            (percpu::current().index(), greeting.len())
+        });
+        assert_eq!(index, cpu);
+        assert_eq!(length, greeting.len());
+    }
+}
+
+// Test that a TLB shootdown finishes, which needs every other CPU to flush.
+#[test_case]
+fn test_tlb_shootdown() {
+    let value = 0u64;
+    let address = VirtAddr::from_ptr(&value);
+
+    tlb::shootdown(address, 1);
+    tlb::shootdown(address, 64);
+}
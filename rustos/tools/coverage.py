@@ -0,0 +1,122 @@
+#!/usr/bin/env python3
+# Run the kernel tests with code coverage, and print a report of which lines
+# they ran.
+# ---
+# The tests are built with LLVM's source-based coverage instrumentation and
+# the coverage feature (see src/coverage.rs), and run in QEMU as usual. Each
+# test kernel writes its profile through the debug console, which
+# tools/runner.py saves to target/coverage/<kernel name>.profraw. The profiles
+# are then merged with llvm-profdata, and llvm-cov reports on them against the
+# test kernels.
+#
+#     tools/coverage.py                 # Print a summary for each file
+#     tools/coverage.py --html          # Write an HTML report as well
+#     tools/coverage.py -- serial       # Only run tests matching 'serial'
+#
+# llvm-profdata and llvm-cov have to match the LLVM version of the nightly
+# compiler. The easiest way to get them is 'rustup component add
+# llvm-tools-preview', which this script looks for first.
+
+import argparse
+import glob
+import json
+import os
+import shutil
+import subprocess
+import sys
+
+ROOT = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+COVERAGE_DIR = os.path.join(ROOT, "target", "coverage")
+# Setting RUSTFLAGS replaces the rustflags in .cargo/config.toml, so they have
+# to be repeated here.
+RUSTFLAGS = ("-C force-frame-pointers=yes "
+             "-Z instrument-coverage -Z no-profiler-runtime")
+
+
+def llvm_tool(name):
+    sysroot = subprocess.check_output(["rustc", "--print", "sysroot"],
+                                      text=True).strip()
+    for path in glob.glob(os.path.join(sysroot, "lib", "rustlib", "*", "bin",
+                                       name)):
+        return path
+    if shutil.which(name):
+        return name
+    sys.exit("can't find {}, try 'rustup component add llvm-tools-preview'"
+             .format(name))
+
+
+def cargo_test(arguments, env):
+    command = ["cargo", "test", "--features", "coverage"]
+    return subprocess.run(command + arguments, cwd=ROOT, env=env).returncode
+
+
+# The paths of the test kernels cargo built, which llvm-cov needs to map the
+# counters back to source lines.
+def test_kernels(env):
+    command = ["cargo", "test", "--features", "coverage", "--no-run",
+               "--message-format=json"]
+    output = subprocess.run(command, cwd=ROOT, env=env, check=True,
+                            stdout=subprocess.PIPE, text=True).stdout
+
+    kernels = []
+    for line in output.splitlines():
+        message = json.loads(line)
+        if message.get("reason") == "compiler-artifact" \
+                and message["profile"]["test"] and message.get("executable"):
+            kernels.append(message["executable"])
+    return kernels
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description="Run the kernel tests with code coverage.")
+    parser.add_argument("--html", action="store_true",
+                        help="write an HTML report to target/coverage/html")
+    parser.add_argument("arguments", nargs="*",
+                        help="passed to cargo test, after '--'")
+    options = parser.parse_args()
+
+    env = dict(os.environ, RUSTFLAGS=RUSTFLAGS,
+               RUSTOS_COVERAGE_DIR=COVERAGE_DIR)
+    shutil.rmtree(COVERAGE_DIR, ignore_errors=True)
+
+    status = cargo_test(["--"] + options.arguments, env)
+    if status != 0:
+        print("coverage: some tests failed, the report may be incomplete",
+              file=sys.stderr)
+
+    profiles = [path for path in glob.glob(os.path.join(COVERAGE_DIR,
+                                                        "*.profraw"))
+                if os.path.getsize(path) > 0]
+    if not profiles:
+        sys.exit("coverage: no profiles were written")
+
+    merged = os.path.join(COVERAGE_DIR, "kernel.profdata")
+    subprocess.run([llvm_tool("llvm-profdata"), "merge", "-sparse",
+                    "-o", merged] + profiles, check=True)
+
+    # llvm-cov takes the first kernel on its own, and any others as options.
+    kernels = test_kernels(env)
+    objects = kernels[:1]
+    for kernel in kernels[1:]:
+        objects += ["-object", kernel]
+
+    # Only report on our own code, not the core library or dependencies,
+    # which are instrumented too.
+    ignore = ["-ignore-filename-regex", r"(\.cargo|rustlib|/rustc/)"]
+    llvm_cov = llvm_tool("llvm-cov")
+    subprocess.run([llvm_cov, "report", "-instr-profile", merged]
+                   + ignore + objects, check=True)
+
+    if options.html:
+        html = os.path.join(COVERAGE_DIR, "html")
+        subprocess.run([llvm_cov, "show", "-format=html", "-output-dir", html,
+                        "-instr-profile", merged] + ignore + objects,
+                       check=True)
+        print("coverage: HTML report written to", html)
+
+    sys.exit(status)
+
+
+if __name__ == "__main__":
+    main()
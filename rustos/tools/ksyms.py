@@ -0,0 +1,173 @@
+#!/usr/bin/env python3
+# Fill in the kernel's symbol table. The kernel reserves an empty .ksyms
+# section, which this replaces with a table of the names and addresses of its
+# functions, taken from the ELF symbol table, so the backtrace module can print
+# function names. See src/backtrace.rs for the layout of the table.
+#
+#     tools/ksyms.py target/x86_64-rustos/debug/rustos
+#
+# The kernel file is changed in place. tools/runner.py runs this on every
+# kernel before starting it, so it only needs to be run by hand on kernels
+# which are started some other way.
+# ---
+# Rust symbol names are mangled, so they are demangled here, where it's easy,
+# rather than in the kernel. Only the legacy mangling scheme, which is the
+# compiler's default, is understood. Anything else is left as it is.
+
+import re
+import struct
+import sys
+
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sI8x")
+ENTRY = struct.Struct("<QIII4x")
+
+SHT_SYMTAB = 2
+STT_FUNC = 2
+
+# The escapes used for characters which aren't allowed in symbol names.
+ESCAPES = {
+    "SP": "@", "BP": "*", "RF": "&", "LT": "<", "GT": ">", "LP": "(",
+    "RP": ")", "C": ",",
+}
+
+HASH = re.compile(r"^h[0-9a-f]{16}$")
+
+
+def demangle(name):
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+
+    parts = []
+    rest = name[3:-1]
+    while rest:
+        match = re.match(r"^(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+
+    if parts and HASH.match(parts[-1]):
+        parts.pop()
+
+    return "::".join(unescape(part) for part in parts)
+
+
+def unescape(part):
+    if part.startswith("_$"):
+        part = part[1:]
+
+    def replace(match):
+        code = match.group(1)
+        if code in ESCAPES:
+            return ESCAPES[code]
+        if code.startswith("u"):
+            return chr(int(code[1:], 16))
+        return match.group(0)
+
+    return re.sub(r"\$([A-Za-z0-9]+)\$", replace, part).replace("..", "::")
+
+
+class Elf:
+    def __init__(self, data):
+        if data[:4] != b"\x7fELF" or data[4] != 2 or data[5] != 1:
+            raise ValueError("not a little-endian 64-bit ELF file")
+
+        self.data = data
+        (section_offset,) = struct.unpack_from("<Q", data, 0x28)
+        entry_size, count, names_index = struct.unpack_from("<HHH", data,
+                                                            0x3a)
+
+        self.sections = []
+        for i in range(count):
+            fields = struct.unpack_from("<IIQQQQIIQQ", data,
+                                        section_offset + i * entry_size)
+            self.sections.append({
+                "name": fields[0], "type": fields[1], "offset": fields[4],
+                "size": fields[5], "link": fields[6], "entry_size": fields[9],
+            })
+
+        names = self.sections[names_index]
+        for section in self.sections:
+            section["name"] = self.string(names, section["name"])
+
+    def string(self, section, offset):
+        start = section["offset"] + offset
+        end = self.data.index(b"\0", start)
+        return self.data[start:end].decode("utf-8", "replace")
+
+    def section(self, name):
+        for section in self.sections:
+            if section["name"] == name:
+                return section
+        return None
+
+    # The address, size and name of every function.
+    def functions(self):
+        for section in self.sections:
+            if section["type"] != SHT_SYMTAB:
+                continue
+            strings = self.sections[section["link"]]
+
+            for offset in range(section["offset"],
+                                section["offset"] + section["size"],
+                                section["entry_size"]):
+                name, info, _, _, value, size = struct.unpack_from(
+                    "<IBBHQQ", self.data, offset)
+                if info & 0xf == STT_FUNC and value != 0:
+                    yield value, size, self.string(strings, name)
+
+
+def build_table(functions, capacity):
+    # Keep one name for each address, as the same function can have several.
+    symbols = {}
+    for address, size, name in functions:
+        symbols.setdefault(address, (size, demangle(name)))
+    symbols = sorted(symbols.items())
+
+    header_size = HEADER.size + len(symbols) * ENTRY.size
+    names = bytearray()
+    entries = bytearray()
+    for address, (size, name) in symbols:
+        encoded = name.encode("utf-8")
+        entries += ENTRY.pack(address, min(size, 0xffffffff),
+                              header_size + len(names), len(encoded))
+        names += encoded
+
+    table = HEADER.pack(MAGIC, len(symbols)) + entries + names
+    if len(table) > capacity:
+        raise ValueError("the symbol table needs {} bytes, but only {} are "
+                         "reserved, increase KSYMS_SIZE in src/backtrace.rs"
+                         .format(len(table), capacity))
+    return table
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: ksyms.py <kernel>")
+
+    with open(sys.argv[1], "r+b") as kernel:
+        elf = Elf(kernel.read())
+
+        # Kernels which don't use the backtrace module don't have the
+        # section, which isn't a problem, they just don't need a table.
+        section = elf.section(".ksyms")
+        if section is None:
+            return
+        if elf.data[section["offset"]:section["offset"] + 4] != MAGIC:
+            sys.exit("ksyms.py: the .ksyms section doesn't hold a table")
+
+        try:
+            table = build_table(elf.functions(), section["size"])
+        except ValueError as error:
+            sys.exit("ksyms.py: {}".format(error))
+
+        table += bytes(section["size"] - len(table))
+        kernel.seek(section["offset"])
+        kernel.write(table)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,44 @@
+#!/usr/bin/env python3
+# Print the console output the kernel sends over the network (see
+# src/net/console.rs). Under QEMU's user networking, the host is 10.0.2.2 to
+# the kernel, so to watch the output of a test run:
+#
+#     tools/netconsole.py 6666 &
+#     RUSTOS_CMDLINE="netconsole=10.0.2.2:6666" cargo test
+#
+# Each datagram is a line, or part of one, and is written out as it arrives.
+# With --output, everything is saved to a file as well, such as to collect
+# test results to pass to tools/test_output_to_junit.py.
+
+import argparse
+import socket
+import sys
+
+
+def main():
+    parser = argparse.ArgumentParser(
+        description="Print the kernel's console output sent over UDP.")
+    parser.add_argument("port", type=int, nargs="?", default=6666)
+    parser.add_argument("--address", default="127.0.0.1",
+                        help="the address to listen on")
+    parser.add_argument("--output", help="a file to save the output to")
+    args = parser.parse_args()
+
+    output = open(args.output, "ab") if args.output else None
+    listener = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+    listener.bind((args.address, args.port))
+
+    try:
+        while True:
+            data, _ = listener.recvfrom(65536)
+            sys.stdout.buffer.write(data)
+            sys.stdout.buffer.flush()
+            if output:
+                output.write(data)
+                output.flush()
+    except KeyboardInterrupt:
+        pass
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,127 @@
+#!/usr/bin/env python3
+# The runner cargo uses to start the kernel in QEMU, for both cargo run and
+# cargo test. It wraps bootimage's runner, turning the arguments given after
+# '--' into a kernel command line, which is passed to QEMU as the fw_cfg file
+# opt/rustos/cmdline (see src/cmdline.rs).
+# ---
+# The arguments cargo test passes to a test binary are translated into the
+# kernel's test options:
+#
+#     cargo test -- serial --skip backspace --exact
+#
+# becomes 'test-filter=serial test-skip=backspace test-exact'. Any 'key=value'
+# arguments, such as 'log=debug' or 'test-format=tap', are passed through as
+# they are, and the RUSTOS_CMDLINE environment variable is added to the end.
+# Other cargo test options, such as --nocapture, don't apply to the kernel and
+# are ignored.
+# ---
+# Some test binaries need the machine set up differently, and are given the
+# extra QEMU arguments in TEST_QEMU_ARGUMENTS, by the name of the test.
+# ---
+# Before the kernel is started, tools/ksyms.py fills in its symbol table, so
+# backtraces show function names.
+# ---
+# The disk image the tests attach as a virtio block device (see Cargo.toml) is
+# written to target/test-disk.img before every run, rather than kept in the
+# repository. It is 128 sectors: the first starts with a signature, and every
+# other sector is filled with its own number.
+# ---
+# If the command line includes 'gdb', QEMU's second serial port is connected
+# to TCP port 4444 (or RUSTOS_GDB_PORT), for GDB to connect to the kernel's GDB
+# stub. See src/gdb.rs.
+# ---
+# If the RUSTOS_COVERAGE_DIR environment variable is set, QEMU's debug console
+# is saved to '<kernel name>.profraw' in that directory, which is where a
+# kernel built with the coverage feature writes its profile.
+
+import os
+import subprocess
+import sys
+
+TOOLS = os.path.dirname(os.path.abspath(__file__))
+TEST_DISK = os.path.join(os.path.dirname(TOOLS), "target", "test-disk.img")
+
+SECTOR_SIZE = 512
+TEST_DISK_SECTORS = 128
+TEST_DISK_SIGNATURE = b"RUSTOS TEST DISK"
+
+# cargo test options which take a value, which should be skipped along with it.
+IGNORED_WITH_VALUE = {"--test-threads", "--color", "--format", "-Z", "--logfile"}
+
+TEST_QEMU_ARGUMENTS = {
+    # tests/smp.rs checks that every CPU starts.
+    "smp": ["-smp", "4"],
+}
+
+
+def kernel_arguments(arguments):
+    kernel = []
+    arguments = iter(arguments)
+
+    for argument in arguments:
+        if argument == "--skip":
+            kernel.append("test-skip=" + next(arguments, ""))
+        elif argument.startswith("--skip="):
+            kernel.append("test-skip=" + argument[len("--skip="):])
+        elif argument == "--exact":
+            kernel.append("test-exact")
+        elif argument in IGNORED_WITH_VALUE:
+            next(arguments, None)
+        elif argument.startswith("-"):
+            continue
+        elif "=" in argument:
+            kernel.append(argument)
+        else:
+            kernel.append("test-filter=" + argument)
+
+    extra = os.environ.get("RUSTOS_CMDLINE", "").split()
+    return " ".join(kernel + extra)
+
+
+def write_test_disk(path):
+    disk = bytearray(TEST_DISK_SIGNATURE.ljust(SECTOR_SIZE, b"\0"))
+    for sector in range(1, TEST_DISK_SECTORS):
+        disk += bytes([sector]) * SECTOR_SIZE
+
+    os.makedirs(os.path.dirname(path), exist_ok=True)
+    with open(path, "wb") as f:
+        f.write(disk)
+
+
+def main():
+    if len(sys.argv) < 2:
+        sys.exit("usage: runner.py <kernel> [arguments...]")
+
+    kernel, arguments = sys.argv[1], sys.argv[2:]
+    subprocess.run([sys.executable, os.path.join(TOOLS, "ksyms.py"), kernel],
+                   check=True)
+    write_test_disk(TEST_DISK)
+
+    command = ["bootimage", "runner", kernel]
+
+    # cargo names test binaries '<test name>-<hash>'.
+    test_name = os.path.basename(kernel).rsplit("-", 1)[0]
+    command += TEST_QEMU_ARGUMENTS.get(test_name, [])
+
+    cmdline = kernel_arguments(arguments)
+    if cmdline:
+        # QEMU splits options on commas, so any in the value have to be
+        # doubled.
+        value = cmdline.replace(",", ",,")
+        command += ["-fw_cfg", "name=opt/rustos/cmdline,string=" + value]
+
+    if "gdb" in cmdline.split():
+        port = os.environ.get("RUSTOS_GDB_PORT", "4444")
+        command += ["-serial", "tcp:localhost:{},server,nowait".format(port)]
+
+    coverage_dir = os.environ.get("RUSTOS_COVERAGE_DIR")
+    if coverage_dir:
+        os.makedirs(coverage_dir, exist_ok=True)
+        name = os.path.basename(kernel) + ".profraw"
+        command += ["-debugcon", "file:" + os.path.join(coverage_dir, name)]
+
+    os.execvp(command[0], command)
+
+
+if __name__ == "__main__":
+    main()
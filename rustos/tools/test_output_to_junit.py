@@ -0,0 +1,100 @@
+#!/usr/bin/env python3
+# Convert the machine readable output of the kernel's test runner into JUnit
+# XML, which most CI systems know how to display.
+# ---
+# Reads TAP 13 or JSON lines test output (from a kernel built with the
+# test-output-tap or test-output-json feature) on standard input, or from the
+# files given, and writes the XML to standard output. Any other lines, such as
+# log output, are ignored. For example:
+#
+#     cargo test --features test-output-json | tools/test_output_to_junit.py
+#
+# Durations are in nanoseconds, and are converted to the seconds JUnit uses.
+
+import fileinput
+import json
+import re
+import sys
+from xml.etree import ElementTree
+
+TAP_RESULT = re.compile(r"^(not )?ok (\d+) - (.*?)( # SKIP.*)?$")
+
+
+def parse_json_lines(lines):
+    for line in lines:
+        try:
+            record = json.loads(line)
+        except ValueError:
+            continue
+        if isinstance(record, dict) and record.get("type") == "test":
+            yield {
+                "name": record["name"],
+                "outcome": record["outcome"],
+                "duration": record.get("duration_ns", 0),
+                "message": record.get("message", ""),
+            }
+
+
+def parse_tap(lines):
+    test = None
+    in_message = False
+
+    for line in lines:
+        match = TAP_RESULT.match(line)
+        if match:
+            if test:
+                yield test
+            failed, _, name, skip = match.groups()
+            outcome = "ignored" if skip else "failed" if failed else "passed"
+            test = {"name": name, "outcome": outcome, "duration": 0,
+                    "message": ""}
+            in_message = False
+        elif test and line.startswith("  duration_ns: "):
+            test["duration"] = int(line.split(":", 1)[1])
+        elif test and line == "  message: |":
+            in_message = True
+        elif test and in_message and line.startswith("    "):
+            test["message"] += line[4:] + "\n"
+        else:
+            in_message = False
+
+    if test:
+        yield test
+
+
+def to_junit(tests):
+    suite = ElementTree.Element("testsuite", name="rustos")
+    counts = {"passed": 0, "failed": 0, "ignored": 0}
+
+    for test in tests:
+        counts[test["outcome"]] += 1
+        module, _, name = test["name"].rpartition("::")
+        seconds = test["duration"] / 1e9
+        case = ElementTree.SubElement(suite, "testcase", classname=module,
+                                      name=name, time=str(seconds))
+        if test["outcome"] == "failed":
+            failure = ElementTree.SubElement(case, "failure",
+                                             message=test["message"].strip())
+            failure.text = test["message"]
+        elif test["outcome"] == "ignored":
+            ElementTree.SubElement(case, "skipped")
+
+    suite.set("tests", str(sum(counts.values())))
+    suite.set("failures", str(counts["failed"]))
+    suite.set("skipped", str(counts["ignored"]))
+    return ElementTree.ElementTree(suite)
+
+
+def main():
+    lines = [line.rstrip("\r\n") for line in fileinput.input()]
+    if any(line.startswith("TAP version") for line in lines):
+        tests = parse_tap(lines)
+    else:
+        tests = parse_json_lines(lines)
+
+    to_junit(tests).write(sys.stdout, encoding="unicode", xml_declaration=True)
+    sys.stdout.write("\n")
+
+
+if __name__ == "__main__":
+    main()